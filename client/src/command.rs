@@ -0,0 +1,333 @@
+use std::collections::HashMap;
+use std::sync::{
+    mpsc::{self, Receiver, Sender, TryRecvError},
+    Arc, Mutex,
+};
+use std::time::{Duration, Instant};
+
+pub use portuni_common::command::{Command, CommandResult, Uplink};
+pub use portuni_common::pairing::DeviceId;
+pub use portuni_common::protocol::{RelayFrame, DEFAULT_PIPE};
+pub use portuni_common::radio::RadioConfig;
+
+use crate::secure::Sessions;
+
+/// Time within which the drone is expected to acknowledge a command, it only listens for
+/// commands every 100 ms and the relay may need a few of those windows
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(2);
+
+// Keyed by the relay pipe and id of the command, along with its deadline
+type Pending = Arc<Mutex<HashMap<(u8, u8), (Sender<CommandResult>, Instant)>>>;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CommandError {
+    /// The drone did not acknowledge the command in time
+    Timeout,
+    /// The serial thread stopped, so the command was never sent
+    Disconnected,
+    /// There is no session with the drone to seal the command with
+    NoSession,
+}
+
+/// Sends commands to the drones through the relay, inserted as a resource by the transceiver
+pub struct CommandLink {
+    relay: Mutex<Sender<RelayFrame>>,
+    pending: Pending,
+    // Id of the next command, by relay pipe. The drone only recognizes repeats of its last
+    // command, so the ids are counted from its nonce once it is paired.
+    next_ids: HashMap<u8, u8>,
+    // Commands are sealed if there is a pre-shared key
+    sessions: Option<Sessions>,
+}
+
+/// Hands acknowledgements received by the serial thread to the commands waiting for them
+#[derive(Clone)]
+pub struct Acknowledgements {
+    pending: Pending,
+}
+
+/// Returns the link for sending commands, `relay` is written to the serial port
+pub fn link(relay: Sender<RelayFrame>) -> (CommandLink, Acknowledgements) {
+    new_link(relay, None)
+}
+
+/// Returns a link that seals the commands with the keys of `sessions`
+pub fn secure_link(
+    relay: Sender<RelayFrame>,
+    sessions: Sessions,
+) -> (CommandLink, Acknowledgements) {
+    new_link(relay, Some(sessions))
+}
+
+fn new_link(
+    relay: Sender<RelayFrame>,
+    sessions: Option<Sessions>,
+) -> (CommandLink, Acknowledgements) {
+    let pending: Pending = Arc::new(Mutex::new(HashMap::new()));
+
+    let link = CommandLink {
+        relay: Mutex::new(relay),
+        pending: pending.clone(),
+        next_ids: HashMap::new(),
+        sessions,
+    };
+
+    (link, Acknowledgements { pending })
+}
+
+impl CommandLink {
+    /// Send a command to the drone on `pipe` of the relay
+    pub fn send(&mut self, pipe: u8, command: Command, timeout: Duration) -> PendingCommand {
+        let uplink = self.uplink(pipe, command);
+
+        let frame = match &self.sessions {
+            Some(sessions) => sessions
+                .seal(pipe, &uplink)
+                .map(|(len, payload)| RelayFrame::Sealed { pipe, len, payload }),
+            None => Some(RelayFrame::Uplink { pipe, uplink }),
+        };
+
+        self.dispatch(pipe, uplink.id, frame, timeout)
+    }
+
+    /// Send `Command::Pair` to the drone with `id` on the default addresses, which is received on
+    /// `pipe` once it moved. With a pre-shared key this starts a session, of which `nonce` is part
+    pub fn pair(
+        &mut self,
+        id: DeviceId,
+        nonce: u32,
+        pipe: u8,
+        timeout: Duration,
+    ) -> PendingCommand {
+        self.next_ids.insert(pipe, nonce as u8);
+        let uplink = self.uplink(pipe, Command::Pair(id));
+
+        let frame = match &self.sessions {
+            Some(sessions) => sessions
+                .pair(&uplink, &id, nonce, pipe)
+                .map(|(len, payload)| RelayFrame::Sealed {
+                    pipe: DEFAULT_PIPE,
+                    len,
+                    payload,
+                }),
+            None => Some(RelayFrame::Uplink {
+                pipe: DEFAULT_PIPE,
+                uplink,
+            }),
+        };
+
+        self.dispatch(DEFAULT_PIPE, uplink.id, frame, timeout)
+    }
+
+    /// The next uplink to the drone on `pipe`
+    fn uplink(&mut self, pipe: u8, command: Command) -> Uplink {
+        let next_id = self.next_ids.entry(pipe).or_insert(0);
+        let id = *next_id;
+        *next_id = next_id.wrapping_add(1);
+
+        Uplink { id, command }
+    }
+
+    /// `frame` is `None` if the command could not be sealed
+    fn dispatch(
+        &mut self,
+        pipe: u8,
+        id: u8,
+        frame: Option<RelayFrame>,
+        timeout: Duration,
+    ) -> PendingCommand {
+        let (send, recv) = mpsc::channel();
+        let deadline = Instant::now() + timeout;
+
+        let frame = match frame {
+            Some(frame) => frame,
+            None => {
+                return PendingCommand {
+                    recv,
+                    deadline,
+                    error: Some(CommandError::NoSession),
+                }
+            }
+        };
+
+        // Replaces a command with the same id, which timed out long ago
+        self.pending
+            .lock()
+            .unwrap()
+            .insert((pipe, id), (send, deadline));
+
+        let sent = self.relay.lock().unwrap().send(frame).is_ok();
+
+        PendingCommand {
+            recv,
+            deadline,
+            error: if sent {
+                None
+            } else {
+                Some(CommandError::Disconnected)
+            },
+        }
+    }
+
+    /// Whether no command to the drone on `pipe` is waiting for its acknowledgement, the relay
+    /// only holds a single command per pipe
+    pub fn is_idle(&self, pipe: u8) -> bool {
+        let now = Instant::now();
+
+        !self
+            .pending
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|(&(p, _), &(_, deadline))| p == pipe && deadline > now)
+    }
+
+    /// Move the relay to another channel or data rate, or `pipe` to other addresses, which drops
+    /// the command pending for that pipe
+    pub fn configure_relay(&mut self, pipe: u8, config: RadioConfig) -> Result<(), CommandError> {
+        self.relay
+            .lock()
+            .unwrap()
+            .send(RelayFrame::Configure { pipe, config })
+            .map_err(|_| CommandError::Disconnected)
+    }
+}
+
+impl Acknowledgements {
+    /// Repeated acknowledgements of a command are ignored
+    pub fn acknowledge(&self, pipe: u8, id: u8, result: CommandResult) {
+        if let Some((send, _)) = self.pending.lock().unwrap().remove(&(pipe, id)) {
+            let _ = send.send(result);
+        }
+    }
+}
+
+/// A command that was sent, which is polled until it is acknowledged or times out
+pub struct PendingCommand {
+    recv: Receiver<CommandResult>,
+    deadline: Instant,
+    // Why the command was never sent
+    error: Option<CommandError>,
+}
+
+impl PendingCommand {
+    /// Returns `None` while the acknowledgement can still arrive
+    pub fn poll(&self) -> Option<Result<CommandResult, CommandError>> {
+        if let Some(error) = self.error {
+            return Some(Err(error));
+        }
+
+        match self.recv.try_recv() {
+            Ok(result) => Some(Ok(result)),
+            Err(TryRecvError::Empty) if Instant::now() < self.deadline => None,
+            Err(_) => Some(Err(CommandError::Timeout)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_acknowledge() {
+        let (send, recv) = mpsc::channel();
+        let (mut link, acks) = link(send);
+
+        let first = link.send(2, Command::LedTest, DEFAULT_TIMEOUT);
+        let second = link.send(2, Command::SetSampleRate(0), DEFAULT_TIMEOUT);
+
+        let ids: Vec<u8> = recv
+            .try_iter()
+            .filter_map(|frame| match frame {
+                RelayFrame::Uplink { uplink, .. } => Some(uplink.id),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(ids, vec![0, 1]);
+        assert_eq!(first.poll(), None);
+
+        acks.acknowledge(2, 1, CommandResult::Invalid);
+        acks.acknowledge(2, 1, CommandResult::Invalid);
+
+        // Only the drone the command was sent to can acknowledge it
+        acks.acknowledge(3, 0, CommandResult::Ok);
+
+        assert_eq!(first.poll(), None);
+        assert_eq!(second.poll(), Some(Ok(CommandResult::Invalid)));
+        assert!(!link.is_idle(2));
+        assert!(link.is_idle(3));
+
+        acks.acknowledge(2, 0, CommandResult::Ok);
+        assert!(link.is_idle(2));
+    }
+
+    #[test]
+    fn test_ids() {
+        let (send, recv) = mpsc::channel();
+        let (mut link, _acks) = link(send);
+
+        let _first = link.send(2, Command::LedTest, DEFAULT_TIMEOUT);
+        let _other = link.send(3, Command::LedTest, DEFAULT_TIMEOUT);
+
+        // A drone paired again is sent the ids of the new link, counted from its nonce
+        let _pair = link.pair([1; 12], 0x1234_56fe, 2, DEFAULT_TIMEOUT);
+        let _second = link.send(2, Command::LedTest, DEFAULT_TIMEOUT);
+        let _third = link.send(2, Command::LedTest, DEFAULT_TIMEOUT);
+
+        let ids: Vec<(u8, u8)> = recv
+            .try_iter()
+            .filter_map(|frame| match frame {
+                RelayFrame::Uplink { pipe, uplink } => Some((pipe, uplink.id)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            ids,
+            vec![(2, 0), (3, 0), (DEFAULT_PIPE, 0xfe), (2, 0xff), (2, 0)]
+        );
+    }
+
+    #[test]
+    fn test_timeout() {
+        let (send, _recv) = mpsc::channel();
+        let (mut link, _acks) = link(send);
+
+        let pending = link.send(2, Command::Reboot, Duration::from_millis(0));
+        assert_eq!(pending.poll(), Some(Err(CommandError::Timeout)));
+        assert!(link.is_idle(2));
+    }
+
+    #[test]
+    fn test_disconnected() {
+        let (send, recv) = mpsc::channel();
+        let (mut link, _acks) = link(send);
+        drop(recv);
+
+        let pending = link.send(DEFAULT_PIPE, Command::RequestStatus, DEFAULT_TIMEOUT);
+        assert_eq!(pending.poll(), Some(Err(CommandError::Disconnected)));
+    }
+
+    #[test]
+    fn test_no_session() {
+        let (send, recv) = mpsc::channel();
+        let (mut link, _acks) = secure_link(send, Sessions::new([0; 32]));
+
+        // Only the drone that is paired can be sent commands
+        let pending = link.send(2, Command::LedTest, DEFAULT_TIMEOUT);
+        assert_eq!(pending.poll(), Some(Err(CommandError::NoSession)));
+
+        let _pending = link.pair([1; 12], 7, 2, DEFAULT_TIMEOUT);
+        let pending = link.send(2, Command::LedTest, DEFAULT_TIMEOUT);
+        assert_eq!(pending.poll(), None);
+
+        let pipes: Vec<u8> = recv
+            .try_iter()
+            .filter_map(|frame| match frame {
+                RelayFrame::Sealed { pipe, .. } => Some(pipe),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(pipes, vec![DEFAULT_PIPE, 2]);
+    }
+}
@@ -0,0 +1,133 @@
+use amethyst::{
+    core::math::{Quaternion, UnitQuaternion, Vector3},
+    ecs::prelude::{Component, DenseVecStorage},
+};
+
+/// Orientation of the drone in the scene, where the y axis points up.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Attitude {
+    pub orientation: UnitQuaternion<f32>,
+}
+
+impl Default for Attitude {
+    fn default() -> Self {
+        Self {
+            orientation: UnitQuaternion::identity(),
+        }
+    }
+}
+
+impl Component for Attitude {
+    type Storage = DenseVecStorage<Self>;
+}
+
+/// A vector in the drone's frame, of which the z axis points up, as seen in the scene. The frames
+/// are a quarter turn around x apart, so the drone's y axis points along -z.
+pub fn to_scene(x: f32, y: f32, z: f32) -> Vector3<f32> {
+    Vector3::new(x, z, -y)
+}
+
+impl Attitude {
+    /// Integrate angular rates (rad/s) in the body frame over `dt` seconds.
+    pub fn rotate(&mut self, rates: Vector3<f32>, dt: f32) {
+        self.orientation *= UnitQuaternion::from_scaled_axis(rates * dt);
+    }
+
+    /// Take the orientation the drone estimated, as w, x, y and z. Its axes map onto the scene
+    /// like the angular rates do.
+    pub fn set(&mut self, quaternion: [f32; 4]) {
+        let [w, x, y, z] = quaternion;
+        let axis = to_scene(x, y, z);
+
+        self.orientation =
+            UnitQuaternion::from_quaternion(Quaternion::new(w, axis.x, axis.y, axis.z));
+    }
+
+    /// Remove pitch and roll, but keep the heading around the y axis.
+    pub fn level(&mut self) {
+        let q = self.orientation.quaternion();
+
+        // Swing-twist decomposition, the twist around y is the heading
+        self.orientation = if q.w == 0.0 && q.j == 0.0 {
+            UnitQuaternion::identity()
+        } else {
+            UnitQuaternion::from_quaternion(Quaternion::new(q.w, 0.0, q.j, 0.0))
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use approx::*;
+    use std::f32::consts::FRAC_PI_2;
+
+    #[test]
+    fn test_rotate() {
+        let mut attitude = Attitude::default();
+
+        // A quarter turn per second around y for one second
+        attitude.rotate(Vector3::new(0.0, FRAC_PI_2, 0.0), 1.0);
+        assert_abs_diff_eq!(attitude.orientation.angle(), FRAC_PI_2, epsilon = 1e-6);
+    }
+
+    #[test]
+    fn test_set() {
+        let mut attitude = Attitude::default();
+
+        // A quarter turn around the drone's z axis, as fused from the same rate
+        let half = std::f32::consts::FRAC_1_SQRT_2;
+        attitude.set([half, 0.0, 0.0, half]);
+
+        let mut integrated = Attitude::default();
+        integrated.rotate(to_scene(0.0, 0.0, FRAC_PI_2), 1.0);
+        assert_abs_diff_eq!(
+            attitude.orientation.angle_to(&integrated.orientation),
+            0.0,
+            epsilon = 1e-6
+        );
+    }
+
+    #[test]
+    fn test_yaw() {
+        let mut attitude = Attitude::default();
+
+        // A positive yaw rate turns the drone's x axis towards its y axis, to the left
+        attitude.rotate(to_scene(0.0, 0.0, FRAC_PI_2), 1.0);
+        assert_abs_diff_eq!(
+            attitude.orientation * to_scene(1.0, 0.0, 0.0),
+            to_scene(0.0, 1.0, 0.0),
+            epsilon = 1e-6
+        );
+
+        // The same quarter turn, as fused by the drone
+        let half = std::f32::consts::FRAC_1_SQRT_2;
+        attitude.set([half, 0.0, 0.0, half]);
+        assert_abs_diff_eq!(
+            attitude.orientation * to_scene(1.0, 0.0, 0.0),
+            to_scene(0.0, 1.0, 0.0),
+            epsilon = 1e-6
+        );
+    }
+
+    #[test]
+    fn test_level() {
+        let heading = UnitQuaternion::from_axis_angle(&Vector3::y_axis(), 1.0);
+        let tilt = UnitQuaternion::from_axis_angle(&Vector3::x_axis(), 0.5);
+
+        let mut attitude = Attitude {
+            orientation: heading * tilt,
+        };
+        attitude.level();
+
+        // Only the heading should remain
+        assert_abs_diff_eq!(attitude.orientation.angle_to(&heading), 0.0, epsilon = 1e-6);
+
+        // Upside down without a heading falls back to level
+        let mut attitude = Attitude {
+            orientation: UnitQuaternion::from_axis_angle(&Vector3::x_axis(), std::f32::consts::PI),
+        };
+        attitude.level();
+        assert_abs_diff_eq!(attitude.orientation.angle(), 0.0, epsilon = 1e-6);
+    }
+}
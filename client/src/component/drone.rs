@@ -0,0 +1,122 @@
+use std::time::Instant;
+
+use amethyst::{
+    core::math::Vector3,
+    ecs::prelude::{Component, DenseVecStorage},
+};
+
+use portuni_common::protocol::{DeviceStatus, Orientation, Telemetry};
+
+use crate::utils::interp::MovingAverage;
+
+/// Gaps between samples longer than this, e.g. after a reset of the drone, are not integrated
+const MAX_SAMPLE_INTERVAL: f32 = 0.5;
+
+/// Seconds between two sample timestamps, which are allowed to wrap
+fn sample_interval(previous: u32, current: u32) -> Option<f32> {
+    let dt = current.wrapping_sub(previous) as f32 / 1_000_000.0;
+
+    if dt <= MAX_SAMPLE_INTERVAL {
+        Some(dt)
+    } else {
+        None
+    }
+}
+
+/// A paired drone in the scene, with the filter state of its telemetry
+pub struct Drone {
+    /// Pipe of the relay the drone is received on
+    pub pipe: u8,
+    /// Position of its model, next to the other drones
+    pub position: Vector3<f32>,
+    /// Set when `position` changed, until the model was moved there
+    pub moved: bool,
+    /// Last sample and status the drone sent
+    pub latest: Option<Telemetry>,
+    pub status: Option<DeviceStatus>,
+    /// Last attitude the drone estimated, once it sends them its samples are no longer integrated
+    pub orientation: Option<Orientation>,
+    /// Host time at which the last sample was taken, once the drone's clock is synchronized
+    pub taken: Option<Instant>,
+    mag_x_avg: MovingAverage,
+    mag_y_avg: MovingAverage,
+    last_timestamp: Option<u32>,
+}
+
+impl Component for Drone {
+    type Storage = DenseVecStorage<Self>;
+}
+
+impl Drone {
+    pub fn new(pipe: u8) -> Drone {
+        Drone {
+            pipe,
+            position: Vector3::zeros(),
+            moved: true,
+            latest: None,
+            status: None,
+            orientation: None,
+            taken: None,
+            mag_x_avg: MovingAverage::new(32, None),
+            mag_y_avg: MovingAverage::new(32, None),
+            last_timestamp: None,
+        }
+    }
+
+    /// Filters a sample, returns the seconds since the previous one if they can be integrated
+    pub fn add(&mut self, value: Telemetry) -> Option<f32> {
+        let dt = self
+            .last_timestamp
+            .and_then(|previous| sample_interval(previous, value.timestamp));
+
+        self.last_timestamp = Some(value.timestamp);
+
+        self.mag_x_avg.add(value.mag_x as f64);
+        self.mag_y_avg.add(value.mag_y as f64);
+
+        self.latest = Some(value);
+
+        dt
+    }
+
+    /// Heading in degrees, from the averaged magnetic field
+    pub fn heading(&self) -> f32 {
+        crate::compass::coords_to_degrees((
+            self.mag_x_avg.get_avg() as f32,
+            self.mag_y_avg.get_avg() as f32,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(timestamp: u32) -> Telemetry {
+        Telemetry {
+            timestamp,
+            mag_x: 0,
+            mag_y: 0,
+            mag_z: 0,
+            accel_x: 0,
+            accel_y: 0,
+            accel_z: 1000,
+            gyro_x: 0.0,
+            gyro_y: 0.0,
+            gyro_z: 0.0,
+            temp: 20,
+        }
+    }
+
+    #[test]
+    fn test_add() {
+        let mut drone = Drone::new(2);
+
+        assert_eq!(drone.add(sample(u32::MAX - 9_999)), None);
+        assert_eq!(drone.add(sample(10_000)), Some(0.02));
+
+        // A gap, e.g. after a reset, is not integrated
+        assert_eq!(drone.add(sample(1_000_000)), None);
+        assert_eq!(drone.latest.as_ref().map(|s| s.timestamp), Some(1_000_000));
+    }
+}
@@ -0,0 +1,4 @@
+pub mod attitude;
+pub mod drone;
+
+pub use self::{attitude::Attitude, drone::Drone};
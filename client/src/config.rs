@@ -1,3 +1,4 @@
+use portuni_common::{command::Encoding, pairing::DeviceId, radio::RadioConfig, secure::Key};
 use serde::{Deserialize, Serialize};
 use std::time::Duration;
 
@@ -25,7 +26,7 @@ mod shim_baud_rate {
 }
 
 fn default_baud_rate() -> u32 {
-    9600
+    115_200
 }
 
 #[derive(Serialize, Deserialize)]
@@ -116,6 +117,30 @@ fn default_parity() -> serialport::Parity {
     serialport::Parity::None
 }
 
+mod shim_key {
+    use portuni_common::secure::{parse_key, Key};
+    use serde::{de::Error, Deserialize, Deserializer, Serialize, Serializer};
+
+    pub fn serialize<S: Serializer>(v: &Option<Key>, s: S) -> Result<S::Ok, S::Error> {
+        let v: Option<String> =
+            v.map(|key| key.iter().map(|byte| format!("{:02x}", byte)).collect());
+
+        v.serialize(s)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Option<Key>, D::Error> {
+        match Option::<String>::deserialize(d)? {
+            Some(v) => match parse_key(&v) {
+                Some(key) => Ok(Some(key)),
+                None => Err(D::Error::custom(
+                    "Invalid key, expected 64 hexadecimal digits",
+                )),
+            },
+            None => Ok(None),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(default)]
 pub struct TransceiverSettings {
@@ -139,19 +164,153 @@ pub struct TransceiverSettings {
 
     // TODO: User can not currently enter a custom time duration
     pub timeout: Duration,
+
+    /// Channel and data rate the drone and relay are moved to after pairing, the addresses are
+    /// derived from the drone's id instead
+    pub radio: RadioConfig,
+
+    /// Telemetry encoding proposed to the drones while pairing, `Compact` saves 4 bytes per sample
+    pub encoding: Encoding,
+
+    /// Samples the drones send per message, up to 4. More samples take fewer payloads, but
+    /// arrive later
+    pub batch_size: u8,
+
+    /// Time without any message after which the link to a drone is lost, and without any
+    /// command after which the drone engages its failsafe. At least 500 ms
+    pub link_timeout_ms: u16,
+
+    /// Pre-shared key as 64 hexadecimal digits, the same as `PORTUNI_KEY` of the firmware. Radio
+    /// frames are only sealed if it is set
+    #[serde(with = "shim_key")]
+    pub key: Option<Key>,
 }
 
 impl Default for TransceiverSettings {
     fn default() -> Self {
         Self {
-            vid: 0x2341,
-            pid: 0x0043,
+            // The ST-LINK of the relay's STM32F3DISCOVERY, as in `config/config.ron`
+            vid: 0x0483,
+            pid: 0x374b,
             baud_rate: 115_200,
             flow_control: serialport::FlowControl::None,
             data_bits: serialport::DataBits::Eight,
             stop_bits: serialport::StopBits::One,
             parity: serialport::Parity::None,
             timeout: Duration::from_millis(10),
+            radio: RadioConfig::default(),
+            encoding: Encoding::Compact,
+            batch_size: 1,
+            link_timeout_ms: 1000,
+            key: None,
+        }
+    }
+}
+
+/// Offset between the drone's body frame and the orientation of its model
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ModelOffset {
+    /// Rotation around the x, y and z axis in degrees
+    pub rotation: [f32; 3],
+    pub translation: [f32; 3],
+}
+
+impl Default for ModelOffset {
+    fn default() -> Self {
+        Self {
+            rotation: [0.0, 0.0, 0.0],
+            translation: [0.0, 0.0, 0.0],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DroneSettings {
+    pub model_offset: ModelOffset,
+}
+
+mod shim_device_id {
+    use portuni_common::pairing::DeviceId;
+    use serde::{de::Error, Deserialize, Deserializer, Serialize, Serializer};
+
+    pub fn serialize<S: Serializer>(v: &DeviceId, s: S) -> Result<S::Ok, S::Error> {
+        let v: String = v.iter().map(|byte| format!("{:02x}", byte)).collect();
+
+        v.serialize(s)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<DeviceId, D::Error> {
+        let v = String::deserialize(d)?;
+        let mut id = DeviceId::default();
+
+        if v.len() != 2 * id.len() || !v.is_ascii() {
+            return Err(D::Error::custom(format_args!("Invalid device id {}", v)));
+        }
+
+        for (i, byte) in id.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&v[2 * i..2 * i + 2], 16)
+                .map_err(|_| D::Error::custom(format_args!("Invalid device id {}", v)))?;
+        }
+
+        Ok(id)
+    }
+}
+
+/// A drone the client paired with, it is paired again without confirmation when it advertises
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PairedDevice {
+    pub name: String,
+    /// The drone's 96-bit unique id, as 24 hexadecimal digits
+    #[serde(with = "shim_device_id")]
+    pub id: DeviceId,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PairedDevices {
+    pub devices: Vec<PairedDevice>,
+}
+
+impl PairedDevices {
+    pub fn get(&self, id: &DeviceId) -> Option<&PairedDevice> {
+        self.devices.iter().find(|device| device.id == *id)
+    }
+
+    /// Adds the drone under a default name, unless it was paired before
+    pub fn add(&mut self, id: DeviceId) -> &PairedDevice {
+        match self.devices.iter().position(|device| device.id == id) {
+            Some(index) => &self.devices[index],
+            None => {
+                let name = format!("drone {}", self.devices.len() + 1);
+                self.devices.push(PairedDevice { name, id });
+                &self.devices[self.devices.len() - 1]
+            }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_paired_devices() {
+        let mut paired = PairedDevices::default();
+        let id = [
+            0x01, 0x23, 0x45, 0x67, 0x89, 0xab, 0xcd, 0xef, 0x00, 0x11, 0x22, 0xff,
+        ];
+
+        assert_eq!(paired.add(id).name, "drone 1");
+        assert_eq!(paired.add(id).name, "drone 1");
+        assert_eq!(paired.devices.len(), 1);
+
+        let text = ron::ser::to_string(&paired).unwrap();
+        assert!(text.contains("\"0123456789abcdef001122ff\""));
+
+        let loaded: PairedDevices = ron::de::from_str(&text).unwrap();
+        assert_eq!(loaded.get(&id), paired.get(&id));
+        assert!(ron::de::from_str::<PairedDevice>("(name: \"\", id: \"0123\")").is_err());
+    }
+}
@@ -0,0 +1,122 @@
+//! Writing the drone's config from `config/device.ron`, see `portuni_common::config`
+use std::collections::VecDeque;
+
+use portuni_common::command::{Command, CommandResult};
+use portuni_common::config::{DeviceConfig, Sensor};
+
+use crate::command::{CommandError, PendingCommand};
+
+/// Commands that change every setting of `config` on the drone, and save it
+///
+/// Uplinks aren't fragmented, so the config is sent a part at a time.
+pub fn commands(config: &DeviceConfig) -> Vec<Command> {
+    let calibration = config.calibration;
+
+    vec![
+        Command::SetGyroScale(config.gyro_scale),
+        Command::SetMagOdr(config.mag_odr),
+        Command::SetCalibration {
+            sensor: Sensor::Gyro,
+            offset: calibration.gyro,
+        },
+        Command::SetCalibration {
+            sensor: Sensor::Accel,
+            offset: calibration.accel,
+        },
+        Command::SetCalibration {
+            sensor: Sensor::Mag,
+            offset: calibration.mag,
+        },
+        Command::SetChannel(config.channel),
+        Command::SaveConfig,
+    ]
+}
+
+/// Short description for the command label, the calibration is left out
+pub fn summary(config: &DeviceConfig) -> String {
+    format!(
+        "config: {:?}, mag {:?}, channel {}",
+        config.gyro_scale, config.mag_odr, config.channel
+    )
+}
+
+/// How a write of the config ended
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WriteResult {
+    Saved,
+    Rejected(Command, CommandResult),
+    Error(Command, CommandError),
+}
+
+/// Commands of a config write, of which the next one is only sent once the previous one is
+/// acknowledged, since the relay holds one command per drone
+pub struct ConfigWrite {
+    pipe: u8,
+    remaining: VecDeque<Command>,
+    pending: Option<(Command, PendingCommand)>,
+}
+
+impl ConfigWrite {
+    pub fn new(pipe: u8, config: &DeviceConfig) -> ConfigWrite {
+        ConfigWrite {
+            pipe,
+            remaining: commands(config).into(),
+            pending: None,
+        }
+    }
+
+    pub fn pipe(&self) -> u8 {
+        self.pipe
+    }
+
+    /// The next command to send, if the previous one was acknowledged
+    pub fn next_command(&self) -> Option<Command> {
+        if self.pending.is_some() {
+            return None;
+        }
+
+        self.remaining.front().copied()
+    }
+
+    pub fn sent(&mut self, pending: PendingCommand) {
+        if let Some(command) = self.remaining.pop_front() {
+            self.pending = Some((command, pending));
+        }
+    }
+
+    /// The result once every command was acknowledged, or one of them wasn't applied
+    pub fn poll(&mut self) -> Option<WriteResult> {
+        let (command, pending) = self.pending.as_ref()?;
+        let command = *command;
+
+        let result = match pending.poll()? {
+            Ok(CommandResult::Ok) if self.remaining.is_empty() => Some(WriteResult::Saved),
+            Ok(CommandResult::Ok) => None,
+            Ok(result) => Some(WriteResult::Rejected(command, result)),
+            Err(error) => Some(WriteResult::Error(command, error)),
+        };
+
+        self.pending = None;
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_commands() {
+        let mut config = DeviceConfig::default();
+        config.calibration.mag = [10, -20, 30];
+
+        let commands = commands(&config);
+        assert_eq!(commands.len(), 7);
+        assert!(commands.contains(&Command::SetCalibration {
+            sensor: Sensor::Mag,
+            offset: [10, -20, 30],
+        }));
+        // Nothing is saved before every setting is changed
+        assert_eq!(commands.last(), Some(&Command::SaveConfig));
+    }
+}
@@ -0,0 +1,241 @@
+//! Log records the drones send with the `radio-log` feature, see `portuni_common::log`
+use std::collections::VecDeque;
+
+use portuni_common::log::{Code, Level};
+use serde::{Deserialize, Serialize};
+
+/// Target of the records in the client's log output, which can be filtered on its own
+pub const FIRMWARE_TARGET: &str = "portuni::firmware";
+
+/// Records kept for the panel, older ones are dropped
+const CAPACITY: usize = 500;
+
+pub const LEVELS: [Level; 5] = [
+    Level::Trace,
+    Level::Debug,
+    Level::Info,
+    Level::Warn,
+    Level::Error,
+];
+
+pub fn log_level(level: Level) -> log::Level {
+    match level {
+        Level::Trace => log::Level::Trace,
+        Level::Debug => log::Level::Debug,
+        Level::Info => log::Level::Info,
+        Level::Warn => log::Level::Warn,
+        Level::Error => log::Level::Error,
+    }
+}
+
+/// What the record says, arguments the code doesn't take are left out
+pub fn describe(code: Code, args: &[i32]) -> String {
+    let arg = |i: usize| args.get(i).copied().unwrap_or_default();
+
+    match code {
+        Code::Boot if arg(0) != 0 => "booted after a watchdog reset".to_string(),
+        Code::Boot => "booted".to_string(),
+        Code::Radio => format!("radio on channel {}, data rate {}", arg(0), arg(1)),
+        Code::Fault => format!("recovered from fault {}", arg(0)),
+        Code::Command => format!("command {}", arg(0)),
+        Code::RadioSwitched if arg(1) != 0 => format!("moved to channel {}, paired", arg(0)),
+        Code::RadioSwitched => format!("moved to channel {}, unpaired", arg(0)),
+        Code::RadioFallback => "new link not confirmed, fell back".to_string(),
+        Code::LinkLost => "heartbeats stopped, failsafe engaged".to_string(),
+        Code::LinkRestored => "link restored".to_string(),
+        Code::Uplink => format!("uplink on pipe {}", arg(0)),
+        Code::RadioReset => "radio reset".to_string(),
+        Code::ConfigLoaded if arg(0) != 0 => "loaded the saved config".to_string(),
+        Code::ConfigLoaded => "no saved config, using the defaults".to_string(),
+        Code::ConfigSaved => "config saved".to_string(),
+        Code::BlackboxOpened => format!("blackbox keeps {} samples", arg(0)),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entry {
+    // Name of the drone at the time, or its pipe while unpaired
+    pub drone: String,
+    pub level: Level,
+    pub text: String,
+}
+
+/// Levels of the records shown, in the panel and in the client's log output
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LogSettings {
+    /// Lowest level written to the client's log output
+    pub output: Level,
+    /// Levels shown in the panel, toggled with the number keys
+    pub panel: Vec<Level>,
+}
+
+impl Default for LogSettings {
+    fn default() -> Self {
+        Self {
+            output: Level::Info,
+            panel: vec![Level::Info, Level::Warn, Level::Error],
+        }
+    }
+}
+
+/// The latest records of all drones, newest last
+pub struct FirmwareLog {
+    entries: VecDeque<Entry>,
+    shown: Vec<Level>,
+    // Shown records skipped from the newest one, the panel follows new records at 0
+    scroll: usize,
+    // Bumped on every change, so the panel is only redrawn when needed
+    pub version: u32,
+}
+
+impl Default for FirmwareLog {
+    fn default() -> Self {
+        FirmwareLog::new(&LogSettings::default().panel)
+    }
+}
+
+impl FirmwareLog {
+    pub fn new(shown: &[Level]) -> FirmwareLog {
+        FirmwareLog {
+            entries: VecDeque::new(),
+            shown: shown.to_vec(),
+            scroll: 0,
+            version: 0,
+        }
+    }
+
+    pub fn push(&mut self, entry: Entry) {
+        if self.entries.len() == CAPACITY {
+            self.entries.pop_front();
+        }
+
+        // A scrolled panel keeps showing the same records
+        if self.scroll > 0 && self.is_shown(entry.level) {
+            self.scroll += 1;
+        }
+
+        self.entries.push_back(entry);
+        self.version = self.version.wrapping_add(1);
+    }
+
+    pub fn is_shown(&self, level: Level) -> bool {
+        self.shown.contains(&level)
+    }
+
+    pub fn toggle(&mut self, level: Level) {
+        match self.shown.iter().position(|&shown| shown == level) {
+            Some(index) => {
+                self.shown.remove(index);
+            }
+            None => self.shown.push(level),
+        }
+
+        self.scroll = 0;
+        self.version = self.version.wrapping_add(1);
+    }
+
+    /// Scroll towards older records by `lines`, or newer ones if negative
+    pub fn scroll(&mut self, lines: isize) {
+        let shown = self.shown().count();
+        let scroll = (self.scroll as isize + lines).max(0) as usize;
+
+        self.scroll = scroll.min(shown.saturating_sub(1));
+        self.version = self.version.wrapping_add(1);
+    }
+
+    pub fn scroll_to_end(&mut self) {
+        self.scroll = 0;
+        self.version = self.version.wrapping_add(1);
+    }
+
+    fn shown(&self) -> impl DoubleEndedIterator<Item = &Entry> {
+        self.entries
+            .iter()
+            .filter(move |entry| self.is_shown(entry.level))
+    }
+
+    /// Up to `lines` of the shown records at the current scroll position, oldest first
+    pub fn page(&self, lines: usize) -> Vec<&Entry> {
+        let mut page: Vec<_> = self.shown().rev().skip(self.scroll).take(lines).collect();
+        page.reverse();
+        page
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(level: Level, text: &str) -> Entry {
+        Entry {
+            drone: "drone 1".to_string(),
+            level,
+            text: text.to_string(),
+        }
+    }
+
+    fn texts(page: Vec<&Entry>) -> Vec<&str> {
+        page.iter().map(|entry| entry.text.as_str()).collect()
+    }
+
+    #[test]
+    fn test_describe() {
+        assert_eq!(describe(Code::Boot, &[1]), "booted after a watchdog reset");
+        assert_eq!(
+            describe(Code::RadioSwitched, &[76, 1]),
+            "moved to channel 76, paired"
+        );
+        // Missing arguments are zero
+        assert_eq!(describe(Code::Command, &[]), "command 0");
+    }
+
+    #[test]
+    fn test_filter() {
+        let mut log = FirmwareLog::new(&[Level::Warn]);
+        log.push(entry(Level::Info, "a"));
+        log.push(entry(Level::Warn, "b"));
+        log.push(entry(Level::Debug, "c"));
+
+        assert_eq!(texts(log.page(10)), ["b"]);
+
+        log.toggle(Level::Info);
+        assert_eq!(texts(log.page(10)), ["a", "b"]);
+
+        log.toggle(Level::Warn);
+        assert_eq!(texts(log.page(10)), ["a"]);
+    }
+
+    #[test]
+    fn test_scroll() {
+        let mut log = FirmwareLog::new(&[Level::Info]);
+        for text in &["a", "b", "c", "d"] {
+            log.push(entry(Level::Info, text));
+        }
+
+        assert_eq!(texts(log.page(2)), ["c", "d"]);
+
+        log.scroll(1);
+        assert_eq!(texts(log.page(2)), ["b", "c"]);
+
+        // Stays on the same records while new ones arrive
+        log.push(entry(Level::Info, "e"));
+        assert_eq!(texts(log.page(2)), ["b", "c"]);
+
+        log.scroll(10);
+        assert_eq!(texts(log.page(2)), ["a"]);
+
+        log.scroll(-10);
+        assert_eq!(texts(log.page(2)), ["d", "e"]);
+    }
+
+    #[test]
+    fn test_capacity() {
+        let mut log = FirmwareLog::new(&[Level::Info]);
+        for _ in 0..CAPACITY + 1 {
+            log.push(entry(Level::Info, "a"));
+        }
+
+        assert_eq!(log.entries.len(), CAPACITY);
+    }
+}
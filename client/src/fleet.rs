@@ -0,0 +1,174 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use portuni_common::{pairing::DeviceId, protocol::PAIRED_PIPES};
+
+/// A paired drone, which the relay receives on a pipe of its own
+#[derive(Debug, Clone, PartialEq)]
+pub struct Member {
+    pub pipe: u8,
+    pub id: DeviceId,
+    pub name: String,
+}
+
+/// The drones that are paired and connected, and the one of which the heading and status are shown
+#[derive(Default)]
+pub struct Fleet {
+    members: Vec<Member>,
+    // Pipe of the selected drone
+    selected: Option<u8>,
+    // How long the links to drones that stopped sending have been lost, by pipe
+    lost: HashMap<u8, Duration>,
+}
+
+impl Fleet {
+    pub fn members(&self) -> &[Member] {
+        &self.members
+    }
+
+    pub fn get(&self, pipe: u8) -> Option<&Member> {
+        self.members.iter().find(|member| member.pipe == pipe)
+    }
+
+    pub fn contains(&self, id: &DeviceId) -> bool {
+        self.members.iter().any(|member| member.id == *id)
+    }
+
+    /// A pipe of the relay that no drone is received on yet
+    pub fn free_pipe(&self) -> Option<u8> {
+        PAIRED_PIPES
+            .iter()
+            .copied()
+            .find(|&pipe| self.get(pipe).is_none())
+    }
+
+    /// The first drone that joins is selected
+    pub fn add(&mut self, member: Member) {
+        self.selected = self.selected.or(Some(member.pipe));
+        self.members.retain(|m| m.pipe != member.pipe);
+        self.members.push(member);
+        self.members.sort_by_key(|member| member.pipe);
+    }
+
+    /// If the drone was selected, the next one is selected instead
+    pub fn remove(&mut self, pipe: u8) -> Option<Member> {
+        let index = self.members.iter().position(|member| member.pipe == pipe)?;
+
+        if self.selected == Some(pipe) {
+            self.select_next();
+            if self.selected == Some(pipe) {
+                self.selected = None;
+            }
+        }
+
+        self.lost.remove(&pipe);
+
+        Some(self.members.remove(index))
+    }
+
+    /// Marks the link to a drone as lost for `duration`, or as restored with `None`
+    pub fn set_lost(&mut self, pipe: u8, duration: Option<Duration>) {
+        match duration {
+            Some(duration) if self.get(pipe).is_some() => {
+                self.lost.insert(pipe, duration);
+            }
+            _ => {
+                self.lost.remove(&pipe);
+            }
+        }
+    }
+
+    /// How long the link to the drone has been lost
+    pub fn lost(&self, pipe: u8) -> Option<Duration> {
+        self.lost.get(&pipe).copied()
+    }
+
+    pub fn selected(&self) -> Option<&Member> {
+        self.get(self.selected?)
+    }
+
+    pub fn is_selected(&self, pipe: u8) -> bool {
+        self.selected == Some(pipe)
+    }
+
+    /// Selects the drone on the next pipe, wrapping around to the first one
+    pub fn select_next(&mut self) {
+        let next = self
+            .members
+            .iter()
+            .find(|member| Some(member.pipe) > self.selected)
+            .or_else(|| self.members.first());
+
+        self.selected = next.map(|member| member.pipe);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn member(pipe: u8) -> Member {
+        Member {
+            pipe,
+            id: [pipe; 12],
+            name: format!("drone {}", pipe),
+        }
+    }
+
+    #[test]
+    fn test_free_pipe() {
+        let mut fleet = Fleet::default();
+        assert_eq!(fleet.free_pipe(), Some(2));
+
+        fleet.add(member(2));
+        fleet.add(member(4));
+        assert_eq!(fleet.free_pipe(), Some(3));
+
+        fleet.add(member(3));
+        fleet.add(member(5));
+        assert_eq!(fleet.free_pipe(), None);
+        assert!(fleet.contains(&[5; 12]));
+    }
+
+    #[test]
+    fn test_select() {
+        let mut fleet = Fleet::default();
+        assert_eq!(fleet.selected(), None);
+
+        fleet.add(member(3));
+        fleet.add(member(2));
+        assert_eq!(fleet.selected(), Some(&member(3)));
+
+        fleet.select_next();
+        assert_eq!(fleet.selected(), Some(&member(2)));
+        fleet.select_next();
+        assert!(fleet.is_selected(3));
+
+        // Removing the selected drone selects the next one
+        fleet.remove(3);
+        assert!(fleet.is_selected(2));
+        fleet.remove(2);
+        assert_eq!(fleet.selected(), None);
+    }
+
+    #[test]
+    fn test_lost() {
+        let mut fleet = Fleet::default();
+        fleet.add(member(2));
+
+        let second = Duration::from_secs(1);
+        fleet.set_lost(2, Some(second));
+        fleet.set_lost(3, Some(second));
+        assert_eq!(fleet.lost(2), Some(second));
+        assert_eq!(fleet.lost(3), None);
+
+        fleet.set_lost(2, None);
+        assert_eq!(fleet.lost(2), None);
+
+        // A drone that joins again isn't lost
+        fleet.set_lost(2, Some(second));
+        fleet.remove(2);
+        fleet.add(member(2));
+        assert_eq!(fleet.lost(2), None);
+    }
+}
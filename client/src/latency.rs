@@ -0,0 +1,187 @@
+//! Latency histograms of the hops between the drones and the client, along with the signal
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Width of a bucket of a `Histogram`
+const BUCKET: Duration = Duration::from_millis(1);
+
+/// Buckets of a `Histogram`, longer latencies are counted in the last one
+const BUCKETS: usize = 1000;
+
+/// Parts of the path between a drone and the client that are timed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Hop {
+    /// From sending a ping to the relay until its pong is read from serial
+    RoundTrip,
+    /// From sending a ping until the drone handled it, which includes the wait for its RX window.
+    /// Relies on the synchronized clock
+    Uplink,
+    /// From taking a sample until it is read from serial, which includes the drone's queue.
+    /// Relies on the synchronized clock
+    Downlink,
+    /// From reading a message from serial until the ECS handles it
+    Client,
+}
+
+pub const HOPS: [Hop; 4] = [Hop::RoundTrip, Hop::Uplink, Hop::Downlink, Hop::Client];
+
+/// Latencies counted in buckets of a millisecond
+pub struct Histogram {
+    counts: Vec<u32>,
+    count: u32,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Histogram {
+            counts: vec![0; BUCKETS],
+            count: 0,
+        }
+    }
+}
+
+impl Histogram {
+    pub fn record(&mut self, latency: Duration) {
+        let bucket = (latency.as_micros() / BUCKET.as_micros()) as usize;
+
+        self.counts[bucket.min(BUCKETS - 1)] += 1;
+        self.count += 1;
+    }
+
+    pub fn count(&self) -> u32 {
+        self.count
+    }
+
+    /// Upper bound of the bucket below which `percentile` percent of the latencies are
+    pub fn percentile(&self, percentile: f64) -> Option<Duration> {
+        if self.count == 0 {
+            return None;
+        }
+
+        let rank = (f64::from(self.count) * percentile / 100.0).ceil().max(1.0) as u32;
+        let mut seen = 0;
+
+        for (bucket, &count) in self.counts.iter().enumerate() {
+            seen += count;
+
+            if seen >= rank {
+                return Some(BUCKET * (bucket as u32 + 1));
+            }
+        }
+
+        None
+    }
+}
+
+/// The 50th, 95th and 99th percentile of a hop
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Percentiles {
+    pub p50: Duration,
+    pub p95: Duration,
+    pub p99: Duration,
+    pub count: u32,
+}
+
+/// Histograms of every hop by relay pipe, shared by the serial thread and the ECS
+#[derive(Clone, Default)]
+pub struct Latencies {
+    inner: Arc<Mutex<HashMap<(u8, Hop), Histogram>>>,
+    // Payloads of which the relay's received power detector was set, and all payloads
+    signal: Arc<Mutex<HashMap<u8, (u32, u32)>>>,
+}
+
+impl Latencies {
+    pub fn new() -> Latencies {
+        Latencies::default()
+    }
+
+    pub fn record(&self, pipe: u8, hop: Hop, latency: Duration) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.entry((pipe, hop)).or_default().record(latency);
+    }
+
+    pub fn percentiles(&self, pipe: u8, hop: Hop) -> Option<Percentiles> {
+        let inner = self.inner.lock().unwrap();
+        let histogram = inner.get(&(pipe, hop))?;
+
+        Some(Percentiles {
+            p50: histogram.percentile(50.0)?,
+            p95: histogram.percentile(95.0)?,
+            p99: histogram.percentile(99.0)?,
+            count: histogram.count(),
+        })
+    }
+
+    /// Called for every payload the relay forwards, with its received power detector
+    pub fn record_signal(&self, pipe: u8, rpd: bool) {
+        let mut signal = self.signal.lock().unwrap();
+        let (strong, count) = signal.entry(pipe).or_default();
+
+        *strong += u32::from(rpd);
+        *count += 1;
+    }
+
+    /// Percentage of the payloads that were received above -64 dBm
+    pub fn signal(&self, pipe: u8) -> Option<f64> {
+        let (strong, count) = *self.signal.lock().unwrap().get(&pipe)?;
+
+        Some(f64::from(strong) * 100.0 / f64::from(count))
+    }
+
+    /// Starts over for every hop, e.g. once another drone is paired on `pipe`
+    pub fn reset(&self, pipe: u8) {
+        self.inner.lock().unwrap().retain(|&(p, _), _| p != pipe);
+        self.signal.lock().unwrap().remove(&pipe);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_percentile() {
+        let mut histogram = Histogram::default();
+        assert_eq!(histogram.percentile(50.0), None);
+
+        for ms in 0..100 {
+            histogram.record(Duration::from_micros(ms * 1000 + 500));
+        }
+
+        assert_eq!(histogram.percentile(50.0), Some(Duration::from_millis(50)));
+        assert_eq!(histogram.percentile(99.0), Some(Duration::from_millis(99)));
+        assert_eq!(
+            histogram.percentile(100.0),
+            Some(Duration::from_millis(100))
+        );
+
+        // Longer latencies end up in the last bucket
+        histogram.record(Duration::from_secs(5));
+        assert_eq!(histogram.percentile(100.0), Some(BUCKET * BUCKETS as u32));
+        assert_eq!(histogram.count(), 101);
+    }
+
+    #[test]
+    fn test_latencies() {
+        let latencies = Latencies::new();
+        assert_eq!(latencies.percentiles(2, Hop::RoundTrip), None);
+
+        latencies.record(2, Hop::RoundTrip, Duration::from_millis(40));
+        latencies.record(3, Hop::RoundTrip, Duration::from_millis(80));
+
+        let percentiles = latencies.percentiles(2, Hop::RoundTrip).unwrap();
+        assert_eq!(percentiles.p99, Duration::from_millis(41));
+        assert_eq!(percentiles.count, 1);
+
+        assert_eq!(latencies.signal(2), None);
+        latencies.record_signal(2, true);
+        latencies.record_signal(2, false);
+        assert_eq!(latencies.signal(2), Some(50.0));
+
+        latencies.reset(2);
+        assert_eq!(latencies.percentiles(2, Hop::RoundTrip), None);
+        assert_eq!(latencies.signal(2), None);
+        assert!(latencies.percentiles(3, Hop::RoundTrip).is_some());
+    }
+}
@@ -3,13 +3,28 @@ mod system;
 mod utils;
 
 mod cobs_buffer;
+mod command;
 mod compass;
+mod component;
 mod config;
+mod device_config;
+mod firmware_log;
+mod fleet;
+mod latency;
+mod negotiation;
+mod reassembly;
+mod recording;
+mod secure;
+mod spectrum;
+mod sync;
 mod transceiver;
 
 use state::app::App;
 
+use firmware_log::{log_level, LogSettings, FIRMWARE_TARGET};
+
 use amethyst::{
+    config::Config,
     core::transform::TransformBundle,
     input::{InputBundle, StringBindings},
     prelude::*,
@@ -20,6 +35,7 @@ use amethyst::{
     },
     ui::{RenderUi, UiBundle},
     utils::application_root_dir,
+    LoggerConfig,
 };
 
 // Move this to a seperate entity creation system
@@ -27,34 +43,33 @@ use amethyst::{
     assets::{AssetPrefab, PrefabData, PrefabLoaderSystemDesc, ProgressCounter},
     core::transform::Transform,
     derive::PrefabData,
-    ecs::prelude::{Component, DenseVecStorage},
     ecs::Entity,
     gltf::{GltfSceneAsset, GltfSceneFormat, GltfSceneLoaderSystemDesc},
-    utils::tag::Tag,
     Error,
 };
 use serde::{self, Deserialize, Serialize};
 
-// The tag
-#[derive(Clone, Serialize, Deserialize)]
-pub struct DroneMarker;
-
-impl Component for DroneMarker {
-    type Storage = DenseVecStorage<Self>;
-}
-
 #[derive(Default, Deserialize, Serialize, PrefabData)]
 #[serde(default)]
 pub struct ScenePrefabData {
     transform: Option<Transform>,
     gltf: Option<AssetPrefab<GltfSceneAsset, GltfSceneFormat>>,
-    tag: Option<Tag<DroneMarker>>,
 }
 
 fn main() -> amethyst::Result<()> {
-    amethyst::start_logger(Default::default());
-
     let app_root = application_root_dir()?;
+
+    // Records of the firmware are filtered apart from the client's own
+    let log_settings =
+        LogSettings::load(app_root.join("config").join("log.ron")).unwrap_or_default();
+    amethyst::start_logger(LoggerConfig {
+        module_levels: vec![(
+            FIRMWARE_TARGET.to_string(),
+            log_level(log_settings.output).to_level_filter(),
+        )],
+        ..Default::default()
+    });
+
     let assets_dir = app_root.join("assets");
     let display_path = app_root.join("config").join("display.ron");
 
@@ -81,6 +96,42 @@ fn main() -> amethyst::Result<()> {
             "transceiver_codec",
             &[],
         )
+        .with_system_desc(
+            system::link::LinkSystem::new(),
+            "link",
+            &["transceiver_codec"],
+        )
+        .with(system::fleet::FleetSystem::default(), "fleet", &["link"])
+        .with(
+            system::command::CommandSystem::default(),
+            "command",
+            &["transceiver_codec"],
+        )
+        .with(
+            system::recording::RecordingSystem::default(),
+            "recording",
+            &["transceiver_codec"],
+        )
+        .with(
+            system::spectrum::SpectrumSystem::default(),
+            "spectrum",
+            &["transceiver_codec"],
+        )
+        .with_system_desc(
+            system::drone::DroneSystem::new(),
+            "drone",
+            &["transceiver_codec", "fleet"],
+        )
+        .with(
+            system::diagnostics::DiagnosticsSystem::default(),
+            "diagnostics",
+            &["transceiver_codec"],
+        )
+        .with_system_desc(
+            system::firmware_log::FirmwareLogSystem::new(log_settings.panel),
+            "firmware_log",
+            &["transceiver_codec"],
+        )
         .with_bundle(InputBundle::<StringBindings>::new())?
         .with_bundle(
             RenderingBundle::<DefaultBackend>::new()
@@ -0,0 +1,254 @@
+use std::time::Duration;
+
+use crate::command::{
+    Command, CommandError, CommandLink, CommandResult, PendingCommand, DEFAULT_PIPE,
+};
+use portuni_common::{
+    command::Encoding,
+    pairing::{self, DeviceId},
+    radio::RadioConfig,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Outcome {
+    /// The drone and relay both moved to the new configuration
+    Switched(RadioConfig),
+    /// The drone refused the configuration, both stay where they are
+    Refused(CommandResult),
+    /// The drone did not answer the proposal, both stay where they are
+    Unanswered(CommandError),
+    /// The new link did not work, both returned to the fallback configuration
+    FellBack(RadioConfig),
+}
+
+enum State {
+    Proposed(PendingCommand),
+    Confirming(PendingCommand),
+}
+
+/// Moves a drone and its pipe of the relay to another radio configuration
+///
+/// The drone is told first, after its acknowledgement the relay follows and a command is sent over
+/// the new link to confirm it. Both ends fall back if that command is not acknowledged.
+pub struct Negotiation {
+    // Pipe of the relay the drone is received on once it switched
+    pipe: u8,
+    target: RadioConfig,
+    // Pipe and configuration the relay returns to
+    fallback: (u8, RadioConfig),
+    // Sent over the new link, any acknowledgement confirms it
+    confirm: Command,
+    timeout: Duration,
+    state: State,
+}
+
+impl Negotiation {
+    /// Moves the drone on `pipe` to the channel and data rate of `target`, falling back to the
+    /// default channel
+    pub fn set_radio(
+        link: &mut CommandLink,
+        pipe: u8,
+        target: RadioConfig,
+        timeout: Duration,
+    ) -> Negotiation {
+        let command = Command::SetRadio {
+            channel: target.channel,
+            data_rate: target.data_rate,
+        };
+
+        Negotiation {
+            pipe,
+            target,
+            fallback: (pipe, target.fallback()),
+            confirm: Command::RequestStatus,
+            timeout,
+            state: State::Proposed(link.send(pipe, command, timeout)),
+        }
+    }
+
+    /// Moves the drone with `id`, which advertised `nonce`, from the default addresses to its
+    /// private ones, which the relay receives on `pipe`
+    ///
+    /// The private addresses are confirmed by proposing `encoding` and `batch_size`, a drone that
+    /// doesn't support them keeps sending single `Message::Telemetry`.
+    pub fn pair(
+        link: &mut CommandLink,
+        id: DeviceId,
+        nonce: u32,
+        pipe: u8,
+        (encoding, batch_size): (Encoding, u8),
+        timeout: Duration,
+    ) -> Negotiation {
+        let default = RadioConfig::default();
+
+        Negotiation {
+            pipe,
+            target: pairing::private_config(&default, &id),
+            fallback: (DEFAULT_PIPE, default),
+            confirm: Command::SetEncoding {
+                encoding,
+                batch_size,
+            },
+            timeout,
+            state: State::Proposed(link.pair(id, nonce, pipe, timeout)),
+        }
+    }
+
+    /// Returns `None` until the negotiation is done
+    pub fn poll(&mut self, link: &mut CommandLink) -> Option<Outcome> {
+        match &self.state {
+            State::Proposed(pending) => match pending.poll()? {
+                Ok(CommandResult::Ok) => {
+                    if let Err(error) = link.configure_relay(self.pipe, self.target) {
+                        return Some(Outcome::Unanswered(error));
+                    }
+
+                    let confirm = link.send(self.pipe, self.confirm, self.timeout);
+                    self.state = State::Confirming(confirm);
+
+                    None
+                }
+                Ok(result) => Some(Outcome::Refused(result)),
+                Err(error) => Some(Outcome::Unanswered(error)),
+            },
+            State::Confirming(pending) => match pending.poll()? {
+                Ok(_) => Some(Outcome::Switched(self.target)),
+                Err(_) => {
+                    // The drone falls back on its own once the confirmation doesn't arrive
+                    let (pipe, fallback) = self.fallback;
+                    let _ = link.configure_relay(pipe, fallback);
+
+                    Some(Outcome::FellBack(fallback))
+                }
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command::{link, RelayFrame, Uplink};
+    use portuni_common::radio::DataRate;
+    use std::sync::mpsc;
+
+    #[test]
+    fn test_switched() {
+        let (send, recv) = mpsc::channel();
+        let (mut link, acks) = link(send);
+        let target = RadioConfig::default().with_link(76, DataRate::Mbps1);
+
+        let mut negotiation = Negotiation::set_radio(&mut link, 2, target, Duration::from_secs(2));
+        assert_eq!(negotiation.poll(&mut link), None);
+
+        acks.acknowledge(2, 0, CommandResult::Ok);
+        assert_eq!(negotiation.poll(&mut link), None);
+
+        // The relay follows the drone, after which the new link is confirmed
+        let frames: Vec<RelayFrame> = recv.try_iter().collect();
+        assert_eq!(frames.len(), 3);
+        assert_eq!(
+            frames[1],
+            RelayFrame::Configure {
+                pipe: 2,
+                config: target
+            }
+        );
+
+        acks.acknowledge(2, 1, CommandResult::Ok);
+        assert_eq!(negotiation.poll(&mut link), Some(Outcome::Switched(target)));
+    }
+
+    #[test]
+    fn test_refused() {
+        let (send, _recv) = mpsc::channel();
+        let (mut link, acks) = link(send);
+        let target = RadioConfig::default().with_link(126, DataRate::Mbps1);
+
+        let mut negotiation = Negotiation::set_radio(&mut link, 2, target, Duration::from_secs(2));
+        acks.acknowledge(2, 0, CommandResult::Invalid);
+
+        assert_eq!(
+            negotiation.poll(&mut link),
+            Some(Outcome::Refused(CommandResult::Invalid))
+        );
+    }
+
+    #[test]
+    fn test_fell_back() {
+        let (send, recv) = mpsc::channel();
+        let (mut link, acks) = link(send);
+        let target = RadioConfig::default().with_link(76, DataRate::Mbps2);
+
+        let mut negotiation =
+            Negotiation::set_radio(&mut link, 2, target, Duration::from_millis(0));
+        acks.acknowledge(2, 0, CommandResult::Ok);
+
+        assert_eq!(negotiation.poll(&mut link), None);
+        assert_eq!(
+            negotiation.poll(&mut link),
+            Some(Outcome::FellBack(RadioConfig::default()))
+        );
+        assert_eq!(
+            recv.try_iter().last(),
+            Some(RelayFrame::Configure {
+                pipe: 2,
+                config: RadioConfig::default()
+            })
+        );
+    }
+
+    #[test]
+    fn test_pair() {
+        let (send, recv) = mpsc::channel();
+        let (mut link, acks) = link(send);
+        let id = [7; 12];
+        let target = pairing::private_config(&RadioConfig::default(), &id);
+
+        let mut negotiation = Negotiation::pair(
+            &mut link,
+            id,
+            0,
+            3,
+            (Encoding::Compact, 2),
+            Duration::from_millis(0),
+        );
+        acks.acknowledge(DEFAULT_PIPE, 0, CommandResult::Ok);
+
+        assert_eq!(negotiation.poll(&mut link), None);
+        assert_eq!(
+            negotiation.poll(&mut link),
+            Some(Outcome::FellBack(RadioConfig::default()))
+        );
+
+        // The drone is received on its own pipe, and on the default one again after falling back
+        let frames: Vec<RelayFrame> = recv.try_iter().collect();
+        assert_eq!(
+            frames[1],
+            RelayFrame::Configure {
+                pipe: 3,
+                config: target
+            }
+        );
+        assert!(matches!(
+            frames[2],
+            RelayFrame::Uplink {
+                pipe: 3,
+                uplink: Uplink {
+                    command: Command::SetEncoding {
+                        encoding: Encoding::Compact,
+                        batch_size: 2
+                    },
+                    ..
+                }
+            }
+        ));
+        assert_eq!(
+            frames[3],
+            RelayFrame::Configure {
+                pipe: DEFAULT_PIPE,
+                config: RadioConfig::default()
+            }
+        );
+    }
+}
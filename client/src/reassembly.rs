@@ -0,0 +1,293 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use postcard::from_bytes;
+
+use portuni_common::{
+    fragment::Fragment,
+    protocol::{Batch, Message},
+};
+
+use crate::secure::Sessions;
+
+/// Time within which every fragment of a message has to arrive
+pub const REASSEMBLY_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// A message of which not every fragment arrived yet
+struct Partial {
+    id: u8,
+    count: u8,
+    data: Vec<u8>,
+    // Index of the fragment that is expected next
+    next: u8,
+    started: Instant,
+}
+
+/// Joins the fragments of the messages of a single drone, which sends them in order
+#[derive(Default)]
+pub struct Reassembly {
+    partial: Option<Partial>,
+}
+
+impl Reassembly {
+    /// Returns the message once its last fragment was pushed
+    ///
+    /// A message of which a fragment is missing, or that isn't complete within
+    /// `REASSEMBLY_TIMEOUT`, is discarded.
+    pub fn push(&mut self, fragment: &[u8], now: Instant) -> Option<Vec<u8>> {
+        let fragment = Fragment::parse(fragment)?;
+
+        let continues = match &self.partial {
+            Some(partial) => {
+                partial.id == fragment.id
+                    && partial.count == fragment.count
+                    && partial.next == fragment.index
+                    && now.duration_since(partial.started) <= REASSEMBLY_TIMEOUT
+            }
+            None => false,
+        };
+
+        if !continues {
+            self.partial = None;
+
+            if fragment.index != 0 {
+                return None;
+            }
+        }
+
+        let partial = self.partial.get_or_insert_with(|| Partial {
+            id: fragment.id,
+            count: fragment.count,
+            data: Vec::new(),
+            next: 0,
+            started: now,
+        });
+        partial.data.extend_from_slice(fragment.data);
+        partial.next += 1;
+
+        if partial.next < partial.count {
+            return None;
+        }
+
+        self.partial.take().map(|partial| partial.data)
+    }
+}
+
+/// Turns the payloads the relay forwards into messages
+pub struct Downlinks {
+    sessions: Option<Sessions>,
+    // By relay pipe
+    reassembly: HashMap<u8, Reassembly>,
+}
+
+impl Downlinks {
+    /// Payloads are opened with `sessions` if the drones seal them
+    pub fn new(sessions: Option<Sessions>) -> Downlinks {
+        Downlinks {
+            sessions,
+            reassembly: HashMap::new(),
+        }
+    }
+
+    /// Returns the messages of `payload` once all of its fragments arrived on `pipe`
+    pub fn receive(&mut self, pipe: u8, payload: &[u8], now: Instant) -> Vec<Message> {
+        self.message(pipe, payload, now)
+            .map(decode)
+            .unwrap_or_default()
+    }
+
+    fn message(&mut self, pipe: u8, payload: &[u8], now: Instant) -> Option<Message> {
+        let fragment = match &self.sessions {
+            Some(sessions) => match sessions.open(pipe, payload) {
+                Some(fragment) => fragment,
+                // Advertisements are the only messages in the clear, and fit a single fragment
+                None => {
+                    let fragment = Fragment::parse(payload).filter(|f| f.count == 1)?;
+
+                    return match from_bytes(fragment.data) {
+                        Ok(advert @ Message::Advertise { .. }) => Some(advert),
+                        _ => None,
+                    };
+                }
+            },
+            None => payload.to_vec(),
+        };
+
+        let message = self
+            .reassembly
+            .entry(pipe)
+            .or_default()
+            .push(&fragment, now)?;
+
+        from_bytes(&message).ok()
+    }
+}
+
+/// Telemetry is always handed on as single `Message::Telemetry`, whichever encoding and batch
+/// size were negotiated
+fn decode(message: Message) -> Vec<Message> {
+    match message {
+        Message::CompactTelemetry(compact) => vec![Message::Telemetry(compact.decode())],
+        Message::Batch(Batch::Float(samples)) => {
+            samples.into_iter().map(Message::Telemetry).collect()
+        }
+        Message::Batch(Batch::Compact(samples)) => samples
+            .iter()
+            .map(|compact| Message::Telemetry(compact.decode()))
+            .collect(),
+        message => vec![message],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use portuni_common::command::GyroScale;
+    use portuni_common::fragment::Fragmenter;
+    use portuni_common::protocol::{CompactTelemetry, Telemetry, DEFAULT_PIPE, PAYLOAD_SIZE};
+
+    fn telemetry() -> Message {
+        Message::Telemetry(Telemetry {
+            timestamp: 1,
+            mag_x: 2,
+            mag_y: 3,
+            mag_z: 4,
+            accel_x: 5,
+            accel_y: 6,
+            accel_z: 7,
+            gyro_x: 8.0,
+            gyro_y: 9.0,
+            gyro_z: 10.0,
+            temp: 11,
+        })
+    }
+
+    fn fragments(fragmenter: &mut Fragmenter, message: &Message) -> Vec<Vec<u8>> {
+        let mut buf = [0u8; PAYLOAD_SIZE];
+        let mut fragments = Vec::new();
+
+        fragmenter.push(message).unwrap();
+        while let Some(fragment) = fragmenter.next(&mut buf) {
+            fragments.push(fragment.to_vec());
+        }
+
+        fragments
+    }
+
+    #[test]
+    fn test_reassemble() {
+        let mut fragmenter = Fragmenter::new(12);
+        let mut downlinks = Downlinks::new(None);
+        let now = Instant::now();
+
+        let first = fragments(&mut fragmenter, &telemetry());
+        let second = fragments(&mut fragmenter, &telemetry());
+        assert!(first.len() > 2);
+
+        // Fragments of other pipes don't mix
+        assert!(downlinks.receive(2, &first[0], now).is_empty());
+        assert!(downlinks.receive(3, &second[0], now).is_empty());
+        assert!(downlinks.receive(2, &first[1], now).is_empty());
+        assert_eq!(downlinks.receive(2, &first[2], now), [telemetry()]);
+    }
+
+    fn compact() -> CompactTelemetry {
+        CompactTelemetry {
+            timestamp: 1,
+            mag_x: 2,
+            mag_y: 3,
+            mag_z: 4,
+            accel_x: 5,
+            accel_y: 6,
+            accel_z: 7,
+            gyro_x: 1000,
+            gyro_y: 0,
+            gyro_z: -1000,
+            gyro_scale: GyroScale::Dps500,
+            temp: 11,
+        }
+    }
+
+    #[test]
+    fn test_compact() {
+        let mut fragmenter = Fragmenter::new(PAYLOAD_SIZE);
+        let mut downlinks = Downlinks::new(None);
+        let compact = compact();
+
+        let payload = fragments(&mut fragmenter, &Message::CompactTelemetry(compact.clone()));
+        assert_eq!(
+            downlinks.receive(2, &payload[0], Instant::now()),
+            [Message::Telemetry(compact.decode())]
+        );
+    }
+
+    #[test]
+    fn test_batch() {
+        let mut fragmenter = Fragmenter::new(PAYLOAD_SIZE);
+        let mut downlinks = Downlinks::new(None);
+        let now = Instant::now();
+
+        let samples: Vec<CompactTelemetry> = (1..=3)
+            .map(|timestamp| CompactTelemetry {
+                timestamp,
+                ..compact()
+            })
+            .collect();
+        let batch = Message::Batch(Batch::Compact(samples.iter().cloned().collect()));
+
+        // Unpacked into samples with their own timestamps
+        let mut messages = Vec::new();
+        for payload in fragments(&mut fragmenter, &batch) {
+            messages.extend(downlinks.receive(2, &payload, now));
+        }
+        let expected: Vec<Message> = samples
+            .iter()
+            .map(|sample| Message::Telemetry(sample.decode()))
+            .collect();
+        assert_eq!(messages, expected);
+    }
+
+    #[test]
+    fn test_incomplete() {
+        let mut fragmenter = Fragmenter::new(12);
+        let mut reassembly = Reassembly::default();
+        let now = Instant::now();
+
+        // A missing fragment discards the message, the next one is joined again
+        let first = fragments(&mut fragmenter, &telemetry());
+        let second = fragments(&mut fragmenter, &telemetry());
+        assert_eq!(reassembly.push(&first[0], now), None);
+        assert_eq!(reassembly.push(&second[1], now), None);
+        assert_eq!(reassembly.push(&first[2], now), None);
+
+        for fragment in &second[..second.len() - 1] {
+            assert_eq!(reassembly.push(fragment, now), None);
+        }
+        assert!(reassembly.push(&second[second.len() - 1], now).is_some());
+
+        // The rest of a message arrived too late
+        let third = fragments(&mut fragmenter, &telemetry());
+        let late = now + REASSEMBLY_TIMEOUT + Duration::from_millis(1);
+        assert_eq!(reassembly.push(&third[0], now), None);
+        assert_eq!(reassembly.push(&third[1], now), None);
+        assert_eq!(reassembly.push(&third[2], late), None);
+    }
+
+    #[test]
+    fn test_clear() {
+        let mut fragmenter = Fragmenter::new(PAYLOAD_SIZE);
+        let mut downlinks = Downlinks::new(Some(Sessions::new([9; 32])));
+        let now = Instant::now();
+
+        let advert = Message::Advertise {
+            id: [1; 12],
+            nonce: 7,
+        };
+        let payload = fragments(&mut fragmenter, &advert).remove(0);
+        assert_eq!(downlinks.receive(DEFAULT_PIPE, &payload, now), [advert]);
+
+        // Anything else has to be sealed
+        let payload = fragments(&mut fragmenter, &Message::Listening).remove(0);
+        assert!(downlinks.receive(DEFAULT_PIPE, &payload, now).is_empty());
+    }
+}
@@ -0,0 +1,323 @@
+//! Samples written to CSV files, recorded live or downloaded from a drone's blackbox afterwards,
+//! see `portuni_common::blackbox`. Both are written alike, with the drone's timestamps
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use portuni_common::blackbox::BlackboxChunk;
+use portuni_common::protocol::Telemetry;
+
+pub const HEADER: &str =
+    "timestamp_us,mag_x,mag_y,mag_z,accel_x,accel_y,accel_z,gyro_x,gyro_y,gyro_z,temp";
+
+/// Time without a chunk after which the rest of a download is requested again
+const RETRY_AFTER: Duration = Duration::from_secs(3);
+/// Requests in a row without a chunk, after which a download is given up
+const MAX_ATTEMPTS: usize = 5;
+
+/// A sample as a line of the file, in milligauss, milli-g and degrees per second
+pub fn row(value: &Telemetry) -> String {
+    format!(
+        "{},{},{},{},{},{},{},{},{},{},{}",
+        value.timestamp,
+        value.mag_x,
+        value.mag_y,
+        value.mag_z,
+        value.accel_x,
+        value.accel_y,
+        value.accel_z,
+        value.gyro_x,
+        value.gyro_y,
+        value.gyro_z,
+        value.temp
+    )
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Source {
+    Live,
+    Blackbox,
+}
+
+/// A new file in `dir` for the samples of drone `name`, named after the time it was started
+pub fn path(dir: &Path, name: &str, source: Source) -> PathBuf {
+    let name: String = name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+        .collect();
+    let source = match source {
+        Source::Live => "live",
+        Source::Blackbox => "blackbox",
+    };
+    let started = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs());
+
+    dir.join(format!("{}-{}-{}.csv", name, source, started))
+}
+
+pub struct Recording {
+    file: BufWriter<File>,
+    pub path: PathBuf,
+    pub samples: usize,
+}
+
+impl Recording {
+    /// Creates the file and its directory, and writes the header
+    pub fn create(path: PathBuf) -> io::Result<Recording> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+
+        let mut file = BufWriter::new(File::create(&path)?);
+        writeln!(file, "{}", HEADER)?;
+
+        Ok(Recording {
+            file,
+            path,
+            samples: 0,
+        })
+    }
+
+    pub fn write(&mut self, value: &Telemetry) -> io::Result<()> {
+        writeln!(self.file, "{}", row(value))?;
+        self.samples += 1;
+
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> io::Result<(PathBuf, usize)> {
+        self.file.flush()?;
+
+        Ok((self.path, self.samples))
+    }
+}
+
+/// The chunks of a blackbox as they arrive, of which the ones after a lost chunk are dropped
+/// and requested again
+pub struct Download {
+    pipe: u8,
+    // Number of the record that is expected next, and the one after the last
+    next: u32,
+    last: Option<u32>,
+    samples: Vec<Telemetry>,
+    attempts: usize,
+    // When the last request was sent or a chunk was taken
+    progress: Option<Instant>,
+}
+
+impl Download {
+    pub fn new(pipe: u8) -> Download {
+        Download {
+            pipe,
+            next: 0,
+            last: None,
+            samples: Vec::new(),
+            attempts: 0,
+            progress: None,
+        }
+    }
+
+    pub fn pipe(&self) -> u8 {
+        self.pipe
+    }
+
+    /// The record from which to request the rest, at first and whenever no chunk was taken for
+    /// a while
+    pub fn request(&mut self, now: Instant) -> Option<u32> {
+        if self.is_complete() || self.attempts >= MAX_ATTEMPTS {
+            return None;
+        }
+
+        match self.progress {
+            Some(progress) if now.saturating_duration_since(progress) < RETRY_AFTER => None,
+            _ => {
+                self.attempts += 1;
+                self.progress = Some(now);
+                Some(self.next)
+            }
+        }
+    }
+
+    pub fn add(&mut self, chunk: BlackboxChunk, now: Instant) {
+        // Records overwritten before they were sent are skipped
+        self.next = self.next.max(chunk.first);
+
+        if chunk.start != self.next || self.is_complete() {
+            return;
+        }
+
+        // Whatever the drone recorded after the first download ended isn't waited for
+        self.last.get_or_insert(chunk.last);
+
+        self.samples.extend(chunk.samples.iter().cloned());
+        self.next = chunk.end;
+        self.attempts = 0;
+        self.progress = Some(now);
+    }
+
+    pub fn is_complete(&self) -> bool {
+        matches!(self.last, Some(last) if self.next >= last)
+    }
+
+    /// Whether the download is complete, or given up
+    pub fn is_finished(&self, now: Instant) -> bool {
+        let waited = self.progress.map_or(true, |progress| {
+            now.saturating_duration_since(progress) >= RETRY_AFTER
+        });
+
+        self.is_complete() || (self.attempts >= MAX_ATTEMPTS && waited)
+    }
+
+    pub fn samples(&self) -> &[Telemetry] {
+        &self.samples
+    }
+}
+
+/// The live recording and the download in progress, if any
+#[derive(Default)]
+pub struct Recorder {
+    pub live: Option<(u8, Recording)>,
+    pub download: Option<Download>,
+    // Set when writing the live recording failed, which stops it
+    pub error: Option<io::Error>,
+}
+
+impl Recorder {
+    pub fn sample(&mut self, pipe: u8, value: &Telemetry) {
+        let failed = match &mut self.live {
+            Some((recorded, recording)) if *recorded == pipe => recording.write(value).err(),
+            _ => None,
+        };
+
+        if let Some(error) = failed {
+            self.live = None;
+            self.error = Some(error);
+        }
+    }
+
+    pub fn chunk(&mut self, pipe: u8, chunk: BlackboxChunk) {
+        if let Some(download) = self.download.as_mut().filter(|d| d.pipe() == pipe) {
+            download.add(chunk, Instant::now());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(n: u32) -> Telemetry {
+        Telemetry {
+            timestamp: n,
+            mag_x: 1,
+            mag_y: -2,
+            mag_z: 3,
+            accel_x: 4,
+            accel_y: -5,
+            accel_z: 1000,
+            gyro_x: 0.5,
+            gyro_y: -1.25,
+            gyro_z: 0.0,
+            temp: 21,
+        }
+    }
+
+    fn chunk(start: u32, end: u32, first: u32, last: u32) -> BlackboxChunk {
+        BlackboxChunk {
+            start,
+            end,
+            first,
+            last,
+            samples: (start..end).map(sample).collect(),
+        }
+    }
+
+    fn timestamps(download: &Download) -> Vec<u32> {
+        download.samples().iter().map(|s| s.timestamp).collect()
+    }
+
+    #[test]
+    fn test_row() {
+        assert_eq!(
+            HEADER.split(',').count(),
+            row(&sample(7)).split(',').count()
+        );
+        assert_eq!(row(&sample(7)), "7,1,-2,3,4,-5,1000,0.5,-1.25,0,21");
+    }
+
+    #[test]
+    fn test_download() {
+        let now = Instant::now();
+        let mut download = Download::new(2);
+
+        assert_eq!(download.request(now), Some(0));
+        assert_eq!(download.request(now), None);
+
+        // Records before 3 were overwritten
+        download.add(chunk(3, 5, 3, 7), now);
+        download.add(chunk(5, 7, 3, 7), now);
+
+        assert!(download.is_complete());
+        assert!(download.is_finished(now));
+        assert_eq!(timestamps(&download), [3, 4, 5, 6]);
+        assert_eq!(download.request(now + RETRY_AFTER), None);
+    }
+
+    #[test]
+    fn test_empty() {
+        let now = Instant::now();
+        let mut download = Download::new(2);
+
+        download.add(chunk(0, 0, 0, 0), now);
+        assert!(download.is_complete());
+        assert!(download.samples().is_empty());
+    }
+
+    #[test]
+    fn test_lost_chunk() {
+        let now = Instant::now();
+        let mut download = Download::new(2);
+        download.request(now);
+
+        download.add(chunk(0, 2, 0, 6), now);
+        // 2..4 was lost, what follows is dropped
+        download.add(chunk(4, 6, 0, 6), now);
+        assert!(!download.is_complete());
+
+        let later = now + RETRY_AFTER;
+        assert_eq!(download.request(later), Some(2));
+
+        // The drone recorded more since the first download ended
+        download.add(chunk(2, 4, 0, 9), later);
+        download.add(chunk(4, 6, 0, 9), later);
+        assert!(download.is_complete());
+        assert_eq!(timestamps(&download), [0, 1, 2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn test_give_up() {
+        let mut now = Instant::now();
+        let mut download = Download::new(2);
+
+        for _ in 0..MAX_ATTEMPTS {
+            assert_eq!(download.request(now), Some(0));
+            assert!(!download.is_finished(now));
+            now += RETRY_AFTER;
+        }
+
+        assert_eq!(download.request(now), None);
+        assert!(download.is_finished(now));
+        assert!(!download.is_complete());
+    }
+
+    #[test]
+    fn test_path() {
+        let path = path(Path::new("recordings"), "drone 1", Source::Blackbox);
+        let name = path.file_name().unwrap().to_str().unwrap();
+
+        assert!(name.starts_with("drone-1-blackbox-"));
+        assert!(name.ends_with(".csv"));
+    }
+}
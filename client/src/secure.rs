@@ -0,0 +1,183 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use portuni_common::{
+    command::Uplink,
+    pairing::DeviceId,
+    protocol::{to_payload, DEFAULT_PIPE, PAYLOAD_SIZE},
+    secure::{self, Direction, Key, Opener, Sealer, CAPACITY, MAX_COUNTER},
+};
+
+/// A sealed uplink, of which the first `len` bytes are used
+pub type SealedPayload = (u8, [u8; PAYLOAD_SIZE]);
+
+struct Session {
+    sealer: Sealer,
+    opener: Opener,
+}
+
+struct Inner {
+    psk: Key,
+    // By relay pipe
+    sessions: HashMap<u8, Session>,
+    // Pipe of the drone that is being paired, it answers on `DEFAULT_PIPE` until it moved
+    pairing: Option<u8>,
+    // Counter of the last `Command::Pair`
+    pair_counter: u32,
+}
+
+/// Sessions with the paired drones, shared by the serial thread and the `CommandLink`
+#[derive(Clone)]
+pub struct Sessions {
+    inner: Arc<Mutex<Inner>>,
+}
+
+/// Uplinks aren't fragmented, the relay sends a single payload per RX window
+fn seal(sealer: &mut Sealer, uplink: &Uplink) -> Option<SealedPayload> {
+    let mut plaintext = [0u8; CAPACITY];
+    let mut payload = [0u8; PAYLOAD_SIZE];
+
+    let plaintext = to_payload(uplink, &mut plaintext).ok()?;
+    let len = sealer.seal(plaintext, &mut payload).ok()?.len();
+
+    Some((len as u8, payload))
+}
+
+impl Sessions {
+    pub fn new(psk: Key) -> Sessions {
+        Sessions {
+            inner: Arc::new(Mutex::new(Inner {
+                psk,
+                sessions: HashMap::new(),
+                pairing: None,
+                pair_counter: 0,
+            })),
+        }
+    }
+
+    /// Seals `Command::Pair` for the drone that advertised `nonce`, and starts the session it
+    /// proposes for `pipe`
+    pub fn pair(
+        &self,
+        uplink: &Uplink,
+        id: &DeviceId,
+        nonce: u32,
+        pipe: u8,
+    ) -> Option<SealedPayload> {
+        let mut inner = self.inner.lock().unwrap();
+
+        // The key of the session is derived from the counter, so every proposal has its own. The
+        // drone only takes counters above the last one, also once the client started again
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_secs() as u32);
+        let counter = (inner.pair_counter + 1).max(now).min(MAX_COUNTER - 1);
+        inner.pair_counter = counter;
+        let pairing_key = secure::pairing_key(&inner.psk, id, nonce);
+        let payload = seal(
+            &mut Sealer::with_counter(&pairing_key, Direction::Uplink, counter),
+            uplink,
+        )?;
+
+        let key = secure::session_key(&inner.psk, id, nonce, counter);
+        let mut sealer = Sealer::new(Direction::Uplink);
+        sealer.start_session(&key);
+
+        let session = Session {
+            sealer,
+            opener: Opener::new(&key, Direction::Downlink),
+        };
+        inner.sessions.insert(pipe, session);
+        inner.pairing = Some(pipe);
+
+        Some(payload)
+    }
+
+    /// Returns `None` if there is no session with the drone on `pipe`
+    pub fn seal(&self, pipe: u8, uplink: &Uplink) -> Option<SealedPayload> {
+        let mut inner = self.inner.lock().unwrap();
+        let session = inner.sessions.get_mut(&pipe)?;
+
+        seal(&mut session.sealer, uplink)
+    }
+
+    /// Opens a payload of the drone on `pipe`, returns the fragment it carries
+    ///
+    /// Returns `None` if there is no session with the drone, or if the payload was altered or
+    /// replayed.
+    pub fn open(&self, pipe: u8, payload: &[u8]) -> Option<Vec<u8>> {
+        let mut inner = self.inner.lock().unwrap();
+
+        let pipe = match pipe {
+            DEFAULT_PIPE => inner.pairing?,
+            pipe => pipe,
+        };
+        let session = inner.sessions.get_mut(&pipe)?;
+        let mut frame = payload.to_vec();
+
+        let fragment = session.opener.open(&mut frame).ok()?.to_vec();
+
+        Some(fragment)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use portuni_common::command::{Command, CommandResult};
+    use portuni_common::protocol::Message;
+    use portuni_common::secure::Responder;
+    use postcard::from_bytes;
+
+    #[test]
+    fn test_pair() {
+        let psk = [9; 32];
+        let id = [1; 12];
+        let nonce = 42;
+        let sessions = Sessions::new(psk);
+
+        let uplink = Uplink {
+            id: 0,
+            command: Command::Pair(id),
+        };
+        let (len, mut payload) = sessions.pair(&uplink, &id, nonce, 2).unwrap();
+
+        // The drone opens the proposal and seals its acknowledgement with the new key
+        let mut drone = Responder::new(&psk, &id, nonce);
+        let key = drone.open(&mut payload[..len as usize]).unwrap().1.unwrap();
+        drone.start_session(&key);
+
+        let mut sealer = Sealer::new(Direction::Downlink);
+        sealer.start_session(&key);
+        let ack = Message::Ack {
+            id: 0,
+            result: CommandResult::Ok,
+        };
+        let mut plaintext = [0u8; CAPACITY];
+        let plaintext = to_payload(&ack, &mut plaintext).unwrap();
+        let frame = sealer
+            .seal(plaintext, &mut [0u8; PAYLOAD_SIZE])
+            .unwrap()
+            .to_vec();
+
+        // It still answers on the default pipe, but not twice with the same frame
+        assert_eq!(
+            sessions.open(DEFAULT_PIPE, &frame),
+            Some(plaintext.to_vec())
+        );
+        assert_eq!(sessions.open(2, &frame), None);
+
+        let command = Uplink {
+            id: 1,
+            command: Command::RequestStatus,
+        };
+        let (len, mut payload) = sessions.seal(2, &command).unwrap();
+        let (plaintext, session) = drone.open(&mut payload[..len as usize]).unwrap();
+        assert_eq!(from_bytes::<Uplink>(plaintext).unwrap(), command);
+        assert_eq!(session, None);
+
+        assert!(sessions.seal(3, &command).is_none());
+        assert!(sessions.open(3, &frame).is_none());
+    }
+}
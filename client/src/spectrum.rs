@@ -0,0 +1,113 @@
+use portuni_common::spectrum::{SpectrumChunk, CHANNELS};
+
+/// Channels on either side that are taken into account when picking a channel, a transmission at
+/// 2 Mbps occupies 2 MHz
+const NEIGHBOURS: usize = 2;
+
+/// Result of the last spectrum scan, assembled from the `Message::Spectrum` chunks
+#[derive(Default)]
+pub struct Spectrum {
+    sweeps: u8,
+    hits: Vec<u8>,
+    received: Vec<bool>,
+    // Increased every time the scan completes, so its viewers know when to update
+    pub version: u32,
+}
+
+impl Spectrum {
+    pub fn add(&mut self, chunk: SpectrumChunk) {
+        // Chunks of an earlier scan are discarded
+        if chunk.sweeps != self.sweeps || self.hits.len() != CHANNELS || self.is_complete() {
+            self.sweeps = chunk.sweeps;
+            self.hits = vec![0; CHANNELS];
+            self.received = vec![false; CHANNELS];
+        }
+
+        let first = chunk.first_channel as usize;
+
+        for (offset, hits) in chunk.hits.iter().enumerate() {
+            if let Some(channel) = self.hits.get_mut(first + offset) {
+                *channel = *hits;
+                self.received[first + offset] = true;
+            }
+        }
+
+        if self.is_complete() {
+            self.version = self.version.wrapping_add(1);
+        }
+    }
+
+    pub fn is_complete(&self) -> bool {
+        !self.received.is_empty() && self.received.iter().all(|&received| received)
+    }
+
+    /// Fraction of the sweeps in which a carrier was detected, per channel
+    pub fn occupancy(&self) -> Vec<f32> {
+        self.hits
+            .iter()
+            .map(|&hits| f32::from(hits) / f32::from(self.sweeps.max(1)))
+            .collect()
+    }
+
+    /// The channel with the least activity on and around it, the lowest one if there's a tie
+    pub fn quietest(&self) -> Option<u8> {
+        if !self.is_complete() {
+            return None;
+        }
+
+        (0..CHANNELS)
+            .min_by_key(|&channel| {
+                let start = channel.saturating_sub(NEIGHBOURS);
+                let end = (channel + NEIGHBOURS + 1).min(CHANNELS);
+
+                // The channel itself weighs as much as all of its neighbours together
+                let around: u32 = self.hits[start..end].iter().map(|&h| u32::from(h)).sum();
+                around + u32::from(self.hits[channel]) * (2 * NEIGHBOURS as u32)
+            })
+            .map(|channel| channel as u8)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use portuni_common::spectrum::{Scan, CHUNK_SIZE};
+
+    fn scan(busy: &[u8]) -> Spectrum {
+        let mut scan = Scan::new(4);
+        while scan.record(busy.contains(&scan.channel())) {}
+
+        let mut spectrum = Spectrum::default();
+        for chunk in scan.report() {
+            spectrum.add(chunk);
+        }
+        spectrum
+    }
+
+    #[test]
+    fn test_complete() {
+        let mut spectrum = Spectrum::default();
+        spectrum.add(SpectrumChunk {
+            first_channel: 0,
+            sweeps: 4,
+            hits: [0; CHUNK_SIZE],
+        });
+
+        assert!(!spectrum.is_complete());
+        assert_eq!(spectrum.quietest(), None);
+
+        let spectrum = scan(&[1]);
+        assert!(spectrum.is_complete());
+        assert_eq!(spectrum.version, 1);
+        assert_eq!(spectrum.occupancy()[1], 1.0);
+    }
+
+    #[test]
+    fn test_quietest() {
+        // Every channel below 7 is within two channels of a busy one
+        assert_eq!(scan(&[1, 4]).quietest(), Some(7));
+
+        let busy: Vec<u8> = (0..CHANNELS as u8).filter(|&c| c != 60).collect();
+        assert_eq!(scan(&busy).quietest(), Some(60));
+    }
+}
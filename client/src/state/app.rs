@@ -1,6 +1,6 @@
 use amethyst::{
     core::transform::Transform,
-    ecs::prelude::{Component, DenseVecStorage, Entity},
+    ecs::prelude::Entity,
     prelude::*,
     renderer::Camera,
     ui::{UiCreator, UiFinder, UiText},
@@ -12,17 +12,17 @@ pub struct CompassUI {
     pub heading: Option<Entity>,
 }
 
-// Move this to a seperate entity creation system
-use crate::{DroneMarker, ScenePrefabData};
+use crate::component::{Attitude, Drone};
+use crate::ScenePrefabData;
 use amethyst::{
     assets::{Completion, Handle, Prefab, PrefabLoader, ProgressCounter, RonFormat},
-    ecs::{Write, WriteStorage},
-    utils::tag::{Tag, TagFinder},
+    ecs::Write,
 };
 
+/// The drone's model, of which the `FleetSystem` spawns one for every connected drone
 #[derive(Default)]
-struct Scene {
-    handle: Option<Handle<Prefab<ScenePrefabData>>>,
+pub struct Scene {
+    pub handle: Option<Handle<Prefab<ScenePrefabData>>>,
 }
 
 #[derive(Debug, Default)]
@@ -31,8 +31,6 @@ pub struct App {
     pub compass_ui: CompassUI,
     trx_status: Option<Entity>,
     progress: Option<ProgressCounter>,
-    initialized: bool,
-    entity: Option<Entity>,
 }
 
 impl SimpleState for App {
@@ -40,8 +38,7 @@ impl SimpleState for App {
         let world = data.world;
 
         world.register::<Drone>();
-        world.register::<DroneMarker>();
-        initialize_drone(world);
+        world.register::<Attitude>();
         initialize_camera(world);
 
         self.progress = Some(ProgressCounter::default());
@@ -61,48 +58,15 @@ impl SimpleState for App {
     }
 
     fn update(&mut self, state_data: &mut StateData<'_, GameData<'_, '_>>) -> SimpleTrans {
-        if !self.initialized {
-            let mut transform = Transform::default();
-
-            let remove = match self.progress.as_ref().map(|p| p.complete()) {
-                None | Some(Completion::Loading) => false,
-                Some(Completion::Complete) => {
-                    let scene_handle = state_data
-                        .world
-                        .read_resource::<Scene>()
-                        .handle
-                        .as_ref()
-                        .unwrap()
-                        .clone();
-
-                    state_data
-                        .world
-                        .create_entity()
-                        .with(scene_handle)
-                        .with(transform)
-                        .build();
-
-                    true
-                }
-
-                Some(Completion::Failed) => {
-                    println!("Error: {:?}", self.progress.as_ref().unwrap().errors());
-                    return Trans::Quit;
-                }
-            };
-
-            if remove {
-                self.progress = None;
-            }
-
-            if self.entity.is_none() {
-                if let Some(entity) = state_data
-                    .world
-                    .exec(|finder: TagFinder<'_, DroneMarker>| finder.find())
-                {
-                    self.entity = Some(entity);
-                    self.initialized = true;
-                }
+        match self.progress.as_ref().map(|p| p.complete()) {
+            None | Some(Completion::Loading) => {}
+            Some(Completion::Complete) => self.progress = None,
+            Some(Completion::Failed) => {
+                log::error!(
+                    "Loading failed: {:?}",
+                    self.progress.as_ref().unwrap().errors()
+                );
+                return Trans::Quit;
             }
         }
 
@@ -143,26 +107,3 @@ fn initialize_camera(world: &mut World) {
         .with(Camera::standard_3d(width, height))
         .build();
 }
-
-pub struct Drone;
-
-impl Drone {
-    fn new() -> Drone {
-        Drone {}
-    }
-}
-
-impl Component for Drone {
-    type Storage = DenseVecStorage<Self>;
-}
-
-fn initialize_drone(world: &mut World) {
-    let mut transform = Transform::default();
-    transform.set_translation_xyz(0.0, 0.0, 0.0);
-
-    world
-        .create_entity()
-        .with(Drone::new())
-        .with(transform)
-        .build();
-}
@@ -0,0 +1,305 @@
+//! Estimates the offset and drift of the drones' clocks against the host's monotonic clock
+//!
+//! The client sends `Command::Ping` with its own time, which the drone echoes in a `Message::Pong`
+//! along with its time. As in NTP, the drone's time is taken to be halfway the round trip. The
+//! uplink waits for the drone's next RX window, which makes the round trip asymmetric, so only the
+//! exchanges with the shortest round trips are used.
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Exchanges the estimate is fitted to, a minute's worth at the rate the client pings
+const MAX_EXCHANGES: usize = 64;
+
+/// Exchanges of which the round trip takes longer than this times the shortest one are not used
+const MAX_RTT_FACTOR: f64 = 1.5;
+
+/// An exchange this far off from the estimate, in microseconds, means the drone was reset
+const MAX_DEVIATION_US: f64 = 1_000_000.0;
+
+/// Extends a wrapping microsecond timestamp, which may step back a little
+#[derive(Default)]
+struct Unwrap {
+    last: Option<i64>,
+}
+
+impl Unwrap {
+    fn extend(&mut self, timestamp: u32) -> i64 {
+        let extended = match self.last {
+            Some(last) => last + i64::from(timestamp.wrapping_sub(last as u32) as i32),
+            None => i64::from(timestamp),
+        };
+        self.last = Some(extended);
+
+        extended
+    }
+}
+
+/// A ping and its pong, in microseconds
+struct Exchange {
+    // Host time halfway the round trip
+    host: f64,
+    drone: f64,
+    rtt: f64,
+}
+
+/// How a drone's clock relates to the host's
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Estimate {
+    /// Host minus drone time in microseconds, at the drone time of `reference`
+    pub offset_us: f64,
+    /// How much faster the host clock runs than the drone's, in parts per million
+    pub drift_ppm: f64,
+    /// Shortest round trip of the exchanges, half of which bounds the error of the offset
+    pub rtt_us: f64,
+    reference: f64,
+}
+
+impl Estimate {
+    fn host(&self, drone: f64) -> f64 {
+        drone + self.offset_us + self.drift_ppm * 1e-6 * (drone - self.reference)
+    }
+}
+
+/// Least squares fit of the offset against the drone's time, over the exchanges with the
+/// shortest round trips
+fn fit(exchanges: &VecDeque<Exchange>) -> Option<Estimate> {
+    let rtt_us = exchanges
+        .iter()
+        .map(|exchange| exchange.rtt)
+        .fold(f64::INFINITY, f64::min);
+    let used: Vec<&Exchange> = exchanges
+        .iter()
+        .filter(|exchange| exchange.rtt <= rtt_us * MAX_RTT_FACTOR)
+        .collect();
+
+    if used.is_empty() {
+        return None;
+    }
+
+    let count = used.len() as f64;
+    let reference = used.iter().map(|e| e.drone).sum::<f64>() / count;
+    let offset_us = used.iter().map(|e| e.host - e.drone).sum::<f64>() / count;
+
+    let (mut sxy, mut sxx) = (0.0, 0.0);
+    for exchange in &used {
+        let x = exchange.drone - reference;
+        sxy += x * (exchange.host - exchange.drone - offset_us);
+        sxx += x * x;
+    }
+
+    // A single exchange, or several at the same time, only give an offset
+    let slope = if sxx > 0.0 { sxy / sxx } else { 0.0 };
+
+    Some(Estimate {
+        offset_us,
+        drift_ppm: slope * 1e6,
+        rtt_us,
+        reference,
+    })
+}
+
+/// The exchanges with a single drone, all times on the host's clock are in microseconds
+#[derive(Default)]
+pub struct ClockSync {
+    drone: Unwrap,
+    exchanges: VecDeque<Exchange>,
+    estimate: Option<Estimate>,
+}
+
+impl ClockSync {
+    /// Adds an exchange of which the ping was `sent` and the pong `received`, with the drone's
+    /// `timestamp` in between. Returns the host time of `timestamp` by the new estimate
+    pub fn exchange(&mut self, sent: f64, timestamp: u32, received: f64) -> f64 {
+        let host = (sent + received) / 2.0;
+        let mut drone = self.drone.extend(timestamp) as f64;
+
+        let is_reset =
+            matches!(self.estimate, Some(e) if (e.host(drone) - host).abs() > MAX_DEVIATION_US);
+        if is_reset {
+            self.exchanges.clear();
+            self.drone = Unwrap::default();
+            drone = self.drone.extend(timestamp) as f64;
+        }
+
+        if self.exchanges.len() == MAX_EXCHANGES {
+            self.exchanges.pop_front();
+        }
+        self.exchanges.push_back(Exchange {
+            host,
+            drone,
+            rtt: received - sent,
+        });
+
+        self.estimate = fit(&self.exchanges);
+        self.estimate.map_or(host, |estimate| estimate.host(drone))
+    }
+
+    /// Host time at which the drone's clock showed `timestamp`, once there was an exchange
+    pub fn host(&mut self, timestamp: u32) -> Option<f64> {
+        let drone = self.drone.extend(timestamp) as f64;
+
+        Some(self.estimate?.host(drone))
+    }
+
+    pub fn estimate(&self) -> Option<Estimate> {
+        self.estimate
+    }
+}
+
+/// Timing of a single ping
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Pong {
+    pub round_trip: Duration,
+    pub uplink: Duration,
+}
+
+/// The clocks of the drones by relay pipe, shared by the serial thread and the `LinkSystem`
+#[derive(Clone)]
+pub struct Clocks {
+    epoch: Instant,
+    inner: Arc<Mutex<HashMap<u8, ClockSync>>>,
+}
+
+impl Default for Clocks {
+    fn default() -> Self {
+        Clocks {
+            epoch: Instant::now(),
+            inner: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+}
+
+impl Clocks {
+    pub fn new() -> Clocks {
+        Clocks::default()
+    }
+
+    fn micros(&self, instant: Instant) -> f64 {
+        instant.duration_since(self.epoch).as_micros() as f64
+    }
+
+    /// The host's time to send with `Command::Ping`, which wraps like the drone's
+    pub fn ping_timestamp(&self) -> u32 {
+        self.micros(Instant::now()) as u64 as u32
+    }
+
+    /// Called for every `Message::Pong`, which echoes `sent` along with the drone's `timestamp`
+    ///
+    /// Returns the round trip, and the part of it until the drone handled the ping.
+    pub fn pong(&self, pipe: u8, sent: u32, timestamp: u32, received: Instant) -> Pong {
+        let received = self.micros(received);
+        let sent = received - f64::from((received as u64 as u32).wrapping_sub(sent));
+
+        let mut inner = self.inner.lock().unwrap();
+        let sync = inner.entry(pipe).or_default();
+        let handled = sync.exchange(sent, timestamp, received);
+
+        Pong {
+            round_trip: Duration::from_micros((received - sent) as u64),
+            uplink: Duration::from_micros((handled - sent).clamp(0.0, received - sent) as u64),
+        }
+    }
+
+    /// Host time at which the clock of the drone on `pipe` showed `timestamp`
+    pub fn host_time(&self, pipe: u8, timestamp: u32) -> Option<Instant> {
+        let micros = self.inner.lock().unwrap().get_mut(&pipe)?.host(timestamp)?;
+        let offset = Duration::from_micros(micros.abs() as u64);
+
+        if micros >= 0.0 {
+            self.epoch.checked_add(offset)
+        } else {
+            self.epoch.checked_sub(offset)
+        }
+    }
+
+    pub fn estimate(&self, pipe: u8) -> Option<Estimate> {
+        self.inner.lock().unwrap().get(&pipe)?.estimate()
+    }
+
+    /// Starts over, e.g. once another drone is paired on `pipe`
+    pub fn reset(&self, pipe: u8) {
+        self.inner.lock().unwrap().remove(&pipe);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Exchanges with a drone of which the clock started at host time `start` and runs `ppm`
+    /// slower, the uplink takes `uplink` and the downlink 1 ms
+    fn exchange(sync: &mut ClockSync, start: f64, ppm: f64, sent: f64, uplink: f64) {
+        let drone = (sent + uplink - start) * (1.0 - ppm * 1e-6);
+        sync.exchange(sent, drone as u64 as u32, sent + uplink + 1000.0);
+    }
+
+    #[test]
+    fn test_offset() {
+        let mut sync = ClockSync::default();
+        assert_eq!(sync.host(0), None);
+
+        // The long round trip is left out
+        exchange(&mut sync, 5e6, 0.0, 6e6, 81_000.0);
+        exchange(&mut sync, 5e6, 0.0, 7e6, 1000.0);
+
+        let estimate = sync.estimate().unwrap();
+        assert!((estimate.offset_us - 5e6).abs() < 1.0);
+        assert_eq!(estimate.rtt_us, 2000.0);
+        assert!((sync.host(3_000_000).unwrap() - 8e6).abs() < 1.0);
+    }
+
+    #[test]
+    fn test_drift() {
+        let mut sync = ClockSync::default();
+
+        for i in 0..10 {
+            exchange(&mut sync, 0.0, 100.0, f64::from(i) * 1e6, 1000.0);
+        }
+
+        let estimate = sync.estimate().unwrap();
+        assert!((estimate.drift_ppm - 100.0).abs() < 1.0);
+
+        // A sample taken 20 s of drone time in
+        let host = sync.host(20_000_000).unwrap();
+        assert!((host - 20e6 / (1.0 - 100e-6)).abs() < 10.0);
+    }
+
+    #[test]
+    fn test_wrap() {
+        let mut sync = ClockSync::default();
+        let start = -f64::from(u32::MAX) + 1e6;
+
+        exchange(&mut sync, start, 0.0, 0.0, 1000.0);
+        exchange(&mut sync, start, 0.0, 2e6, 1000.0);
+
+        // The drone's clock wrapped in between
+        assert!((sync.host(1_000_000).unwrap() - 2e6).abs() < 10.0);
+    }
+
+    #[test]
+    fn test_reset() {
+        let mut sync = ClockSync::default();
+        exchange(&mut sync, 0.0, 0.0, 60e6, 1000.0);
+
+        // The drone booted again
+        exchange(&mut sync, 70e6, 0.0, 71e6, 1000.0);
+        let estimate = sync.estimate().unwrap();
+        assert!((estimate.offset_us - 70e6).abs() < 1.0);
+    }
+
+    #[test]
+    fn test_clocks() {
+        let clocks = Clocks::new();
+        assert_eq!(clocks.host_time(2, 0), None);
+
+        let sent = clocks.ping_timestamp();
+        let pong = clocks.pong(2, sent, 1000, Instant::now());
+        assert!(pong.uplink <= pong.round_trip);
+        assert!(clocks.host_time(2, 1000).is_some());
+        assert!(clocks.estimate(3).is_none());
+
+        clocks.reset(2);
+        assert!(clocks.estimate(2).is_none());
+    }
+}
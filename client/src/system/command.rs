@@ -0,0 +1,146 @@
+use amethyst::{
+    config::Config,
+    ecs::prelude::{Read, System, WriteExpect, WriteStorage},
+    input::{InputHandler, StringBindings},
+    ui::{UiFinder, UiText},
+    utils::application_root_dir,
+    winit::VirtualKeyCode,
+};
+
+use portuni_common::config::DeviceConfig;
+
+use crate::command::{Command, CommandLink, PendingCommand, DEFAULT_TIMEOUT};
+use crate::device_config::{ConfigWrite, WriteResult};
+use crate::fleet::Fleet;
+
+/// Keys that send a command to the drone
+const BINDINGS: [(VirtualKeyCode, Command); 4] = [
+    (VirtualKeyCode::T, Command::LedTest),
+    (VirtualKeyCode::I, Command::RequestStatus),
+    (VirtualKeyCode::S, Command::ScanSpectrum { sweeps: 8 }),
+    (VirtualKeyCode::C, Command::RequestConfig),
+];
+
+/// Writes `config/device.ron` to the selected drone
+const WRITE_CONFIG_KEY: VirtualKeyCode = VirtualKeyCode::W;
+
+/// Sends commands to the selected drone on key presses
+#[derive(Default)]
+pub struct CommandSystem {
+    pending: Vec<(Command, PendingCommand)>,
+    pressed: Vec<VirtualKeyCode>,
+    config_write: Option<ConfigWrite>,
+}
+
+impl CommandSystem {
+    fn key_pressed(&mut self, input: &InputHandler<StringBindings>, key: VirtualKeyCode) -> bool {
+        let was_down = self.pressed.contains(&key);
+        let is_down = input.key_is_down(key);
+
+        if is_down && !was_down {
+            self.pressed.push(key);
+        } else if !is_down && was_down {
+            self.pressed.retain(|&pressed| pressed != key);
+        }
+
+        is_down && !was_down
+    }
+
+    /// Starts writing the config file, which is read again on every press so it can be edited
+    /// while the client runs
+    fn write_config(&mut self, pipe: u8) -> String {
+        if self.config_write.is_some() {
+            return "config: already writing".to_string();
+        }
+
+        let path = match application_root_dir() {
+            Ok(path) => path.join("config").join("device.ron"),
+            Err(err) => return format!("config: {}", err),
+        };
+
+        match DeviceConfig::load(path) {
+            Ok(config) if config.is_valid() => {
+                self.config_write = Some(ConfigWrite::new(pipe, &config));
+                "config: writing".to_string()
+            }
+            Ok(_) => "config: channel out of range".to_string(),
+            Err(err) => format!("config: {}", err),
+        }
+    }
+}
+
+impl<'s> System<'s> for CommandSystem {
+    type SystemData = (
+        WriteExpect<'s, CommandLink>,
+        Read<'s, Fleet>,
+        Read<'s, InputHandler<StringBindings>>,
+        UiFinder<'s>,
+        WriteStorage<'s, UiText>,
+    );
+
+    fn run(&mut self, (mut link, fleet, input, ui_finder, mut ui_text): Self::SystemData) {
+        let mut text = None;
+
+        for &(key, command) in BINDINGS.iter() {
+            if self.key_pressed(&input, key) {
+                match fleet.selected() {
+                    Some(member) => {
+                        let pending = link.send(member.pipe, command, DEFAULT_TIMEOUT);
+                        self.pending.push((command, pending));
+                    }
+                    None => text = Some(format!("{:?}: no drone selected", command)),
+                }
+            }
+        }
+
+        if self.key_pressed(&input, WRITE_CONFIG_KEY) {
+            text = Some(match fleet.selected() {
+                Some(member) => self.write_config(member.pipe),
+                None => "config: no drone selected".to_string(),
+            });
+        }
+
+        if let Some(write) = &mut self.config_write {
+            if let Some(command) = write.next_command() {
+                if link.is_idle(write.pipe()) {
+                    write.sent(link.send(write.pipe(), command, DEFAULT_TIMEOUT));
+                }
+            }
+
+            if let Some(result) = write.poll() {
+                text = Some(match result {
+                    WriteResult::Saved => "config: saved".to_string(),
+                    WriteResult::Rejected(command, result) => {
+                        format!("config: {:?}: {:?}", command, result)
+                    }
+                    WriteResult::Error(command, error) => {
+                        format!("config: {:?}: {:?}", command, error)
+                    }
+                });
+                self.config_write = None;
+            }
+        }
+
+        self.pending
+            .retain(|(command, pending)| match pending.poll() {
+                Some(Ok(result)) => {
+                    text = Some(format!("{:?}: {:?}", command, result));
+                    false
+                }
+                Some(Err(error)) => {
+                    text = Some(format!("{:?}: {:?}", command, error));
+                    false
+                }
+                None => true,
+            });
+
+        if let Some(text) = text {
+            if let Some(label) = ui_finder
+                .find("command")
+                .and_then(|entity| ui_text.get_mut(entity))
+            {
+                label.text = text;
+            }
+        }
+    }
+}
@@ -0,0 +1,91 @@
+use std::time::{Duration, Instant};
+
+use amethyst::{
+    ecs::prelude::{Read, ReadExpect, System, WriteStorage},
+    input::{InputHandler, StringBindings},
+    ui::{UiFinder, UiText},
+    winit::VirtualKeyCode,
+};
+
+use crate::fleet::Fleet;
+use crate::latency::{Hop, Latencies, HOPS};
+
+/// Time between two updates of the panel
+const REFRESH_INTERVAL: Duration = Duration::from_millis(500);
+
+fn label(hop: Hop) -> &'static str {
+    match hop {
+        Hop::RoundTrip => "round trip",
+        Hop::Uplink => "uplink",
+        Hop::Downlink => "downlink",
+        Hop::Client => "client",
+    }
+}
+
+/// Shows the latencies of the selected drone, toggled with `D`
+#[derive(Default)]
+pub struct DiagnosticsSystem {
+    is_visible: bool,
+    pressed: bool,
+    refreshed: Option<Instant>,
+}
+
+impl<'s> System<'s> for DiagnosticsSystem {
+    type SystemData = (
+        ReadExpect<'s, Latencies>,
+        Read<'s, Fleet>,
+        Read<'s, InputHandler<StringBindings>>,
+        UiFinder<'s>,
+        WriteStorage<'s, UiText>,
+    );
+
+    fn run(&mut self, (latencies, fleet, input, ui_finder, mut ui_text): Self::SystemData) {
+        let is_down = input.key_is_down(VirtualKeyCode::D);
+        let toggled = is_down && !self.pressed;
+        self.pressed = is_down;
+
+        if toggled {
+            self.is_visible = !self.is_visible;
+        } else if matches!(self.refreshed, Some(at) if at.elapsed() < REFRESH_INTERVAL) {
+            return;
+        }
+
+        self.refreshed = Some(Instant::now());
+
+        let text = match fleet.selected() {
+            Some(member) if self.is_visible => {
+                let mut lines = vec![format!("{} latency  p50 / p95 / p99", member.name)];
+
+                for &hop in HOPS.iter() {
+                    let line = match latencies.percentiles(member.pipe, hop) {
+                        Some(p) => format!(
+                            "{:<10}  {} / {} / {} ms  ({})",
+                            label(hop),
+                            p.p50.as_millis(),
+                            p.p95.as_millis(),
+                            p.p99.as_millis(),
+                            p.count
+                        ),
+                        None => format!("{:<10}  -", label(hop)),
+                    };
+                    lines.push(line);
+                }
+
+                if let Some(signal) = latencies.signal(member.pipe) {
+                    lines.push(format!("{:<10}  {:.0}% above -64 dBm", "signal", signal));
+                }
+
+                lines.join("\n")
+            }
+            None if self.is_visible => "no drone selected".to_string(),
+            _ => String::new(),
+        };
+
+        if let Some(panel) = ui_finder
+            .find("diagnostics")
+            .and_then(|entity| ui_text.get_mut(entity))
+        {
+            panel.text = text;
+        }
+    }
+}
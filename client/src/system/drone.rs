@@ -1,16 +1,90 @@
-use amethyst::core::{SystemDesc, Transform};
-use amethyst::derive::SystemDesc;
-use amethyst::ecs::{Join, Read, ReadStorage, System, SystemData, World, WriteStorage};
+use amethyst::{
+    config::Config,
+    core::{
+        math::{UnitQuaternion, Vector3},
+        SystemDesc, Transform,
+    },
+    ecs::prelude::{Join, Read, System, SystemData, World, WriteStorage},
+    input::{InputHandler, StringBindings},
+    utils::application_root_dir,
+    winit::VirtualKeyCode,
+};
 
-#[derive(SystemDesc)]
-pub struct DroneSystem;
+use crate::component::{Attitude, Drone};
+use crate::config::DroneSettings;
+use crate::fleet::Fleet;
+
+/// Maps the `Attitude` of every drone onto the `Transform` of its model.
+pub struct DroneSystem {
+    offset_rotation: UnitQuaternion<f32>,
+    offset_translation: Vector3<f32>,
+}
+
+impl DroneSystem {
+    pub fn new() -> DroneSystem {
+        DroneSystem::from_settings(&DroneSettings::default())
+    }
+
+    fn from_settings(settings: &DroneSettings) -> DroneSystem {
+        let [x, y, z] = settings.model_offset.rotation;
+        let [tx, ty, tz] = settings.model_offset.translation;
+
+        DroneSystem {
+            offset_rotation: UnitQuaternion::from_euler_angles(
+                x.to_radians(),
+                y.to_radians(),
+                z.to_radians(),
+            ),
+            offset_translation: Vector3::new(tx, ty, tz),
+        }
+    }
+}
+
+impl<'a, 'b> SystemDesc<'a, 'b, DroneSystem> for DroneSystem {
+    fn build(self, world: &mut World) -> DroneSystem {
+        <DroneSystem as System<'_>>::SystemData::setup(world);
+
+        let config_path = match application_root_dir() {
+            Ok(path) => path.join("config").join("drone.ron"),
+            Err(err) => panic!(err),
+        };
+
+        // A missing or invalid file should not prevent the model from rendering
+        let settings = DroneSettings::load(config_path).unwrap_or_default();
+        let system = DroneSystem::from_settings(&settings);
+
+        world.insert(settings);
+
+        system
+    }
+}
 
 impl<'s> System<'s> for DroneSystem {
-    type SystemData = (WriteStorage<'s, Transform>, ReadStorage<'s, Drone>);
+    type SystemData = (
+        WriteStorage<'s, Transform>,
+        WriteStorage<'s, Attitude>,
+        WriteStorage<'s, Drone>,
+        Read<'s, Fleet>,
+        Read<'s, InputHandler<StringBindings>>,
+    );
+
+    fn run(&mut self, (mut transforms, mut attitudes, mut drones, fleet, input): Self::SystemData) {
+        let reset_level = input.key_is_down(VirtualKeyCode::L);
+
+        for (drone, attitude, transform) in (&mut drones, &mut attitudes, &mut transforms).join() {
+            // Only the selected drone is levelled
+            if reset_level && fleet.is_selected(drone.pipe) {
+                attitude.level();
+            }
+
+            transform.set_rotation(attitude.orientation * self.offset_rotation);
 
-    fn run(&mut self, (mut transforms, drones): Self::SystemData) {
-        for (drone, transform) in (&drones, &mut transforms).join() {
-            // let movement
+            // Only when its place next to the other drones changed, the translation is left alone
+            // otherwise
+            if drone.moved {
+                transform.set_translation(drone.position + self.offset_translation);
+                drone.moved = false;
+            }
         }
     }
 }
@@ -0,0 +1,155 @@
+use amethyst::{
+    core::SystemDesc,
+    ecs::prelude::{Read, System, SystemData, World, Write, WriteStorage},
+    input::{InputHandler, StringBindings},
+    ui::{UiFinder, UiText},
+    winit::VirtualKeyCode,
+};
+
+use portuni_common::log::Level;
+
+use crate::firmware_log::{FirmwareLog, LEVELS};
+
+/// Records shown at once
+const LINES: usize = 14;
+
+/// Keys toggling the levels in `LEVELS`
+const LEVEL_KEYS: [VirtualKeyCode; 5] = [
+    VirtualKeyCode::Key1,
+    VirtualKeyCode::Key2,
+    VirtualKeyCode::Key3,
+    VirtualKeyCode::Key4,
+    VirtualKeyCode::Key5,
+];
+
+fn label(level: Level) -> &'static str {
+    match level {
+        Level::Trace => "trace",
+        Level::Debug => "debug",
+        Level::Info => "info",
+        Level::Warn => "warn",
+        Level::Error => "error",
+    }
+}
+
+/// Shows the firmware's log records, toggled with `F`
+///
+/// The number keys toggle the levels, `PageUp` and `PageDown` scroll and `End` follows the newest
+/// records again.
+pub struct FirmwareLogSystem {
+    shown: Vec<Level>,
+    is_visible: bool,
+    // Keys that were down during the previous frame
+    pressed: Vec<VirtualKeyCode>,
+    version: Option<u32>,
+}
+
+impl FirmwareLogSystem {
+    /// Shows the records of the `shown` levels until they are toggled
+    pub fn new(shown: Vec<Level>) -> FirmwareLogSystem {
+        FirmwareLogSystem {
+            shown,
+            is_visible: false,
+            pressed: Vec::new(),
+            version: None,
+        }
+    }
+
+    fn key_pressed(&mut self, input: &InputHandler<StringBindings>, key: VirtualKeyCode) -> bool {
+        let was_down = self.pressed.contains(&key);
+        let is_down = input.key_is_down(key);
+
+        if is_down && !was_down {
+            self.pressed.push(key);
+        } else if !is_down && was_down {
+            self.pressed.retain(|&pressed| pressed != key);
+        }
+
+        is_down && !was_down
+    }
+}
+
+impl<'a, 'b> SystemDesc<'a, 'b, FirmwareLogSystem> for FirmwareLogSystem {
+    fn build(self, world: &mut World) -> FirmwareLogSystem {
+        <FirmwareLogSystem as System<'_>>::SystemData::setup(world);
+
+        world.insert(FirmwareLog::new(&self.shown));
+
+        self
+    }
+}
+
+impl<'s> System<'s> for FirmwareLogSystem {
+    type SystemData = (
+        Write<'s, FirmwareLog>,
+        Read<'s, InputHandler<StringBindings>>,
+        UiFinder<'s>,
+        WriteStorage<'s, UiText>,
+    );
+
+    fn run(&mut self, (mut log, input, ui_finder, mut ui_text): Self::SystemData) {
+        let toggled = self.key_pressed(&input, VirtualKeyCode::F);
+        if toggled {
+            self.is_visible = !self.is_visible;
+        }
+
+        if self.is_visible {
+            for (&level, &key) in LEVELS.iter().zip(LEVEL_KEYS.iter()) {
+                if self.key_pressed(&input, key) {
+                    log.toggle(level);
+                }
+            }
+
+            if self.key_pressed(&input, VirtualKeyCode::PageUp) {
+                log.scroll(LINES as isize);
+            }
+            if self.key_pressed(&input, VirtualKeyCode::PageDown) {
+                log.scroll(-(LINES as isize));
+            }
+            if self.key_pressed(&input, VirtualKeyCode::End) {
+                log.scroll_to_end();
+            }
+        }
+
+        if !toggled && self.version == Some(log.version) {
+            return;
+        }
+
+        self.version = Some(log.version);
+
+        let text = if self.is_visible {
+            let levels: Vec<_> = LEVELS
+                .iter()
+                .enumerate()
+                .map(|(i, &level)| {
+                    if log.is_shown(level) {
+                        format!("{} {}", i + 1, label(level))
+                    } else {
+                        format!("{} -", i + 1)
+                    }
+                })
+                .collect();
+
+            let mut lines = vec![format!("firmware log  {}", levels.join("  "))];
+            for entry in log.page(LINES) {
+                lines.push(format!(
+                    "{:<5}  {}: {}",
+                    label(entry.level),
+                    entry.drone,
+                    entry.text
+                ));
+            }
+
+            lines.join("\n")
+        } else {
+            String::new()
+        };
+
+        if let Some(panel) = ui_finder
+            .find("firmware_log")
+            .and_then(|entity| ui_text.get_mut(entity))
+        {
+            panel.text = text;
+        }
+    }
+}
@@ -0,0 +1,160 @@
+use amethyst::{
+    assets::{Handle, Prefab},
+    core::{math::Vector3, ParentHierarchy, Transform},
+    ecs::prelude::{Entities, Join, Read, ReadExpect, System, Write, WriteStorage},
+    input::{InputHandler, StringBindings},
+    renderer::{palette::Srgba, resources::Tint},
+    ui::{UiFinder, UiText},
+    winit::VirtualKeyCode,
+};
+
+use crate::component::{Attitude, Drone};
+use crate::fleet::Fleet;
+use crate::state::app::Scene;
+use crate::ScenePrefabData;
+
+/// Distance between the models of two drones
+const SPACING: f32 = 1.5;
+
+/// Tint of the models of drones to which the link is lost
+const LOST_TINT: (f32, f32, f32, f32) = (0.3, 0.3, 0.3, 1.0);
+
+/// Spawns a model from `prefab/scene.ron` for every drone in the `Fleet`, and selects the next
+/// drone on tab. Drones to which the link is lost are greyed out
+#[derive(Default)]
+pub struct FleetSystem {
+    pressed: bool,
+    text: String,
+}
+
+impl<'s> System<'s> for FleetSystem {
+    type SystemData = (
+        Entities<'s>,
+        Write<'s, Fleet>,
+        Read<'s, Scene>,
+        ReadExpect<'s, ParentHierarchy>,
+        WriteStorage<'s, Handle<Prefab<ScenePrefabData>>>,
+        WriteStorage<'s, Transform>,
+        WriteStorage<'s, Drone>,
+        WriteStorage<'s, Attitude>,
+        WriteStorage<'s, Tint>,
+        Read<'s, InputHandler<StringBindings>>,
+        UiFinder<'s>,
+        WriteStorage<'s, UiText>,
+    );
+
+    fn run(
+        &mut self,
+        (
+            entities,
+            mut fleet,
+            scene,
+            hierarchy,
+            mut prefabs,
+            mut transforms,
+            mut drones,
+            mut attitudes,
+            mut tints,
+            input,
+            ui_finder,
+            mut ui_text,
+        ): Self::SystemData,
+    ) {
+        // Drones that left the fleet are removed along with the entities of their model
+        for (entity, drone) in (&entities, &drones).join() {
+            if fleet.get(drone.pipe).is_none() {
+                for child in hierarchy.all_children_iter(entity) {
+                    let _ = entities.delete(child);
+                }
+                let _ = entities.delete(entity);
+            }
+        }
+
+        let handle = match &scene.handle {
+            Some(handle) => handle,
+            None => return,
+        };
+
+        for member in fleet.members() {
+            if (&drones).join().any(|drone| drone.pipe == member.pipe) {
+                continue;
+            }
+
+            entities
+                .build_entity()
+                .with(handle.clone(), &mut prefabs)
+                .with(Transform::default(), &mut transforms)
+                .with(Drone::new(member.pipe), &mut drones)
+                .with(Attitude::default(), &mut attitudes)
+                .build();
+        }
+
+        // Side by side in the order of their pipes, centered on the origin
+        let count = fleet.members().len() as f32;
+
+        for (index, member) in fleet.members().iter().enumerate() {
+            let x = (index as f32 - (count - 1.0) / 2.0) * SPACING;
+
+            let position = Vector3::new(x, 0.0, 0.0);
+
+            for drone in (&mut drones)
+                .join()
+                .filter(|drone| drone.pipe == member.pipe && drone.position != position)
+            {
+                drone.position = position;
+                drone.moved = true;
+            }
+        }
+
+        // The tint is applied to every part of the model
+        for (entity, drone) in (&entities, &drones).join() {
+            let is_lost = fleet.lost(drone.pipe).is_some();
+
+            for part in std::iter::once(entity).chain(hierarchy.all_children_iter(entity)) {
+                if is_lost && !tints.contains(part) {
+                    let (r, g, b, a) = LOST_TINT;
+                    let _ = tints.insert(part, Tint(Srgba::new(r, g, b, a)));
+                } else if !is_lost {
+                    tints.remove(part);
+                }
+            }
+        }
+
+        let is_down = input.key_is_down(VirtualKeyCode::Tab);
+        if is_down && !self.pressed {
+            fleet.select_next();
+        }
+        self.pressed = is_down;
+
+        let text = fleet
+            .members()
+            .iter()
+            .map(|member| {
+                let name = match fleet.lost(member.pipe) {
+                    Some(lost) => {
+                        format!("{} (link lost {:.1} s)", member.name, lost.as_secs_f32())
+                    }
+                    None => member.name.clone(),
+                };
+
+                if fleet.is_selected(member.pipe) {
+                    format!("[{}]", name)
+                } else {
+                    name
+                }
+            })
+            .collect::<Vec<_>>()
+            .join(" ");
+
+        if text != self.text {
+            if let Some(label) = ui_finder
+                .find("fleet")
+                .and_then(|entity| ui_text.get_mut(entity))
+            {
+                label.text = text.clone();
+            }
+
+            self.text = text;
+        }
+    }
+}
@@ -0,0 +1,293 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use amethyst::{
+    config::Config,
+    core::SystemDesc,
+    ecs::prelude::{Read, ReadExpect, System, SystemData, Write, WriteExpect, WriteStorage},
+    input::{InputHandler, StringBindings},
+    prelude::*,
+    ui::{UiFinder, UiText},
+    utils::application_root_dir,
+    winit::VirtualKeyCode,
+};
+
+use portuni_common::{
+    command::{Encoding, MIN_LINK_TIMEOUT_MS},
+    pairing::DeviceId,
+};
+
+use crate::command::{Command, CommandLink, RadioConfig, DEFAULT_PIPE, DEFAULT_TIMEOUT};
+use crate::config::{PairedDevices, TransceiverSettings};
+use crate::fleet::{Fleet, Member};
+use crate::latency::Latencies;
+use crate::negotiation::{Negotiation, Outcome};
+use crate::sync::Clocks;
+
+/// Time without any message from a paired drone after which it is removed from the fleet, e.g.
+/// because it was reset and advertises itself on the default addresses again. Until then it is
+/// shown as lost, once the configured link timeout passed
+const REMOVE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Heartbeats sent to a drone per link timeout, so a single one may get lost
+const HEARTBEATS_PER_TIMEOUT: u32 = 3;
+
+/// Time between two pings that synchronize the clock of a drone
+const PING_INTERVAL: Duration = Duration::from_secs(1);
+
+/// What the transceiver heard from the drones since the last frame
+#[derive(Default)]
+pub struct LinkStatus {
+    // Id and nonce of an unpaired drone that advertised itself
+    pub advertised: Option<(DeviceId, u32)>,
+    // Time of the last message, by relay pipe
+    pub last_message: HashMap<u8, Instant>,
+}
+
+/// Whether `interval` passed since the time in `last` for `pipe`
+fn is_due(last: &HashMap<u8, Instant>, pipe: u8, interval: Duration) -> bool {
+    !matches!(last.get(&pipe), Some(last) if last.elapsed() < interval)
+}
+
+enum Step {
+    Pairing(DeviceId, u8),
+    Radio(u8),
+}
+
+/// Pairs with drones that advertise themselves and adds them to the `Fleet`, a single drone is
+/// then moved to the configured channel and data rate
+pub struct LinkSystem {
+    paired_path: PathBuf,
+    paired: PairedDevices,
+    // Channel and data rate the relay is on
+    current: RadioConfig,
+    negotiation: Option<(Step, Negotiation)>,
+    // An unknown drone and its nonce, which is paired once the user confirms it
+    unconfirmed: Option<(DeviceId, u32)>,
+    // Time of the last heartbeat and ping, by relay pipe
+    heartbeats: HashMap<u8, Instant>,
+    pings: HashMap<u8, Instant>,
+    pressed: bool,
+}
+
+impl LinkSystem {
+    pub fn new() -> LinkSystem {
+        LinkSystem {
+            paired_path: PathBuf::new(),
+            paired: PairedDevices::default(),
+            current: RadioConfig::default(),
+            negotiation: None,
+            unconfirmed: None,
+            heartbeats: HashMap::new(),
+            pings: HashMap::new(),
+            pressed: false,
+        }
+    }
+
+    fn name(&self, id: &DeviceId) -> String {
+        match self.paired.get(id) {
+            Some(device) => device.name.clone(),
+            None => id.iter().map(|byte| format!("{:02x}", byte)).collect(),
+        }
+    }
+
+    /// Returns the text to show
+    fn pair(
+        &mut self,
+        link: &mut CommandLink,
+        fleet: &Fleet,
+        proposal: (Encoding, u8),
+        id: DeviceId,
+        nonce: u32,
+    ) -> String {
+        match fleet.free_pipe() {
+            Some(pipe) => {
+                let negotiation =
+                    Negotiation::pair(link, id, nonce, pipe, proposal, DEFAULT_TIMEOUT);
+                self.negotiation = Some((Step::Pairing(id, pipe), negotiation));
+
+                format!("pairing with {}", self.name(&id))
+            }
+            None => format!("no pipe left for {}", self.name(&id)),
+        }
+    }
+}
+
+impl<'a, 'b> SystemDesc<'a, 'b, LinkSystem> for LinkSystem {
+    fn build(self, world: &mut World) -> LinkSystem {
+        <LinkSystem as System<'_>>::SystemData::setup(world);
+
+        let paired_path = match application_root_dir() {
+            Ok(path) => path.join("config").join("paired.ron"),
+            Err(err) => panic!(err),
+        };
+
+        // Nothing was paired yet if the file doesn't exist
+        let paired = PairedDevices::load(&paired_path).unwrap_or_default();
+
+        LinkSystem {
+            paired_path,
+            paired,
+            ..self
+        }
+    }
+}
+
+impl<'s> System<'s> for LinkSystem {
+    type SystemData = (
+        WriteExpect<'s, CommandLink>,
+        ReadExpect<'s, TransceiverSettings>,
+        ReadExpect<'s, Clocks>,
+        ReadExpect<'s, Latencies>,
+        Write<'s, LinkStatus>,
+        Write<'s, Fleet>,
+        Read<'s, InputHandler<StringBindings>>,
+        UiFinder<'s>,
+        WriteStorage<'s, UiText>,
+    );
+
+    fn run(
+        &mut self,
+        (
+            mut link,
+            settings,
+            clocks,
+            latencies,
+            mut status,
+            mut fleet,
+            input,
+            ui_finder,
+            mut ui_text,
+        ): Self::SystemData,
+    ) {
+        let mut text = None;
+        let proposal = (settings.encoding, settings.batch_size);
+
+        if let Some((id, nonce)) = status.advertised.take() {
+            // A drone that advertises itself again was reset, and has to be paired again
+            let stale = fleet.members().iter().find(|m| m.id == id).map(|m| m.pipe);
+            if let Some(pipe) = stale {
+                fleet.remove(pipe);
+            }
+
+            if self.negotiation.is_none() {
+                if self.paired.get(&id).is_some() {
+                    text = Some(self.pair(&mut link, &fleet, proposal, id, nonce));
+                } else {
+                    if !matches!(self.unconfirmed, Some((unconfirmed, _)) if unconfirmed == id) {
+                        text = Some(format!("press P to pair with {}", self.name(&id)));
+                    }
+
+                    // The nonce changes when the drone boots again
+                    self.unconfirmed = Some((id, nonce));
+                }
+            }
+        }
+
+        let is_down = input.key_is_down(VirtualKeyCode::P);
+
+        if is_down && !self.pressed && self.negotiation.is_none() {
+            if let Some((id, nonce)) = self.unconfirmed.take() {
+                self.paired.add(id);
+
+                if let Err(err) = self.paired.write(&self.paired_path) {
+                    log::error!("Could not store paired devices: {:?}", err);
+                }
+
+                text = Some(self.pair(&mut link, &fleet, proposal, id, nonce));
+            }
+        }
+
+        self.pressed = is_down;
+
+        if let Some((step, mut negotiation)) = self.negotiation.take() {
+            match (step, negotiation.poll(&mut link)) {
+                (step, None) => self.negotiation = Some((step, negotiation)),
+                (Step::Pairing(id, pipe), Some(outcome)) => {
+                    let name = self.name(&id);
+                    text = Some(format!("{}: {:?}", name, outcome));
+
+                    if let Outcome::Switched(config) = outcome {
+                        status.last_message.insert(pipe, Instant::now());
+                        clocks.reset(pipe);
+                        latencies.reset(pipe);
+                        fleet.add(Member { pipe, id, name });
+
+                        // The relay's channel is shared, so only a single drone is moved
+                        let target =
+                            config.with_link(settings.radio.channel, settings.radio.data_rate);
+
+                        if fleet.members().len() == 1 && target != config {
+                            let negotiation =
+                                Negotiation::set_radio(&mut link, pipe, target, DEFAULT_TIMEOUT);
+                            self.negotiation = Some((Step::Radio(pipe), negotiation));
+                        }
+                    }
+                }
+                (Step::Radio(_), Some(outcome)) => {
+                    text = Some(format!("radio: {:?}", outcome));
+
+                    if let Outcome::Switched(config) | Outcome::FellBack(config) = outcome {
+                        self.current = self.current.with_link(config.channel, config.data_rate);
+                    }
+                }
+            }
+        }
+
+        let timeout_ms = settings.link_timeout_ms.max(MIN_LINK_TIMEOUT_MS);
+        let link_timeout = Duration::from_millis(u64::from(timeout_ms));
+        let mut stale = Vec::new();
+
+        for pipe in fleet.members().iter().map(|m| m.pipe).collect::<Vec<_>>() {
+            match status.last_message.get(&pipe).map(Instant::elapsed) {
+                Some(elapsed) if elapsed <= link_timeout => fleet.set_lost(pipe, None),
+                Some(elapsed) if elapsed <= REMOVE_TIMEOUT => fleet.set_lost(pipe, Some(elapsed)),
+                _ => stale.push(pipe),
+            }
+        }
+
+        if self.negotiation.is_none() {
+            for pipe in stale {
+                if let Some(member) = fleet.remove(pipe) {
+                    text = Some(format!("{} removed", member.name));
+                }
+            }
+
+            // Sent in between other commands, without waiting for the acknowledgement. The drone
+            // engages its failsafe once none of them arrives within the timeout
+            let interval = link_timeout / HEARTBEATS_PER_TIMEOUT;
+
+            for member in fleet.members() {
+                let pipe = member.pipe;
+
+                if is_due(&self.heartbeats, pipe, interval) && link.is_idle(pipe) {
+                    let _ = link.send(pipe, Command::Heartbeat { timeout_ms }, interval);
+                    self.heartbeats.insert(pipe, Instant::now());
+                }
+
+                // The pong is handled by the serial thread
+                if is_due(&self.pings, pipe, PING_INTERVAL) && link.is_idle(pipe) {
+                    let sent = clocks.ping_timestamp();
+                    let _ = link.send(pipe, Command::Ping { sent }, DEFAULT_TIMEOUT);
+                    self.pings.insert(pipe, Instant::now());
+                }
+            }
+
+            // Drones that were reset advertise themselves on the default channel
+            if fleet.members().is_empty() && self.current != RadioConfig::default() {
+                self.current = RadioConfig::default();
+                let _ = link.configure_relay(DEFAULT_PIPE, self.current);
+            }
+        }
+
+        if let Some(text) = text {
+            if let Some(label) = ui_finder
+                .find("link")
+                .and_then(|entity| ui_text.get_mut(entity))
+            {
+                label.text = text;
+            }
+        }
+    }
+}
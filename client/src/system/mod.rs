@@ -1,4 +1,17 @@
+pub mod command;
+pub mod diagnostics;
+pub mod drone;
+pub mod firmware_log;
+pub mod fleet;
+pub mod link;
+pub mod recording;
+pub mod spectrum;
 pub mod transceiver;
 pub mod ui;
 
-pub use self::{transceiver::TransceiverCodecSystem, ui::UiEventHandlerSystem};
+pub use self::{
+    command::CommandSystem, diagnostics::DiagnosticsSystem, drone::DroneSystem,
+    firmware_log::FirmwareLogSystem, fleet::FleetSystem, link::LinkSystem,
+    recording::RecordingSystem, spectrum::SpectrumSystem, transceiver::TransceiverCodecSystem,
+    ui::UiEventHandlerSystem,
+};
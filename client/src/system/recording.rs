@@ -0,0 +1,186 @@
+use std::time::Instant;
+
+use amethyst::{
+    ecs::prelude::{Read, System, Write, WriteExpect, WriteStorage},
+    input::{InputHandler, StringBindings},
+    ui::{UiFinder, UiText},
+    utils::application_root_dir,
+    winit::VirtualKeyCode,
+};
+
+use portuni_common::command::CommandResult;
+
+use crate::command::{Command, CommandLink, PendingCommand, DEFAULT_TIMEOUT};
+use crate::fleet::Fleet;
+use crate::recording::{path, Download, Recorder, Recording, Source};
+
+/// Starts and stops recording the selected drone's samples
+const LIVE_KEY: VirtualKeyCode = VirtualKeyCode::R;
+/// Downloads the selected drone's blackbox
+const BLACKBOX_KEY: VirtualKeyCode = VirtualKeyCode::B;
+
+/// A new recording in `recordings/` of the client
+fn create(name: &str, source: Source) -> Result<Recording, String> {
+    let root = application_root_dir().map_err(|err| err.to_string())?;
+
+    Recording::create(path(&root.join("recordings"), name, source)).map_err(|err| err.to_string())
+}
+
+/// Records the selected drone's samples to `recordings/`, and downloads its blackbox there
+#[derive(Default)]
+pub struct RecordingSystem {
+    pressed: Vec<VirtualKeyCode>,
+    // Request for the download in progress
+    pending: Option<PendingCommand>,
+}
+
+impl RecordingSystem {
+    fn key_pressed(&mut self, input: &InputHandler<StringBindings>, key: VirtualKeyCode) -> bool {
+        let was_down = self.pressed.contains(&key);
+        let is_down = input.key_is_down(key);
+
+        if is_down && !was_down {
+            self.pressed.push(key);
+        } else if !is_down && was_down {
+            self.pressed.retain(|&pressed| pressed != key);
+        }
+
+        is_down && !was_down
+    }
+
+    fn toggle_live(recorder: &mut Recorder, fleet: &Fleet) -> String {
+        if let Some((_, recording)) = recorder.live.take() {
+            return match recording.finish() {
+                Ok((path, samples)) => {
+                    format!("recorded {} samples to {}", samples, path.display())
+                }
+                Err(err) => format!("recording: {}", err),
+            };
+        }
+
+        let member = match fleet.selected() {
+            Some(member) => member,
+            None => return "recording: no drone selected".to_string(),
+        };
+
+        match create(&member.name, Source::Live) {
+            Ok(recording) => {
+                let text = format!("recording to {}", recording.path.display());
+                recorder.live = Some((member.pipe, recording));
+                text
+            }
+            Err(err) => format!("recording: {}", err),
+        }
+    }
+
+    /// Writes the samples of a finished download, even if it wasn't complete
+    fn save_download(download: &Download, name: &str) -> String {
+        let written = create(name, Source::Blackbox).and_then(|mut recording| {
+            for value in download.samples() {
+                recording.write(value).map_err(|err| err.to_string())?;
+            }
+            recording.finish().map_err(|err| err.to_string())
+        });
+
+        let outcome = if download.is_complete() {
+            "downloaded"
+        } else {
+            "gave up after"
+        };
+
+        match written {
+            Ok((path, samples)) => {
+                format!(
+                    "blackbox: {} {} samples to {}",
+                    outcome,
+                    samples,
+                    path.display()
+                )
+            }
+            Err(err) => format!("blackbox: {}", err),
+        }
+    }
+}
+
+impl<'s> System<'s> for RecordingSystem {
+    type SystemData = (
+        Write<'s, Recorder>,
+        WriteExpect<'s, CommandLink>,
+        Read<'s, Fleet>,
+        Read<'s, InputHandler<StringBindings>>,
+        UiFinder<'s>,
+        WriteStorage<'s, UiText>,
+    );
+
+    fn run(
+        &mut self,
+        (mut recorder, mut link, fleet, input, ui_finder, mut ui_text): Self::SystemData,
+    ) {
+        let now = Instant::now();
+        let mut text = None;
+
+        if self.key_pressed(&input, LIVE_KEY) {
+            text = Some(Self::toggle_live(&mut recorder, &fleet));
+        }
+
+        if let Some(error) = recorder.error.take() {
+            text = Some(format!("recording: {}", error));
+        }
+
+        if self.key_pressed(&input, BLACKBOX_KEY) {
+            let downloading = recorder.download.is_some();
+
+            text = Some(match fleet.selected() {
+                _ if downloading => "blackbox: already downloading".to_string(),
+                Some(member) => {
+                    recorder.download = Some(Download::new(member.pipe));
+                    "blackbox: downloading".to_string()
+                }
+                None => "blackbox: no drone selected".to_string(),
+            });
+        }
+
+        // A rejected request ends the download, one that timed out is sent again
+        if let Some(result) = self.pending.as_ref().and_then(PendingCommand::poll) {
+            self.pending = None;
+
+            match result {
+                Ok(CommandResult::Ok) | Err(_) => {}
+                Ok(result) => {
+                    text = Some(format!("blackbox: {:?}", result));
+                    recorder.download = None;
+                }
+            }
+        }
+
+        if let Some(download) = &mut recorder.download {
+            let pipe = download.pipe();
+
+            if self.pending.is_none() && link.is_idle(pipe) {
+                if let Some(from) = download.request(now) {
+                    let command = Command::ReadBlackbox { from };
+                    self.pending = Some(link.send(pipe, command, DEFAULT_TIMEOUT));
+                }
+            }
+
+            if download.is_finished(now) {
+                let name = match fleet.get(pipe) {
+                    Some(member) => member.name.clone(),
+                    None => format!("pipe-{}", pipe),
+                };
+
+                text = Some(Self::save_download(download, &name));
+                recorder.download = None;
+            }
+        }
+
+        if let Some(text) = text {
+            if let Some(label) = ui_finder
+                .find("command")
+                .and_then(|entity| ui_text.get_mut(entity))
+            {
+                label.text = text;
+            }
+        }
+    }
+}
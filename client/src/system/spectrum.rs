@@ -0,0 +1,94 @@
+use amethyst::{
+    ecs::prelude::{Entities, Entity, Read, System, WriteStorage},
+    ui::{Anchor, UiFinder, UiImage, UiText, UiTransform},
+};
+
+use crate::spectrum::Spectrum;
+
+/// Size of the bar of a fully occupied channel
+const BAR_WIDTH: f32 = 3.0;
+const BAR_HEIGHT: f32 = 100.0;
+
+const BAR_COLOR: [f32; 4] = [0.6, 0.6, 0.6, 1.0];
+const QUIETEST_COLOR: [f32; 4] = [0.4, 1.0, 0.4, 1.0];
+
+/// Shows the result of the last spectrum scan as a bar chart along the bottom of the window
+#[derive(Default)]
+pub struct SpectrumSystem {
+    bars: Vec<Entity>,
+    version: u32,
+}
+
+impl<'s> System<'s> for SpectrumSystem {
+    type SystemData = (
+        Entities<'s>,
+        Read<'s, Spectrum>,
+        WriteStorage<'s, UiTransform>,
+        WriteStorage<'s, UiImage>,
+        UiFinder<'s>,
+        WriteStorage<'s, UiText>,
+    );
+
+    fn run(
+        &mut self,
+        (entities, spectrum, mut transforms, mut images, ui_finder, mut ui_text): Self::SystemData,
+    ) {
+        if spectrum.version == self.version {
+            return;
+        }
+
+        self.version = spectrum.version;
+
+        let occupancy = spectrum.occupancy();
+        let quietest = spectrum.quietest();
+
+        if self.bars.is_empty() {
+            let offset = (occupancy.len() as f32 - 1.0) * BAR_WIDTH / 2.0;
+
+            self.bars = (0..occupancy.len())
+                .map(|channel| {
+                    let transform = UiTransform::new(
+                        format!("spectrum_{}", channel),
+                        Anchor::BottomMiddle,
+                        Anchor::BottomMiddle,
+                        channel as f32 * BAR_WIDTH - offset,
+                        20.0,
+                        1.0,
+                        BAR_WIDTH - 1.0,
+                        1.0,
+                    );
+
+                    entities
+                        .build_entity()
+                        .with(transform, &mut transforms)
+                        .with(UiImage::SolidColor(BAR_COLOR), &mut images)
+                        .build()
+                })
+                .collect();
+        }
+
+        for (channel, (bar, occupancy)) in self.bars.iter().zip(occupancy).enumerate() {
+            if let Some(transform) = transforms.get_mut(*bar) {
+                // Free channels keep a sliver, so the extent of the chart remains visible
+                transform.height = (occupancy * BAR_HEIGHT).max(1.0);
+            }
+
+            let color = if quietest == Some(channel as u8) {
+                QUIETEST_COLOR
+            } else {
+                BAR_COLOR
+            };
+
+            let _ = images.insert(*bar, UiImage::SolidColor(color));
+        }
+
+        if let (Some(channel), Some(label)) = (
+            quietest,
+            ui_finder
+                .find("spectrum")
+                .and_then(|entity| ui_text.get_mut(entity)),
+        ) {
+            label.text = format!("quietest channel {}", channel);
+        }
+    }
+}
@@ -1,57 +1,54 @@
+use std::io::Write as _;
 use std::sync::{
     mpsc::{self, Receiver, Sender},
     Arc, Mutex,
 };
 use std::thread;
+use std::time::Instant;
 
 use amethyst::{
     config::Config,
-    core::{SystemDesc, Transform},
-    ecs::prelude::{Join, Read, ReadExpect, ReadStorage, System, SystemData, Write, WriteStorage},
+    core::SystemDesc,
+    ecs::prelude::{Join, Read, ReadExpect, System, SystemData, Write, WriteStorage},
     prelude::*,
     ui::{UiFinder, UiText},
     utils::application_root_dir,
-    utils::tag::Tag,
 };
 
-use serialport::{open_with_settings, SerialPortSettings};
+use serialport::{open_with_settings, SerialPort, SerialPortSettings};
 
+use crate::command::{self, Acknowledgements, RelayFrame};
 use crate::config::TransceiverSettings;
+use crate::device_config;
+use crate::firmware_log::{describe, log_level, Entry, FirmwareLog, FIRMWARE_TARGET};
+use crate::fleet::Fleet;
+use crate::latency::{Hop, Latencies};
+use crate::reassembly::Downlinks;
+use crate::recording::Recorder;
+use crate::secure::Sessions;
+use crate::spectrum::Spectrum;
+use crate::sync::Clocks;
+use crate::system::link::LinkStatus;
 use crate::transceiver::TransceiverDevice;
 
-use serde::{Deserialize, Serialize};
+pub use portuni_common::protocol::{encode, Downlink, Message, Telemetry, PAYLOAD_SIZE};
 
-#[derive(Serialize, Deserialize, Debug, PartialEq)]
-pub struct Telemetry {
-    mag_x: i16,
-    mag_y: i16,
-    gyro_x: f32,
-    gyro_y: f32,
-    gyro_z: f32,
-    temp: i8,
+/// A message from the serial thread, samples carry the host time at which they were taken once
+/// the drone's clock is synchronized
+pub struct Received {
+    pub downlink: Downlink,
+    pub taken: Option<Instant>,
+    // When the message was read from serial
+    pub received: Instant,
 }
 
-use crate::utils::interp::MovingAverage;
-
 pub struct TransceiverCodecSystem {
-    trx_recv: Option<Arc<Mutex<Receiver<Telemetry>>>>,
-    mag_x_avg: MovingAverage,
-    mag_y_avg: MovingAverage,
-    gyro_x_avg: MovingAverage,
-    gyro_y_avg: MovingAverage,
-    gyro_z_avg: MovingAverage,
+    trx_recv: Option<Arc<Mutex<Receiver<Received>>>>,
 }
 
 impl TransceiverCodecSystem {
     pub fn new() -> TransceiverCodecSystem {
-        TransceiverCodecSystem {
-            trx_recv: None,
-            mag_x_avg: MovingAverage::new(32, None),
-            mag_y_avg: MovingAverage::new(32, None),
-            gyro_x_avg: MovingAverage::new(16, None),
-            gyro_y_avg: MovingAverage::new(16, None),
-            gyro_z_avg: MovingAverage::new(16, None),
-        }
+        TransceiverCodecSystem { trx_recv: None }
     }
 }
 
@@ -71,40 +68,71 @@ impl<'a, 'b> SystemDesc<'a, 'b, TransceiverCodecSystem> for TransceiverCodecSyst
             Err(e) => panic!(e),
         };
 
-        let (send, recv): (Sender<Telemetry>, Receiver<Telemetry>) = mpsc::channel();
+        let (send, recv): (Sender<Received>, Receiver<Received>) = mpsc::channel();
         let recv = Arc::new(Mutex::new(recv));
 
-        thread::spawn(move || read_serial(settings, send));
+        let clocks = Clocks::new();
+        world.insert(clocks.clone());
+
+        let latencies = Latencies::new();
+        world.insert(latencies.clone());
+
+        let (relay_send, relay_recv) = mpsc::channel();
+        let sessions = settings.key.map(Sessions::new);
+        let (link, acks) = match &sessions {
+            Some(sessions) => command::secure_link(relay_send, sessions.clone()),
+            None => command::link(relay_send),
+        };
+        world.insert(link);
+
+        thread::spawn(move || {
+            read_serial(
+                settings, send, relay_recv, acks, sessions, clocks, latencies,
+            )
+        });
 
         TransceiverCodecSystem {
             trx_recv: Some(recv),
-            mag_x_avg: MovingAverage::new(32, None),
-            mag_y_avg: MovingAverage::new(32, None),
-            gyro_x_avg: MovingAverage::new(16, None),
-            gyro_y_avg: MovingAverage::new(16, None),
-            gyro_z_avg: MovingAverage::new(16, None),
         }
     }
 }
 
+use crate::component::{attitude::to_scene, Attitude, Drone};
 use crate::state::app::CompassUI;
-use crate::DroneMarker;
-use amethyst::core::timing::Time;
 
 impl<'a> System<'a> for TransceiverCodecSystem {
     // TODO: Create seperate human-readable type for thread
     type SystemData = (
-        Read<'a, Option<Arc<Mutex<Receiver<Telemetry>>>>>,
+        Read<'a, Option<Arc<Mutex<Receiver<Message>>>>>,
         UiFinder<'a>,
         WriteStorage<'a, UiText>,
-        WriteStorage<'a, Transform>,
-        ReadStorage<'a, Tag<DroneMarker>>,
-        Read<'a, Time>,
+        WriteStorage<'a, Attitude>,
+        WriteStorage<'a, Drone>,
+        Read<'a, Fleet>,
+        Write<'a, Spectrum>,
+        Write<'a, LinkStatus>,
+        ReadExpect<'a, Clocks>,
+        ReadExpect<'a, Latencies>,
+        Write<'a, FirmwareLog>,
+        Write<'a, Recorder>,
     );
 
     fn run(
         &mut self,
-        (mut _first, ui_finder, mut ui_text, mut transforms, drones, time): Self::SystemData,
+        (
+            mut _first,
+            ui_finder,
+            mut ui_text,
+            mut attitudes,
+            mut drones,
+            fleet,
+            mut spectrum,
+            mut link,
+            clocks,
+            latencies,
+            mut firmware_log,
+            mut recorder,
+        ): Self::SystemData,
     ) {
         // TODO: Look into .and_then and .map to make this easier to read and more succinct
         let recv = match &self.trx_recv {
@@ -117,52 +145,274 @@ impl<'a> System<'a> for TransceiverCodecSystem {
             _ => return,
         };
 
-        let value = match data.try_recv() {
-            Ok(v) => v,
-            _ => return,
-        };
+        // Process every sample that arrived since the last frame, using the drones' timestamps
+        let mut updated = false;
+        let mut fault = None;
+        let mut status = None;
+        let mut device_config = None;
+
+        for Received {
+            downlink: Downlink { pipe, message },
+            taken,
+            received,
+        } in data.try_iter()
+        {
+            let now = Instant::now();
+            latencies.record(pipe, Hop::Client, now.saturating_duration_since(received));
+            link.last_message.insert(pipe, now);
+
+            let value = match message {
+                Message::Telemetry(value) => value,
+                Message::Fault(error) => {
+                    log::warn!("Fault on pipe {}: {:?}", pipe, error);
+                    fault = Some((pipe, error));
+                    continue;
+                }
+                Message::Status(value) => {
+                    for drone in (&mut drones).join().filter(|drone| drone.pipe == pipe) {
+                        drone.status = Some(value.clone());
+                    }
 
-        let mag_x_avg = self.mag_x_avg.add(value.mag_x as f64) as f32;
-        let mag_y_avg = self.mag_y_avg.add(value.mag_y as f64) as f32;
+                    if fleet.is_selected(pipe) {
+                        status = Some(value);
+                    }
+                    continue;
+                }
+                Message::Spectrum(chunk) => {
+                    spectrum.add(chunk);
+                    continue;
+                }
+                Message::Blackbox(chunk) => {
+                    recorder.chunk(pipe, chunk);
+                    continue;
+                }
+                Message::Advertise { id, nonce } => {
+                    link.advertised = Some((id, nonce));
+                    continue;
+                }
+                // Handled by the serial thread and the relay respectively, compact telemetry and
+                // batches are unpacked by the serial thread as well
+                Message::Ack { .. }
+                | Message::Listening
+                | Message::CompactTelemetry(_)
+                | Message::Batch(_) => continue,
+                // Only keeps the link alive
+                Message::Heartbeat => continue,
+                // Handled by the serial thread as well
+                Message::Pong { .. } => continue,
+                Message::Config(config) => {
+                    log::info!("Config of pipe {}: {:?}", pipe, config);
+                    if fleet.is_selected(pipe) {
+                        device_config = Some(config);
+                    }
+                    continue;
+                }
+                Message::Orientation(orientation) => {
+                    for (drone, attitude) in (&mut drones, &mut attitudes).join() {
+                        if drone.pipe != pipe {
+                            continue;
+                        }
 
-        println!("Data: {:?}", value);
+                        attitude.set(orientation.quaternion);
+                        drone.orientation = Some(orientation.clone());
 
-        let degrees = crate::compass::coords_to_degrees((mag_x_avg, mag_y_avg));
+                        updated |= fleet.is_selected(pipe);
+                    }
+                    continue;
+                }
+                Message::Log { level, code, args } => {
+                    let drone = match fleet.get(pipe) {
+                        Some(member) => member.name.clone(),
+                        None => format!("pipe {}", pipe),
+                    };
+                    let text = describe(code, &args);
+
+                    log::log!(target: FIRMWARE_TARGET, log_level(level), "{}: {}", drone, text);
+                    firmware_log.push(Entry { drone, level, text });
+                    continue;
+                }
+            };
 
-        let gyro_x =
-            value.gyro_x * (std::f32::consts::PI / 180.0) / (1.0 / time.delta_real_seconds());
+            recorder.sample(pipe, &value);
 
-        let gyro_y =
-            value.gyro_z * (std::f32::consts::PI / 180.0) / (1.0 / time.delta_real_seconds());
+            // Body rates in rad/s, the gyroscope's z axis maps to the scene's y axis
+            let rates = to_scene(
+                value.gyro_x.to_radians(),
+                value.gyro_y.to_radians(),
+                value.gyro_z.to_radians(),
+            );
 
-        let gyro_z =
-            value.gyro_y * (std::f32::consts::PI / 180.0) / (1.0 / time.delta_real_seconds());
+            // Telemetry of unpaired drones has no model to go to
+            for (drone, attitude) in (&mut drones, &mut attitudes).join() {
+                if drone.pipe != pipe {
+                    continue;
+                }
 
-        for (drone, transform) in (&drones, &mut transforms).join() {
-            // println!("Doing a join");
-            // transform.set_translation_x(0.5);
-            transform.prepend_rotation_x_axis(gyro_x as f32);
-            transform.prepend_rotation_y_axis(gyro_y as f32);
-            transform.prepend_rotation_z_axis(gyro_z as f32);
-            // FIXME: Convert to Vector3 to save two lines
-            // transform.append_rotation_x();
-            // transform.append_rotation_y();
-            // transform.append_rotation_z();
-            // transform.
-            // transform
+                // Drones that fuse their attitude are rendered as they estimate it
+                if let Some(dt) = drone.add(value.clone()) {
+                    if drone.orientation.is_none() {
+                        attitude.rotate(rates, dt);
+                    }
+                }
+                drone.taken = taken;
+
+                updated |= fleet.is_selected(pipe);
+            }
         }
+
+        if let Some((pipe, error)) = fault {
+            if let Some(fault) = ui_finder
+                .find("fault")
+                .and_then(|entity| ui_text.get_mut(entity))
+            {
+                let name = fleet
+                    .get(pipe)
+                    .map_or("unpaired drone", |m| m.name.as_str());
+                fault.text = format!("{} recovered from {:?} fault", name, error);
+            }
+        }
+
+        if let Some(status) = status {
+            if let Some(command) = ui_finder
+                .find("command")
+                .and_then(|entity| ui_text.get_mut(entity))
+            {
+                command.text = format!(
+                    "{} Hz, {:?}, {:?} x{}, {} dropped",
+                    status.settings.sample_rate_hz,
+                    status.settings.gyro_scale,
+                    status.settings.encoding,
+                    status.settings.batch_size,
+                    status.dropped
+                );
+            }
+        }
+
+        if let Some(config) = device_config {
+            if let Some(command) = ui_finder
+                .find("command")
+                .and_then(|entity| ui_text.get_mut(entity))
+            {
+                command.text = device_config::summary(&config);
+            }
+        }
+
+        // The heading and sensors of the selected drone are shown
+        let drone = match (&drones).join().find(|drone| fleet.is_selected(drone.pipe)) {
+            Some(drone) if updated => drone,
+            _ => return,
+        };
+
+        let value = match &drone.latest {
+            Some(v) => v,
+            _ => return,
+        };
+
+        if let Some(text) = ui_finder
+            .find("attitude")
+            .and_then(|entity| ui_text.get_mut(entity))
+        {
+            text.text = match &drone.orientation {
+                Some(orientation) => {
+                    let [roll, pitch, yaw] = orientation.euler;
+                    format!(
+                        "roll {:>7.2} pitch {:>7.2} yaw {:>7.2}",
+                        f32::from(roll) / 100.0,
+                        f32::from(pitch) / 100.0,
+                        f32::from(yaw) / 100.0,
+                    )
+                }
+                None => String::new(),
+            };
+        }
+
         if let Some(heading) = ui_finder
             .find("heading")
             .and_then(|entity| ui_text.get_mut(entity))
         {
-            heading.text = format!("{:0padding$.0}", degrees, padding = 3);
+            heading.text = format!("{:0padding$.0}", drone.heading(), padding = 3);
+        }
+
+        if let Some(accel) = ui_finder
+            .find("accel")
+            .and_then(|entity| ui_text.get_mut(entity))
+        {
+            accel.text = format!(
+                "accel {:>6.3} {:>6.3} {:>6.3} g",
+                f32::from(value.accel_x) / 1000.0,
+                f32::from(value.accel_y) / 1000.0,
+                f32::from(value.accel_z) / 1000.0,
+            );
+        }
+
+        if let Some(mag) = ui_finder
+            .find("mag")
+            .and_then(|entity| ui_text.get_mut(entity))
+        {
+            mag.text = format!(
+                "mag {:>6.3} {:>6.3} {:>6.3} G",
+                f32::from(value.mag_x) / 1000.0,
+                f32::from(value.mag_y) / 1000.0,
+                f32::from(value.mag_z) / 1000.0,
+            );
+        }
+
+        // The age of the latest sample is the latency from the sensors to the client
+        if let Some(sync) = ui_finder
+            .find("sync")
+            .and_then(|entity| ui_text.get_mut(entity))
+        {
+            sync.text = match (clocks.estimate(drone.pipe), drone.taken) {
+                (Some(estimate), Some(taken)) => format!(
+                    "clock {:+.1} ms {:+.0} ppm, sample {:.0} ms ago",
+                    estimate.offset_us / 1000.0,
+                    estimate.drift_ppm,
+                    Instant::now()
+                        .saturating_duration_since(taken)
+                        .as_secs_f32()
+                        * 1000.0,
+                ),
+                _ => "clock not synchronized".to_string(),
+            };
         }
     }
 }
 
 use crate::cobs_buffer::{Buffer, BufferResult};
+use serde::Deserialize;
+
+/// A payload as the relay forwards it, a fragment of a `Message` that may be sealed. See
+/// `portuni_common::protocol::Relayed`
+#[derive(Deserialize)]
+struct Relayed {
+    pipe: u8,
+    // The relay's received power detector, set above -64 dBm
+    rpd: bool,
+    payload: Vec<u8>,
+}
+
+/// Writes frames to the relay, which sends commands during the drone's next RX window
+fn write_relay(mut port: Box<dyn SerialPort>, relay: Receiver<RelayFrame>) {
+    // A sealed uplink takes up a full payload after the pipe and length
+    let mut buf = [0u8; 2 * PAYLOAD_SIZE];
+
+    for frame in relay {
+        if let Ok(frame) = encode(&frame, &mut buf) {
+            // TODO: Dispatch error if the frame could not be written
+            let _ = port.write_all(frame);
+        }
+    }
+}
 
-fn read_serial(config: TransceiverSettings, send: Sender<Telemetry>) {
+fn read_serial(
+    config: TransceiverSettings,
+    send: Sender<Received>,
+    relay: Receiver<RelayFrame>,
+    acks: Acknowledgements,
+    sessions: Option<Sessions>,
+    clocks: Clocks,
+    latencies: Latencies,
+) {
     let trx = TransceiverDevice::new((config.vid, config.pid)).unwrap();
 
     // TODO: Dispatch error if device or multiple are connected
@@ -177,8 +427,13 @@ fn read_serial(config: TransceiverSettings, send: Sender<Telemetry>) {
     // TODO: Dispatch error if serial port can not be opened
     let mut port = open_with_settings(&port_name, &settings).unwrap();
 
+    // TODO: Dispatch error if the serial port can not be shared with the writer
+    let writer = port.try_clone().unwrap();
+    thread::spawn(move || write_relay(writer, relay));
+
     let mut serial_buf: Vec<u8> = vec![0; 256];
     let mut window_buf = Buffer::new();
+    let mut downlinks = Downlinks::new(sessions);
 
     loop {
         // TODO: Reduce indentation
@@ -188,14 +443,48 @@ fn read_serial(config: TransceiverSettings, send: Sender<Telemetry>) {
             'cobs: while !window.is_empty() {
                 use BufferResult::*;
 
-                window = match window_buf.write::<Telemetry>(&window) {
+                window = match window_buf.write::<Relayed>(&window) {
                     Consumed => break 'cobs,
                     Overfull(new_window) => new_window,
                     DeserErr(new_window) => new_window,
                     Success { data, remaining } => {
-                        // println!("Data: {:?}", data);
-
-                        send.send(data).unwrap();
+                        let Relayed { pipe, rpd, payload } = data;
+                        latencies.record_signal(pipe, rpd);
+
+                        let now = Instant::now();
+
+                        // Messages that aren't complete yet, or were forged, are dropped
+                        for message in downlinks.receive(pipe, &payload, now) {
+                            let taken = match &message {
+                                Message::Telemetry(value) => {
+                                    clocks.host_time(pipe, value.timestamp)
+                                }
+                                _ => None,
+                            };
+
+                            if let Some(taken) = taken {
+                                let downlink = now.saturating_duration_since(taken);
+                                latencies.record(pipe, Hop::Downlink, downlink);
+                            }
+
+                            match message {
+                                Message::Ack { id, result } => acks.acknowledge(pipe, id, result),
+                                Message::Pong { sent, timestamp } => {
+                                    let pong = clocks.pong(pipe, sent, timestamp, now);
+                                    latencies.record(pipe, Hop::RoundTrip, pong.round_trip);
+                                    latencies.record(pipe, Hop::Uplink, pong.uplink);
+                                }
+                                message => {
+                                    let downlink = Downlink { pipe, message };
+                                    send.send(Received {
+                                        downlink,
+                                        taken,
+                                        received: now,
+                                    })
+                                    .unwrap()
+                                }
+                            }
+                        }
 
                         remaining
                     }
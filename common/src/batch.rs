@@ -0,0 +1,149 @@
+//! Collects samples into a `Message::Batch`, so more samples fit in the same number of payloads
+use crate::command::MAX_BATCH_SIZE;
+use crate::protocol::{Batch, Message};
+
+/// Collects samples until a batch is full, the batch size is negotiated by the client
+pub struct Batcher {
+    size: u8,
+    batch: Option<Batch>,
+}
+
+impl Default for Batcher {
+    fn default() -> Self {
+        Batcher {
+            size: 1,
+            batch: None,
+        }
+    }
+}
+
+impl Batcher {
+    pub fn new() -> Batcher {
+        Batcher::default()
+    }
+
+    /// Samples collected so far are sent with the next call to `push`, sizes past
+    /// `MAX_BATCH_SIZE` are ignored
+    pub fn set_size(&mut self, size: u8) {
+        if size <= MAX_BATCH_SIZE {
+            self.size = size;
+        }
+    }
+
+    /// Adds a sample, returns the message to queue once the batch is full
+    ///
+    /// A batch size of 1, or 0, returns every sample as is, as do messages other than samples. A
+    /// sample of another encoding than the one of the batch returns the batch, and starts a new
+    /// one.
+    pub fn push(&mut self, sample: Message) -> Option<Message> {
+        if self.size <= 1 && self.batch.is_none() {
+            return Some(sample);
+        }
+
+        let full = match (&mut self.batch, sample) {
+            (Some(Batch::Float(samples)), Message::Telemetry(sample)) => {
+                let _ = samples.push(sample);
+                samples.len() >= usize::from(self.size)
+            }
+            (Some(Batch::Compact(samples)), Message::CompactTelemetry(sample)) => {
+                let _ = samples.push(sample);
+                samples.len() >= usize::from(self.size)
+            }
+            (batch, Message::Telemetry(sample)) => {
+                let mut samples = heapless::Vec::new();
+                let _ = samples.push(sample);
+
+                return batch.replace(Batch::Float(samples)).map(Message::Batch);
+            }
+            (batch, Message::CompactTelemetry(sample)) => {
+                let mut samples = heapless::Vec::new();
+                let _ = samples.push(sample);
+
+                return batch.replace(Batch::Compact(samples)).map(Message::Batch);
+            }
+            (_, message) => return Some(message),
+        };
+
+        if full {
+            self.batch.take().map(Message::Batch)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::telemetry;
+    use crate::protocol::{to_payload, CompactTelemetry};
+    use crate::sampler;
+    use std::vec::Vec;
+
+    fn compact(n: u32) -> CompactTelemetry {
+        let mut sensors = crate::mock::MockSensors::default();
+
+        sampler::sample_compact(&mut sensors, n).unwrap()
+    }
+
+    #[test]
+    fn test_single() {
+        let mut batcher = Batcher::new();
+        let sample = Message::Telemetry(telemetry(1));
+
+        assert_eq!(batcher.push(sample.clone()), Some(sample));
+    }
+
+    #[test]
+    fn test_batch() {
+        let mut batcher = Batcher::new();
+        batcher.set_size(3);
+
+        let batches: Vec<Message> = (0..7)
+            .filter_map(|n| batcher.push(Message::Telemetry(telemetry(n))))
+            .collect();
+        assert_eq!(batches.len(), 2);
+
+        match &batches[1] {
+            Message::Batch(Batch::Float(samples)) => {
+                let timestamps: Vec<u32> = samples.iter().map(|s| s.timestamp).collect();
+                assert_eq!(timestamps, [3, 4, 5]);
+            }
+            message => panic!("{:?}", message),
+        }
+
+        // A full batch fits in a message that can be fragmented
+        let mut buf = [0u8; crate::fragment::MAX_MESSAGE_SIZE];
+        batcher.set_size(MAX_BATCH_SIZE);
+        let full = (0..u32::from(MAX_BATCH_SIZE))
+            .filter_map(|n| batcher.push(Message::Telemetry(telemetry(u32::MAX - n))))
+            .last()
+            .unwrap();
+        assert!(to_payload(&full, &mut buf).is_ok());
+    }
+
+    #[test]
+    fn test_mixed() {
+        let mut batcher = Batcher::new();
+        batcher.set_size(2);
+
+        // Other messages pass, a sample of another encoding ends the batch
+        assert_eq!(batcher.push(Message::Telemetry(telemetry(0))), None);
+        assert_eq!(batcher.push(Message::Listening), Some(Message::Listening));
+        assert!(matches!(
+            batcher.push(Message::CompactTelemetry(compact(1))),
+            Some(Message::Batch(Batch::Float(_)))
+        ));
+
+        // The rest is sent once batching is turned off
+        batcher.set_size(1);
+        assert!(matches!(
+            batcher.push(Message::CompactTelemetry(compact(2))),
+            Some(Message::Batch(Batch::Compact(_)))
+        ));
+        assert!(matches!(
+            batcher.push(Message::CompactTelemetry(compact(3))),
+            Some(Message::CompactTelemetry(_))
+        ));
+    }
+}
@@ -0,0 +1,532 @@
+//! Samples kept in flash, so the ones the client missed can be downloaded after the link is back
+//!
+//! The log is a ring of the sectors of a `Flash`, which works alike on the spare pages of the
+//! internal flash and on an external SPI flash. Every sector starts with a header of the format's
+//! version and the sector's sequence number, followed by records of `RECORD_SIZE` bytes, each a
+//! sample and a CRC-16. The sector of sequence number `n` is `n % sectors`, the oldest one is
+//! erased once the newest is full.
+//!
+//! Records are numbered from the first one written after the flash was erased, so they keep their
+//! number while older ones are overwritten. Torn records are skipped, the sample rate being too
+//! high to stall for a retry.
+use heapless::{consts::U2, Vec};
+use serde::{Deserialize, Serialize};
+
+use crate::config::crc16;
+use crate::hal::Flash;
+use crate::protocol::Telemetry;
+use crate::Error;
+
+/// Version of the format of the sectors, which changes along with `RECORD_SIZE` or its layout
+pub const BLACKBOX_VERSION: u8 = 1;
+
+/// Size of a record, the header takes up the first record of every sector
+pub const RECORD_SIZE: usize = 32;
+
+/// Version, a reserved byte, the sequence number and the CRC
+const HEADER_SIZE: usize = 8;
+
+/// Timestamp, magnetic field, acceleration, angular rate, temperature and a reserved byte
+const SAMPLE_SIZE: usize = 30;
+
+const ERASED: u8 = 0xff;
+
+/// Records `start..end` of the log, of which the torn ones are left out
+///
+/// `first..last` are the records the log held when the chunk was sent, the client has them all
+/// once it received every chunk up to `last`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct BlackboxChunk {
+    pub start: u32,
+    pub end: u32,
+    pub first: u32,
+    pub last: u32,
+    pub samples: Vec<Telemetry, U2>,
+}
+
+fn encode(sample: &Telemetry) -> [u8; RECORD_SIZE] {
+    let mut record = [0; RECORD_SIZE];
+
+    record[0..4].copy_from_slice(&sample.timestamp.to_le_bytes());
+    let fields = [
+        sample.mag_x,
+        sample.mag_y,
+        sample.mag_z,
+        sample.accel_x,
+        sample.accel_y,
+        sample.accel_z,
+    ];
+    for (i, field) in fields.iter().enumerate() {
+        record[4 + 2 * i..6 + 2 * i].copy_from_slice(&field.to_le_bytes());
+    }
+    let rates = [sample.gyro_x, sample.gyro_y, sample.gyro_z];
+    for (i, rate) in rates.iter().enumerate() {
+        record[16 + 4 * i..20 + 4 * i].copy_from_slice(&rate.to_le_bytes());
+    }
+    record[28] = sample.temp as u8;
+
+    let crc = crc16(&record[..SAMPLE_SIZE]).to_le_bytes();
+    record[SAMPLE_SIZE..].copy_from_slice(&crc);
+
+    record
+}
+
+/// The sample in a record, `None` if it was torn by a reset
+fn decode(record: &[u8; RECORD_SIZE]) -> Option<Telemetry> {
+    let crc = u16::from_le_bytes([record[SAMPLE_SIZE], record[SAMPLE_SIZE + 1]]);
+    if crc16(&record[..SAMPLE_SIZE]) != crc {
+        return None;
+    }
+
+    let i16_at = |i: usize| i16::from_le_bytes([record[i], record[i + 1]]);
+    let f32_at =
+        |i: usize| f32::from_le_bytes([record[i], record[i + 1], record[i + 2], record[i + 3]]);
+
+    Some(Telemetry {
+        timestamp: u32::from_le_bytes([record[0], record[1], record[2], record[3]]),
+        mag_x: i16_at(4),
+        mag_y: i16_at(6),
+        mag_z: i16_at(8),
+        accel_x: i16_at(10),
+        accel_y: i16_at(12),
+        accel_z: i16_at(14),
+        gyro_x: f32_at(16),
+        gyro_y: f32_at(20),
+        gyro_z: f32_at(24),
+        temp: record[28] as i8,
+    })
+}
+
+fn encode_header(sequence: u32) -> [u8; HEADER_SIZE] {
+    let mut header = [0; HEADER_SIZE];
+
+    header[0] = BLACKBOX_VERSION;
+    header[2..6].copy_from_slice(&sequence.to_le_bytes());
+    let crc = crc16(&header[..6]).to_le_bytes();
+    header[6..].copy_from_slice(&crc);
+
+    header
+}
+
+/// The sequence number in a header, `None` if it is of another version or was torn by a reset
+fn decode_header(header: &[u8; HEADER_SIZE]) -> Option<u32> {
+    if header[0] != BLACKBOX_VERSION
+        || crc16(&header[..6]) != u16::from_le_bytes([header[6], header[7]])
+    {
+        return None;
+    }
+
+    Some(u32::from_le_bytes([
+        header[2], header[3], header[4], header[5],
+    ]))
+}
+
+/// The log in the sectors of `flash`
+pub struct Blackbox<F> {
+    flash: F,
+    // Sequence numbers of the oldest and newest sector, there is none until the first record is
+    // written
+    oldest: u32,
+    newest: Option<u32>,
+    // Record in the newest sector that is written next, the header is record 0
+    next: usize,
+    // Record that is sent next, while the log is being downloaded
+    download: Option<u32>,
+}
+
+impl<F: Flash> Blackbox<F> {
+    /// Find the newest sector, and the first erased record in it
+    pub fn open(mut flash: F) -> Result<Blackbox<F>, Error> {
+        let sectors = flash.sectors();
+        let mut range: Option<(u32, u32)> = None;
+
+        for sector in 0..sectors {
+            let mut header = [0; HEADER_SIZE];
+            flash.read(sector * flash.sector_size(), &mut header)?;
+
+            // A sector of which the sequence number doesn't match its position is left over
+            let sequence = match decode_header(&header) {
+                Some(sequence) if sequence as usize % sectors == sector => sequence,
+                _ => continue,
+            };
+
+            range = Some(match range {
+                Some((oldest, newest)) => (oldest.min(sequence), newest.max(sequence)),
+                None => (sequence, sequence),
+            });
+        }
+
+        let mut blackbox = Blackbox {
+            flash,
+            oldest: 0,
+            newest: None,
+            next: 1,
+            download: None,
+        };
+
+        if let Some((oldest, newest)) = range {
+            // Sectors that were overwritten don't count, even if their header was torn
+            let overwritten = (newest + 1).saturating_sub(sectors as u32);
+            blackbox.oldest = oldest.max(overwritten);
+            blackbox.newest = Some(newest);
+
+            // Records that were torn aren't written again
+            let start = blackbox.sector_start(newest);
+            for i in 1..=blackbox.records_per_sector() {
+                let mut record = [0; RECORD_SIZE];
+                blackbox.flash.read(start + i * RECORD_SIZE, &mut record)?;
+
+                if record.iter().any(|&byte| byte != ERASED) {
+                    blackbox.next = i + 1;
+                }
+            }
+        }
+
+        Ok(blackbox)
+    }
+
+    /// Records that fit in a sector after its header
+    fn records_per_sector(&self) -> usize {
+        self.flash.sector_size() / RECORD_SIZE - 1
+    }
+
+    fn sector_start(&self, sequence: u32) -> usize {
+        sequence as usize % self.flash.sectors() * self.flash.sector_size()
+    }
+
+    /// Numbers of the records that are kept, the first one and the one after the last
+    pub fn range(&self) -> (u32, u32) {
+        let per_sector = self.records_per_sector() as u32;
+
+        match self.newest {
+            Some(newest) => (
+                self.oldest * per_sector,
+                newest * per_sector + self.next as u32 - 1,
+            ),
+            None => (0, 0),
+        }
+    }
+
+    /// Write a sample after the last one, unless the log is being downloaded
+    ///
+    /// Erasing the next sector once the newest one is full takes as long as the flash needs.
+    pub fn append(&mut self, sample: &Telemetry) -> Result<(), Error> {
+        if self.download.is_some() {
+            return Ok(());
+        }
+
+        if self.newest.is_none() || self.next > self.records_per_sector() {
+            self.start_sector()?;
+        }
+
+        let newest = self.newest.unwrap_or_default();
+        let address = self.sector_start(newest) + self.next * RECORD_SIZE;
+
+        // A record that fails is skipped, it doesn't decode
+        self.next += 1;
+        self.flash.write(address, &encode(sample))
+    }
+
+    fn start_sector(&mut self) -> Result<(), Error> {
+        let sequence = self.newest.map_or(0, |newest| newest + 1);
+        let address = self.sector_start(sequence);
+
+        self.flash.erase(sequence as usize % self.flash.sectors())?;
+
+        self.newest = Some(sequence);
+        self.next = 1;
+        let overwritten = (sequence + 1).saturating_sub(self.flash.sectors() as u32);
+        self.oldest = self.oldest.max(overwritten);
+
+        self.flash.write(address, &encode_header(sequence))
+    }
+
+    /// The sample of record `number`, `None` if it isn't kept or was torn
+    pub fn read(&mut self, number: u32) -> Result<Option<Telemetry>, Error> {
+        let (first, last) = self.range();
+        if !(first..last).contains(&number) {
+            return Ok(None);
+        }
+
+        let per_sector = self.records_per_sector() as u32;
+        let sequence = number / per_sector;
+        let start = self.sector_start(sequence);
+
+        let mut header = [0; HEADER_SIZE];
+        self.flash.read(start, &mut header)?;
+        if decode_header(&header) != Some(sequence) {
+            return Ok(None);
+        }
+
+        let mut record = [0; RECORD_SIZE];
+        let index = (number % per_sector) as usize + 1;
+        self.flash.read(start + index * RECORD_SIZE, &mut record)?;
+
+        Ok(decode(&record))
+    }
+
+    /// Send the records from `from` with `next_chunk`, or from the oldest one if it was
+    /// overwritten. No samples are written until every chunk was taken
+    pub fn start_download(&mut self, from: u32) {
+        let (first, _) = self.range();
+
+        self.download = Some(from.max(first));
+    }
+
+    pub fn is_downloading(&self) -> bool {
+        self.download.is_some()
+    }
+
+    /// The next chunk of a download, a log without records is sent as a single empty chunk
+    ///
+    /// At most a sector's worth of records is read per chunk, so a sector that was torn doesn't
+    /// stall the radio. A download that fails to read ends early.
+    pub fn next_chunk(&mut self) -> Option<BlackboxChunk> {
+        let start = self.download?;
+        let (first, last) = self.range();
+        let limit = start
+            .saturating_add(self.records_per_sector() as u32)
+            .min(last);
+
+        let mut samples = Vec::new();
+        let mut end = start;
+
+        while end < limit && !samples.is_full() {
+            match self.read(end) {
+                Ok(Some(sample)) => {
+                    let _ = samples.push(sample);
+                }
+                Ok(None) => {}
+                Err(_) => {
+                    self.download = None;
+                    return None;
+                }
+            }
+            end += 1;
+        }
+
+        self.download = if end < last { Some(end) } else { None };
+
+        Some(BlackboxChunk {
+            start,
+            end,
+            first,
+            last,
+            samples,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fragment::MAX_MESSAGE_SIZE;
+    use crate::mock::{telemetry, MockFlash};
+    use crate::protocol::{to_payload, Message};
+    use std::vec::Vec;
+
+    /// Seven records per sector
+    fn blackbox() -> Blackbox<MockFlash> {
+        Blackbox::open(MockFlash::new(256, 4)).unwrap()
+    }
+
+    fn download(blackbox: &mut Blackbox<MockFlash>, from: u32) -> Vec<BlackboxChunk> {
+        blackbox.start_download(from);
+
+        core::iter::from_fn(|| blackbox.next_chunk()).collect()
+    }
+
+    #[test]
+    fn test_record() {
+        let sample = Telemetry {
+            timestamp: u32::MAX,
+            mag_x: i16::MIN,
+            mag_y: -1,
+            mag_z: i16::MAX,
+            accel_x: 1000,
+            accel_y: -1000,
+            accel_z: 0,
+            gyro_x: -500.25,
+            gyro_y: 0.0,
+            gyro_z: 2000.0,
+            temp: i8::MIN,
+        };
+
+        let mut record = encode(&sample);
+        assert_eq!(decode(&record), Some(sample));
+
+        record[10] ^= 0x01;
+        assert_eq!(decode(&record), None);
+        assert_eq!(decode(&[ERASED; RECORD_SIZE]), None);
+    }
+
+    #[test]
+    fn test_chunk_fits() {
+        let sample = Telemetry {
+            timestamp: u32::MAX,
+            mag_x: i16::MIN,
+            mag_y: i16::MIN,
+            mag_z: i16::MIN,
+            accel_x: i16::MIN,
+            accel_y: i16::MIN,
+            accel_z: i16::MIN,
+            gyro_x: -500.0,
+            gyro_y: -500.0,
+            gyro_z: -500.0,
+            temp: i8::MIN,
+        };
+        let chunk = BlackboxChunk {
+            start: u32::MAX,
+            end: u32::MAX,
+            first: u32::MAX,
+            last: u32::MAX,
+            samples: core::iter::repeat(sample).take(2).collect(),
+        };
+
+        let mut buf = [0u8; MAX_MESSAGE_SIZE];
+        assert!(to_payload(&Message::Blackbox(chunk), &mut buf).is_ok());
+    }
+
+    #[test]
+    fn test_append_and_read() {
+        let mut blackbox = blackbox();
+        assert_eq!(blackbox.range(), (0, 0));
+
+        for n in 0..10 {
+            blackbox.append(&telemetry(n)).unwrap();
+        }
+
+        assert_eq!(blackbox.range(), (0, 10));
+        assert_eq!(blackbox.read(9).unwrap(), Some(telemetry(9)));
+        assert_eq!(blackbox.read(10).unwrap(), None);
+
+        // Continues after the last record
+        let mut blackbox = Blackbox::open(blackbox.flash).unwrap();
+        assert_eq!(blackbox.range(), (0, 10));
+        blackbox.append(&telemetry(10)).unwrap();
+        assert_eq!(blackbox.read(10).unwrap(), Some(telemetry(10)));
+    }
+
+    #[test]
+    fn test_wrap() {
+        let mut blackbox = blackbox();
+
+        // Five sectors' worth, the first one was overwritten
+        for n in 0..31 {
+            blackbox.append(&telemetry(n)).unwrap();
+        }
+
+        assert_eq!(blackbox.flash.erases, 5);
+        assert_eq!(blackbox.range(), (7, 31));
+        assert_eq!(blackbox.read(6).unwrap(), None);
+        assert_eq!(blackbox.read(7).unwrap(), Some(telemetry(7)));
+        assert_eq!(blackbox.read(30).unwrap(), Some(telemetry(30)));
+
+        let blackbox = Blackbox::open(blackbox.flash).unwrap();
+        assert_eq!(blackbox.range(), (7, 31));
+    }
+
+    #[test]
+    fn test_torn() {
+        let mut blackbox = blackbox();
+        for n in 0..3 {
+            blackbox.append(&telemetry(n)).unwrap();
+        }
+
+        // A reset while writing the second record, which is skipped
+        let mut flash = blackbox.flash;
+        flash.data[2 * RECORD_SIZE + 4] ^= 0x01;
+        let mut blackbox = Blackbox::open(flash).unwrap();
+        assert_eq!(blackbox.read(1).unwrap(), None);
+
+        let chunks = download(&mut blackbox, 0);
+        let samples: Vec<u32> = chunks
+            .iter()
+            .flat_map(|chunk| chunk.samples.iter().map(|s| s.timestamp))
+            .collect();
+        assert_eq!(samples, [0, 2]);
+        assert_eq!((chunks[0].start, chunks[0].end), (0, 3));
+    }
+
+    #[test]
+    fn test_version() {
+        let mut blackbox = blackbox();
+        blackbox.append(&telemetry(0)).unwrap();
+
+        let mut flash = blackbox.flash;
+        flash.data[0] = BLACKBOX_VERSION + 1;
+
+        let blackbox = Blackbox::open(flash).unwrap();
+        assert_eq!(blackbox.range(), (0, 0));
+    }
+
+    #[test]
+    fn test_write_error() {
+        let mut flash = MockFlash::new(256, 4);
+        // The header is the first write
+        flash.failures = 2;
+
+        let mut blackbox = Blackbox::open(flash).unwrap();
+        assert_eq!(blackbox.append(&telemetry(0)), Err(Error::Flash));
+        assert_eq!(blackbox.append(&telemetry(1)), Err(Error::Flash));
+        blackbox.append(&telemetry(2)).unwrap();
+
+        // Without a header the sector is left out once opened again
+        assert_eq!(blackbox.read(1).unwrap(), None);
+        let blackbox = Blackbox::open(blackbox.flash).unwrap();
+        assert_eq!(blackbox.range(), (0, 0));
+    }
+
+    #[test]
+    fn test_download() {
+        let mut blackbox = blackbox();
+        for n in 0..10 {
+            blackbox.append(&telemetry(n)).unwrap();
+        }
+
+        blackbox.start_download(0);
+        assert!(blackbox.is_downloading());
+
+        // Nothing is written while downloading
+        blackbox.append(&telemetry(100)).unwrap();
+
+        let chunks: Vec<BlackboxChunk> = core::iter::from_fn(|| blackbox.next_chunk()).collect();
+        assert_eq!(chunks.len(), 5);
+        assert!(chunks
+            .iter()
+            .all(|chunk| (chunk.first, chunk.last) == (0, 10)));
+        assert!(chunks.windows(2).all(|pair| pair[0].end == pair[1].start));
+        assert_eq!(chunks[4].samples[1], telemetry(9));
+
+        assert!(!blackbox.is_downloading());
+        blackbox.append(&telemetry(10)).unwrap();
+        assert_eq!(blackbox.range(), (0, 11));
+    }
+
+    #[test]
+    fn test_download_from() {
+        let mut blackbox = blackbox();
+        for n in 0..31 {
+            blackbox.append(&telemetry(n)).unwrap();
+        }
+
+        // Overwritten records are skipped
+        let chunks = download(&mut blackbox, 2);
+        assert_eq!(chunks[0].start, 7);
+        assert_eq!(chunks[0].samples[0], telemetry(7));
+
+        let chunks = download(&mut blackbox, 29);
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(chunks[0].samples.len(), 2);
+    }
+
+    #[test]
+    fn test_download_empty() {
+        let mut blackbox = blackbox();
+
+        let chunks = download(&mut blackbox, 0);
+        assert_eq!(chunks.len(), 1);
+        assert_eq!((chunks[0].start, chunks[0].end, chunks[0].last), (0, 0, 0));
+        assert!(chunks[0].samples.is_empty());
+    }
+}
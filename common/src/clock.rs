@@ -0,0 +1,53 @@
+/// Microsecond clock derived from a free-running cycle counter, such as the DWT's
+///
+/// The cycle counter wraps every 2^32 cycles, so `now()` has to be called at least that often,
+/// which is ~9 minutes at the default 8 MHz system clock.
+pub struct Clock {
+    cycles_per_us: u32,
+    last: u32,
+    remainder: u32,
+    micros: u32,
+}
+
+impl Clock {
+    pub fn new(sysclk_hz: u32, cycles: u32) -> Clock {
+        Clock {
+            cycles_per_us: sysclk_hz / 1_000_000,
+            last: cycles,
+            remainder: 0,
+            micros: 0,
+        }
+    }
+
+    /// Microseconds since the clock was created, wraps after ~71 minutes
+    pub fn now(&mut self, cycles: u32) -> u32 {
+        let elapsed = u64::from(cycles.wrapping_sub(self.last)) + u64::from(self.remainder);
+
+        self.last = cycles;
+        self.micros = self
+            .micros
+            .wrapping_add((elapsed / u64::from(self.cycles_per_us)) as u32);
+        self.remainder = (elapsed % u64::from(self.cycles_per_us)) as u32;
+
+        self.micros
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_now() {
+        let mut clock = Clock::new(8_000_000, 0);
+
+        assert_eq!(clock.now(8), 1);
+        // The remaining cycles are carried over
+        assert_eq!(clock.now(20), 2);
+        assert_eq!(clock.now(24), 3);
+
+        // The cycle counter wraps
+        let mut clock = Clock::new(8_000_000, u32::MAX - 7);
+        assert_eq!(clock.now(8), 2);
+    }
+}
@@ -0,0 +1,347 @@
+//! Commands sent from the client to the drone, during the RX windows the drone opens
+use postcard::from_bytes;
+use serde::{Deserialize, Serialize};
+
+use crate::config::{MagOdr, Sensor};
+use crate::pairing::DeviceId;
+use crate::radio::{DataRate, MAX_CHANNEL};
+
+/// Sample rates the sensors' output data rates can keep up with
+pub const MIN_SAMPLE_RATE_HZ: u16 = 1;
+pub const MAX_SAMPLE_RATE_HZ: u16 = 200;
+
+/// Most samples sent in a single `Message::Batch`
+pub const MAX_BATCH_SIZE: u8 = 4;
+
+/// Shortest link timeout, the drone only listens for commands every 100 ms
+pub const MIN_LINK_TIMEOUT_MS: u16 = 500;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GyroScale {
+    Dps250,
+    Dps500,
+    Dps2000,
+}
+
+/// How telemetry is encoded, the drone sends `Float` until the client negotiates another one
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    /// `Message::Telemetry`, with the angular rate in degrees per second
+    Float,
+    /// `Message::CompactTelemetry`, with the gyroscope's raw counts and full-scale
+    Compact,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command {
+    SetSampleRate(u16),
+    SetGyroScale(GyroScale),
+    /// Reply with a `Message::Status`
+    RequestStatus,
+    Reboot,
+    /// Light the status LEDs for a few seconds
+    LedTest,
+    /// Move the link, which falls back to the default channel unless the client confirms it
+    SetRadio {
+        channel: u8,
+        data_rate: DataRate,
+    },
+    /// Sweep every channel with the received power detector, which interrupts the link for about
+    /// a second per 8 sweeps
+    ScanSpectrum {
+        sweeps: u8,
+    },
+    /// Move to the private addresses of the drone with this id, other drones ignore it
+    Pair(DeviceId),
+    /// Encode telemetry as proposed from now on, and send `batch_size` samples per message
+    SetEncoding {
+        encoding: Encoding,
+        batch_size: u8,
+    },
+    /// Sent periodically by the client, the drone engages its failsafe once no command arrived
+    /// for `timeout_ms`
+    Heartbeat {
+        timeout_ms: u16,
+    },
+    /// Reply with a `Message::Pong`, which echoes `sent`, the client's time in microseconds
+    Ping {
+        sent: u32,
+    },
+    /// Reply with a `Message::Config`, the config the drone runs with
+    RequestConfig,
+    SetMagOdr(MagOdr),
+    /// Offsets subtracted from a sensor's readings, in the units of `Calibration`
+    SetCalibration {
+        sensor: Sensor,
+        offset: [i16; 3],
+    },
+    /// Channel of the default link, which the drone moves to once it reboots
+    SetChannel(u8),
+    /// Write the config the drone runs with to flash, from which it is loaded at boot
+    SaveConfig,
+    /// Send the records of the blackbox from `from` on, see `blackbox`, which stops recording
+    /// until they were all sent
+    ReadBlackbox {
+        from: u32,
+    },
+}
+
+impl Command {
+    pub fn validate(&self) -> CommandResult {
+        match *self {
+            Command::SetSampleRate(hz)
+                if !(MIN_SAMPLE_RATE_HZ..=MAX_SAMPLE_RATE_HZ).contains(&hz) =>
+            {
+                CommandResult::Invalid
+            }
+            Command::SetRadio { channel, .. } | Command::SetChannel(channel)
+                if channel > MAX_CHANNEL =>
+            {
+                CommandResult::Invalid
+            }
+            Command::ScanSpectrum { sweeps: 0 } => CommandResult::Invalid,
+            Command::SetEncoding { batch_size, .. }
+                if !(1..=MAX_BATCH_SIZE).contains(&batch_size) =>
+            {
+                CommandResult::Invalid
+            }
+            Command::Heartbeat { timeout_ms } if timeout_ms < MIN_LINK_TIMEOUT_MS => {
+                CommandResult::Invalid
+            }
+            _ => CommandResult::Ok,
+        }
+    }
+}
+
+/// A command as sent over the air, the drone acknowledges it by `id`
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Uplink {
+    pub id: u8,
+    pub command: Command,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CommandResult {
+    Ok,
+    /// The command's arguments are out of range, nothing was changed
+    Invalid,
+    /// A peripheral could not be reconfigured
+    Failed,
+}
+
+/// Settings that can be changed by commands
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Settings {
+    pub sample_rate_hz: u16,
+    pub gyro_scale: GyroScale,
+    pub encoding: Encoding,
+    pub batch_size: u8,
+}
+
+impl Default for Settings {
+    fn default() -> Settings {
+        Settings {
+            sample_rate_hz: 50,
+            gyro_scale: GyroScale::Dps500,
+            encoding: Encoding::Float,
+            batch_size: 1,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Incoming {
+    /// A command that has not been handled yet
+    Command(Uplink),
+    /// The relay repeats a command until it is acknowledged, so only the result is sent again
+    Repeat { id: u8, result: CommandResult },
+}
+
+/// Decodes uplink frames and filters out the ones that were already handled
+#[derive(Default)]
+pub struct CommandReceiver {
+    last: Option<(u8, CommandResult)>,
+}
+
+impl CommandReceiver {
+    pub fn new() -> CommandReceiver {
+        CommandReceiver::default()
+    }
+
+    /// Returns `None` for frames that could not be decoded
+    pub fn receive(&mut self, frame: &[u8]) -> Option<Incoming> {
+        let uplink: Uplink = from_bytes(frame).ok()?;
+
+        match self.last {
+            Some((id, result)) if id == uplink.id => Some(Incoming::Repeat { id, result }),
+            _ => Some(Incoming::Command(uplink)),
+        }
+    }
+
+    /// Record the result of a command, so repeats of it are not handled again
+    pub fn handled(&mut self, id: u8, result: CommandResult) {
+        self.last = Some((id, result));
+    }
+
+    /// Forget the last command once the drone is unpaired, the client that pairs next counts its
+    /// ids anew
+    pub fn end_session(&mut self) {
+        self.last = None;
+    }
+}
+
+/// Decides when the drone stops transmitting to listen for commands
+pub struct RxWindow {
+    interval: usize,
+    sent: usize,
+}
+
+impl RxWindow {
+    /// Open a window after every `interval` messages
+    pub fn new(interval: usize) -> RxWindow {
+        RxWindow { interval, sent: 0 }
+    }
+
+    /// Count messages that were sent, returns whether a window is due
+    pub fn record(&mut self, sent: usize) -> bool {
+        self.sent = self.sent.saturating_add(sent);
+        self.sent >= self.interval
+    }
+
+    pub fn opened(&mut self) {
+        self.sent = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{to_payload, PAYLOAD_SIZE};
+    use crate::secure::CAPACITY;
+
+    fn frame(uplink: &Uplink) -> [u8; PAYLOAD_SIZE] {
+        let mut buf = [0u8; PAYLOAD_SIZE];
+        to_payload(uplink, &mut buf).unwrap();
+        buf
+    }
+
+    #[test]
+    fn test_validate() {
+        assert_eq!(Command::SetSampleRate(50).validate(), CommandResult::Ok);
+        assert_eq!(Command::SetSampleRate(0).validate(), CommandResult::Invalid);
+        assert_eq!(
+            Command::SetSampleRate(1000).validate(),
+            CommandResult::Invalid
+        );
+        assert_eq!(Command::Reboot.validate(), CommandResult::Ok);
+
+        let set_radio = |channel| Command::SetRadio {
+            channel,
+            data_rate: DataRate::Mbps1,
+        };
+        assert_eq!(set_radio(125).validate(), CommandResult::Ok);
+        assert_eq!(
+            Command::ScanSpectrum { sweeps: 0 }.validate(),
+            CommandResult::Invalid
+        );
+        assert_eq!(set_radio(126).validate(), CommandResult::Invalid);
+
+        assert_eq!(
+            Command::Heartbeat { timeout_ms: 100 }.validate(),
+            CommandResult::Invalid
+        );
+        assert_eq!(Command::SetChannel(126).validate(), CommandResult::Invalid);
+    }
+
+    #[test]
+    fn test_config_commands_fit() {
+        // Sealed uplinks aren't fragmented, so the config is set one part at a time
+        let uplink = Uplink {
+            id: 255,
+            command: Command::SetCalibration {
+                sensor: Sensor::Gyro,
+                offset: [i16::MIN; 3],
+            },
+        };
+
+        let mut buf = [0u8; PAYLOAD_SIZE];
+        assert!(to_payload(&uplink, &mut buf).unwrap().len() <= CAPACITY);
+    }
+
+    #[test]
+    fn test_receive_repeat() {
+        let mut receiver = CommandReceiver::new();
+        let uplink = Uplink {
+            id: 7,
+            command: Command::SetSampleRate(0),
+        };
+
+        assert_eq!(
+            receiver.receive(&frame(&uplink)),
+            Some(Incoming::Command(uplink))
+        );
+        receiver.handled(7, CommandResult::Invalid);
+
+        // The earlier result is repeated without handling the command again
+        assert_eq!(
+            receiver.receive(&frame(&uplink)),
+            Some(Incoming::Repeat {
+                id: 7,
+                result: CommandResult::Invalid
+            })
+        );
+
+        let next = Uplink {
+            id: 8,
+            command: Command::LedTest,
+        };
+        assert_eq!(
+            receiver.receive(&frame(&next)),
+            Some(Incoming::Command(next))
+        );
+    }
+
+    #[test]
+    fn test_receive_after_pairing() {
+        let mut receiver = CommandReceiver::new();
+        let uplink = Uplink {
+            id: 0,
+            command: Command::LedTest,
+        };
+
+        receiver.receive(&frame(&uplink));
+        receiver.handled(0, CommandResult::Ok);
+
+        // The drone fell back to the default addresses, and a restarted client pairs with the
+        // same id
+        receiver.end_session();
+
+        let pair = Uplink {
+            id: 0,
+            command: Command::Pair([1; 12]),
+        };
+        assert_eq!(
+            receiver.receive(&frame(&pair)),
+            Some(Incoming::Command(pair))
+        );
+    }
+
+    #[test]
+    fn test_receive_garbage() {
+        let mut receiver = CommandReceiver::new();
+        assert_eq!(receiver.receive(&[0xff, 0xff, 0x00]), None);
+    }
+
+    #[test]
+    fn test_rx_window() {
+        let mut window = RxWindow::new(5);
+
+        assert!(!window.record(3));
+        assert!(window.record(2));
+
+        // Stays due until the window could be opened
+        assert!(window.record(0));
+        window.opened();
+        assert!(!window.record(4));
+    }
+}
@@ -0,0 +1,507 @@
+//! Settings of the drone that are kept in flash, and loaded at boot
+//!
+//! The config is written to the first two sectors of a `Flash` in slots of `SLOT_SIZE` bytes, each
+//! one after the last. Once every slot of a sector was used the config moves on to the other one,
+//! and the full sector is only erased after the first slot of the other one was read back intact,
+//! so a reset never leaves the flash without a config. The newest slot that is intact is loaded,
+//! from the sector that was written last. A slot holds the format's version, the length of the
+//! encoded config, the config itself and a CRC-16 over all of them. Slots of another version are
+//! skipped, so the defaults are used after the format changed.
+use postcard::{from_bytes, to_slice};
+use serde::{Deserialize, Serialize};
+
+use crate::command::GyroScale;
+use crate::hal::{F32x3, Flash, I16x3};
+use crate::radio::{DEFAULT_CHANNEL, MAX_CHANNEL};
+use crate::units;
+use crate::Error;
+
+/// Version of the format of the slots, which changes along with `DeviceConfig`
+pub const CONFIG_VERSION: u8 = 1;
+
+/// Size of a slot, which fits the largest encoded `DeviceConfig`
+pub const SLOT_SIZE: usize = 64;
+
+/// Version, length and CRC
+const OVERHEAD: usize = 4;
+
+const ERASED: u8 = 0xff;
+
+/// Output data rates of the LSM303DLHC magnetometer
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MagOdr {
+    Hz0_75,
+    Hz1_5,
+    Hz3,
+    Hz7_5,
+    Hz15,
+    Hz30,
+    Hz75,
+    Hz220,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Sensor {
+    Gyro,
+    Accel,
+    Mag,
+}
+
+/// Offsets subtracted from the readings of the sensors, along the x, y and z axis
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(default)]
+pub struct Calibration {
+    // Angular rate in hundredths of a degree per second
+    pub gyro: [i16; 3],
+    // Acceleration in milli-g
+    pub accel: [i16; 3],
+    // Magnetic field in milligauss
+    pub mag: [i16; 3],
+}
+
+fn subtract(value: I16x3, offset: [i16; 3]) -> I16x3 {
+    I16x3 {
+        x: value.x.saturating_sub(offset[0]),
+        y: value.y.saturating_sub(offset[1]),
+        z: value.z.saturating_sub(offset[2]),
+    }
+}
+
+impl Calibration {
+    pub fn set(&mut self, sensor: Sensor, offset: [i16; 3]) {
+        match sensor {
+            Sensor::Gyro => self.gyro = offset,
+            Sensor::Accel => self.accel = offset,
+            Sensor::Mag => self.mag = offset,
+        }
+    }
+
+    /// Angular rate in degrees per second
+    pub fn gyro(&self, dps: F32x3) -> F32x3 {
+        let [x, y, z] = self.gyro;
+
+        F32x3 {
+            x: dps.x - f32::from(x) / 100.0,
+            y: dps.y - f32::from(y) / 100.0,
+            z: dps.z - f32::from(z) / 100.0,
+        }
+    }
+
+    /// Angular rate in raw counts, at the full-scale they were read at
+    pub fn gyro_raw(&self, raw: I16x3, scale: GyroScale) -> I16x3 {
+        let [x, y, z] = self.gyro;
+        let offset = [
+            units::gyro_counts(x, scale),
+            units::gyro_counts(y, scale),
+            units::gyro_counts(z, scale),
+        ];
+
+        subtract(raw, offset)
+    }
+
+    /// Acceleration in milli-g
+    pub fn accel(&self, mg: I16x3) -> I16x3 {
+        subtract(mg, self.accel)
+    }
+
+    /// Magnetic field in milligauss
+    pub fn mag(&self, mgauss: I16x3) -> I16x3 {
+        subtract(mgauss, self.mag)
+    }
+}
+
+/// Settings of the drone that are kept across resets
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(default)]
+pub struct DeviceConfig {
+    pub gyro_scale: GyroScale,
+    pub mag_odr: MagOdr,
+    // Channel of the default link, on which the drone advertises after boot
+    pub channel: u8,
+    pub calibration: Calibration,
+}
+
+impl Default for DeviceConfig {
+    fn default() -> DeviceConfig {
+        DeviceConfig {
+            gyro_scale: GyroScale::Dps500,
+            mag_odr: MagOdr::Hz30,
+            channel: DEFAULT_CHANNEL,
+            calibration: Calibration::default(),
+        }
+    }
+}
+
+impl DeviceConfig {
+    pub fn is_valid(&self) -> bool {
+        self.channel <= MAX_CHANNEL
+    }
+}
+
+/// CRC-16/CCITT-FALSE
+pub(crate) fn crc16(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0xffff;
+
+    for &byte in data {
+        crc ^= u16::from(byte) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+
+    crc
+}
+
+/// The config in a slot, `None` if it is of another version or was torn by a reset
+fn decode(slot: &[u8; SLOT_SIZE]) -> Option<DeviceConfig> {
+    let len = usize::from(slot[1]);
+    if slot[0] != CONFIG_VERSION || len > SLOT_SIZE - OVERHEAD {
+        return None;
+    }
+
+    let (data, rest) = slot.split_at(2 + len);
+    if crc16(data) != u16::from_le_bytes([rest[0], rest[1]]) {
+        return None;
+    }
+
+    from_bytes(&data[2..]).ok().filter(DeviceConfig::is_valid)
+}
+
+fn encode(config: &DeviceConfig) -> Result<[u8; SLOT_SIZE], Error> {
+    let mut slot = [ERASED; SLOT_SIZE];
+
+    let len = to_slice(config, &mut slot[2..SLOT_SIZE - 2])
+        .map_err(|_| Error::Encode)?
+        .len();
+    slot[0] = CONFIG_VERSION;
+    slot[1] = len as u8;
+
+    let crc = crc16(&slot[..2 + len]).to_le_bytes();
+    slot[2 + len..4 + len].copy_from_slice(&crc);
+
+    Ok(slot)
+}
+
+/// Number of used slots of a sector, and the newest config that is intact. Slots are used in order
+/// so the ones after the last used one are erased.
+fn scan<F: Flash>(flash: &mut F, sector: usize) -> Result<(usize, Option<DeviceConfig>), Error> {
+    let start = sector * flash.sector_size();
+    let mut saved = None;
+    let mut used = 0;
+
+    for i in 0..flash.sector_size() / SLOT_SIZE {
+        let mut slot = [0; SLOT_SIZE];
+        flash.read(start + i * SLOT_SIZE, &mut slot)?;
+
+        if slot.iter().all(|&byte| byte == ERASED) {
+            continue;
+        }
+
+        used = i + 1;
+        if let Some(config) = decode(&slot) {
+            saved = Some(config);
+        }
+    }
+
+    Ok((used, saved))
+}
+
+/// The config in the first two sectors of `flash`
+pub struct ConfigStore<F> {
+    flash: F,
+    // The sector that is written, and the slot of it that is written next. The sector is full
+    // once it equals the number of slots.
+    sector: usize,
+    next: usize,
+    // The other sector still holds the slots from before, it is erased once the config in this
+    // one was read back
+    stale: bool,
+    saved: Option<DeviceConfig>,
+}
+
+impl<F: Flash> ConfigStore<F> {
+    /// Find the newest config that is intact
+    pub fn open(mut flash: F) -> Result<ConfigStore<F>, Error> {
+        if flash.sectors() < 2 {
+            return Err(Error::Flash);
+        }
+
+        let first = scan(&mut flash, 0)?;
+        let second = scan(&mut flash, 1)?;
+
+        // The old sector was full when the config moved on, so if both are used the one with
+        // fewer slots was written last
+        let (sector, (next, saved), (old, restored)) =
+            if second.0 > 0 && (first.0 == 0 || second.0 < first.0) {
+                (1, second, first)
+            } else {
+                (0, first, second)
+            };
+
+        Ok(ConfigStore {
+            flash,
+            sector,
+            next,
+            stale: old > 0,
+            // The slot in the new sector was torn by a reset
+            saved: saved.or(restored),
+        })
+    }
+
+    /// The newest config that was saved, if any
+    pub fn saved(&self) -> Option<DeviceConfig> {
+        self.saved
+    }
+
+    /// Write the config to the next slot, unless it didn't change, and read it back
+    pub fn save(&mut self, config: &DeviceConfig) -> Result<(), Error> {
+        if self.saved == Some(*config) {
+            return Ok(());
+        }
+
+        let slot = encode(config)?;
+
+        if self.next >= self.flash.sector_size() / SLOT_SIZE {
+            // Only if erasing it after the last switch failed
+            if self.stale {
+                self.flash.erase(1 - self.sector)?;
+            }
+
+            self.sector = 1 - self.sector;
+            self.next = 0;
+            self.stale = true;
+        }
+
+        // A slot that fails is skipped, it doesn't decode
+        let address = self.sector * self.flash.sector_size() + self.next * SLOT_SIZE;
+        self.next += 1;
+        self.flash.write(address, &slot)?;
+
+        let mut written = [0; SLOT_SIZE];
+        self.flash.read(address, &mut written)?;
+        if decode(&written) != Some(*config) {
+            return Err(Error::Flash);
+        }
+
+        self.saved = Some(*config);
+
+        // The config is saved either way, a sector that failed to erase is erased by the next
+        // switch
+        if self.stale && self.flash.erase(1 - self.sector).is_ok() {
+            self.stale = false;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockFlash;
+
+    fn config(channel: u8) -> DeviceConfig {
+        DeviceConfig {
+            gyro_scale: GyroScale::Dps2000,
+            mag_odr: MagOdr::Hz75,
+            channel,
+            calibration: Calibration {
+                gyro: [i16::MIN, i16::MAX, -150],
+                accel: [i16::MIN, 20, -30],
+                mag: [40, -50, i16::MAX],
+            },
+        }
+    }
+
+    #[test]
+    fn test_crc16() {
+        assert_eq!(crc16(b"123456789"), 0x29b1);
+    }
+
+    #[test]
+    fn test_slot_fits() {
+        let slot = encode(&config(125)).unwrap();
+
+        assert_eq!(decode(&slot), Some(config(125)));
+    }
+
+    #[test]
+    fn test_empty() {
+        let store = ConfigStore::open(MockFlash::new(256, 2)).unwrap();
+
+        assert_eq!(store.saved(), None);
+        assert_eq!(store.next, 0);
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let mut store = ConfigStore::open(MockFlash::new(256, 2)).unwrap();
+        store.save(&config(1)).unwrap();
+        store.save(&config(2)).unwrap();
+
+        let store = ConfigStore::open(store.flash).unwrap();
+        assert_eq!(store.saved(), Some(config(2)));
+        assert_eq!(store.next, 2);
+    }
+
+    #[test]
+    fn test_unchanged() {
+        let mut store = ConfigStore::open(MockFlash::new(256, 2)).unwrap();
+        store.save(&config(1)).unwrap();
+        store.save(&config(1)).unwrap();
+
+        assert_eq!(store.flash.writes, 1);
+    }
+
+    #[test]
+    fn test_wear() {
+        // Four slots per sector
+        let mut store = ConfigStore::open(MockFlash::new(256, 2)).unwrap();
+        for channel in 0..9 {
+            store.save(&config(channel)).unwrap();
+        }
+
+        // Each one after the first slot of the other sector was written
+        assert_eq!(store.flash.erases, 2);
+        assert_eq!((store.sector, store.next), (0, 1));
+
+        let store = ConfigStore::open(store.flash).unwrap();
+        assert_eq!(store.saved(), Some(config(8)));
+        assert!(!store.stale);
+    }
+
+    #[test]
+    fn test_reset_while_switching() {
+        let mut store = ConfigStore::open(MockFlash::new(256, 2)).unwrap();
+        for channel in 0..4 {
+            store.save(&config(channel)).unwrap();
+        }
+
+        // A reset after the first slot of the second sector was written, before the first sector
+        // was erased
+        let mut flash = store.flash;
+        flash.data[256..256 + SLOT_SIZE].copy_from_slice(&encode(&config(4)).unwrap());
+
+        let store = ConfigStore::open(flash).unwrap();
+        assert_eq!(store.saved(), Some(config(4)));
+        assert_eq!((store.sector, store.next, store.stale), (1, 1, true));
+
+        // A reset while writing it, the config of the full sector is kept
+        let mut flash = store.flash;
+        flash.data[256 + 3] ^= 0x01;
+
+        let mut store = ConfigStore::open(flash).unwrap();
+        assert_eq!(store.saved(), Some(config(3)));
+
+        // The full sector is erased once the next one was read back
+        store.save(&config(5)).unwrap();
+        assert!(!store.stale);
+        assert!(store.flash.data[..256].iter().all(|&byte| byte == ERASED));
+
+        let store = ConfigStore::open(store.flash).unwrap();
+        assert_eq!(store.saved(), Some(config(5)));
+        assert_eq!((store.sector, store.next), (1, 2));
+    }
+
+    #[test]
+    fn test_single_sector() {
+        assert!(ConfigStore::open(MockFlash::new(256, 1)).is_err());
+    }
+
+    #[test]
+    fn test_corrupt() {
+        let mut store = ConfigStore::open(MockFlash::new(256, 2)).unwrap();
+        store.save(&config(1)).unwrap();
+        store.save(&config(2)).unwrap();
+
+        // A reset while writing the second slot
+        let mut flash = store.flash;
+        flash.data[SLOT_SIZE + 3] ^= 0x01;
+        let mut store = ConfigStore::open(flash).unwrap();
+        assert_eq!(store.saved(), Some(config(1)));
+
+        // The torn slot is skipped
+        store.save(&config(3)).unwrap();
+        assert_eq!(store.next, 3);
+    }
+
+    #[test]
+    fn test_version() {
+        let mut flash = MockFlash::new(256, 2);
+        let mut slot = encode(&config(1)).unwrap();
+        slot[0] = CONFIG_VERSION + 1;
+        flash.data[..SLOT_SIZE].copy_from_slice(&slot);
+
+        let store = ConfigStore::open(flash).unwrap();
+        assert_eq!(store.saved(), None);
+    }
+
+    #[test]
+    fn test_write_error() {
+        let mut flash = MockFlash::new(256, 2);
+        flash.failures = 1;
+
+        let mut store = ConfigStore::open(flash).unwrap();
+        assert_eq!(store.save(&config(1)), Err(Error::Flash));
+        assert_eq!(store.saved(), None);
+
+        store.save(&config(1)).unwrap();
+        assert_eq!(store.next, 2);
+
+        // The slot that failed stays erased
+        let store = ConfigStore::open(store.flash).unwrap();
+        assert_eq!(store.saved(), Some(config(1)));
+        assert_eq!(store.next, 2);
+    }
+
+    #[test]
+    fn test_invalid() {
+        let mut flash = MockFlash::new(256, 2);
+        let slot = encode(&config(MAX_CHANNEL + 1)).unwrap();
+        flash.data[..SLOT_SIZE].copy_from_slice(&slot);
+
+        let store = ConfigStore::open(flash).unwrap();
+        assert_eq!(store.saved(), None);
+    }
+
+    #[test]
+    fn test_calibration() {
+        let calibration = Calibration {
+            gyro: [150, 0, -175],
+            accel: [10, -20, 0],
+            mag: [0, 0, i16::MAX],
+        };
+
+        let gyro = calibration.gyro(F32x3 {
+            x: 2.0,
+            y: 0.0,
+            z: 0.0,
+        });
+        assert_eq!((gyro.x, gyro.y, gyro.z), (0.5, 0.0, 1.75));
+
+        // 1.75 dps is 100 counts at ±500 dps
+        let raw = calibration.gyro_raw(I16x3 { x: 0, y: 0, z: 0 }, GyroScale::Dps500);
+        assert_eq!(raw.z, 100);
+
+        let accel = calibration.accel(I16x3 {
+            x: 0,
+            y: 0,
+            z: 1000,
+        });
+        assert_eq!(
+            accel,
+            I16x3 {
+                x: -10,
+                y: 20,
+                z: 1000
+            }
+        );
+
+        let mag = calibration.mag(I16x3 { x: 0, y: 0, z: -10 });
+        assert_eq!(mag.z, i16::MIN);
+    }
+}
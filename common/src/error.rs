@@ -0,0 +1,26 @@
+use serde::{Deserialize, Serialize};
+
+/// Errors of the firmware, by the part of the board that caused them
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// The L3GD20 gyroscope or the accelerometer of the LSM303DLHC
+    Imu,
+    /// The magnetometer of the LSM303DLHC
+    Mag,
+    /// The nRF24L01+ radio
+    Radio,
+    /// A message does not fit in a payload, or there is no session to seal it with
+    Encode,
+    /// The independent watchdog reset the board
+    Watchdog,
+    /// Flash memory could not be read, erased or programmed
+    Flash,
+}
+
+impl Error {
+    /// Whether retrying or re-initializing the peripheral may help, an `Encode` error is a
+    /// property of the message rather than of the board
+    pub fn is_fault(self) -> bool {
+        self != Error::Encode
+    }
+}
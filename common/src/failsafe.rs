@@ -0,0 +1,105 @@
+//! Link-loss failsafe of the drone, which engages once the client's heartbeats stop arriving
+//!
+//! The failsafe is armed by the first `Command::Heartbeat`, before that the drone doesn't know
+//! how often to expect the client. Any command counts as a heartbeat.
+
+/// Counts the status ticks since the last command from the client
+#[derive(Default)]
+pub struct Failsafe {
+    // Ticks without a command after which the link is lost, `None` until armed
+    timeout_ticks: Option<u32>,
+    ticks: u32,
+    engaged: bool,
+}
+
+impl Failsafe {
+    pub fn new() -> Failsafe {
+        Failsafe::default()
+    }
+
+    /// Expect a command at least every `timeout_ticks` from now on
+    pub fn arm(&mut self, timeout_ticks: u32) {
+        self.timeout_ticks = Some(timeout_ticks.max(1));
+    }
+
+    /// Stop expecting commands, e.g. once the drone is no longer paired. Returns whether this
+    /// releases the failsafe
+    pub fn disarm(&mut self) -> bool {
+        self.timeout_ticks = None;
+
+        self.received()
+    }
+
+    /// Called for every command that is received, returns whether this releases the failsafe
+    pub fn received(&mut self) -> bool {
+        self.ticks = 0;
+
+        let released = self.engaged;
+        self.engaged = false;
+
+        released
+    }
+
+    /// Called at the status rate, returns whether the failsafe engages in this tick
+    pub fn tick(&mut self) -> bool {
+        let timeout_ticks = match self.timeout_ticks {
+            Some(ticks) => ticks,
+            None => return false,
+        };
+
+        self.ticks = self.ticks.saturating_add(1);
+
+        if self.ticks >= timeout_ticks && !self.engaged {
+            self.engaged = true;
+            return true;
+        }
+
+        false
+    }
+
+    pub fn is_engaged(&self) -> bool {
+        self.engaged
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unarmed() {
+        let mut failsafe = Failsafe::new();
+
+        for _ in 0..100 {
+            assert!(!failsafe.tick());
+        }
+        assert!(!failsafe.is_engaged());
+    }
+
+    #[test]
+    fn test_engage() {
+        let mut failsafe = Failsafe::new();
+        failsafe.arm(3);
+
+        assert!(!failsafe.tick());
+        assert!(!failsafe.received());
+        assert!(!failsafe.tick());
+        assert!(!failsafe.tick());
+
+        // Only engages once, until a command releases it
+        assert!(failsafe.tick());
+        assert!(!failsafe.tick());
+        assert!(failsafe.is_engaged());
+
+        assert!(failsafe.received());
+        assert!(!failsafe.is_engaged());
+        assert!(!failsafe.tick());
+        assert!(!failsafe.tick());
+
+        assert!(failsafe.tick());
+        assert!(failsafe.disarm());
+        for _ in 0..3 {
+            assert!(!failsafe.tick());
+        }
+    }
+}
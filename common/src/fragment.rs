@@ -0,0 +1,222 @@
+//! Splits messages that don't fit in a single radio payload into fragments
+//!
+//! Every message sent by the drone is fragmented, even if it fits in one payload. A fragment
+//! starts with a two-byte header:
+//!
+//! | message id (1) | index (4 bits) | count (4 bits) | data |
+//!
+//! Fragments of a message share its id and are sent in order, the receiver joins them once all
+//! `count` arrived.
+use serde::Serialize;
+
+use crate::protocol::{to_payload, PAYLOAD_SIZE};
+use crate::Error;
+
+pub const HEADER_SIZE: usize = 2;
+/// Largest number of fragments of a single message
+pub const MAX_FRAGMENTS: usize = 15;
+/// Largest message that can be fragmented
+pub const MAX_MESSAGE_SIZE: usize = 128;
+
+/// A fragment that was parsed, `data` is the part of the message it carries
+#[derive(Debug, PartialEq)]
+pub struct Fragment<'a> {
+    pub id: u8,
+    pub index: u8,
+    pub count: u8,
+    pub data: &'a [u8],
+}
+
+impl<'a> Fragment<'a> {
+    /// Returns `None` if `frame` has no header, no data or an index past its count
+    pub fn parse(frame: &'a [u8]) -> Option<Fragment<'a>> {
+        if frame.len() <= HEADER_SIZE {
+            return None;
+        }
+
+        let index = frame[1] >> 4;
+        let count = frame[1] & 0x0f;
+        if index >= count {
+            return None;
+        }
+
+        Some(Fragment {
+            id: frame[0],
+            index,
+            count,
+            data: &frame[HEADER_SIZE..],
+        })
+    }
+}
+
+/// Splits messages into fragments of at most `size` bytes, header included
+pub struct Fragmenter {
+    size: usize,
+    // Id of the last message, which wraps
+    id: u8,
+    message: [u8; MAX_MESSAGE_SIZE],
+    len: usize,
+    // Part of `message` that was fragmented
+    sent: usize,
+}
+
+impl Fragmenter {
+    /// `size` is at most `PAYLOAD_SIZE`, and less if the fragments are sealed
+    pub fn new(size: usize) -> Fragmenter {
+        Fragmenter {
+            size: size.min(PAYLOAD_SIZE),
+            id: 0,
+            message: [0; MAX_MESSAGE_SIZE],
+            len: 0,
+            sent: 0,
+        }
+    }
+
+    /// Whether fragments of the last message were not taken yet
+    pub fn is_pending(&self) -> bool {
+        self.sent < self.len
+    }
+
+    /// Drops the fragments of the last message that were not taken yet
+    pub fn clear(&mut self) {
+        self.len = 0;
+        self.sent = 0;
+    }
+
+    /// Queue a message, replacing the fragments of the previous one that were not taken yet
+    pub fn push<T: Serialize>(&mut self, message: &T) -> Result<(), Error> {
+        self.clear();
+
+        let len = to_payload(message, &mut self.message)?.len();
+        if len > MAX_FRAGMENTS * (self.size - HEADER_SIZE) {
+            return Err(Error::Encode);
+        }
+
+        self.id = self.id.wrapping_add(1);
+        self.len = len;
+        self.sent = 0;
+
+        Ok(())
+    }
+
+    /// Takes back the last fragment, so `next` writes it again
+    pub fn rewind(&mut self) {
+        let capacity = self.size - HEADER_SIZE;
+
+        self.sent = self.sent.saturating_sub(1) / capacity * capacity;
+    }
+
+    /// Write the next fragment of the queued message, returns `None` once every one was taken
+    pub fn next<'a>(&mut self, buf: &'a mut [u8; PAYLOAD_SIZE]) -> Option<&'a [u8]> {
+        if !self.is_pending() {
+            return None;
+        }
+
+        let capacity = self.size - HEADER_SIZE;
+        // A message is never empty
+        let count = (self.len - 1) / capacity + 1;
+        let index = self.sent / capacity;
+        let end = self.len.min(self.sent + capacity);
+        let data = &self.message[self.sent..end];
+
+        buf[0] = self.id;
+        buf[1] = ((index << 4) | count) as u8;
+        buf[HEADER_SIZE..HEADER_SIZE + data.len()].copy_from_slice(data);
+
+        self.sent = end;
+
+        Some(&buf[..HEADER_SIZE + data.len()])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::telemetry;
+    use crate::protocol::Message;
+    use postcard::from_bytes;
+    use std::vec::Vec;
+
+    fn take(fragmenter: &mut Fragmenter) -> Vec<Vec<u8>> {
+        let mut buf = [0u8; PAYLOAD_SIZE];
+        let mut fragments = Vec::new();
+
+        while let Some(fragment) = fragmenter.next(&mut buf) {
+            fragments.push(fragment.to_vec());
+        }
+
+        fragments
+    }
+
+    #[test]
+    fn test_single() {
+        let mut fragmenter = Fragmenter::new(PAYLOAD_SIZE);
+        let message = Message::Telemetry(telemetry(u32::MAX));
+
+        fragmenter.push(&message).unwrap();
+        let fragments = take(&mut fragmenter);
+        assert_eq!(fragments.len(), 1);
+        assert!(fragments[0].len() <= PAYLOAD_SIZE);
+
+        let fragment = Fragment::parse(&fragments[0]).unwrap();
+        assert_eq!((fragment.id, fragment.index, fragment.count), (1, 0, 1));
+        assert_eq!(from_bytes::<Message>(fragment.data).unwrap(), message);
+    }
+
+    #[test]
+    fn test_split() {
+        let mut fragmenter = Fragmenter::new(12);
+        let message = Message::Telemetry(telemetry(u32::MAX));
+
+        fragmenter.push(&message).unwrap();
+        assert!(fragmenter.is_pending());
+        let fragments = take(&mut fragmenter);
+        assert!(!fragmenter.is_pending());
+        assert!(fragments.len() > 2);
+
+        let mut joined = Vec::new();
+        for (i, fragment) in fragments.iter().enumerate() {
+            assert!(fragment.len() <= 12);
+
+            let fragment = Fragment::parse(fragment).unwrap();
+            assert_eq!(
+                (fragment.index, fragment.count),
+                (i as u8, fragments.len() as u8)
+            );
+            joined.extend_from_slice(fragment.data);
+        }
+        assert_eq!(from_bytes::<Message>(&joined).unwrap(), message);
+
+        // The next message has another id
+        fragmenter.push(&Message::Listening).unwrap();
+        assert_eq!(Fragment::parse(&take(&mut fragmenter)[0]).unwrap().id, 2);
+    }
+
+    #[test]
+    fn test_too_large() {
+        let mut fragmenter = Fragmenter::new(HEADER_SIZE + 1);
+
+        fragmenter.push(&Message::Listening).unwrap();
+        assert_eq!(
+            fragmenter.push(&Message::Telemetry(telemetry(0))),
+            Err(Error::Encode)
+        );
+        assert!(!fragmenter.is_pending());
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(Fragment::parse(&[1, 0x01]), None);
+        assert_eq!(Fragment::parse(&[1, 0x11, 0]), None);
+        assert_eq!(Fragment::parse(&[1, 0x00, 0]), None);
+        assert_eq!(
+            Fragment::parse(&[1, 0x12, 0]),
+            Some(Fragment {
+                id: 1,
+                index: 1,
+                count: 2,
+                data: &[0]
+            })
+        );
+    }
+}
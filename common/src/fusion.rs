@@ -0,0 +1,369 @@
+//! Attitude of the drone from its gyroscope, accelerometer and magnetometer, with Madgwick's filter
+//!
+//! The angular rate is integrated into a quaternion, of which the drift is corrected by a gradient
+//! descent step towards the orientation in which gravity and the magnetic field are measured. See
+//! "An efficient orientation filter for inertial and inertial/magnetic sensor arrays" by Sebastian
+//! Madgwick. The earth's frame has its z axis pointing up, and the board lies level with its x
+//! axis pointing north at the identity.
+use libm::{asinf, atan2f, roundf, sqrtf};
+
+use crate::protocol::{Orientation, Telemetry};
+
+/// Gain of the correction, higher values trust the accelerometer and magnetometer more than the
+/// gyroscope
+pub const BETA: f32 = 0.1;
+
+/// Gaps between samples longer than this, e.g. after a reset of the sensors, are not integrated.
+/// It is above the interval of the lowest sample rate
+const MAX_SAMPLE_INTERVAL_US: u32 = 1_500_000;
+
+/// Rotation from the board's frame to the earth's, as w, x, y and z
+pub type Quaternion = [f32; 4];
+
+fn normalize3(v: [f32; 3]) -> Option<[f32; 3]> {
+    let norm = sqrtf(v[0] * v[0] + v[1] * v[1] + v[2] * v[2]);
+
+    if norm > 0.0 {
+        Some([v[0] / norm, v[1] / norm, v[2] / norm])
+    } else {
+        None
+    }
+}
+
+fn normalize4(q: Quaternion) -> Option<Quaternion> {
+    let norm = sqrtf(q[0] * q[0] + q[1] * q[1] + q[2] * q[2] + q[3] * q[3]);
+
+    if norm > 0.0 {
+        Some([q[0] / norm, q[1] / norm, q[2] / norm, q[3] / norm])
+    } else {
+        None
+    }
+}
+
+/// Gradient of the error between gravity as measured and as expected at `q`
+fn gradient_imu(q: Quaternion, a: [f32; 3]) -> Quaternion {
+    let [q0, q1, q2, q3] = q;
+    let [ax, ay, az] = a;
+
+    let (q0q0, q1q1, q2q2, q3q3) = (q0 * q0, q1 * q1, q2 * q2, q3 * q3);
+
+    [
+        4.0 * q0 * q2q2 + 2.0 * q2 * ax + 4.0 * q0 * q1q1 - 2.0 * q1 * ay,
+        4.0 * q1 * q3q3 - 2.0 * q3 * ax + 4.0 * q0q0 * q1 - 2.0 * q0 * ay - 4.0 * q1
+            + 8.0 * q1 * q1q1
+            + 8.0 * q1 * q2q2
+            + 4.0 * q1 * az,
+        4.0 * q0q0 * q2 + 2.0 * q0 * ax + 4.0 * q2 * q3q3 - 2.0 * q3 * ay - 4.0 * q2
+            + 8.0 * q2 * q1q1
+            + 8.0 * q2 * q2q2
+            + 4.0 * q2 * az,
+        4.0 * q1q1 * q3 - 2.0 * q1 * ax + 4.0 * q2q2 * q3 - 2.0 * q2 * ay,
+    ]
+}
+
+/// Gradient of the error between gravity and the magnetic field as measured and as expected at
+/// `q`. The field is only compared in its direction north and down, so its declination doesn't
+/// matter
+fn gradient_marg(q: Quaternion, a: [f32; 3], m: [f32; 3]) -> Quaternion {
+    let [q0, q1, q2, q3] = q;
+    let [ax, ay, az] = a;
+    let [mx, my, mz] = m;
+
+    let (q0q0, q0q1, q0q2, q0q3) = (q0 * q0, q0 * q1, q0 * q2, q0 * q3);
+    let (q1q1, q1q2, q1q3) = (q1 * q1, q1 * q2, q1 * q3);
+    let (q2q2, q2q3, q3q3) = (q2 * q2, q2 * q3, q3 * q3);
+
+    // The measured field in the earth's frame
+    let hx = mx * q0q0 - 2.0 * q0 * my * q3
+        + 2.0 * q0 * mz * q2
+        + mx * q1q1
+        + 2.0 * q1 * my * q2
+        + 2.0 * q1 * mz * q3
+        - mx * q2q2
+        - mx * q3q3;
+    let hy = 2.0 * q0 * mx * q3 + my * q0q0 - 2.0 * q0 * mz * q1 + 2.0 * q1 * mx * q2 - my * q1q1
+        + my * q2q2
+        + 2.0 * q2 * mz * q3
+        - my * q3q3;
+    let hz = -2.0 * q0 * mx * q2 + 2.0 * q0 * my * q1 + mz * q0q0 + 2.0 * q1 * mx * q3 - mz * q1q1
+        + 2.0 * q2 * my * q3
+        - mz * q2q2
+        + mz * q3q3;
+
+    // Twice the reference field, which points north and down
+    let bx = 2.0 * sqrtf(hx * hx + hy * hy);
+    let bz = 2.0 * hz;
+
+    // Errors of gravity and of the field along every axis
+    let gx = 2.0 * q1q3 - 2.0 * q0q2 - ax;
+    let gy = 2.0 * q0q1 + 2.0 * q2q3 - ay;
+    let gz = 1.0 - 2.0 * q1q1 - 2.0 * q2q2 - az;
+    let fx = bx * (0.5 - q2q2 - q3q3) + bz * (q1q3 - q0q2) - mx;
+    let fy = bx * (q1q2 - q0q3) + bz * (q0q1 + q2q3) - my;
+    let fz = bx * (q0q2 + q1q3) + bz * (0.5 - q1q1 - q2q2) - mz;
+
+    [
+        -2.0 * q2 * gx + 2.0 * q1 * gy - bz * q2 * fx + (-bx * q3 + bz * q1) * fy + bx * q2 * fz,
+        2.0 * q3 * gx + 2.0 * q0 * gy - 4.0 * q1 * gz
+            + bz * q3 * fx
+            + (bx * q2 + bz * q0) * fy
+            + (bx * q3 - 2.0 * bz * q1) * fz,
+        -2.0 * q0 * gx + 2.0 * q3 * gy - 4.0 * q2 * gz
+            + (-2.0 * bx * q2 - bz * q0) * fx
+            + (bx * q1 + bz * q3) * fy
+            + (bx * q0 - 2.0 * bz * q2) * fz,
+        2.0 * q1 * gx
+            + 2.0 * q2 * gy
+            + (-2.0 * bx * q3 + bz * q1) * fx
+            + (-bx * q0 + bz * q2) * fy
+            + bx * q1 * fz,
+    ]
+}
+
+/// Roll, pitch and yaw in degrees, rotated in that order around the x, y and z axis
+pub fn euler(q: Quaternion) -> [f32; 3] {
+    let [w, x, y, z] = q;
+
+    let roll = atan2f(2.0 * (w * x + y * z), 1.0 - 2.0 * (x * x + y * y));
+    // Clamped, as rounding errors take it just past a quarter turn when pointing straight up
+    let pitch = asinf((2.0 * (w * y - z * x)).max(-1.0).min(1.0));
+    let yaw = atan2f(2.0 * (w * z + x * y), 1.0 - 2.0 * (y * y + z * z));
+
+    [roll.to_degrees(), pitch.to_degrees(), yaw.to_degrees()]
+}
+
+/// Estimates the attitude from every sample, and reports it after every `interval` of them
+pub struct Fusion {
+    q: Quaternion,
+    interval: u8,
+    fused: u8,
+    last_timestamp: Option<u32>,
+}
+
+impl Fusion {
+    pub fn new(interval: u8) -> Fusion {
+        Fusion {
+            q: [1.0, 0.0, 0.0, 0.0],
+            interval: interval.max(1),
+            fused: 0,
+            last_timestamp: None,
+        }
+    }
+
+    pub fn quaternion(&self) -> Quaternion {
+        self.q
+    }
+
+    /// Integrate a sample, returns the orientation once `interval` samples were fused
+    ///
+    /// Without an acceleration the angular rate is only integrated, without a magnetic field the
+    /// heading drifts along with the gyroscope.
+    pub fn update(&mut self, sample: &Telemetry) -> Option<Orientation> {
+        let dt = self
+            .last_timestamp
+            .map(|previous| sample.timestamp.wrapping_sub(previous))
+            .filter(|&dt| dt <= MAX_SAMPLE_INTERVAL_US);
+
+        self.last_timestamp = Some(sample.timestamp);
+
+        if let Some(dt) = dt {
+            self.integrate(sample, dt as f32 / 1_000_000.0);
+        }
+
+        self.fused += 1;
+        if self.fused < self.interval {
+            return None;
+        }
+
+        self.fused = 0;
+
+        Some(self.orientation(sample.timestamp))
+    }
+
+    fn integrate(&mut self, sample: &Telemetry, dt: f32) {
+        let [q0, q1, q2, q3] = self.q;
+        let gx = sample.gyro_x.to_radians();
+        let gy = sample.gyro_y.to_radians();
+        let gz = sample.gyro_z.to_radians();
+
+        // Rate of change of the quaternion from the angular rate in the board's frame
+        let mut q_dot = [
+            0.5 * (-q1 * gx - q2 * gy - q3 * gz),
+            0.5 * (q0 * gx + q2 * gz - q3 * gy),
+            0.5 * (q0 * gy - q1 * gz + q3 * gx),
+            0.5 * (q0 * gz + q1 * gy - q2 * gx),
+        ];
+
+        let accel = [
+            f32::from(sample.accel_x),
+            f32::from(sample.accel_y),
+            f32::from(sample.accel_z),
+        ];
+        let mag = [
+            f32::from(sample.mag_x),
+            f32::from(sample.mag_y),
+            f32::from(sample.mag_z),
+        ];
+
+        if let Some(a) = normalize3(accel) {
+            let step = match normalize3(mag) {
+                Some(m) => gradient_marg(self.q, a, m),
+                None => gradient_imu(self.q, a),
+            };
+
+            // The gradient is zero once the measurements agree with the estimate
+            if let Some(step) = normalize4(step) {
+                for (rate, step) in q_dot.iter_mut().zip(step.iter()) {
+                    *rate -= BETA * step;
+                }
+            }
+        }
+
+        let q = [
+            q0 + q_dot[0] * dt,
+            q1 + q_dot[1] * dt,
+            q2 + q_dot[2] * dt,
+            q3 + q_dot[3] * dt,
+        ];
+
+        if let Some(q) = normalize4(q) {
+            self.q = q;
+        }
+    }
+
+    /// The current estimate, with the Euler angles in hundredths of a degree
+    pub fn orientation(&self, timestamp: u32) -> Orientation {
+        let centi = |degrees: f32| roundf(degrees * 100.0) as i16;
+        let [roll, pitch, yaw] = euler(self.q);
+
+        Orientation {
+            timestamp,
+            quaternion: self.q,
+            euler: [centi(roll), centi(pitch), centi(yaw)],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::telemetry;
+
+    /// A level board, heading north in a field that points down at 60 degrees
+    fn level(timestamp: u32) -> Telemetry {
+        Telemetry {
+            timestamp,
+            mag_x: 250,
+            mag_y: 0,
+            mag_z: -433,
+            accel_x: 0,
+            accel_y: 0,
+            accel_z: 1000,
+            gyro_x: 0.0,
+            gyro_y: 0.0,
+            gyro_z: 0.0,
+            temp: 20,
+        }
+    }
+
+    fn assert_close(actual: [f32; 3], expected: [f32; 3], epsilon: f32) {
+        for (a, e) in actual.iter().zip(expected.iter()) {
+            assert!((a - e).abs() < epsilon, "{:?} != {:?}", actual, expected);
+        }
+    }
+
+    #[test]
+    fn test_euler() {
+        assert_close(euler([1.0, 0.0, 0.0, 0.0]), [0.0, 0.0, 0.0], 1e-4);
+
+        // A quarter turn around each axis
+        let half = core::f32::consts::FRAC_1_SQRT_2;
+        assert_close(euler([half, half, 0.0, 0.0]), [90.0, 0.0, 0.0], 1e-3);
+        assert_close(euler([half, 0.0, half, 0.0]), [0.0, 90.0, 0.0], 1e-1);
+        assert_close(euler([half, 0.0, 0.0, half]), [0.0, 0.0, 90.0], 1e-3);
+    }
+
+    #[test]
+    fn test_level() {
+        let mut fusion = Fusion::new(1);
+
+        for n in 0..200 {
+            fusion.update(&level(n * 10_000));
+        }
+
+        let [w, ..] = fusion.quaternion();
+        assert!(w > 0.9999);
+    }
+
+    #[test]
+    fn test_gyro() {
+        let mut fusion = Fusion::new(1);
+
+        // Without a field, a quarter turn per second around z for a second at 100 Hz
+        let mut orientation = None;
+        for n in 0..=100 {
+            let sample = Telemetry {
+                gyro_z: 90.0,
+                mag_x: 0,
+                mag_z: 0,
+                ..level(n * 10_000)
+            };
+            orientation = fusion.update(&sample);
+        }
+
+        let orientation = orientation.unwrap();
+        assert_eq!(orientation.timestamp, 1_000_000);
+        assert!((orientation.euler[2] - 9000).abs() < 50);
+        assert!(orientation.euler[0].abs() < 10 && orientation.euler[1].abs() < 10);
+    }
+
+    #[test]
+    fn test_converges() {
+        let mut fusion = Fusion::new(1);
+
+        // Rolled a quarter turn around x, gravity and the field are measured along other axes
+        let sample = |timestamp| Telemetry {
+            accel_y: 1000,
+            accel_z: 0,
+            mag_y: -433,
+            mag_z: 0,
+            ..level(timestamp)
+        };
+
+        for n in 0..3000 {
+            fusion.update(&sample(n * 10_000));
+        }
+
+        let orientation = fusion.orientation(0);
+        assert!((orientation.euler[0] - 9000).abs() < 100);
+        assert!(orientation.euler[1].abs() < 100 && orientation.euler[2].abs() < 100);
+    }
+
+    #[test]
+    fn test_gap() {
+        let mut fusion = Fusion::new(1);
+        fusion.update(&level(0));
+
+        // Would be five turns if it were integrated
+        let sample = Telemetry {
+            gyro_x: 900.0,
+            ..level(2_000_000)
+        };
+        fusion.update(&sample);
+
+        let [w, ..] = fusion.quaternion();
+        assert!(w > 0.9999);
+    }
+
+    #[test]
+    fn test_interval() {
+        let mut fusion = Fusion::new(3);
+
+        let reported: std::vec::Vec<u32> = (0..7)
+            .filter_map(|n| fusion.update(&telemetry(n)))
+            .map(|orientation| orientation.timestamp)
+            .collect();
+
+        assert_eq!(reported, [2, 5]);
+    }
+}
@@ -0,0 +1,87 @@
+//! Traits that separate the firmware's logic from the peripherals of the board
+use crate::command::GyroScale;
+use crate::Error;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct I16x3 {
+    pub x: i16,
+    pub y: i16,
+    pub z: i16,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct F32x3 {
+    pub x: f32,
+    pub y: f32,
+    pub z: f32,
+}
+
+/// Gyroscope, accelerometer and temperature sensor
+pub trait ImuSource {
+    /// Angular rate in degrees per second
+    fn gyro(&mut self) -> Result<F32x3, Error>;
+
+    /// Angular rate in raw counts, along with the full-scale they were read at
+    fn gyro_raw(&mut self) -> Result<(I16x3, GyroScale), Error>;
+
+    /// Acceleration in milli-g
+    fn accel(&mut self) -> Result<I16x3, Error>;
+
+    /// Temperature in degrees Celsius
+    fn temp(&mut self) -> Result<i8, Error>;
+
+    /// Re-initialize the sensor after it failed
+    fn reset(&mut self) -> Result<(), Error>;
+}
+
+pub trait MagSource {
+    /// Magnetic field in milligauss
+    fn mag(&mut self) -> Result<I16x3, Error>;
+
+    /// Re-initialize the sensor after it failed
+    fn reset(&mut self) -> Result<(), Error>;
+}
+
+/// A radio that transmits, apart from short windows in which it listens for commands
+pub trait Radio {
+    /// Clear the interrupt flags, which releases the IRQ line
+    fn clear_interrupts(&mut self) -> Result<(), Error>;
+
+    /// Whether the TX FIFO has room for another payload
+    fn can_send(&mut self) -> Result<bool, Error>;
+
+    fn send(&mut self, payload: &[u8]) -> Result<(), Error>;
+
+    /// Re-initialize the radio after it failed, which discards the TX FIFO
+    fn reset(&mut self) -> Result<(), Error>;
+
+    /// Switch to receive mode once the TX FIFO is empty, `can_send` is false while listening
+    fn listen(&mut self) -> Result<(), Error>;
+
+    /// Switch back to transmit mode
+    fn stop_listening(&mut self) -> Result<(), Error>;
+
+    fn is_listening(&self) -> bool;
+
+    /// Copy a received payload into `buf`, returns its length
+    fn read(&mut self, buf: &mut [u8]) -> Result<Option<usize>, Error>;
+}
+
+/// Flash memory, of which erased bytes read `0xff` and programming only clears bits
+pub trait Flash {
+    /// Size of the smallest part that can be erased, in bytes
+    fn sector_size(&self) -> usize;
+
+    fn sectors(&self) -> usize;
+
+    fn read(&mut self, address: usize, buf: &mut [u8]) -> Result<(), Error>;
+
+    /// Program erased memory, `address` and the length of `data` are even
+    fn write(&mut self, address: usize, data: &[u8]) -> Result<(), Error>;
+
+    fn erase(&mut self, sector: usize) -> Result<(), Error>;
+}
+
+pub trait StatusLed {
+    fn set(&mut self, on: bool);
+}
@@ -0,0 +1,32 @@
+#![no_std]
+
+#[cfg(test)]
+extern crate std;
+
+pub mod batch;
+pub mod blackbox;
+pub mod clock;
+pub mod command;
+pub mod config;
+pub mod error;
+pub mod failsafe;
+pub mod fragment;
+pub mod fusion;
+pub mod hal;
+pub mod log;
+pub mod pairing;
+pub mod protocol;
+pub mod radio;
+pub mod recovery;
+pub mod relay;
+pub mod sampler;
+pub mod secure;
+pub mod spectrum;
+pub mod status;
+pub mod transmitter;
+pub mod units;
+
+#[cfg(test)]
+pub mod mock;
+
+pub use self::error::Error;
@@ -0,0 +1,93 @@
+//! Leveled log records of the firmware, compact enough to be sent to the client
+//!
+//! A record carries a `Code` and a few numeric arguments rather than text, the client knows what
+//! each code means.
+use heapless::{consts::U3, Vec};
+use serde::{Deserialize, Serialize};
+
+/// Numeric arguments a record takes at most
+pub const MAX_ARGS: usize = 3;
+
+/// Arguments of a record, in the order its `Code` lists them
+pub type Args = Vec<i32, U3>;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Level {
+    Trace,
+    Debug,
+    Info,
+    Warn,
+    Error,
+}
+
+/// What a record is about, along with its arguments
+///
+/// New codes are only added at the end, so older clients still decode the ones they know.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Code {
+    /// The board started, with whether the watchdog reset it
+    Boot,
+    /// The radio is set up, with its channel and `DataRate` index
+    Radio,
+    /// A peripheral failed and was re-initialized, with the `Error` index
+    Fault,
+    /// A command arrived, with its id
+    Command,
+    /// The link moved, with the channel and whether it is on private addresses. The relay
+    /// precedes them with the pipe
+    RadioSwitched,
+    /// A new link wasn't confirmed in time, and the drone falls back to the previous one
+    RadioFallback,
+    /// The client's heartbeats stopped, the failsafe engaged
+    LinkLost,
+    /// A command arrived while the failsafe was engaged, which released it
+    LinkRestored,
+    /// The relay sent an uplink, with its pipe
+    Uplink,
+    /// The relay's radio failed and was re-initialized
+    RadioReset,
+    /// The config was read at boot, with whether a saved one was found
+    ConfigLoaded,
+    /// The config was written to flash
+    ConfigSaved,
+    /// The blackbox was opened at boot, with the number of records it keeps
+    BlackboxOpened,
+}
+
+/// Arguments of a record, of which the ones beyond `MAX_ARGS` are left out
+pub fn args(values: &[i32]) -> Args {
+    values.iter().take(MAX_ARGS).cloned().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{to_payload, Message, PAYLOAD_SIZE};
+    use postcard::from_bytes;
+
+    #[test]
+    fn test_level() {
+        assert!(Level::Warn > Level::Info);
+        assert!(Level::Trace < Level::Debug);
+    }
+
+    #[test]
+    fn test_args() {
+        assert_eq!(&args(&[1, -2, 3, 4])[..], &[1, -2, 3]);
+        assert!(args(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_log_fits_payload() {
+        let message = Message::Log {
+            level: Level::Error,
+            code: Code::RadioReset,
+            args: args(&[i32::MIN, i32::MAX, i32::MIN]),
+        };
+
+        let mut buf = [0u8; PAYLOAD_SIZE];
+        let output = to_payload(&message, &mut buf).unwrap();
+
+        assert_eq!(from_bytes::<Message>(output).unwrap(), message);
+    }
+}
@@ -0,0 +1,270 @@
+//! Implementations of the `hal` traits for tests on the host
+use std::vec::Vec;
+
+use crate::command::GyroScale;
+use crate::hal::{F32x3, Flash, I16x3, ImuSource, MagSource, Radio, StatusLed};
+use crate::protocol::Telemetry;
+use crate::Error;
+
+/// A sample in which every field is derived from `n`
+pub fn telemetry(n: u32) -> Telemetry {
+    Telemetry {
+        timestamp: n,
+        mag_x: n as i16,
+        mag_y: n as i16,
+        mag_z: n as i16,
+        accel_x: n as i16,
+        accel_y: n as i16,
+        accel_z: n as i16,
+        gyro_x: n as f32,
+        gyro_y: n as f32,
+        gyro_z: n as f32,
+        temp: n as i8,
+    }
+}
+
+/// Fails the next `failures` calls, or every call until reset while `stuck`
+fn fail(failures: &mut usize, stuck: bool, error: Error) -> Result<(), Error> {
+    if stuck {
+        return Err(error);
+    }
+
+    if *failures > 0 {
+        *failures -= 1;
+        return Err(error);
+    }
+
+    Ok(())
+}
+
+#[derive(Default)]
+pub struct MockSensors {
+    pub mag: I16x3,
+    pub accel: I16x3,
+    pub gyro: F32x3,
+    // Read at ±500 dps
+    pub gyro_raw: I16x3,
+    pub temp: i8,
+    pub imu_failures: usize,
+    pub imu_stuck: bool,
+    pub imu_resets: usize,
+    pub mag_failures: usize,
+    pub mag_stuck: bool,
+    pub mag_resets: usize,
+}
+
+impl ImuSource for MockSensors {
+    fn gyro(&mut self) -> Result<F32x3, Error> {
+        fail(&mut self.imu_failures, self.imu_stuck, Error::Imu)?;
+
+        Ok(self.gyro)
+    }
+
+    fn gyro_raw(&mut self) -> Result<(I16x3, GyroScale), Error> {
+        fail(&mut self.imu_failures, self.imu_stuck, Error::Imu)?;
+
+        Ok((self.gyro_raw, GyroScale::Dps500))
+    }
+
+    fn accel(&mut self) -> Result<I16x3, Error> {
+        fail(&mut self.imu_failures, self.imu_stuck, Error::Imu)?;
+
+        Ok(self.accel)
+    }
+
+    fn temp(&mut self) -> Result<i8, Error> {
+        fail(&mut self.imu_failures, self.imu_stuck, Error::Imu)?;
+
+        Ok(self.temp)
+    }
+
+    fn reset(&mut self) -> Result<(), Error> {
+        self.imu_resets += 1;
+        self.imu_stuck = false;
+
+        Ok(())
+    }
+}
+
+impl MagSource for MockSensors {
+    fn mag(&mut self) -> Result<I16x3, Error> {
+        fail(&mut self.mag_failures, self.mag_stuck, Error::Mag)?;
+
+        Ok(self.mag)
+    }
+
+    fn reset(&mut self) -> Result<(), Error> {
+        self.mag_resets += 1;
+        self.mag_stuck = false;
+
+        Ok(())
+    }
+}
+
+/// A radio with a TX FIFO of `capacity` payloads, which is emptied by clearing `sent`
+///
+/// Payloads in `received` are read while listening.
+pub struct MockRadio {
+    pub capacity: usize,
+    pub sent: Vec<Vec<u8>>,
+    pub received: Vec<Vec<u8>>,
+    pub listening: bool,
+    pub interrupts_cleared: usize,
+    pub failures: usize,
+    pub stuck: bool,
+    pub resets: usize,
+}
+
+impl MockRadio {
+    pub fn new(capacity: usize) -> MockRadio {
+        MockRadio {
+            capacity,
+            sent: Vec::new(),
+            received: Vec::new(),
+            listening: false,
+            interrupts_cleared: 0,
+            failures: 0,
+            stuck: false,
+            resets: 0,
+        }
+    }
+}
+
+impl Radio for MockRadio {
+    fn clear_interrupts(&mut self) -> Result<(), Error> {
+        self.interrupts_cleared += 1;
+
+        Ok(())
+    }
+
+    fn can_send(&mut self) -> Result<bool, Error> {
+        Ok(!self.listening && self.sent.len() < self.capacity)
+    }
+
+    fn send(&mut self, payload: &[u8]) -> Result<(), Error> {
+        fail(&mut self.failures, self.stuck, Error::Radio)?;
+
+        self.sent.push(payload.to_vec());
+
+        Ok(())
+    }
+
+    fn reset(&mut self) -> Result<(), Error> {
+        self.resets += 1;
+        self.stuck = false;
+        self.sent.clear();
+        self.listening = false;
+
+        Ok(())
+    }
+
+    fn listen(&mut self) -> Result<(), Error> {
+        fail(&mut self.failures, self.stuck, Error::Radio)?;
+
+        self.listening = true;
+
+        Ok(())
+    }
+
+    fn stop_listening(&mut self) -> Result<(), Error> {
+        self.listening = false;
+
+        Ok(())
+    }
+
+    fn is_listening(&self) -> bool {
+        self.listening
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<Option<usize>, Error> {
+        if !self.listening || self.received.is_empty() {
+            return Ok(None);
+        }
+
+        let payload = self.received.remove(0);
+        buf[..payload.len()].copy_from_slice(&payload);
+
+        Ok(Some(payload.len()))
+    }
+}
+
+/// Flash of `sectors` sectors of `sector_size` bytes, which are erased at first
+///
+/// Like the STM32's flash, programming a half-word that isn't erased fails.
+pub struct MockFlash {
+    pub data: Vec<u8>,
+    pub sector_size: usize,
+    pub writes: usize,
+    pub erases: usize,
+    pub failures: usize,
+}
+
+impl MockFlash {
+    pub fn new(sector_size: usize, sectors: usize) -> MockFlash {
+        MockFlash {
+            data: std::vec![0xff; sector_size * sectors],
+            sector_size,
+            writes: 0,
+            erases: 0,
+            failures: 0,
+        }
+    }
+}
+
+impl Flash for MockFlash {
+    fn sector_size(&self) -> usize {
+        self.sector_size
+    }
+
+    fn sectors(&self) -> usize {
+        self.data.len() / self.sector_size
+    }
+
+    fn read(&mut self, address: usize, buf: &mut [u8]) -> Result<(), Error> {
+        let data = self.data.get(address..address + buf.len());
+        buf.copy_from_slice(data.ok_or(Error::Flash)?);
+
+        Ok(())
+    }
+
+    fn write(&mut self, address: usize, data: &[u8]) -> Result<(), Error> {
+        fail(&mut self.failures, false, Error::Flash)?;
+
+        let target = self
+            .data
+            .get_mut(address..address + data.len())
+            .ok_or(Error::Flash)?;
+        if (address | data.len()) & 1 != 0 || target.iter().any(|&byte| byte != 0xff) {
+            return Err(Error::Flash);
+        }
+
+        target.copy_from_slice(data);
+        self.writes += 1;
+
+        Ok(())
+    }
+
+    fn erase(&mut self, sector: usize) -> Result<(), Error> {
+        let start = sector * self.sector_size;
+        let sector = self
+            .data
+            .get_mut(start..start + self.sector_size)
+            .ok_or(Error::Flash)?;
+
+        sector.iter_mut().for_each(|byte| *byte = 0xff);
+        self.erases += 1;
+
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+pub struct MockLed {
+    pub on: bool,
+}
+
+impl StatusLed for MockLed {
+    fn set(&mut self, on: bool) {
+        self.on = on;
+    }
+}
@@ -0,0 +1,105 @@
+//! Pairing a drone with a client, after which they talk on addresses derived from the drone's id
+//!
+//! An unpaired drone advertises its id on the default addresses. Once the client confirms it with
+//! `Command::Pair`, both move to the private addresses of that id.
+//!
+//! The relay receives several drones at once on pipes that share all but the first address byte
+//! with the default address, so only the first byte of the address the drone sends to is private.
+use crate::radio::RadioConfig;
+
+/// The 96-bit unique device id of the STM32
+pub type DeviceId = [u8; 12];
+
+/// 64-bit FNV-1a
+pub(crate) fn hash(id: &DeviceId, salt: u8) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+
+    for &byte in id.iter().chain(&[salt]) {
+        hash ^= u64::from(byte);
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+
+    hash
+}
+
+fn address(id: &DeviceId, salt: u8) -> [u8; 5] {
+    let bytes = hash(id, salt).to_le_bytes();
+    let mut address = [bytes[0], bytes[1], bytes[2], bytes[3], bytes[4]];
+
+    // Addresses that start with few level shifts, or look like the preamble, are received poorly.
+    // The first byte of the default address is left for unpaired drones
+    if let 0x00 | 0xff | 0x55 | 0xaa | 0x11 = address[0] {
+        address[0] ^= 0x3c;
+    }
+
+    address
+}
+
+/// The configuration with the private addresses of `id`
+pub fn private_config(config: &RadioConfig, id: &DeviceId) -> RadioConfig {
+    let mut tx_address = RadioConfig::default().tx_address;
+    tx_address[0] = address(id, 0)[0];
+
+    RadioConfig {
+        tx_address,
+        rx_address: address(id, 1),
+        ..*config
+    }
+}
+
+/// Whether the configuration uses private addresses rather than the default ones
+pub fn is_paired(config: &RadioConfig) -> bool {
+    let default = RadioConfig::default();
+
+    config.tx_address != default.tx_address || config.rx_address != default.rx_address
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command::{Command, Uplink};
+    use crate::protocol::{encode, Message, PAYLOAD_SIZE};
+
+    #[test]
+    fn test_private_config() {
+        let config = RadioConfig::default();
+        let first = private_config(&config, &[1; 12]);
+        let second = private_config(&config, &[2; 12]);
+
+        assert_eq!(first, private_config(&config, &[1; 12]));
+        assert_ne!(first.tx_address, second.tx_address);
+        assert_ne!(first.tx_address, first.rx_address);
+        assert_eq!(first.tx_address[1..], config.tx_address[1..]);
+        assert_eq!(first.channel, config.channel);
+
+        assert!(is_paired(&first));
+        assert!(!is_paired(&config));
+    }
+
+    #[test]
+    fn test_address_first_byte() {
+        for n in 0..=255 {
+            let first = address(&[n; 12], 0)[0];
+            assert!(![0x00, 0xff, 0x55, 0xaa, 0x11].contains(&first));
+        }
+    }
+
+    #[test]
+    fn test_fits_payload() {
+        let mut buf = [0u8; PAYLOAD_SIZE];
+        assert!(encode(
+            &Message::Advertise {
+                id: [0xff; 12],
+                nonce: u32::MAX,
+            },
+            &mut buf
+        )
+        .is_ok());
+
+        let uplink = Uplink {
+            id: u8::MAX,
+            command: Command::Pair([0xff; 12]),
+        };
+        assert!(encode(&uplink, &mut buf).is_ok());
+    }
+}
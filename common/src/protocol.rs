@@ -0,0 +1,337 @@
+use heapless::{consts::U4, Vec};
+use postcard::{to_slice, to_slice_cobs};
+use serde::{Deserialize, Serialize};
+
+use crate::blackbox::BlackboxChunk;
+use crate::command::{CommandResult, GyroScale, Settings, Uplink};
+use crate::config::DeviceConfig;
+use crate::log::{Args, Code, Level};
+use crate::pairing::DeviceId;
+use crate::radio::RadioConfig;
+use crate::spectrum::SpectrumChunk;
+use crate::units;
+use crate::Error;
+
+/// Maximum size of a single nRF24L01+ payload
+pub const PAYLOAD_SIZE: usize = 32;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Telemetry {
+    // Time at which the sample was taken in microseconds since boot, wraps after ~71 minutes
+    pub timestamp: u32,
+    // Magnetic field in milligauss
+    pub mag_x: i16,
+    pub mag_y: i16,
+    pub mag_z: i16,
+    // Acceleration in milli-g
+    pub accel_x: i16,
+    pub accel_y: i16,
+    pub accel_z: i16,
+    // Angular rate in degrees per second
+    pub gyro_x: f32,
+    pub gyro_y: f32,
+    pub gyro_z: f32,
+    pub temp: i8,
+}
+
+/// `Telemetry` with the gyroscope's raw counts, which saves 4 bytes per sample
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CompactTelemetry {
+    pub timestamp: u32,
+    pub mag_x: i16,
+    pub mag_y: i16,
+    pub mag_z: i16,
+    pub accel_x: i16,
+    pub accel_y: i16,
+    pub accel_z: i16,
+    // Angular rate in counts of the L3GD20 at `gyro_scale`
+    pub gyro_x: i16,
+    pub gyro_y: i16,
+    pub gyro_z: i16,
+    pub gyro_scale: GyroScale,
+    pub temp: i8,
+}
+
+impl CompactTelemetry {
+    /// Convert the angular rate into degrees per second
+    pub fn decode(&self) -> Telemetry {
+        let dps = |raw| units::gyro_dps(raw, self.gyro_scale);
+
+        Telemetry {
+            timestamp: self.timestamp,
+            mag_x: self.mag_x,
+            mag_y: self.mag_y,
+            mag_z: self.mag_z,
+            accel_x: self.accel_x,
+            accel_y: self.accel_y,
+            accel_z: self.accel_z,
+            gyro_x: dps(self.gyro_x),
+            gyro_y: dps(self.gyro_y),
+            gyro_z: dps(self.gyro_z),
+            temp: self.temp,
+        }
+    }
+}
+
+/// Attitude estimated by the drone, see `fusion`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Orientation {
+    // Timestamp of the last sample that was fused
+    pub timestamp: u32,
+    // Rotation from the board's frame to the earth's, as w, x, y and z
+    pub quaternion: [f32; 4],
+    // Roll, pitch and yaw in hundredths of a degree
+    pub euler: [i16; 3],
+}
+
+/// Samples that are sent together, each with its own timestamp. Up to `MAX_BATCH_SIZE`, which
+/// fits in `fragment::MAX_MESSAGE_SIZE` either way
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum Batch {
+    Float(Vec<Telemetry, U4>),
+    Compact(Vec<CompactTelemetry, U4>),
+}
+
+/// Messages sent by the drone
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum Message {
+    Telemetry(Telemetry),
+    /// A fault the firmware recovered from
+    Fault(Error),
+    /// Result of the uplink command with the same id
+    Ack {
+        id: u8,
+        result: CommandResult,
+    },
+    /// Reply to `Command::RequestStatus`
+    Status(DeviceStatus),
+    /// The drone listens for a command right after this message, the relay matches on its
+    /// encoding, `LISTENING`, so it has to remain the fifth variant. It is sent as is, neither
+    /// fragmented nor sealed
+    Listening,
+    /// Part of the result of `Command::ScanSpectrum`
+    Spectrum(SpectrumChunk),
+    /// Sent by an unpaired drone on the default addresses, until a client pairs with it. It is
+    /// never sealed, the nonce is picked at boot and is part of the keys in `secure`
+    Advertise {
+        id: DeviceId,
+        nonce: u32,
+    },
+    /// Sent instead of `Telemetry` once `Encoding::Compact` was negotiated
+    CompactTelemetry(CompactTelemetry),
+    /// Sent instead of single samples once a batch size of more than one was negotiated
+    Batch(Batch),
+    /// Sent periodically by a paired drone, so the client notices a lost link even if no
+    /// samples are sent
+    Heartbeat,
+    /// Reply to `Command::Ping`, with the drone's time in microseconds when it was handled
+    Pong {
+        sent: u32,
+        timestamp: u32,
+    },
+    /// A record of the firmware's log at or above the level forwarded over the radio
+    Log {
+        level: Level,
+        code: Code,
+        args: Args,
+    },
+    /// Reply to `Command::RequestConfig`
+    Config(DeviceConfig),
+    /// Sent after every few samples by firmware built with the `fusion` feature
+    Orientation(Orientation),
+    /// Sent while the log is downloaded after `Command::ReadBlackbox`, see `blackbox`
+    Blackbox(BlackboxChunk),
+}
+
+/// Encoded `Message::Listening`
+pub const LISTENING: [u8; 1] = [0x04];
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DeviceStatus {
+    pub settings: Settings,
+    // Samples dropped since boot
+    pub dropped: u32,
+}
+
+/// Pipe on which the relay receives drones on the default addresses
+pub const DEFAULT_PIPE: u8 = 1;
+
+/// Pipes on which the relay receives paired drones, their addresses only differ from the one of
+/// `DEFAULT_PIPE` in the first byte
+pub const PAIRED_PIPES: [u8; 4] = [2, 3, 4, 5];
+
+/// A message from the drone on a pipe of the relay
+///
+/// The relay forwards payloads over serial without decoding them, see `Relayed`. The payload is a
+/// fragment of an encoded `Message`, see `fragment`, which is sealed by `secure` if the drone has a
+/// pre-shared key.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Downlink {
+    pub pipe: u8,
+    pub message: Message,
+}
+
+/// A payload the relay forwards to the client over serial, COBS-encoded
+///
+/// The payload is prefixed with the pipe it was received on, the received power detector and its
+/// length. The nRF24L01+ doesn't measure the signal strength, the detector is only set when it was
+/// above -64 dBm.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct Relayed<'a> {
+    pub pipe: u8,
+    pub rpd: bool,
+    pub payload: &'a [u8],
+}
+
+/// Frames the client writes to the relay over serial
+///
+/// The relay matches on the variant's index, so new variants are only added at the end.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum RelayFrame {
+    /// Sent to the drone on `pipe` during its next RX window
+    Uplink { pipe: u8, uplink: Uplink },
+    /// Applied by the relay itself, right away. The channel and data rate are shared by all pipes
+    Configure { pipe: u8, config: RadioConfig },
+    /// An uplink sealed by `secure`, which is sent like `Uplink`. Only the first `len` bytes of
+    /// the payload are used
+    Sealed {
+        pipe: u8,
+        len: u8,
+        payload: [u8; PAYLOAD_SIZE],
+    },
+}
+
+/// Encode a message into a radio payload, which doesn't need a delimiter
+pub fn to_payload<'a, T: Serialize>(message: &T, buf: &'a mut [u8]) -> Result<&'a mut [u8], Error> {
+    to_slice(message, buf).map_err(|_| Error::Encode)
+}
+
+/// COBS-encode a frame for the serial link, including the trailing delimiter
+pub fn encode<'a, T: Serialize>(message: &T, buf: &'a mut [u8]) -> Result<&'a mut [u8], Error> {
+    to_slice_cobs(message, buf).map_err(|_| Error::Encode)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::telemetry;
+    use postcard::{from_bytes, from_bytes_cobs};
+
+    #[test]
+    fn test_message_fits_payload() {
+        let message = Message::Telemetry(Telemetry {
+            timestamp: u32::MAX,
+            mag_x: i16::MIN,
+            mag_y: i16::MIN,
+            mag_z: i16::MIN,
+            accel_x: i16::MIN,
+            accel_y: i16::MIN,
+            accel_z: i16::MIN,
+            gyro_x: -500.0,
+            gyro_y: -500.0,
+            gyro_z: -500.0,
+            temp: i8::MIN,
+        });
+
+        let mut buf = [0u8; PAYLOAD_SIZE];
+        let output = to_payload(&message, &mut buf).unwrap();
+
+        assert_eq!(from_bytes::<Message>(output).unwrap(), message);
+    }
+
+    #[test]
+    fn test_compact_telemetry() {
+        let compact = CompactTelemetry {
+            timestamp: 1,
+            mag_x: 2,
+            mag_y: 3,
+            mag_z: 4,
+            accel_x: 5,
+            accel_y: 6,
+            accel_z: 7,
+            gyro_x: 1000,
+            gyro_y: -1000,
+            gyro_z: 0,
+            gyro_scale: GyroScale::Dps500,
+            temp: 8,
+        };
+
+        let telemetry = compact.decode();
+        assert_eq!(
+            (telemetry.gyro_x, telemetry.gyro_y, telemetry.gyro_z),
+            (17.5, -17.5, 0.0)
+        );
+        assert_eq!((telemetry.timestamp, telemetry.temp), (1, 8));
+
+        let mut buf = [0u8; PAYLOAD_SIZE];
+        let float = to_payload(&Message::Telemetry(telemetry), &mut buf)
+            .unwrap()
+            .len();
+        let mut buf = [0u8; PAYLOAD_SIZE];
+        let compact = to_payload(&Message::CompactTelemetry(compact), &mut buf)
+            .unwrap()
+            .len();
+        assert!(compact < float);
+    }
+
+    #[test]
+    fn test_listening() {
+        let mut buf = [0u8; PAYLOAD_SIZE];
+        assert_eq!(
+            to_payload(&Message::Listening, &mut buf).unwrap(),
+            &LISTENING
+        );
+
+        // The encoding starts with the index of the variant, so none may be added before it
+        let variants = [
+            Message::Telemetry(telemetry(1)),
+            Message::Fault(Error::Radio),
+            Message::Ack {
+                id: 1,
+                result: CommandResult::Ok,
+            },
+            Message::Status(DeviceStatus {
+                settings: Settings::default(),
+                dropped: 0,
+            }),
+            Message::Listening,
+        ];
+
+        for (index, message) in variants.iter().enumerate() {
+            let mut buf = [0u8; PAYLOAD_SIZE];
+            assert_eq!(to_payload(message, &mut buf).unwrap()[0], index as u8);
+        }
+        assert_eq!(usize::from(LISTENING[0]), variants.len() - 1);
+    }
+
+    #[test]
+    fn test_downlink() {
+        let message = Message::Ack {
+            id: 3,
+            result: CommandResult::Ok,
+        };
+
+        // What the relay does with a payload received on pipe 2
+        let mut buf = [0u8; PAYLOAD_SIZE];
+        let payload = to_payload(&message, &mut buf).unwrap();
+        let relayed = Relayed {
+            pipe: 2,
+            rpd: true,
+            payload,
+        };
+
+        let mut frame = [0u8; 2 * PAYLOAD_SIZE];
+        let frame = encode(&relayed, &mut frame).unwrap();
+        assert_eq!(&frame[1..4], &[2, 1, payload.len() as u8]);
+
+        let relayed = from_bytes_cobs::<Relayed>(frame).unwrap();
+        assert_eq!((relayed.pipe, relayed.rpd), (2, true));
+        assert_eq!(from_bytes::<Message>(relayed.payload).unwrap(), message);
+    }
+
+    #[test]
+    fn test_encode_too_large() {
+        let mut buf = [0u8; 4];
+        assert_eq!(encode(&[0u32; 4], &mut buf), Err(Error::Encode));
+    }
+}
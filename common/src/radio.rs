@@ -0,0 +1,172 @@
+//! Configuration of the nRF24L01+ link, shared by the drone, the relay and the client
+use serde::{Deserialize, Serialize};
+
+/// Channels are 1 MHz apart, starting at 2400 MHz
+pub const MAX_CHANNEL: u8 = 125;
+
+/// Channel both ends return to when a new configuration doesn't work out
+pub const DEFAULT_CHANNEL: u8 = 100;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DataRate {
+    Kbps250,
+    Mbps1,
+    Mbps2,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(default)]
+pub struct RadioConfig {
+    pub channel: u8,
+    pub data_rate: DataRate,
+    // Transmit power of the drone, from 0 (-18 dBm) to 3 (0 dBm)
+    pub pa_level: u8,
+    // Address the drone sends to and the relay listens on
+    pub tx_address: [u8; 5],
+    // Address the drone listens on during RX windows
+    pub rx_address: [u8; 5],
+}
+
+impl Default for RadioConfig {
+    fn default() -> RadioConfig {
+        RadioConfig {
+            channel: DEFAULT_CHANNEL,
+            data_rate: DataRate::Kbps250,
+            pa_level: 3,
+            tx_address: [0x11; 5],
+            rx_address: [0x22; 5],
+        }
+    }
+}
+
+impl RadioConfig {
+    pub fn is_valid(&self) -> bool {
+        self.channel <= MAX_CHANNEL && self.pa_level <= 3
+    }
+
+    /// The same configuration on another channel and data rate
+    pub fn with_link(&self, channel: u8, data_rate: DataRate) -> RadioConfig {
+        RadioConfig {
+            channel,
+            data_rate,
+            ..*self
+        }
+    }
+
+    /// The same configuration on the default channel and data rate
+    pub fn fallback(&self) -> RadioConfig {
+        let default = RadioConfig::default();
+        self.with_link(default.channel, default.data_rate)
+    }
+}
+
+/// Keeps track of a new link configuration on the drone, until the client confirms it by sending
+/// a command over it
+///
+/// If no command arrives in time, the drone falls back to the configuration it was given.
+pub struct Negotiation {
+    // Id of the command that proposed the configuration, which the relay may still repeat
+    proposal: Option<u8>,
+    fallback: RadioConfig,
+    ticks_left: u32,
+    timeout_ticks: u32,
+}
+
+impl Negotiation {
+    pub fn new(timeout_ticks: u32) -> Negotiation {
+        Negotiation {
+            proposal: None,
+            fallback: RadioConfig::default(),
+            ticks_left: 0,
+            timeout_ticks,
+        }
+    }
+
+    /// Called once the configuration proposed by command `id` is applied
+    pub fn switched(&mut self, id: u8, fallback: RadioConfig) {
+        self.proposal = Some(id);
+        self.fallback = fallback;
+        self.ticks_left = self.timeout_ticks;
+    }
+
+    /// Called for every command that is received
+    pub fn received(&mut self, id: u8) {
+        if matches!(self.proposal, Some(proposal) if proposal != id) {
+            self.proposal = None;
+        }
+    }
+
+    /// Returns the configuration to fall back to once the timeout expires
+    pub fn tick(&mut self) -> Option<RadioConfig> {
+        self.proposal?;
+
+        self.ticks_left = self.ticks_left.saturating_sub(1);
+
+        if self.ticks_left == 0 {
+            self.proposal = None;
+            return Some(self.fallback);
+        }
+
+        None
+    }
+
+    pub fn is_pending(&self) -> bool {
+        self.proposal.is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_valid() {
+        let config = RadioConfig::default();
+
+        assert!(config.is_valid());
+        assert!(!config.with_link(126, DataRate::Mbps1).is_valid());
+        assert_eq!(config.with_link(2, DataRate::Mbps2).tx_address, [0x11; 5]);
+    }
+
+    #[test]
+    fn test_confirmed() {
+        let config = RadioConfig::default().with_link(2, DataRate::Mbps2);
+        let mut negotiation = Negotiation::new(3);
+        negotiation.switched(4, config.fallback());
+
+        // The relay may repeat the proposal, which doesn't confirm the new link
+        negotiation.received(4);
+        assert_eq!(negotiation.tick(), None);
+
+        negotiation.received(5);
+        assert!(!negotiation.is_pending());
+
+        for _ in 0..5 {
+            assert_eq!(negotiation.tick(), None);
+        }
+    }
+
+    #[test]
+    fn test_fallback() {
+        let config = RadioConfig {
+            tx_address: [0x33; 5],
+            ..RadioConfig::default().with_link(2, DataRate::Mbps2)
+        };
+        let mut negotiation = Negotiation::new(3);
+        assert_eq!(negotiation.tick(), None);
+
+        negotiation.switched(4, config.fallback());
+        assert_eq!(negotiation.tick(), None);
+        assert_eq!(negotiation.tick(), None);
+        assert_eq!(
+            negotiation.tick(),
+            Some(RadioConfig {
+                tx_address: [0x33; 5],
+                ..RadioConfig::default()
+            })
+        );
+
+        // Only falls back once
+        assert_eq!(negotiation.tick(), None);
+    }
+}
@@ -0,0 +1,165 @@
+use crate::Error;
+
+/// Attempts of an operation before the peripheral is re-initialized
+pub const MAX_ATTEMPTS: u8 = 3;
+
+/// Run `op` up to `attempts` times, returns the last error if every attempt failed
+///
+/// Errors that aren't faults of the peripheral are returned right away.
+pub fn retry<T, F>(attempts: u8, mut op: F) -> Result<T, Error>
+where
+    F: FnMut() -> Result<T, Error>,
+{
+    let mut result = op();
+
+    for _ in 1..attempts {
+        match &result {
+            Err(error) if error.is_fault() => result = op(),
+            _ => break,
+        }
+    }
+
+    result
+}
+
+/// Retries failed operations and re-initializes the peripheral if retrying does not help
+///
+/// Faults that were recovered from are kept, so they can be reported to the client.
+#[derive(Default)]
+pub struct Recovery {
+    fault: Option<Error>,
+}
+
+impl Recovery {
+    /// Run `op` on `peripheral` with bounded retries, after which `reset` is called with the last
+    /// error and `op` gets one final attempt
+    ///
+    /// `op` is called again as a whole, so it has to keep whatever a failed attempt took, e.g. the
+    /// `Transmitter` keeps the fragment the radio failed to send.
+    pub fn run<P, T, F, R>(&mut self, peripheral: &mut P, mut op: F, reset: R) -> Result<T, Error>
+    where
+        F: FnMut(&mut P) -> Result<T, Error>,
+        R: FnOnce(&mut P, Error) -> Result<(), Error>,
+    {
+        let error = match retry(MAX_ATTEMPTS, || op(peripheral)) {
+            Ok(value) => return Ok(value),
+            // A reset doesn't help, nor is it a fault to report
+            Err(e) if !e.is_fault() => return Err(e),
+            Err(e) => e,
+        };
+
+        reset(peripheral, error)?;
+
+        let value = op(peripheral)?;
+        self.fault = Some(error);
+
+        Ok(value)
+    }
+
+    /// The last fault that was recovered from, if it has not been taken yet
+    pub fn take_fault(&mut self) -> Option<Error> {
+        self.fault.take()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hal::Radio;
+    use crate::mock::{MockRadio, MockSensors};
+    use crate::sampler::{reset, sample};
+
+    #[test]
+    fn test_retry() {
+        let mut calls = 0;
+        let result = retry(3, || {
+            calls += 1;
+            if calls < 3 {
+                Err(Error::Imu)
+            } else {
+                Ok(calls)
+            }
+        });
+        assert_eq!(result, Ok(3));
+
+        let mut calls = 0;
+        let result: Result<(), Error> = retry(3, || {
+            calls += 1;
+            Err(Error::Radio)
+        });
+        assert_eq!(result, Err(Error::Radio));
+        assert_eq!(calls, 3);
+    }
+
+    #[test]
+    fn test_transient_fault() {
+        let mut recovery = Recovery::default();
+        let mut sensors = MockSensors {
+            mag_failures: 2,
+            ..MockSensors::default()
+        };
+
+        // Retrying is enough, so nothing is re-initialized or reported
+        assert!(recovery.run(&mut sensors, |s| sample(s, 0), reset).is_ok());
+        assert_eq!(sensors.mag_resets, 0);
+        assert_eq!(recovery.take_fault(), None);
+    }
+
+    #[test]
+    fn test_reinitialize() {
+        let mut recovery = Recovery::default();
+        let mut sensors = MockSensors {
+            imu_stuck: true,
+            ..MockSensors::default()
+        };
+
+        assert!(recovery.run(&mut sensors, |s| sample(s, 0), reset).is_ok());
+        assert_eq!((sensors.imu_resets, sensors.mag_resets), (1, 0));
+        assert_eq!(recovery.take_fault(), Some(Error::Imu));
+        assert_eq!(recovery.take_fault(), None);
+
+        let mut radio = MockRadio::new(3);
+        radio.stuck = true;
+
+        assert!(recovery
+            .run(&mut radio, |r| r.send(&[1, 0]), |r, _| r.reset())
+            .is_ok());
+        assert_eq!(radio.resets, 1);
+        assert_eq!(recovery.take_fault(), Some(Error::Radio));
+    }
+
+    #[test]
+    fn test_not_a_fault() {
+        let mut recovery = Recovery::default();
+        let mut radio = MockRadio::new(3);
+        let mut calls = 0;
+
+        let result: Result<(), Error> = recovery.run(
+            &mut radio,
+            |_| {
+                calls += 1;
+                Err(Error::Encode)
+            },
+            |r, _| r.reset(),
+        );
+
+        assert_eq!(result, Err(Error::Encode));
+        assert_eq!((calls, radio.resets), (1, 0));
+        assert_eq!(recovery.take_fault(), None);
+    }
+
+    #[test]
+    fn test_unrecoverable() {
+        let mut recovery = Recovery::default();
+        let mut sensors = MockSensors {
+            mag_stuck: true,
+            ..MockSensors::default()
+        };
+
+        // The reset does not help, e.g. because the sensor is disconnected
+        let result = recovery.run(&mut sensors, |s| sample(s, 0), |_, _| Ok(()));
+
+        assert_eq!(result, Err(Error::Mag));
+        assert_eq!(recovery.take_fault(), None);
+    }
+}
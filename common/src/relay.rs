@@ -0,0 +1,273 @@
+//! Logic of the relay between the client's serial port and the drones' radio link
+//!
+//! The relay listens on `DEFAULT_PIPE` for unpaired drones and on `PAIRED_PIPES` for paired ones,
+//! and forwards every payload to the client as a `Relayed`. The client writes `RelayFrame`s, of
+//! which an uplink is held for its pipe until the drone announces its next RX window.
+use heapless::{consts::U64, Vec};
+use postcard::from_bytes_cobs;
+
+use crate::protocol::{
+    to_payload, RelayFrame, DEFAULT_PIPE, LISTENING, PAIRED_PIPES, PAYLOAD_SIZE,
+};
+use crate::radio::RadioConfig;
+
+/// Pipes of the nRF24L01+, of which the relay uses `DEFAULT_PIPE` and `PAIRED_PIPES`
+pub const PIPES: usize = 6;
+
+/// Times an uplink is offered to the drone before it is dropped, as a window can be missed
+pub const UPLINK_ATTEMPTS: u8 = 10;
+
+/// Whether the relay receives drones on `pipe`
+pub fn is_pipe(pipe: u8) -> bool {
+    pipe == DEFAULT_PIPE || PAIRED_PIPES.contains(&pipe)
+}
+
+/// Whether `payload` is a `Message::Listening`, after which the drone listens for an uplink
+pub fn is_listening(payload: &[u8]) -> bool {
+    payload == LISTENING
+}
+
+/// Collects the bytes read from the serial port into frames
+#[derive(Default)]
+pub struct Frames {
+    buf: Vec<u8, U64>,
+    // The current frame didn't fit the buffer, and is dropped at its delimiter
+    overflow: bool,
+}
+
+impl Frames {
+    pub fn new() -> Frames {
+        Frames::default()
+    }
+
+    /// Returns the frame that `byte` completes, frames that are too long or malformed are dropped
+    pub fn push(&mut self, byte: u8) -> Option<RelayFrame> {
+        if byte != 0 {
+            if self.buf.push(byte).is_err() {
+                self.overflow = true;
+            }
+
+            return None;
+        }
+
+        let frame = if self.overflow {
+            None
+        } else {
+            from_bytes_cobs(&mut self.buf).ok()
+        };
+
+        self.buf = Vec::new();
+        self.overflow = false;
+
+        frame
+    }
+}
+
+/// An uplink that is sent right away
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Outgoing {
+    // Address the drone listens on
+    pub address: [u8; 5],
+    payload: [u8; PAYLOAD_SIZE],
+    len: usize,
+}
+
+impl Outgoing {
+    pub fn payload(&self) -> &[u8] {
+        &self.payload[..self.len]
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Pending {
+    payload: [u8; PAYLOAD_SIZE],
+    len: usize,
+    attempts: u8,
+}
+
+/// The uplinks for every pipe, and the addresses the drones listen on
+pub struct Relay {
+    addresses: [[u8; 5]; PIPES],
+    pending: [Option<Pending>; PIPES],
+}
+
+impl Default for Relay {
+    fn default() -> Self {
+        let mut addresses = [[0; 5]; PIPES];
+        addresses[usize::from(DEFAULT_PIPE)] = RadioConfig::default().rx_address;
+
+        Relay {
+            addresses,
+            pending: [None; PIPES],
+        }
+    }
+}
+
+impl Relay {
+    pub fn new() -> Relay {
+        Relay::default()
+    }
+
+    /// Handles a frame from the client, returns the configuration the radio has to apply to a
+    /// pipe. A new uplink replaces the one that is pending for its pipe
+    pub fn frame(&mut self, frame: &RelayFrame) -> Option<(u8, RadioConfig)> {
+        match frame {
+            RelayFrame::Uplink { pipe, uplink } => {
+                let mut payload = [0; PAYLOAD_SIZE];
+                let len = to_payload(uplink, &mut payload).ok()?.len();
+
+                self.hold(*pipe, payload, len);
+                None
+            }
+            RelayFrame::Sealed { pipe, len, payload } => {
+                let len = usize::from(*len);
+
+                if len <= PAYLOAD_SIZE {
+                    self.hold(*pipe, *payload, len);
+                }
+                None
+            }
+            RelayFrame::Configure { pipe, config } => {
+                if !is_pipe(*pipe) || !config.is_valid() {
+                    return None;
+                }
+
+                // A pending uplink was meant for the previous link
+                let index = usize::from(*pipe);
+                self.addresses[index] = config.rx_address;
+                self.pending[index] = None;
+
+                Some((*pipe, *config))
+            }
+        }
+    }
+
+    fn hold(&mut self, pipe: u8, payload: [u8; PAYLOAD_SIZE], len: usize) {
+        if is_pipe(pipe) {
+            self.pending[usize::from(pipe)] = Some(Pending {
+                payload,
+                len,
+                attempts: UPLINK_ATTEMPTS,
+            });
+        }
+    }
+
+    /// Called once the drone on `pipe` announced an RX window, returns the uplink to send in it.
+    /// The drone ignores repeats of a command it already handled, so it is offered again until
+    /// `UPLINK_ATTEMPTS` are used up
+    pub fn window(&mut self, pipe: u8) -> Option<Outgoing> {
+        if !is_pipe(pipe) {
+            return None;
+        }
+
+        let index = usize::from(pipe);
+        let pending = self.pending[index].as_mut()?;
+
+        pending.attempts -= 1;
+        let outgoing = Outgoing {
+            address: self.addresses[index],
+            payload: pending.payload,
+            len: pending.len,
+        };
+
+        if pending.attempts == 0 {
+            self.pending[index] = None;
+        }
+
+        Some(outgoing)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command::{Command, Uplink};
+    use crate::protocol::encode;
+
+    fn push(frames: &mut Frames, frame: &RelayFrame) -> Option<RelayFrame> {
+        let mut buf = [0u8; 64];
+        let encoded = encode(frame, &mut buf).unwrap();
+        let (last, rest) = encoded.split_last().unwrap();
+
+        for &byte in rest {
+            assert_eq!(frames.push(byte), None);
+        }
+        frames.push(*last)
+    }
+
+    fn uplink(pipe: u8) -> RelayFrame {
+        RelayFrame::Uplink {
+            pipe,
+            uplink: Uplink {
+                id: 1,
+                command: Command::LedTest,
+            },
+        }
+    }
+
+    #[test]
+    fn test_frames() {
+        let mut frames = Frames::new();
+        let frame = uplink(2);
+        assert_eq!(push(&mut frames, &frame), Some(frame));
+
+        // A frame that is too long is dropped, the next one is read again
+        for _ in 0..100 {
+            assert_eq!(frames.push(1), None);
+        }
+        assert_eq!(frames.push(0), None);
+
+        let frame = RelayFrame::Sealed {
+            pipe: 3,
+            len: 20,
+            payload: [7; PAYLOAD_SIZE],
+        };
+        assert_eq!(push(&mut frames, &frame), Some(frame));
+    }
+
+    #[test]
+    fn test_window() {
+        let mut relay = Relay::new();
+        assert_eq!(relay.window(DEFAULT_PIPE), None);
+
+        relay.frame(&uplink(DEFAULT_PIPE));
+
+        for _ in 0..UPLINK_ATTEMPTS {
+            let outgoing = relay.window(DEFAULT_PIPE).unwrap();
+            assert_eq!(outgoing.address, RadioConfig::default().rx_address);
+
+            let Uplink { id, .. } = postcard::from_bytes(outgoing.payload()).unwrap();
+            assert_eq!(id, 1);
+        }
+
+        assert_eq!(relay.window(DEFAULT_PIPE), None);
+    }
+
+    #[test]
+    fn test_configure() {
+        let mut relay = Relay::new();
+        let config = RadioConfig {
+            rx_address: [0x33; 5],
+            ..RadioConfig::default()
+        };
+
+        // Pipe 0 isn't used for drones
+        assert_eq!(
+            relay.frame(&RelayFrame::Configure { pipe: 0, config }),
+            None
+        );
+        relay.frame(&uplink(0));
+        assert_eq!(relay.window(0), None);
+
+        // A pending uplink is dropped, later ones go to the new address
+        relay.frame(&uplink(2));
+        assert_eq!(
+            relay.frame(&RelayFrame::Configure { pipe: 2, config }),
+            Some((2, config))
+        );
+        assert_eq!(relay.window(2), None);
+
+        relay.frame(&uplink(2));
+        assert_eq!(relay.window(2).unwrap().address, [0x33; 5]);
+    }
+}
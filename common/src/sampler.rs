@@ -0,0 +1,133 @@
+use crate::hal::{ImuSource, MagSource};
+use crate::protocol::{CompactTelemetry, Telemetry};
+use crate::Error;
+
+/// Read all sensors into a single sample
+pub fn sample<S: ImuSource + MagSource>(
+    sensors: &mut S,
+    timestamp: u32,
+) -> Result<Telemetry, Error> {
+    let mag = sensors.mag()?;
+    let accel = sensors.accel()?;
+    let gyro = sensors.gyro()?;
+    let temp = sensors.temp()?;
+
+    Ok(Telemetry {
+        timestamp,
+        mag_x: mag.x,
+        mag_y: mag.y,
+        mag_z: mag.z,
+        accel_x: accel.x,
+        accel_y: accel.y,
+        accel_z: accel.z,
+        gyro_x: gyro.x,
+        gyro_y: gyro.y,
+        gyro_z: gyro.z,
+        temp,
+    })
+}
+
+/// Read all sensors into a single sample, with the gyroscope's raw counts
+pub fn sample_compact<S: ImuSource + MagSource>(
+    sensors: &mut S,
+    timestamp: u32,
+) -> Result<CompactTelemetry, Error> {
+    let mag = sensors.mag()?;
+    let accel = sensors.accel()?;
+    let (gyro, gyro_scale) = sensors.gyro_raw()?;
+    let temp = sensors.temp()?;
+
+    Ok(CompactTelemetry {
+        timestamp,
+        mag_x: mag.x,
+        mag_y: mag.y,
+        mag_z: mag.z,
+        accel_x: accel.x,
+        accel_y: accel.y,
+        accel_z: accel.z,
+        gyro_x: gyro.x,
+        gyro_y: gyro.y,
+        gyro_z: gyro.z,
+        gyro_scale,
+        temp,
+    })
+}
+
+/// Re-initialize the sensor that caused `error`
+pub fn reset<S: ImuSource + MagSource>(sensors: &mut S, error: Error) -> Result<(), Error> {
+    match error {
+        Error::Mag => MagSource::reset(sensors),
+        _ => ImuSource::reset(sensors),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hal::{F32x3, I16x3};
+    use crate::mock::MockSensors;
+
+    #[test]
+    fn test_sample() {
+        let mut sensors = MockSensors {
+            mag: I16x3 { x: 1, y: 2, z: 3 },
+            accel: I16x3 { x: 4, y: 5, z: 6 },
+            gyro: F32x3 {
+                x: 7.0,
+                y: 8.0,
+                z: 9.0,
+            },
+            temp: 10,
+            ..MockSensors::default()
+        };
+
+        let telemetry = sample(&mut sensors, 42).unwrap();
+
+        assert_eq!(telemetry.timestamp, 42);
+        assert_eq!(
+            (telemetry.mag_x, telemetry.mag_y, telemetry.mag_z),
+            (1, 2, 3)
+        );
+        assert_eq!(
+            (telemetry.accel_x, telemetry.accel_y, telemetry.accel_z),
+            (4, 5, 6)
+        );
+        assert_eq!(
+            (telemetry.gyro_x, telemetry.gyro_y, telemetry.gyro_z),
+            (7.0, 8.0, 9.0)
+        );
+        assert_eq!(telemetry.temp, 10);
+    }
+
+    #[test]
+    fn test_sample_compact() {
+        let mut sensors = MockSensors {
+            gyro_raw: I16x3 {
+                x: 1000,
+                y: -1000,
+                z: 3,
+            },
+            temp: 10,
+            ..MockSensors::default()
+        };
+
+        let compact = sample_compact(&mut sensors, 42).unwrap();
+
+        assert_eq!(
+            (compact.gyro_x, compact.gyro_y, compact.gyro_z),
+            (1000, -1000, 3)
+        );
+        assert_eq!(compact.gyro_scale, crate::command::GyroScale::Dps500);
+        assert_eq!((compact.timestamp, compact.temp), (42, 10));
+    }
+
+    #[test]
+    fn test_sample_error() {
+        let mut sensors = MockSensors {
+            mag_failures: 1,
+            ..MockSensors::default()
+        };
+
+        assert_eq!(sample(&mut sensors, 0), Err(Error::Mag));
+    }
+}
@@ -0,0 +1,580 @@
+//! Authenticated encryption of radio frames with ChaCha20-Poly1305 and a pre-shared key
+//!
+//! A sealed frame holds a header, the ciphertext and the first `TAG_SIZE` bytes of the tag:
+//!
+//! | header (4) | ciphertext (up to 20) | tag (8) |
+//!
+//! The header is a counter that is never used twice with the same key, and that the receiver
+//! accepts only once. Messages are fragmented by `fragment` first, every fragment is sealed on
+//! its own.
+//!
+//! Each pairing seals with a key of its own, derived from the pre-shared key, the drone's id, a
+//! nonce the drone picks at boot and advertises, and the counter of the sealed `Command::Pair`.
+//! Frames of an earlier boot or pairing therefore don't open. `Command::Pair` itself is sealed
+//! with a key derived from the first three alone, its counter has to increase for every pairing
+//! within a boot of the drone.
+use chacha20poly1305::aead::{AeadInPlace, NewAead};
+use chacha20poly1305::{ChaCha20Poly1305, Nonce};
+
+use crate::pairing::{self, DeviceId};
+use crate::protocol::PAYLOAD_SIZE;
+use crate::Error;
+
+/// A 256-bit key
+pub type Key = [u8; 32];
+
+pub const HEADER_SIZE: usize = 4;
+/// Size of the truncated tag, a forged frame is accepted with a chance of 2^-64
+pub const TAG_SIZE: usize = 8;
+/// Plaintext that fits in a single frame
+pub const CAPACITY: usize = PAYLOAD_SIZE - HEADER_SIZE - TAG_SIZE;
+/// Largest counter, at 100 frames per second it runs out after 497 days
+pub const MAX_COUNTER: u32 = u32::MAX;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Direction {
+    /// From the drone to the client
+    Downlink,
+    /// From the client to the drone, the relay repeats these frames until they are acknowledged
+    Uplink,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OpenError {
+    /// Too short to be a sealed frame
+    Malformed,
+    /// The tag doesn't match, the frame was altered or sealed with another key
+    Forged,
+    /// A frame with the same or a later counter was opened before
+    Replayed,
+}
+
+/// Parses 64 hexadecimal digits
+pub fn parse_key(hex: &str) -> Option<Key> {
+    let mut key = Key::default();
+
+    if hex.len() != 2 * key.len() || !hex.is_ascii() {
+        return None;
+    }
+
+    for (i, byte) in key.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16).ok()?;
+    }
+
+    Some(key)
+}
+
+/// Key with which the client seals `Command::Pair` for the drone with `id`, which advertised
+/// `nonce`
+pub fn pairing_key(psk: &Key, id: &DeviceId, nonce: u32) -> Key {
+    derive(psk, id, nonce, 0)
+}
+
+/// Key of the session that starts with a `Command::Pair` sealed with `counter`, which is never 0
+pub fn session_key(psk: &Key, id: &DeviceId, nonce: u32, counter: u32) -> Key {
+    derive(psk, id, nonce, counter)
+}
+
+fn derive(psk: &Key, id: &DeviceId, nonce: u32, counter: u32) -> Key {
+    let mut derived = [0u8; 12];
+    derived[..4].copy_from_slice(&nonce.to_le_bytes());
+    derived[4..8].copy_from_slice(&counter.to_le_bytes());
+    derived[8..].copy_from_slice(&pairing::hash(id, 2).to_le_bytes()[..4]);
+
+    // Encrypting zeroes leaves the keystream for this nonce, the tag is of no use
+    let mut key = Key::default();
+    let _ = cipher(psk).encrypt_in_place_detached(&Nonce::from(derived), &[], &mut key);
+
+    key
+}
+
+fn cipher(key: &Key) -> ChaCha20Poly1305 {
+    ChaCha20Poly1305::new(&chacha20poly1305::Key::from(*key))
+}
+
+fn nonce(direction: Direction, counter: u32) -> Nonce {
+    let mut nonce = [0u8; 12];
+    nonce[0] = direction as u8;
+    nonce[4..8].copy_from_slice(&counter.to_le_bytes());
+
+    Nonce::from(nonce)
+}
+
+/// Encrypts `buf` in place, returns the truncated tag over the header and ciphertext
+fn seal_in_place(
+    cipher: &ChaCha20Poly1305,
+    direction: Direction,
+    counter: u32,
+    buf: &mut [u8],
+) -> [u8; TAG_SIZE] {
+    let mut tag = [0u8; TAG_SIZE];
+
+    // Only fails for plaintext of more than 256 GiB
+    if let Ok(full) =
+        cipher.encrypt_in_place_detached(&nonce(direction, counter), &counter.to_le_bytes(), buf)
+    {
+        tag.copy_from_slice(&full[..TAG_SIZE]);
+    }
+
+    tag
+}
+
+/// Compares in constant time, so the timing doesn't tell how much of a forged tag was right
+fn tags_match(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+/// Seals fragments into frames
+pub struct Sealer {
+    // Nothing is sealed until a session starts
+    cipher: Option<ChaCha20Poly1305>,
+    direction: Direction,
+    // Counter of the next frame, which isn't reset when the key changes
+    counter: u32,
+}
+
+impl Sealer {
+    pub fn new(direction: Direction) -> Sealer {
+        Sealer {
+            cipher: None,
+            direction,
+            counter: 1,
+        }
+    }
+
+    /// Seals with `key` from the frame with `counter` on, e.g. a random one for `Command::Pair`
+    pub fn with_counter(key: &Key, direction: Direction, counter: u32) -> Sealer {
+        Sealer {
+            cipher: Some(cipher(key)),
+            direction,
+            counter,
+        }
+    }
+
+    /// Seal with `key` from now on, the counter continues so it is never repeated for a key
+    pub fn start_session(&mut self, key: &Key) {
+        self.cipher = Some(cipher(key));
+    }
+
+    pub fn end_session(&mut self) {
+        self.cipher = None;
+    }
+
+    pub fn has_session(&self) -> bool {
+        self.cipher.is_some()
+    }
+
+    /// Seals up to `CAPACITY` bytes of `plaintext` into a frame
+    ///
+    /// Fails without a session, or once the counter ran out.
+    pub fn seal<'a>(
+        &mut self,
+        plaintext: &[u8],
+        buf: &'a mut [u8; PAYLOAD_SIZE],
+    ) -> Result<&'a [u8], Error> {
+        let cipher = match &self.cipher {
+            Some(cipher) if plaintext.len() <= CAPACITY => cipher,
+            _ => return Err(Error::Encode),
+        };
+
+        // The last counter is never used, so it doesn't wrap
+        if self.counter == MAX_COUNTER {
+            return Err(Error::Encode);
+        }
+
+        let tag_start = HEADER_SIZE + plaintext.len();
+        buf[..HEADER_SIZE].copy_from_slice(&self.counter.to_le_bytes());
+        buf[HEADER_SIZE..tag_start].copy_from_slice(plaintext);
+
+        let tag = seal_in_place(
+            cipher,
+            self.direction,
+            self.counter,
+            &mut buf[HEADER_SIZE..tag_start],
+        );
+        buf[tag_start..tag_start + TAG_SIZE].copy_from_slice(&tag);
+
+        self.counter += 1;
+
+        Ok(&buf[..tag_start + TAG_SIZE])
+    }
+}
+
+/// Opens frames, and rejects the ones that were altered or replayed
+pub struct Opener {
+    cipher: ChaCha20Poly1305,
+    direction: Direction,
+    // Counter of the last frame that was opened
+    last: Option<u32>,
+}
+
+impl Opener {
+    pub fn new(key: &Key, direction: Direction) -> Opener {
+        Opener {
+            cipher: cipher(key),
+            direction,
+            last: None,
+        }
+    }
+
+    /// Authenticates and decrypts `frame` in place, returns its plaintext
+    ///
+    /// Counters have to increase, except for uplink frames. The relay repeats those unaltered, so
+    /// the last one is accepted again.
+    pub fn open<'a>(&mut self, frame: &'a mut [u8]) -> Result<&'a [u8], OpenError> {
+        self.open_in_place(frame)?;
+
+        Ok(&frame[HEADER_SIZE..frame.len() - TAG_SIZE])
+    }
+
+    /// Leaves the frame as it was if it doesn't open, returns its counter
+    fn open_in_place(&mut self, frame: &mut [u8]) -> Result<u32, OpenError> {
+        if frame.len() < HEADER_SIZE + TAG_SIZE || frame.len() > PAYLOAD_SIZE {
+            return Err(OpenError::Malformed);
+        }
+
+        let mut counter = [0u8; HEADER_SIZE];
+        counter.copy_from_slice(&frame[..HEADER_SIZE]);
+        let counter = u32::from_le_bytes(counter);
+
+        let replayed = match (self.last, self.direction) {
+            (Some(last), Direction::Uplink) => counter < last,
+            (Some(last), Direction::Downlink) => counter <= last,
+            (None, _) => false,
+        };
+        if replayed {
+            return Err(OpenError::Replayed);
+        }
+
+        let tag_start = frame.len() - TAG_SIZE;
+        let mut text = [0u8; CAPACITY];
+        let text = &mut text[..tag_start - HEADER_SIZE];
+        text.copy_from_slice(&frame[HEADER_SIZE..tag_start]);
+
+        // The keystream is XORed in either direction, so encrypting the ciphertext yields the
+        // plaintext. Sealing that again gives the full tag, of which the frame only carries a part
+        seal_in_place(&self.cipher, self.direction, counter, text);
+        let mut ciphertext = [0u8; CAPACITY];
+        let ciphertext = &mut ciphertext[..text.len()];
+        ciphertext.copy_from_slice(text);
+        let tag = seal_in_place(&self.cipher, self.direction, counter, ciphertext);
+
+        if !tags_match(&tag, &frame[tag_start..]) {
+            return Err(OpenError::Forged);
+        }
+
+        frame[HEADER_SIZE..tag_start].copy_from_slice(text);
+        self.last = Some(counter);
+
+        Ok(counter)
+    }
+}
+
+struct Session {
+    key: Key,
+    opener: Opener,
+}
+
+/// The drone's end of a session, which opens commands and starts a session on `Command::Pair`
+pub struct Responder {
+    psk: Key,
+    id: DeviceId,
+    nonce: u32,
+    // Opens `Command::Pair` for the whole boot, so one that was recorded doesn't open again
+    pairing: Opener,
+    // Whether the last `Command::Pair` is still repeated by the relay, rather than replayed once a
+    // command of its session arrived or the session ended
+    repeating: bool,
+    session: Option<Session>,
+}
+
+impl Responder {
+    /// `nonce` has to differ from the ones of earlier boots
+    pub fn new(psk: &Key, id: &DeviceId, nonce: u32) -> Responder {
+        Responder {
+            psk: *psk,
+            id: *id,
+            nonce,
+            pairing: Opener::new(&pairing_key(psk, id, nonce), Direction::Uplink),
+            repeating: false,
+            session: None,
+        }
+    }
+
+    /// Opens a command in place, returns its plaintext
+    ///
+    /// A command sealed with the pairing key rather than the session's comes with the key of the
+    /// session it starts, which only `Command::Pair` may start. The relay repeats it until it is
+    /// acknowledged, so it opens again until a command of the session arrived.
+    pub fn open<'a>(&mut self, frame: &'a mut [u8]) -> Option<(&'a [u8], Option<Key>)> {
+        let opened = self
+            .session
+            .as_mut()
+            .map(|session| session.opener.open_in_place(frame));
+
+        let session = match opened {
+            Some(Ok(_)) => {
+                self.repeating = false;
+                None
+            }
+            _ => {
+                let counter = frame.get(..HEADER_SIZE)?;
+                let counter = u32::from_le_bytes([counter[0], counter[1], counter[2], counter[3]]);
+
+                if !self.repeating && matches!(self.pairing.last, Some(last) if counter <= last) {
+                    return None;
+                }
+
+                let counter = self.pairing.open_in_place(frame).ok()?;
+                self.repeating = true;
+
+                Some(session_key(&self.psk, &self.id, self.nonce, counter))
+            }
+        };
+
+        Some((&frame[HEADER_SIZE..frame.len() - TAG_SIZE], session))
+    }
+
+    /// Open commands with `key` from now on, the session goes on if it already has that key
+    pub fn start_session(&mut self, key: &Key) {
+        if matches!(&self.session, Some(session) if session.key == *key) {
+            return;
+        }
+
+        self.session = Some(Session {
+            key: *key,
+            opener: Opener::new(key, Direction::Uplink),
+        });
+    }
+
+    pub fn end_session(&mut self) {
+        self.session = None;
+        self.repeating = false;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command::{Command, Uplink};
+    use crate::protocol::{to_payload, Message};
+    use postcard::from_bytes;
+    use serde::Serialize;
+    use std::vec::Vec;
+
+    const PSK: Key = [0x5a; 32];
+    const ID: DeviceId = [7; 12];
+
+    fn seal<T: Serialize>(sealer: &mut Sealer, message: &T) -> Vec<u8> {
+        let mut plaintext = [0u8; CAPACITY];
+        let plaintext = to_payload(message, &mut plaintext).unwrap();
+
+        sealer
+            .seal(plaintext, &mut [0u8; PAYLOAD_SIZE])
+            .unwrap()
+            .to_vec()
+    }
+
+    fn open(opener: &mut Opener, frame: &[u8]) -> Option<Message> {
+        let mut frame = frame.to_vec();
+
+        from_bytes(opener.open(&mut frame).ok()?).ok()
+    }
+
+    fn session() -> (Sealer, Opener) {
+        let key = session_key(&PSK, &ID, 1, 2);
+        let mut sealer = Sealer::new(Direction::Downlink);
+        sealer.start_session(&key);
+
+        (sealer, Opener::new(&key, Direction::Downlink))
+    }
+
+    #[test]
+    fn test_parse_key() {
+        let hex = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+        let key = parse_key(hex).unwrap();
+        assert_eq!(key[0x1f], 0x1f);
+
+        assert_eq!(parse_key(&hex[1..]), None);
+        assert_eq!(parse_key(&hex.replace('0', "g")), None);
+    }
+
+    #[test]
+    fn test_seal_open() {
+        let (mut sealer, mut opener) = session();
+        let ack = Message::Ack {
+            id: 3,
+            result: crate::command::CommandResult::Ok,
+        };
+
+        let frame = seal(&mut sealer, &ack);
+        assert!(frame.len() <= PAYLOAD_SIZE);
+
+        // The plaintext isn't readable
+        let plain = to_payload(&ack, &mut [0u8; PAYLOAD_SIZE]).unwrap().to_vec();
+        assert_ne!(frame[HEADER_SIZE..HEADER_SIZE + plain.len()], plain[..]);
+
+        assert_eq!(open(&mut opener, &frame), Some(ack));
+    }
+
+    #[test]
+    fn test_seal_error() {
+        let mut buf = [0u8; PAYLOAD_SIZE];
+
+        // Nothing is sealed without a session, nor more than fits in a frame
+        let mut sealer = Sealer::new(Direction::Downlink);
+        assert_eq!(sealer.seal(&[0], &mut buf), Err(Error::Encode));
+
+        sealer.start_session(&[1; 32]);
+        assert_eq!(
+            sealer.seal(&[0; CAPACITY + 1], &mut buf),
+            Err(Error::Encode)
+        );
+        assert!(sealer.seal(&[0; CAPACITY], &mut buf).is_ok());
+
+        // The counter doesn't wrap
+        let mut sealer = Sealer::with_counter(&[1; 32], Direction::Uplink, MAX_COUNTER);
+        assert_eq!(sealer.seal(&[0], &mut buf), Err(Error::Encode));
+    }
+
+    #[test]
+    fn test_tampered() {
+        let (mut sealer, mut opener) = session();
+        let frame = seal(&mut sealer, &Message::Listening);
+
+        for i in 0..frame.len() {
+            let mut tampered = frame.clone();
+            tampered[i] ^= 0x01;
+            assert_eq!(opener.open(&mut tampered).err(), Some(OpenError::Forged));
+        }
+
+        // Truncated, or sealed with another key
+        assert_eq!(
+            opener.open(&mut frame[..HEADER_SIZE + 1].to_vec()).err(),
+            Some(OpenError::Malformed)
+        );
+        let mut other = Sealer::new(Direction::Downlink);
+        other.start_session(&session_key(&PSK, &ID, 1, 3));
+        let mut forged = seal(&mut other, &Message::Listening);
+        assert_eq!(opener.open(&mut forged).err(), Some(OpenError::Forged));
+
+        // The frame is left as it was
+        assert!(opener.open(&mut frame.clone()).is_ok());
+    }
+
+    #[test]
+    fn test_replayed() {
+        let (mut sealer, mut opener) = session();
+        let first = seal(&mut sealer, &Message::Listening);
+        let second = seal(&mut sealer, &Message::Listening);
+
+        assert!(opener.open(&mut second.clone()).is_ok());
+        assert_eq!(
+            opener.open(&mut second.clone()).err(),
+            Some(OpenError::Replayed)
+        );
+        assert_eq!(
+            opener.open(&mut first.clone()).err(),
+            Some(OpenError::Replayed)
+        );
+
+        // A frame sealed for the drone doesn't open as one of the drone
+        let key = session_key(&PSK, &ID, 1, 2);
+        let mut uplink = Sealer::with_counter(&key, Direction::Uplink, 10);
+        let mut frame = seal(&mut uplink, &Message::Listening);
+        assert_eq!(opener.open(&mut frame).err(), Some(OpenError::Forged));
+    }
+
+    #[test]
+    fn test_pairing() {
+        let nonce = 0x1234_5678;
+        let uplink = Uplink {
+            id: 0,
+            command: Command::Pair(ID),
+        };
+
+        let mut client =
+            Sealer::with_counter(&pairing_key(&PSK, &ID, nonce), Direction::Uplink, 99);
+        let pair = seal(&mut client, &uplink);
+
+        let mut drone = Responder::new(&PSK, &ID, nonce);
+        let session = session_key(&PSK, &ID, nonce, 99);
+        {
+            let mut frame = pair.clone();
+            let (plaintext, key) = drone.open(&mut frame).unwrap();
+            assert_eq!(from_bytes::<Uplink>(plaintext).unwrap(), uplink);
+            assert_eq!(key, Some(session));
+        }
+        drone.start_session(&session);
+
+        // Commands of the session, which the relay repeats
+        let mut client = Sealer::new(Direction::Uplink);
+        client.start_session(&session);
+        let command = seal(&mut client, &uplink);
+
+        assert_eq!(
+            drone.open(&mut command.clone()).map(|(_, key)| key),
+            Some(None)
+        );
+        assert_eq!(
+            drone.open(&mut command.clone()).map(|(_, key)| key),
+            Some(None)
+        );
+
+        // A `Command::Pair` with an earlier counter doesn't open
+        let mut client =
+            Sealer::with_counter(&pairing_key(&PSK, &ID, nonce), Direction::Uplink, 98);
+        assert!(drone.open(&mut seal(&mut client, &uplink)).is_none());
+
+        // A drone that booted again, or another drone, doesn't take the pairing
+        assert!(Responder::new(&PSK, &ID, nonce + 1)
+            .open(&mut pair.clone())
+            .is_none());
+        assert!(Responder::new(&PSK, &[8; 12], nonce)
+            .open(&mut pair.clone())
+            .is_none());
+        assert!(Responder::new(&[0; 32], &ID, nonce)
+            .open(&mut pair.clone())
+            .is_none());
+    }
+
+    #[test]
+    fn test_pairing_replayed() {
+        let nonce = 7;
+        let pair = Uplink {
+            id: 0,
+            command: Command::Pair(ID),
+        };
+        let reboot = Uplink {
+            id: 1,
+            command: Command::Reboot,
+        };
+
+        let mut client = Sealer::with_counter(&pairing_key(&PSK, &ID, nonce), Direction::Uplink, 5);
+        let pair_frame = seal(&mut client, &pair);
+        let session = session_key(&PSK, &ID, nonce, 5);
+
+        let mut drone = Responder::new(&PSK, &ID, nonce);
+        let key = drone.open(&mut pair_frame.clone()).unwrap().1.unwrap();
+        drone.start_session(&key);
+
+        // Repeated by the relay before the acknowledgement arrived, which keeps the session
+        let key = drone.open(&mut pair_frame.clone()).unwrap().1.unwrap();
+        drone.start_session(&key);
+
+        let mut client = Sealer::new(Direction::Uplink);
+        client.start_session(&session);
+        let first = seal(&mut client, &reboot);
+        let second = seal(&mut client, &reboot);
+        assert!(drone.open(&mut first.clone()).is_some());
+        assert!(drone.open(&mut second.clone()).is_some());
+
+        // Recorded and replayed, neither starts the session over
+        assert!(drone.open(&mut pair_frame.clone()).is_none());
+        assert!(drone.open(&mut first.clone()).is_none());
+
+        // Nor once the session ended
+        drone.end_session();
+        assert!(drone.open(&mut pair_frame.clone()).is_none());
+    }
+}
@@ -0,0 +1,138 @@
+//! Sweeping the channels with the nRF24L01+ received power detector
+use serde::{Deserialize, Serialize};
+
+use crate::radio::MAX_CHANNEL;
+
+pub const CHANNELS: usize = MAX_CHANNEL as usize + 1;
+
+/// Number of channels per `Message::Spectrum`, a full report doesn't fit in a single payload
+pub const CHUNK_SIZE: usize = 16;
+
+/// Occupancy of `CHUNK_SIZE` channels, starting at `first_channel`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SpectrumChunk {
+    pub first_channel: u8,
+    pub sweeps: u8,
+    // Number of sweeps in which a carrier was detected, channels past the last one are zero
+    pub hits: [u8; CHUNK_SIZE],
+}
+
+/// Visits every channel `sweeps` times and counts how often a carrier was detected
+pub struct Scan {
+    sweeps: u8,
+    sweep: u8,
+    channel: u8,
+    hits: [u8; CHANNELS],
+}
+
+impl Scan {
+    pub fn new(sweeps: u8) -> Scan {
+        Scan {
+            sweeps,
+            sweep: 0,
+            channel: 0,
+            hits: [0; CHANNELS],
+        }
+    }
+
+    /// Channel the radio should be listening on
+    pub fn channel(&self) -> u8 {
+        self.channel
+    }
+
+    /// Record the received power detector of the current channel, returns false once done
+    pub fn record(&mut self, carrier: bool) -> bool {
+        if carrier {
+            self.hits[self.channel as usize] += 1;
+        }
+
+        if self.channel < MAX_CHANNEL {
+            self.channel += 1;
+            return true;
+        }
+
+        self.channel = 0;
+        self.sweep += 1;
+
+        self.sweep < self.sweeps
+    }
+
+    pub fn report(&self) -> Report {
+        Report {
+            sweeps: self.sweeps,
+            hits: self.hits,
+            next: 0,
+        }
+    }
+}
+
+/// The result of a scan, split into chunks that each fit in a payload
+pub struct Report {
+    sweeps: u8,
+    hits: [u8; CHANNELS],
+    next: usize,
+}
+
+impl Iterator for Report {
+    type Item = SpectrumChunk;
+
+    fn next(&mut self) -> Option<SpectrumChunk> {
+        if self.next >= CHANNELS {
+            return None;
+        }
+
+        let end = (self.next + CHUNK_SIZE).min(CHANNELS);
+        let mut hits = [0; CHUNK_SIZE];
+        hits[..end - self.next].copy_from_slice(&self.hits[self.next..end]);
+
+        let chunk = SpectrumChunk {
+            first_channel: self.next as u8,
+            sweeps: self.sweeps,
+            hits,
+        };
+
+        self.next = end;
+
+        Some(chunk)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{encode, Message, PAYLOAD_SIZE};
+
+    #[test]
+    fn test_scan() {
+        let mut scan = Scan::new(2);
+        let mut steps = 0;
+
+        // Busy on channel 3 in both sweeps
+        loop {
+            steps += 1;
+            if !scan.record(scan.channel() == 3) {
+                break;
+            }
+        }
+
+        assert_eq!(steps, 2 * CHANNELS);
+
+        let chunks: std::vec::Vec<SpectrumChunk> = scan.report().collect();
+        assert_eq!(chunks.len(), 8);
+        assert_eq!(chunks[0].hits[3], 2);
+        assert_eq!(chunks[0].sweeps, 2);
+        assert_eq!(chunks[7].first_channel, 112);
+    }
+
+    #[test]
+    fn test_chunk_fits_payload() {
+        let chunk = SpectrumChunk {
+            first_channel: 112,
+            sweeps: u8::MAX,
+            hits: [u8::MAX; CHUNK_SIZE],
+        };
+
+        let mut buf = [0u8; PAYLOAD_SIZE];
+        assert!(encode(&Message::Spectrum(chunk), &mut buf).is_ok());
+    }
+}
@@ -0,0 +1,110 @@
+use crate::hal::StatusLed;
+
+/// Blinks one LED while running and lights another while samples are being dropped
+///
+/// While the link is lost both LEDs blink in turns instead.
+#[derive(Default)]
+pub struct Status {
+    is_on: bool,
+    last_dropped: u32,
+    test_ticks: u32,
+    link_lost: bool,
+}
+
+impl Status {
+    /// Called at the blink rate with the number of samples dropped since boot
+    pub fn tick<R: StatusLed, F: StatusLed>(
+        &mut self,
+        running: &mut R,
+        fault: &mut F,
+        dropped: u32,
+    ) {
+        if self.test_ticks > 0 {
+            self.test_ticks -= 1;
+            running.set(true);
+            fault.set(true);
+        } else if self.link_lost {
+            running.set(self.is_on);
+            fault.set(!self.is_on);
+        } else {
+            running.set(self.is_on);
+            fault.set(dropped != self.last_dropped);
+        }
+
+        self.is_on = !self.is_on;
+        self.last_dropped = dropped;
+    }
+
+    /// Light both LEDs for the next `ticks` ticks
+    pub fn led_test(&mut self, ticks: u32) {
+        self.test_ticks = ticks;
+    }
+
+    /// Called when the failsafe engages or is released
+    pub fn set_link_lost(&mut self, link_lost: bool) {
+        self.link_lost = link_lost;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockLed;
+
+    #[test]
+    fn test_tick() {
+        let mut status = Status::default();
+        let mut running = MockLed::default();
+        let mut fault = MockLed::default();
+
+        status.tick(&mut running, &mut fault, 0);
+        assert_eq!((running.on, fault.on), (false, false));
+
+        status.tick(&mut running, &mut fault, 0);
+        assert_eq!((running.on, fault.on), (true, false));
+
+        // Only lit for the tick in which samples were dropped
+        status.tick(&mut running, &mut fault, 3);
+        assert_eq!((running.on, fault.on), (false, true));
+
+        status.tick(&mut running, &mut fault, 3);
+        assert_eq!((running.on, fault.on), (true, false));
+    }
+
+    #[test]
+    fn test_led_test() {
+        let mut status = Status::default();
+        let mut running = MockLed::default();
+        let mut fault = MockLed::default();
+
+        status.led_test(2);
+
+        for _ in 0..2 {
+            status.tick(&mut running, &mut fault, 1);
+            assert_eq!((running.on, fault.on), (true, true));
+        }
+
+        // Drops during the test don't light the fault LED afterwards
+        status.tick(&mut running, &mut fault, 1);
+        assert!(!fault.on);
+    }
+
+    #[test]
+    fn test_link_lost() {
+        let mut status = Status::default();
+        let mut running = MockLed::default();
+        let mut fault = MockLed::default();
+
+        status.set_link_lost(true);
+
+        status.tick(&mut running, &mut fault, 0);
+        assert_eq!((running.on, fault.on), (false, true));
+
+        status.tick(&mut running, &mut fault, 0);
+        assert_eq!((running.on, fault.on), (true, false));
+
+        status.set_link_lost(false);
+        status.tick(&mut running, &mut fault, 0);
+        assert_eq!((running.on, fault.on), (false, false));
+    }
+}
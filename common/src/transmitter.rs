@@ -0,0 +1,311 @@
+use crate::fragment::Fragmenter;
+use crate::hal::Radio;
+use crate::protocol::{Message, LISTENING, PAYLOAD_SIZE};
+use crate::secure::{self, Direction, Key, Sealer};
+use crate::Error;
+
+/// Moves queued messages into the radio's TX FIFO, a fragment at a time
+pub struct Transmitter {
+    buf: [u8; PAYLOAD_SIZE],
+    fragmenter: Fragmenter,
+    // Seals the fragments if the drone has a pre-shared key
+    sealer: Option<Sealer>,
+    // Whether the fragments of the queued message are sealed
+    sealed: bool,
+}
+
+impl Default for Transmitter {
+    fn default() -> Self {
+        Transmitter {
+            buf: [0; PAYLOAD_SIZE],
+            fragmenter: Fragmenter::new(PAYLOAD_SIZE),
+            sealer: None,
+            sealed: false,
+        }
+    }
+}
+
+impl Transmitter {
+    pub fn new() -> Transmitter {
+        Transmitter::default()
+    }
+
+    /// Seals every message but `Message::Advertise` and `Message::Listening`, which are the only
+    /// ones sent until a session starts
+    pub fn secure() -> Transmitter {
+        Transmitter {
+            fragmenter: Fragmenter::new(secure::CAPACITY),
+            sealer: Some(Sealer::new(Direction::Downlink)),
+            ..Transmitter::default()
+        }
+    }
+
+    pub fn start_session(&mut self, key: &Key) {
+        if let Some(sealer) = &mut self.sealer {
+            sealer.start_session(key);
+        }
+    }
+
+    /// Drops the key along with the fragments that weren't sealed yet
+    pub fn end_session(&mut self) {
+        if let Some(sealer) = &mut self.sealer {
+            sealer.end_session();
+
+            if self.sealed {
+                self.fragmenter.clear();
+            }
+        }
+    }
+
+    /// Called on the radio's IRQ, sends messages from `next` until the TX FIFO is full
+    ///
+    /// Returns the number of messages of which the first fragment was sent.
+    pub fn service<R, F>(&mut self, radio: &mut R, mut next: F) -> Result<usize, Error>
+    where
+        R: Radio,
+        F: FnMut() -> Option<Message>,
+    {
+        radio.clear_interrupts()?;
+
+        let mut sent = 0;
+
+        while radio.can_send()? {
+            // The rest of a message that didn't fit in a single fragment, or the fragment the radio
+            // failed to take
+            if self.fragmenter.is_pending() {
+                match self.send_fragment(radio) {
+                    Err(Error::Encode) => {}
+                    result => result?,
+                }
+                continue;
+            }
+
+            let message = match next() {
+                Some(m) => m,
+                None => break,
+            };
+
+            // A message that doesn't fit or can't be sealed is dropped, it never would be sent
+            match self.send(radio, &message) {
+                Err(Error::Encode) => continue,
+                result => result?,
+            }
+
+            sent += 1;
+        }
+
+        Ok(sent)
+    }
+
+    /// Send a single message, the TX FIFO is expected to have room for it
+    ///
+    /// Only the first fragment is sent, the next calls to `service` send the rest, or send it
+    /// again if the radio failed to take it. The fragments of a message that were not sent yet are
+    /// dropped.
+    pub fn send<R: Radio>(&mut self, radio: &mut R, message: &Message) -> Result<(), Error> {
+        self.sealed = match &self.sealer {
+            Some(_) if matches!(message, Message::Advertise { .. }) => false,
+            // Without a session the message is dropped, rather than sent in the clear
+            Some(sealer) if !sealer.has_session() => {
+                self.fragmenter.clear();
+                return Ok(());
+            }
+            Some(_) => true,
+            None => false,
+        };
+
+        self.fragmenter.push(message)?;
+        self.send_fragment(radio)
+    }
+
+    fn send_fragment<R: Radio>(&mut self, radio: &mut R) -> Result<(), Error> {
+        let mut fragment = [0u8; PAYLOAD_SIZE];
+        let fragment = match self.fragmenter.next(&mut fragment) {
+            Some(fragment) => fragment,
+            None => return Ok(()),
+        };
+
+        let result = match &mut self.sealer {
+            Some(sealer) if self.sealed => sealer
+                .seal(fragment, &mut self.buf)
+                .and_then(|frame| radio.send(frame)),
+            _ => radio.send(fragment),
+        };
+
+        match result {
+            Err(Error::Encode) => self.fragmenter.clear(),
+            Err(_) => self.fragmenter.rewind(),
+            Ok(()) => {}
+        }
+
+        result
+    }
+
+    /// Announce an RX window to the relay and start listening for a command
+    ///
+    /// `LISTENING` is sent as is, so the relay can match on it.
+    pub fn open_window<R: Radio>(&mut self, radio: &mut R) -> Result<(), Error> {
+        radio.send(&LISTENING)?;
+
+        radio.listen()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fragment::Fragment;
+    use crate::mock::{telemetry, MockRadio};
+    use crate::recovery::Recovery;
+    use crate::secure::Opener;
+    use postcard::from_bytes;
+    use std::vec::Vec;
+
+    /// Joins the fragments of consecutive messages
+    fn join(fragments: &[&[u8]]) -> Vec<Message> {
+        let mut messages = Vec::new();
+        let mut message = Vec::new();
+
+        for fragment in fragments {
+            let fragment = Fragment::parse(fragment).unwrap();
+            message.extend_from_slice(fragment.data);
+
+            if fragment.index + 1 == fragment.count {
+                messages.push(from_bytes(&message).unwrap());
+                message.clear();
+            }
+        }
+
+        messages
+    }
+
+    #[test]
+    fn test_service() {
+        let mut radio = MockRadio::new(3);
+        let mut transmitter = Transmitter::new();
+        let mut queue: Vec<Message> = (0..5).map(|n| Message::Telemetry(telemetry(n))).collect();
+        queue.reverse();
+
+        // Only three fit in the TX FIFO
+        assert_eq!(transmitter.service(&mut radio, || queue.pop()), Ok(3));
+        assert_eq!(radio.interrupts_cleared, 1);
+        assert_eq!(queue.len(), 2);
+
+        assert_eq!(join(&[&radio.sent[1]]), [Message::Telemetry(telemetry(1))]);
+
+        // After the FIFO is emptied the remaining messages are sent
+        radio.sent.clear();
+        assert_eq!(transmitter.service(&mut radio, || queue.pop()), Ok(2));
+        assert_eq!(transmitter.service(&mut radio, || queue.pop()), Ok(0));
+    }
+
+    #[test]
+    fn test_service_error() {
+        let mut radio = MockRadio::new(3);
+        radio.failures = 1;
+
+        let mut transmitter = Transmitter::new();
+        let result = transmitter.service(&mut radio, || Some(Message::Telemetry(telemetry(0))));
+
+        assert_eq!(result, Err(Error::Radio));
+    }
+
+    #[test]
+    fn test_recovered_fault() {
+        let mut radio = MockRadio::new(3);
+        radio.stuck = true;
+
+        let mut transmitter = Transmitter::new();
+        let mut recovery = Recovery::default();
+        let mut queue: Vec<Message> = (0..3).map(|n| Message::Telemetry(telemetry(n))).collect();
+        queue.reverse();
+
+        // Every attempt sends the message the first one took from the queue
+        let result = recovery.run(
+            &mut radio,
+            |radio| transmitter.service(radio, || queue.pop()),
+            |radio, _| radio.reset(),
+        );
+
+        assert!(result.is_ok());
+        assert_eq!(recovery.take_fault(), Some(Error::Radio));
+        let fragments: Vec<&[u8]> = radio.sent.iter().map(|f| f.as_slice()).collect();
+        assert_eq!(
+            join(&fragments),
+            (0..3)
+                .map(|n| Message::Telemetry(telemetry(n)))
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_open_window() {
+        let mut radio = MockRadio::new(3);
+        let mut transmitter = Transmitter::new();
+
+        transmitter.open_window(&mut radio).unwrap();
+        assert_eq!(radio.sent, [LISTENING.to_vec()]);
+
+        // Nothing is sent while listening
+        radio.sent.clear();
+        let result = transmitter.service(&mut radio, || Some(Message::Telemetry(telemetry(0))));
+        assert_eq!(result, Ok(0));
+
+        radio.stop_listening().unwrap();
+        assert_eq!(transmitter.service(&mut radio, || None), Ok(0));
+        assert!(radio.can_send().unwrap());
+    }
+
+    #[test]
+    fn test_secure() {
+        let mut radio = MockRadio::new(3);
+        let mut transmitter = Transmitter::secure();
+        let advert = Message::Advertise {
+            id: [1; 12],
+            nonce: u32::MAX,
+        };
+        let mut queue = std::vec![Message::Telemetry(telemetry(0)), advert.clone()];
+
+        // Only the advertisement goes out before the session starts, in the clear and whole
+        assert_eq!(transmitter.service(&mut radio, || queue.pop()), Ok(2));
+        assert_eq!(radio.sent.len(), 1);
+        assert_eq!(Fragment::parse(&radio.sent[0]).unwrap().count, 1);
+        assert_eq!(join(&[&radio.sent[0]]), [advert]);
+
+        let key = [3; 32];
+        transmitter.start_session(&key);
+        radio.sent.clear();
+
+        // Telemetry takes two sealed fragments, the second one is sent once the FIFO has room
+        let mut queue: Vec<Message> = (0..2).map(|n| Message::Telemetry(telemetry(n))).collect();
+        queue.reverse();
+        assert_eq!(transmitter.service(&mut radio, || queue.pop()), Ok(2));
+        assert_eq!(radio.sent.len(), 3);
+        let mut frames: Vec<Vec<u8>> = radio.sent.drain(..).collect();
+        assert_eq!(transmitter.service(&mut radio, || queue.pop()), Ok(0));
+        frames.append(&mut radio.sent);
+        assert_eq!(frames.len(), 4);
+
+        let mut opener = Opener::new(&key, Direction::Downlink);
+        let fragments: Vec<&[u8]> = frames
+            .iter_mut()
+            .map(|frame| opener.open(frame).unwrap())
+            .collect();
+        assert_eq!(
+            join(&fragments),
+            [
+                Message::Telemetry(telemetry(0)),
+                Message::Telemetry(telemetry(1))
+            ]
+        );
+
+        // The rest of a message is dropped along with the session
+        transmitter
+            .send(&mut radio, &Message::Telemetry(telemetry(2)))
+            .unwrap();
+        transmitter.end_session();
+        radio.sent.clear();
+        assert_eq!(transmitter.service(&mut radio, || None), Ok(0));
+        assert!(radio.sent.is_empty());
+    }
+}
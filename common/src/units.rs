@@ -0,0 +1,98 @@
+//! Conversions from raw LSM303DLHC readings into physical units
+use crate::command::GyroScale;
+use crate::hal::I16x3;
+
+/// Convert a raw accelerometer reading into milli-g
+///
+/// The output of the accelerometer is 12-bit and left-justified, `mg_per_lsb` depends on the
+/// full-scale, e.g. 1 for ±2 g and 12 for ±16 g.
+pub fn accel_mg(raw: i16, mg_per_lsb: i16) -> i16 {
+    (raw >> 4) * mg_per_lsb
+}
+
+/// Resolution of the L3GD20 in millidegrees per second per LSB
+fn gyro_mdps_per_lsb(scale: GyroScale) -> f32 {
+    match scale {
+        GyroScale::Dps250 => 8.75,
+        GyroScale::Dps500 => 17.5,
+        GyroScale::Dps2000 => 70.0,
+    }
+}
+
+/// Convert a raw L3GD20 reading into degrees per second, at the full-scale it was read at
+pub fn gyro_dps(raw: i16, scale: GyroScale) -> f32 {
+    f32::from(raw) * gyro_mdps_per_lsb(scale) / 1000.0
+}
+
+/// Convert hundredths of a degree per second into the nearest raw L3GD20 reading at `scale`
+pub fn gyro_counts(cdps: i16, scale: GyroScale) -> i16 {
+    let counts = f32::from(cdps) * 10.0 / gyro_mdps_per_lsb(scale);
+
+    // Rounded half away from zero, `f32::round` isn't available without std
+    if counts < 0.0 {
+        (counts - 0.5) as i16
+    } else {
+        (counts + 0.5) as i16
+    }
+}
+
+/// Convert a raw magnetometer reading into milligauss
+///
+/// This assumes the default gain of ±1.3 gauss, for which the z axis has a lower resolution than
+/// the x and y axis.
+pub fn mag_mgauss(raw: I16x3) -> I16x3 {
+    let scale = |v: i16, lsb_per_gauss: i32| (i32::from(v) * 1000 / lsb_per_gauss) as i16;
+
+    I16x3 {
+        x: scale(raw.x, 1100),
+        y: scale(raw.y, 1100),
+        z: scale(raw.z, 980),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_accel_mg() {
+        // 1 g at ±2 g is 1000 LSB, shifted left by 4 bits
+        assert_eq!(accel_mg(1000 << 4, 1), 1000);
+        assert_eq!(accel_mg(-1000 << 4, 1), -1000);
+        assert_eq!(accel_mg(1000 << 4, 12), 12000);
+    }
+
+    #[test]
+    fn test_gyro_dps() {
+        assert_eq!(gyro_dps(1000, GyroScale::Dps250), 8.75);
+        assert_eq!(gyro_dps(-1000, GyroScale::Dps500), -17.5);
+        assert_eq!(gyro_dps(2000, GyroScale::Dps2000), 140.0);
+    }
+
+    #[test]
+    fn test_gyro_counts() {
+        assert_eq!(gyro_counts(875, GyroScale::Dps250), 1000);
+        assert_eq!(gyro_counts(-175, GyroScale::Dps500), -100);
+        // 1 dps is 14.3 counts at ±2000 dps
+        assert_eq!(gyro_counts(100, GyroScale::Dps2000), 14);
+        assert_eq!(gyro_counts(i16::MIN, GyroScale::Dps250), i16::MIN);
+    }
+
+    #[test]
+    fn test_mag_mgauss() {
+        let raw = I16x3 {
+            x: 1100,
+            y: -550,
+            z: 980,
+        };
+
+        assert_eq!(
+            mag_mgauss(raw),
+            I16x3 {
+                x: 1000,
+                y: -500,
+                z: 1000
+            }
+        );
+    }
+}
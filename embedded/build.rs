@@ -0,0 +1,8 @@
+use std::env;
+
+fn main() {
+    // defmt places the log strings in a section of its own linker script
+    if env::var_os("CARGO_FEATURE_RTT").is_some() {
+        println!("cargo:rustc-link-arg=-Tdefmt.x");
+    }
+}
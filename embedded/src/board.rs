@@ -0,0 +1,95 @@
+//! Setup shared by the drone and the relay, of which the nRF24L01+ is wired alike
+use cortex_m::peripheral::{DCB, DWT};
+use embedded_nrf24l01::{RxMode, TxMode, NRF24L01};
+
+use f3::hal::{
+    gpio::gpiob::{AFRH, MODER, OTYPER, PB0, PB1, PB13, PB14, PB15, PB2, PUPDR},
+    gpio::{Floating, Input, Output, PushPull, AF5},
+    prelude::*,
+    rcc::{Clocks, APB1},
+    spi::Spi,
+    stm32f30x::{EXTI, SPI2, SYSCFG},
+};
+
+use portuni_common::{radio::DataRate, Error};
+
+pub type Nrf24Device = NRF24L01<
+    PB2<Output<PushPull>>,
+    PB0<Output<PushPull>>,
+    Spi<SPI2, (PB13<AF5>, PB14<AF5>, PB15<AF5>)>,
+>;
+
+pub type Nrf24Tx = TxMode<Nrf24Device>;
+pub type Nrf24Rx = RxMode<Nrf24Device>;
+
+pub fn data_rate(data_rate: DataRate) -> embedded_nrf24l01::DataRate {
+    match data_rate {
+        DataRate::Kbps250 => embedded_nrf24l01::DataRate::R250Kbps,
+        DataRate::Mbps1 => embedded_nrf24l01::DataRate::R1Mbps,
+        DataRate::Mbps2 => embedded_nrf24l01::DataRate::R2Mbps,
+    }
+}
+
+/// The pins of GPIOB the nRF24L01+ is connected to, IRQ has to be on `PB1`
+pub struct Nrf24Pins<'a> {
+    pub ce: PB2<Input<Floating>>,
+    pub csn: PB0<Input<Floating>>,
+    pub irq: PB1<Input<Floating>>,
+    pub sck: PB13<Input<Floating>>,
+    pub miso: PB14<Input<Floating>>,
+    pub mosi: PB15<Input<Floating>>,
+    pub moder: &'a mut MODER,
+    pub otyper: &'a mut OTYPER,
+    pub pupdr: &'a mut PUPDR,
+    pub afrh: &'a mut AFRH,
+}
+
+/// Set up the nRF24L01+ on SPI2, which is left powered down
+pub fn nrf24(
+    pins: Nrf24Pins,
+    spi2: SPI2,
+    clocks: Clocks,
+    apb1: &mut APB1,
+) -> Result<Nrf24Device, Error> {
+    let ce = pins.ce.into_push_pull_output(pins.moder, pins.otyper);
+    let csn = pins.csn.into_push_pull_output(pins.moder, pins.otyper);
+    let _irq = pins.irq.into_pull_up_input(pins.moder, pins.pupdr);
+
+    let sck = pins.sck.into_af5(pins.moder, pins.afrh);
+    let miso = pins.miso.into_af5(pins.moder, pins.afrh);
+    let mosi = pins.mosi.into_af5(pins.moder, pins.afrh);
+
+    let spi = Spi::spi2(
+        spi2,
+        (sck, miso, mosi),
+        embedded_hal::spi::Mode {
+            phase: embedded_hal::spi::Phase::CaptureOnFirstTransition,
+            polarity: embedded_hal::spi::Polarity::IdleLow,
+        },
+        1.mhz(),
+        clocks,
+        apb1,
+    );
+
+    NRF24L01::new(ce, csn, spi)
+        .map(|radio| radio.power_down())
+        .map_err(|_| Error::Radio)
+}
+
+/// Route EXTI line 1 to PB1, on which the nRF24L01+ pulls IRQ low
+#[allow(unsafe_code)]
+pub fn listen_radio_irq(syscfg: &SYSCFG, exti: &EXTI) {
+    // 0b001 selects port B, see RM0316 12.1.3
+    syscfg
+        .exticr1
+        .modify(|_, w| unsafe { w.exti1().bits(0b001) });
+    exti.imr1.modify(|_, w| w.mr1().set_bit());
+    exti.ftsr1.modify(|_, w| w.tr1().set_bit());
+}
+
+/// Start the cycle counter, which is the monotonic timer for scheduling
+pub fn start_cycle_counter(dcb: &mut DCB, dwt: &mut DWT) {
+    dcb.enable_trace();
+    DWT::unlock();
+    dwt.enable_cycle_counter();
+}
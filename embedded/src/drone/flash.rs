@@ -0,0 +1,180 @@
+//! Pages of the STM32F303's internal flash, which the firmware doesn't occupy
+//!
+//! The CPU stalls while a page is erased or programmed, since it runs from the same flash. Erasing
+//! a page takes up to 40 ms, during which no task runs.
+use f3::hal::stm32f30x::FLASH;
+
+use portuni_common::{hal::Flash, Error};
+
+/// Start of the internal flash in the address space
+const FLASH_START: usize = 0x0800_0000;
+/// Size of a page of the STM32F303xC, the unit that is erased
+pub const PAGE_SIZE: usize = 2048;
+/// Pages of the STM32F303VC's 256 KiB
+const PAGES: usize = 128;
+
+/// The last two pages hold the config, see `portuni_common::config`
+pub const CONFIG_PAGES: usize = 2;
+pub const CONFIG_PAGE: usize = PAGES - CONFIG_PAGES;
+
+/// Pages before the config that hold the blackbox, see `portuni_common::blackbox`
+pub const BLACKBOX_PAGES: usize = 64;
+pub const BLACKBOX_PAGE: usize = CONFIG_PAGE - BLACKBOX_PAGES;
+
+// Defined by cortex-m-rt's linker script, the initial values of `.data` are the last part of the
+// flash that the firmware occupies
+extern "C" {
+    static __sidata: u32;
+    static __sdata: u32;
+    static __edata: u32;
+}
+
+/// The first page the firmware doesn't occupy
+#[allow(unsafe_code)]
+pub fn first_free_page() -> usize {
+    // Only the addresses of the symbols are used
+    let end = unsafe {
+        let sidata = &__sidata as *const u32 as usize;
+        let sdata = &__sdata as *const u32 as usize;
+        let edata = &__edata as *const u32 as usize;
+        sidata + (edata - sdata)
+    };
+
+    (end - FLASH_START - 1) / PAGE_SIZE + 1
+}
+
+/// The consecutive pages from `first`, for which an address of 0 is the start of `first`
+pub struct InternalFlash {
+    first: usize,
+    pages: usize,
+}
+
+impl InternalFlash {
+    /// `None` if the pages are outside of the flash, or occupied by the firmware
+    pub fn new(first: usize, pages: usize) -> Option<InternalFlash> {
+        if first < first_free_page() || first + pages > PAGES {
+            return None;
+        }
+
+        Some(InternalFlash { first, pages })
+    }
+
+    fn start(&self) -> usize {
+        FLASH_START + self.first * PAGE_SIZE
+    }
+
+    fn contains(&self, address: usize, len: usize) -> bool {
+        matches!(address.checked_add(len), Some(end) if end <= self.pages * PAGE_SIZE)
+    }
+}
+
+/// The FLASH peripheral, of which the access control register belongs to the clock setup
+#[allow(unsafe_code)]
+fn flash() -> &'static f3::hal::stm32f30x::flash::RegisterBlock {
+    // Only the key, status, control and address registers are used, which nothing else touches
+    unsafe { &*FLASH::ptr() }
+}
+
+fn wait() -> Result<(), Error> {
+    let flash = flash();
+    while flash.sr.read().bsy().bit_is_set() {}
+
+    let sr = flash.sr.read();
+    let failed = sr.pgerr().bit_is_set() || sr.wrprterr().bit_is_set();
+
+    // The flags are cleared by writing 1
+    flash.sr.write(|w| {
+        w.eop().set_bit();
+        w.pgerr().set_bit();
+        w.wrprterr().set_bit()
+    });
+
+    if failed {
+        Err(Error::Flash)
+    } else {
+        Ok(())
+    }
+}
+
+/// Run `f` with the flash unlocked for erasing and programming, and lock it again
+#[allow(unsafe_code)]
+fn unlocked<T>(f: impl FnOnce() -> Result<T, Error>) -> Result<T, Error> {
+    let flash = flash();
+
+    if flash.cr.read().lock().bit_is_set() {
+        flash.keyr.write(|w| unsafe { w.fkeyr().bits(0x4567_0123) });
+        flash.keyr.write(|w| unsafe { w.fkeyr().bits(0xcdef_89ab) });
+    }
+
+    let result = wait().and_then(|_| f());
+    flash.cr.modify(|_, w| w.lock().set_bit());
+
+    result
+}
+
+impl Flash for InternalFlash {
+    fn sector_size(&self) -> usize {
+        PAGE_SIZE
+    }
+
+    fn sectors(&self) -> usize {
+        self.pages
+    }
+
+    #[allow(unsafe_code)]
+    fn read(&mut self, address: usize, buf: &mut [u8]) -> Result<(), Error> {
+        if !self.contains(address, buf.len()) {
+            return Err(Error::Flash);
+        }
+
+        let start = self.start() + address;
+        for (i, byte) in buf.iter_mut().enumerate() {
+            // Flash is memory-mapped, and checked to be within the pages
+            *byte = unsafe { core::ptr::read_volatile((start + i) as *const u8) };
+        }
+
+        Ok(())
+    }
+
+    #[allow(unsafe_code)]
+    fn write(&mut self, address: usize, data: &[u8]) -> Result<(), Error> {
+        if !self.contains(address, data.len()) || (address | data.len()) & 1 != 0 {
+            return Err(Error::Flash);
+        }
+
+        let start = self.start() + address;
+        unlocked(|| {
+            let flash = flash();
+            flash.cr.modify(|_, w| w.pg().set_bit());
+
+            // The flash is programmed a half-word at a time
+            let result = data.chunks(2).enumerate().try_for_each(|(i, pair)| {
+                let half_word = u16::from_le_bytes([pair[0], pair[1]]);
+                unsafe { core::ptr::write_volatile((start + 2 * i) as *mut u16, half_word) };
+                wait()
+            });
+
+            flash.cr.modify(|_, w| w.pg().clear_bit());
+            result
+        })
+    }
+
+    #[allow(unsafe_code)]
+    fn erase(&mut self, sector: usize) -> Result<(), Error> {
+        if sector >= self.pages {
+            return Err(Error::Flash);
+        }
+
+        let address = (self.start() + sector * PAGE_SIZE) as u32;
+        unlocked(|| {
+            let flash = flash();
+            flash.cr.modify(|_, w| w.per().set_bit());
+            flash.ar.write(|w| unsafe { w.far().bits(address) });
+            flash.cr.modify(|_, w| w.strt().set_bit());
+
+            let result = wait();
+            flash.cr.modify(|_, w| w.per().clear_bit());
+            result
+        })
+    }
+}
@@ -0,0 +1,532 @@
+//! Implementations of the `portuni_common::hal` traits for the drone's STM32F3DISCOVERY
+use cortex_m::peripheral::DWT;
+use embedded_nrf24l01::{Configuration, CrcMode, StandbyMode};
+
+use f3::{
+    hal::{
+        gpio::gpioe::PEx,
+        gpio::{Output, PushPull},
+        prelude::*,
+        stm32f30x::{IWDG, RCC},
+    },
+    l3gd20, lsm303dlhc, L3gd20, Lsm303dlhc,
+};
+
+use portuni_common::{
+    command::GyroScale,
+    config::{Calibration, DeviceConfig, MagOdr},
+    hal::{F32x3, I16x3, ImuSource, MagSource, Radio, StatusLed},
+    pairing::DeviceId,
+    radio::RadioConfig,
+    units, Error,
+};
+
+use super::{ACCEL_ODR, ACCEL_SENSITIVITY};
+use crate::board::{data_rate, Nrf24Device, Nrf24Rx, Nrf24Tx};
+
+/// Resolution of the accelerometer in mg/LSB for the configured full-scale
+fn accel_mg_per_lsb() -> i16 {
+    use f3::lsm303dlhc::Sensitivity;
+
+    match ACCEL_SENSITIVITY {
+        Sensitivity::G1 => 1,
+        Sensitivity::G2 => 2,
+        Sensitivity::G4 => 4,
+        Sensitivity::G12 => 12,
+    }
+}
+
+fn gyro_scale(scale: GyroScale) -> l3gd20::Scale {
+    match scale {
+        GyroScale::Dps250 => l3gd20::Scale::Dps250,
+        GyroScale::Dps500 => l3gd20::Scale::Dps500,
+        GyroScale::Dps2000 => l3gd20::Scale::Dps2000,
+    }
+}
+
+fn mag_odr(odr: MagOdr) -> lsm303dlhc::MagOdr {
+    match odr {
+        MagOdr::Hz0_75 => lsm303dlhc::MagOdr::Hz0_75,
+        MagOdr::Hz1_5 => lsm303dlhc::MagOdr::Hz1_5,
+        MagOdr::Hz3 => lsm303dlhc::MagOdr::Hz3,
+        MagOdr::Hz7_5 => lsm303dlhc::MagOdr::Hz7_5,
+        MagOdr::Hz15 => lsm303dlhc::MagOdr::Hz15,
+        MagOdr::Hz30 => lsm303dlhc::MagOdr::Hz30,
+        MagOdr::Hz75 => lsm303dlhc::MagOdr::Hz75,
+        MagOdr::Hz220 => lsm303dlhc::MagOdr::Hz220,
+    }
+}
+
+/// The L3GD20 and LSM303DLHC on the board, of which the readings are calibrated
+pub struct Sensors {
+    pub l3gd20: L3gd20,
+    pub lsm303dlhc: Lsm303dlhc,
+    gyro_scale: GyroScale,
+    mag_odr: MagOdr,
+    calibration: Calibration,
+}
+
+impl Sensors {
+    pub fn new(
+        l3gd20: L3gd20,
+        lsm303dlhc: Lsm303dlhc,
+        config: &DeviceConfig,
+    ) -> Result<Sensors, Error> {
+        let mut sensors = Sensors {
+            l3gd20,
+            lsm303dlhc,
+            gyro_scale: config.gyro_scale,
+            mag_odr: config.mag_odr,
+            calibration: config.calibration,
+        };
+
+        ImuSource::reset(&mut sensors)?;
+        MagSource::reset(&mut sensors)?;
+
+        Ok(sensors)
+    }
+
+    /// Change the gyroscope's full-scale, which is kept across resets
+    pub fn set_gyro_scale(&mut self, scale: GyroScale) -> Result<(), Error> {
+        self.gyro_scale = scale;
+        self.l3gd20
+            .set_scale(gyro_scale(scale))
+            .map_err(|_| Error::Imu)
+    }
+
+    /// Change the magnetometer's output data rate, which is kept across resets
+    pub fn set_mag_odr(&mut self, odr: MagOdr) -> Result<(), Error> {
+        self.mag_odr = odr;
+        self.lsm303dlhc
+            .mag_odr(mag_odr(odr))
+            .map_err(|_| Error::Mag)
+    }
+
+    pub fn set_calibration(&mut self, calibration: Calibration) {
+        self.calibration = calibration;
+    }
+}
+
+impl ImuSource for Sensors {
+    fn gyro(&mut self) -> Result<F32x3, Error> {
+        let raw = self.l3gd20.gyro().map_err(|_| Error::Imu)?;
+        let scale = gyro_scale(self.gyro_scale);
+
+        Ok(self.calibration.gyro(F32x3 {
+            x: scale.degrees(raw.x),
+            y: scale.degrees(raw.y),
+            z: scale.degrees(raw.z),
+        }))
+    }
+
+    fn gyro_raw(&mut self) -> Result<(I16x3, GyroScale), Error> {
+        let raw = self.l3gd20.gyro().map_err(|_| Error::Imu)?;
+
+        let raw = I16x3 {
+            x: raw.x,
+            y: raw.y,
+            z: raw.z,
+        };
+
+        Ok((
+            self.calibration.gyro_raw(raw, self.gyro_scale),
+            self.gyro_scale,
+        ))
+    }
+
+    fn accel(&mut self) -> Result<I16x3, Error> {
+        let raw = self.lsm303dlhc.accel().map_err(|_| Error::Imu)?;
+
+        Ok(self.calibration.accel(I16x3 {
+            x: units::accel_mg(raw.x, accel_mg_per_lsb()),
+            y: units::accel_mg(raw.y, accel_mg_per_lsb()),
+            z: units::accel_mg(raw.z, accel_mg_per_lsb()),
+        }))
+    }
+
+    fn temp(&mut self) -> Result<i8, Error> {
+        self.l3gd20.temp().map_err(|_| Error::Imu)
+    }
+
+    fn reset(&mut self) -> Result<(), Error> {
+        self.l3gd20
+            .set_scale(gyro_scale(self.gyro_scale))
+            .map_err(|_| Error::Imu)?;
+        self.lsm303dlhc
+            .accel_odr(ACCEL_ODR)
+            .map_err(|_| Error::Imu)?;
+        self.lsm303dlhc
+            .set_accel_sensitivity(ACCEL_SENSITIVITY)
+            .map_err(|_| Error::Imu)
+    }
+}
+
+impl MagSource for Sensors {
+    fn mag(&mut self) -> Result<I16x3, Error> {
+        let raw = self.lsm303dlhc.mag().map_err(|_| Error::Mag)?;
+
+        Ok(self.calibration.mag(units::mag_mgauss(I16x3 {
+            x: raw.x,
+            y: raw.y,
+            z: raw.z,
+        })))
+    }
+
+    fn reset(&mut self) -> Result<(), Error> {
+        self.lsm303dlhc
+            .mag_odr(mag_odr(self.mag_odr))
+            .map_err(|_| Error::Mag)
+    }
+}
+
+fn configure_radio(
+    radio: &mut StandbyMode<Nrf24Device>,
+    config: &RadioConfig,
+) -> Result<(), Error> {
+    radio
+        .set_frequency(config.channel)
+        .map_err(|_| Error::Radio)?;
+    radio
+        .set_tx_addr(&config.tx_address)
+        .map_err(|_| Error::Radio)?;
+    radio
+        .set_rx_addr(0, &config.rx_address)
+        .map_err(|_| Error::Radio)?;
+    radio.set_auto_retransmit(0, 0).map_err(|_| Error::Radio)?;
+    radio
+        .set_crc(Some(CrcMode::TwoBytes))
+        .map_err(|_| Error::Radio)?;
+    radio
+        .set_rf(data_rate(config.data_rate), config.pa_level)
+        .map_err(|_| Error::Radio)?;
+    radio
+        .set_auto_ack(&[false, false, false, false, false, false])
+        .map_err(|_| Error::Radio)?;
+    radio
+        .set_pipes_rx_enable(&[true, false, false, false, false, false])
+        .map_err(|_| Error::Radio)?;
+    radio
+        .set_pipes_rx_lengths(&[None, Some(1), Some(1), Some(1), Some(1), Some(1)])
+        .map_err(|_| Error::Radio)?;
+
+    radio.flush_rx().map_err(|_| Error::Radio)?;
+    radio.flush_tx().map_err(|_| Error::Radio)
+}
+
+enum State {
+    Tx(Nrf24Tx),
+    Rx(Nrf24Rx),
+    // Listening on a channel for the received power detector only
+    Scan(Nrf24Rx),
+    PoweredDown(Nrf24Device),
+}
+
+/// The nRF24L01+, which keeps hold of the device while it is being re-initialized
+pub struct Nrf24 {
+    state: Option<State>,
+    config: RadioConfig,
+}
+
+impl Nrf24 {
+    pub fn new(device: Nrf24Device, config: RadioConfig) -> Result<Nrf24, Error> {
+        let mut radio = Nrf24 {
+            state: Some(State::PoweredDown(device)),
+            config,
+        };
+
+        radio.reset()?;
+
+        Ok(radio)
+    }
+
+    /// Re-initialize the radio with another configuration, which is kept across resets
+    pub fn configure(&mut self, config: RadioConfig) -> Result<(), Error> {
+        self.config = config;
+        self.reset()
+    }
+
+    pub fn config(&self) -> &RadioConfig {
+        &self.config
+    }
+
+    fn tx(&mut self) -> Result<&mut Nrf24Tx, Error> {
+        match &mut self.state {
+            Some(State::Tx(tx)) => Ok(tx),
+            _ => Err(Error::Radio),
+        }
+    }
+
+    fn rx(&mut self) -> Result<&mut Nrf24Rx, Error> {
+        match &mut self.state {
+            Some(State::Rx(rx)) => Ok(rx),
+            _ => Err(Error::Radio),
+        }
+    }
+
+    /// Listen on `channel` for the received power detector, until the radio is reconfigured
+    pub fn scan(&mut self, channel: u8) -> Result<(), Error> {
+        let standby = match self.state.take() {
+            Some(State::Tx(tx)) => tx.standby().map_err(|(device, _)| device),
+            Some(State::Rx(rx)) | Some(State::Scan(rx)) => Ok(rx.standby()),
+            Some(State::PoweredDown(device)) => Err(device),
+            None => return Err(Error::Radio),
+        };
+
+        let rx = standby.and_then(|mut standby| {
+            if standby.set_frequency(channel).is_err() {
+                return Err(standby.power_down());
+            }
+
+            standby.rx().map_err(|(device, _)| device)
+        });
+
+        match rx {
+            Ok(rx) => {
+                self.state = Some(State::Scan(rx));
+                Ok(())
+            }
+            Err(device) => {
+                self.state = Some(State::PoweredDown(device));
+                Err(Error::Radio)
+            }
+        }
+    }
+
+    /// Whether a carrier was detected on the channel that is being scanned
+    pub fn carrier(&mut self) -> Result<bool, Error> {
+        match &mut self.state {
+            Some(State::Scan(rx)) => rx.has_carrier().map_err(|_| Error::Radio),
+            _ => Err(Error::Radio),
+        }
+    }
+
+    pub fn is_scanning(&self) -> bool {
+        match self.state {
+            Some(State::Scan(_)) => true,
+            _ => false,
+        }
+    }
+}
+
+impl Radio for Nrf24 {
+    fn clear_interrupts(&mut self) -> Result<(), Error> {
+        if let Some(State::Rx(rx)) = &mut self.state {
+            return rx.clear_interrupts().map_err(|_| Error::Radio);
+        }
+
+        // Reading the status clears TX_DS, `WouldBlock` only means a payload is still in flight
+        match self.tx()?.poll_send() {
+            Err(nb::Error::Other(_)) => Err(Error::Radio),
+            _ => Ok(()),
+        }
+    }
+
+    fn can_send(&mut self) -> Result<bool, Error> {
+        if self.is_listening() {
+            return Ok(false);
+        }
+
+        self.tx()?.can_send().map_err(|_| Error::Radio)
+    }
+
+    fn send(&mut self, payload: &[u8]) -> Result<(), Error> {
+        self.tx()?.send(payload).map_err(|_| Error::Radio)
+    }
+
+    fn reset(&mut self) -> Result<(), Error> {
+        let device = match self.state.take() {
+            Some(State::Tx(tx)) => match tx.standby() {
+                Ok(standby) => standby.power_down(),
+                Err((device, _)) => device,
+            },
+            Some(State::Rx(rx)) | Some(State::Scan(rx)) => rx.standby().power_down(),
+            Some(State::PoweredDown(device)) => device,
+            None => return Err(Error::Radio),
+        };
+
+        let mut standby = match StandbyMode::power_up(device) {
+            Ok(standby) => standby,
+            Err((device, _)) => {
+                self.state = Some(State::PoweredDown(device));
+                return Err(Error::Radio);
+            }
+        };
+
+        if configure_radio(&mut standby, &self.config).is_err() {
+            self.state = Some(State::PoweredDown(standby.power_down()));
+            return Err(Error::Radio);
+        }
+
+        match standby.tx() {
+            Ok(tx) => {
+                self.state = Some(State::Tx(tx));
+                Ok(())
+            }
+            Err((device, _)) => {
+                self.state = Some(State::PoweredDown(device));
+                Err(Error::Radio)
+            }
+        }
+    }
+
+    fn listen(&mut self) -> Result<(), Error> {
+        // Waits for the TX FIFO to be sent, a failed switch leaves the radio powered down until
+        // the next send resets it
+        let standby = match self.state.take() {
+            Some(State::Tx(tx)) => tx.standby().map_err(|(device, _)| device),
+            Some(state) => {
+                self.state = Some(state);
+                return Err(Error::Radio);
+            }
+            None => return Err(Error::Radio),
+        };
+
+        match standby.and_then(|standby| standby.rx().map_err(|(device, _)| device)) {
+            Ok(rx) => {
+                self.state = Some(State::Rx(rx));
+                Ok(())
+            }
+            Err(device) => {
+                self.state = Some(State::PoweredDown(device));
+                Err(Error::Radio)
+            }
+        }
+    }
+
+    fn stop_listening(&mut self) -> Result<(), Error> {
+        let standby = match self.state.take() {
+            Some(State::Rx(rx)) => rx.standby(),
+            Some(state) => {
+                self.state = Some(state);
+                return Err(Error::Radio);
+            }
+            None => return Err(Error::Radio),
+        };
+
+        match standby.tx() {
+            Ok(tx) => {
+                self.state = Some(State::Tx(tx));
+                Ok(())
+            }
+            Err((device, _)) => {
+                self.state = Some(State::PoweredDown(device));
+                Err(Error::Radio)
+            }
+        }
+    }
+
+    fn is_listening(&self) -> bool {
+        match self.state {
+            Some(State::Rx(_)) => true,
+            _ => false,
+        }
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<Option<usize>, Error> {
+        let rx = self.rx()?;
+
+        if rx.can_read().map_err(|_| Error::Radio)?.is_none() {
+            return Ok(None);
+        }
+
+        let payload = rx.read().map_err(|_| Error::Radio)?;
+        let len = payload.len().min(buf.len());
+        buf[..len].copy_from_slice(&payload[..len]);
+
+        Ok(Some(len))
+    }
+}
+
+pub struct Led(pub PEx<Output<PushPull>>);
+
+impl StatusLed for Led {
+    fn set(&mut self, on: bool) {
+        if on {
+            self.0.set_high();
+        } else {
+            self.0.set_low();
+        }
+    }
+}
+
+/// Whether the last reset was caused by the independent watchdog, clears the reset flags
+pub fn reset_by_watchdog(rcc: &RCC) -> bool {
+    let iwdg = rcc.csr.read().iwdgrstf().bit_is_set();
+    rcc.csr.modify(|_, w| w.rmvf().set_bit());
+
+    iwdg
+}
+
+/// Address of the 96-bit unique device id in the system memory
+const UID_ADDRESS: usize = 0x1fff_f7ac;
+
+/// The unique device id, programmed into every STM32 at the factory
+#[allow(unsafe_code)]
+pub fn unique_id() -> DeviceId {
+    let mut id = [0; 12];
+
+    for (i, byte) in id.iter_mut().enumerate() {
+        // The id is read-only and always present, there is no peripheral that owns it
+        *byte = unsafe { core::ptr::read_volatile((UID_ADDRESS + i) as *const u8) };
+    }
+
+    id
+}
+
+/// Readings mixed into the nonce, the gyroscope's noise alone changes several bits of each
+const NONCE_READINGS: usize = 32;
+
+/// A nonce that differs from the ones of earlier boots, from the noise of the sensors and the
+/// cycles each reading took. It isn't random enough for a key, it only has to avoid repeats
+pub fn boot_nonce(sensors: &mut Sensors) -> u32 {
+    // 32-bit FNV-1a
+    let mut nonce: u32 = 0x811c_9dc5;
+    let mut mix = |value: u32| {
+        for &byte in value.to_le_bytes().iter() {
+            nonce ^= u32::from(byte);
+            nonce = nonce.wrapping_mul(0x0100_0193);
+        }
+    };
+
+    for _ in 0..NONCE_READINGS {
+        if let Ok(gyro) = sensors.gyro() {
+            mix(gyro.x.to_bits() ^ gyro.y.to_bits().rotate_left(11) ^ gyro.z.to_bits());
+        }
+        if let Ok(mag) = MagSource::mag(sensors) {
+            mix(mag.x as u32 ^ ((mag.y as u32) << 16) ^ (mag.z as u32).rotate_left(8));
+        }
+        mix(DWT::get_cycle_count());
+    }
+
+    nonce
+}
+
+/// The independent watchdog, which resets the board unless it is fed before it times out
+///
+/// It runs on the ~40 kHz LSI and can't be stopped once started, not even by a debugger.
+pub struct Watchdog(IWDG);
+
+impl Watchdog {
+    #[allow(unsafe_code)]
+    pub fn start(iwdg: IWDG, timeout_ms: u32) -> Watchdog {
+        // A prescaler of 32 gives 1.25 ticks per millisecond, the reload value is 12-bit
+        let reload = (timeout_ms * 40 / 32).min(0xfff) as u16;
+
+        iwdg.kr.write(|w| unsafe { w.key().bits(0xcccc) });
+        iwdg.kr.write(|w| unsafe { w.key().bits(0x5555) });
+        iwdg.pr.write(|w| unsafe { w.pr().bits(0b011) });
+        iwdg.rlr.write(|w| unsafe { w.rl().bits(reload) });
+
+        // Wait for the prescaler and reload value to be updated
+        while iwdg.sr.read().bits() != 0 {}
+
+        let mut watchdog = Watchdog(iwdg);
+        watchdog.feed();
+        watchdog
+    }
+
+    #[allow(unsafe_code)]
+    pub fn feed(&mut self) {
+        self.0.kr.write(|w| unsafe { w.key().bits(0xaaaa) });
+    }
+}
@@ -0,0 +1,988 @@
+//! Firmware of the drone, built with the `tx` feature
+mod flash;
+mod hal;
+
+use cortex_m::peripheral::{DWT, SCB};
+use rtic::cyccnt::{Instant, U32Ext as _};
+
+use f3::{
+    hal::{
+        delay::Delay,
+        flash::FlashExt,
+        gpio::gpioe::{PEx, PE13, PE15},
+        gpio::{GpioExt, Output, PushPull},
+        i2c::I2c,
+        prelude::*,
+        rcc::RccExt,
+        spi::Spi,
+        stm32f30x,
+        stm32f30x::{i2c1, EXTI, SYSCFG, TIM7},
+        time::U32Ext,
+        timer::{Event, Timer},
+    },
+    l3gd20,
+    led::{Direction, Leds},
+    lsm303dlhc::{self, AccelOdr, Sensitivity},
+    L3gd20, Lsm303dlhc,
+};
+
+use heapless::{
+    consts::U8,
+    spsc::{Consumer, Producer, Queue},
+};
+
+use portuni_common::{
+    batch::Batcher,
+    blackbox::Blackbox,
+    clock::Clock,
+    command::{
+        Command, CommandReceiver, CommandResult, Encoding, Incoming, RxWindow, Settings, Uplink,
+    },
+    config::{ConfigStore, DeviceConfig},
+    failsafe::Failsafe,
+    fusion::Fusion,
+    hal::Radio,
+    pairing::{self, DeviceId},
+    protocol::{DeviceStatus, Message, Telemetry, PAYLOAD_SIZE},
+    radio::{Negotiation, RadioConfig},
+    recovery::{retry, Recovery, MAX_ATTEMPTS},
+    sampler,
+    secure::{self, Responder},
+    spectrum::{Report, Scan},
+    status::Status,
+    transmitter::Transmitter,
+    Error as Fault,
+};
+
+use crate::board::{self, Nrf24Pins};
+use crate::log;
+use crate::or_reset;
+use flash::{InternalFlash, BLACKBOX_PAGE, BLACKBOX_PAGES, CONFIG_PAGE, CONFIG_PAGES};
+use hal::{Led, Nrf24, Sensors, Watchdog};
+
+/// Accelerometer output data rate
+const ACCEL_ODR: AccelOdr = AccelOdr::Hz100;
+/// Accelerometer full-scale, `G1` is ±2 g at 1 mg/LSB
+const ACCEL_SENSITIVITY: Sensitivity = Sensitivity::G1;
+/// Rate at which the status LEDs blink, the watchdog is fed at the same rate
+const LED_RATE_HZ: u32 = 4;
+/// Number of messages sent between two RX windows, 100 ms at the default sample rate
+const RX_WINDOW_INTERVAL: usize = 5;
+/// Time the radio listens for a command after announcing a window
+const RX_WINDOW_US: u32 = 4000;
+/// Number of status ticks `Command::LedTest` lights the LEDs for
+const LED_TEST_TICKS: u32 = 3 * LED_RATE_HZ;
+/// Time between acknowledging a command that interrupts the link and acting on it, so the
+/// acknowledgement is sent first
+const ACK_DELAY_MS: u32 = 100;
+/// Time spent on each channel during a spectrum scan
+const SCAN_DWELL_MS: u32 = 1;
+/// Number of status ticks in which a new radio configuration has to be confirmed by a command,
+/// after which the drone falls back to the default channel or addresses
+const RADIO_CONFIRM_TICKS: u32 = 3 * LED_RATE_HZ;
+/// Number of status ticks between two `Message::Advertise` while unpaired
+const ADVERTISE_TICKS: u32 = LED_RATE_HZ;
+/// Number of status ticks between two `Message::Heartbeat` while paired
+const HEARTBEAT_TICKS: u32 = LED_RATE_HZ;
+/// Sample rate while the failsafe is engaged, unless a lower one was set. Fewer messages leave
+/// the radio idle for longer, while RX windows are still opened for the client to come back
+const FAILSAFE_SAMPLE_RATE_HZ: u16 = 10;
+/// Time after which the watchdog resets the board if the status task stops running
+const WATCHDOG_TIMEOUT_MS: u32 = 1000;
+/// Number of samples fused between two `Message::Orientation`, with the `fusion` feature
+const ORIENTATION_INTERVAL: u8 = 2;
+/// Pre-shared key as 64 hexadecimal digits, the radio frames are only sealed if it is set
+const KEY: Option<&str> = option_env!("PORTUNI_KEY");
+
+/// Number of status ticks in `timeout_ms`, at least one
+fn timeout_ticks(timeout_ms: u16) -> u32 {
+    (u32::from(timeout_ms) * LED_RATE_HZ).saturating_sub(1) / 1000 + 1
+}
+
+// Tasks, from the highest to the lowest priority:
+//
+// * `sample` runs on TIM7 at the configured sample rate and queues a timestamped sample. With the
+//   `fusion` feature it also updates the attitude, which is queued after every few samples. With
+//   the `blackbox` feature it spawns `record`
+// * `radio` runs on the nRF24L01+ IRQ line and sends queued samples. After every
+//   `RX_WINDOW_INTERVAL` messages it sends `Message::Listening` and listens for a command until
+//   `close_window` runs. During a blackbox download it sends the records while nothing is queued
+// * `command` handles a received command and queues its acknowledgement
+// * `record` writes a sample to the blackbox, stalling every task while a page is erased
+// * `switch_radio` moves the link to another channel and data rate
+// * `start_scan` and `scan` sweep the channels, during which nothing is sent
+// * `status` blinks the LEDs and feeds the watchdog, it is scheduled on the DWT cycle counter.
+//   While unpaired it also advertises the board's unique id, while paired it sends heartbeats and
+//   engages the failsafe once the client's heartbeats stop arriving
+//
+// Failed reads and sends are retried, after which the peripheral is re-initialized and a
+// `Message::Fault` is sent to the client. Any task that hangs keeps `status` from running, after
+// which the watchdog resets the board.
+#[rtic::app(device = f3::hal::stm32f30x, peripherals = true, monotonic = rtic::cyccnt::CYCCNT)]
+const APP: () = {
+    struct Resources {
+        sensors: Sensors,
+        sensor_recovery: Recovery,
+        sample_timer: Timer<TIM7>,
+        // Negotiated by the client, copies of the ones in `settings`
+        encoding: Encoding,
+        batcher: Batcher,
+        fusion: Fusion,
+        clock: Clock,
+        samples: Producer<'static, Message, U8>,
+        queue: Consumer<'static, Message, U8>,
+        radio: Nrf24,
+        radio_recovery: Recovery,
+        transmitter: Transmitter,
+        rx_window: RxWindow,
+        rx_window_period: u32,
+        commands: CommandReceiver,
+        negotiation: Negotiation,
+        failsafe: Failsafe,
+        settings: Settings,
+        // Loaded at boot, and changed by commands until it is saved
+        config: DeviceConfig,
+        config_store: Option<ConfigStore<InternalFlash>>,
+        // Only opened with the `blackbox` feature
+        blackbox: Option<Blackbox<InternalFlash>>,
+        // The link the drone boots on, and falls back to when pairing fails
+        default_link: RadioConfig,
+        uid: DeviceId,
+        // Picked at boot and advertised, see `secure`
+        nonce: u32,
+        responder: Option<Responder>,
+        cycles_per_ms: u32,
+        exti: EXTI,
+        led_w: Led,
+        led_s: Led,
+        led_period: u32,
+        indicator: Status,
+        watchdog: Watchdog,
+        // Samples that were dropped because the queue was full or a read failed
+        #[init(0)]
+        dropped: u32,
+        // Spectrum scan in progress, and the result that is being sent
+        #[init(None)]
+        scan: Option<Scan>,
+        #[init(None)]
+        spectrum: Option<Report>,
+    }
+
+    #[init(schedule = [status])]
+    fn init(cx: init::Context) -> init::LateResources {
+        static mut QUEUE: Queue<Message, U8> = Queue(heapless::i::Queue::new());
+
+        // Cortex and device peripherals
+        let mut cp = cx.core;
+        let dp = cx.device;
+
+        let reset_by_watchdog = hal::reset_by_watchdog(&dp.RCC);
+
+        // Started first, so a hang during the setup resets the board as well
+        let watchdog = Watchdog::start(dp.IWDG, WATCHDOG_TIMEOUT_MS);
+
+        // SYSCFG is needed to route the radio IRQ pin to EXTI
+        dp.RCC.apb2enr.modify(|_, w| w.syscfgen().set_bit());
+
+        // Split RCC and Flash into different functionalities
+        // See: https://blog.japaric.io/brave-new-io/#freezing-the-clock-configuration
+        let mut flash = dp.FLASH.constrain();
+        let mut rcc = dp.RCC.constrain();
+
+        // Split GPIO into independent pins and registers
+        let mut gpioa = dp.GPIOA.split(&mut rcc.ahb);
+        let mut gpiob = dp.GPIOB.split(&mut rcc.ahb);
+        let mut gpioe = dp.GPIOE.split(&mut rcc.ahb);
+
+        // LEDs
+        let led_w: PE15<Output<PushPull>> = gpioe
+            .pe15
+            .into_push_pull_output(&mut gpioe.moder, &mut gpioe.otyper);
+        let led_s: PE13<Output<PushPull>> = gpioe
+            .pe13
+            .into_push_pull_output(&mut gpioe.moder, &mut gpioe.otyper);
+
+        // L3GD20 Gyroscope and temperature sensor
+        let l3gd20_nss = gpioe
+            .pe3
+            .into_push_pull_output(&mut gpioe.moder, &mut gpioe.otyper);
+
+        // Clocks
+        let clocks = rcc.cfgr.freeze(&mut flash.acr);
+
+        // The `L3gd20` abstraction exposed by the `f3` crate requires a specific pin configuration
+        // to be used and won't accept any configuration other than the one used here. Trying to
+        // use a different pin configuration will result in a compiler error.
+        let l3gd20_sck = gpioa.pa5.into_af5(&mut gpioa.moder, &mut gpioa.afrl);
+        let l3gd20_miso = gpioa.pa6.into_af5(&mut gpioa.moder, &mut gpioa.afrl);
+        let l3gd20_mosi = gpioa.pa7.into_af5(&mut gpioa.moder, &mut gpioa.afrl);
+        let l3gd20_spi = Spi::spi1(
+            dp.SPI1,
+            (l3gd20_sck, l3gd20_miso, l3gd20_mosi),
+            l3gd20::MODE,
+            1.mhz(),
+            clocks,
+            &mut rcc.apb2,
+        );
+        let l3gd20 = or_reset(L3gd20::new(l3gd20_spi, l3gd20_nss));
+
+        // LSM303DLHC Magnetometer and accelerometer
+        let lsm303dlhc_scl = gpiob.pb6.into_af4(&mut gpiob.moder, &mut gpiob.afrl);
+        let lsm303dlhc_sda = gpiob.pb7.into_af4(&mut gpiob.moder, &mut gpiob.afrl);
+        let lsm303dlhc_i2c = I2c::i2c1(
+            dp.I2C1,
+            (lsm303dlhc_scl, lsm303dlhc_sda),
+            400.khz(),
+            clocks,
+            &mut rcc.apb1,
+        );
+        let lsm303dlhc = or_reset(Lsm303dlhc::new(lsm303dlhc_i2c));
+
+        // Without the config pages the defaults are used, and saving fails
+        let config_store = InternalFlash::new(CONFIG_PAGE, CONFIG_PAGES)
+            .and_then(|flash| ConfigStore::open(flash).ok());
+        let stored = config_store.as_ref().and_then(ConfigStore::saved);
+        let config = stored.unwrap_or_default();
+
+        // Without the pages, if the firmware occupies them, nothing is recorded
+        let blackbox = if cfg!(feature = "blackbox") {
+            InternalFlash::new(BLACKBOX_PAGE, BLACKBOX_PAGES)
+                .and_then(|flash| Blackbox::open(flash).ok())
+        } else {
+            None
+        };
+
+        let settings = Settings {
+            gyro_scale: config.gyro_scale,
+            ..Settings::default()
+        };
+        let mut sensors = or_reset(Sensors::new(l3gd20, lsm303dlhc, &config));
+
+        let radio_pins = Nrf24Pins {
+            ce: gpiob.pb2,
+            csn: gpiob.pb0,
+            irq: gpiob.pb1,
+            sck: gpiob.pb13,
+            miso: gpiob.pb14,
+            mosi: gpiob.pb15,
+            moder: &mut gpiob.moder,
+            otyper: &mut gpiob.otyper,
+            pupdr: &mut gpiob.pupdr,
+            afrh: &mut gpiob.afrh,
+        };
+        let radio = or_reset(board::nrf24(radio_pins, dp.SPI2, clocks, &mut rcc.apb1));
+        let default_link = RadioConfig {
+            channel: config.channel,
+            ..RadioConfig::default()
+        };
+        let mut radio = or_reset(Nrf24::new(radio, default_link));
+
+        board::listen_radio_irq(&dp.SYSCFG, &dp.EXTI);
+
+        // The cycle counter is both the monotonic timer for scheduling and the sample clock, and
+        // timestamps the log records
+        board::start_cycle_counter(&mut cp.DCB, &mut cp.DWT);
+
+        info!(Boot, "boot, reset by watchdog: {:bool}", reset_by_watchdog);
+        let is_stored = stored.is_some();
+        info!(ConfigLoaded, "config loaded, stored: {:bool}", is_stored);
+        if let Some((first, last)) = blackbox.as_ref().map(Blackbox::range) {
+            let records = last - first;
+            info!(BlackboxOpened, "blackbox opened, records: {:u32}", records);
+        }
+        let channel = radio.config().channel;
+        let data_rate = radio.config().data_rate as u8;
+        debug!(
+            Radio,
+            "radio on channel {:u8} at rate {:u8}", channel, data_rate
+        );
+
+        let clock = Clock::new(clocks.sysclk().0, DWT::get_cycle_count());
+
+        let uid = hal::unique_id();
+        let nonce = hal::boot_nonce(&mut sensors);
+
+        // A key that doesn't parse resets the board, rather than sending in the clear
+        let responder = KEY.map(|hex| {
+            let key = or_reset(secure::parse_key(hex).ok_or(()));
+            Responder::new(&key, &uid, nonce)
+        });
+        let transmitter = match responder {
+            Some(_) => Transmitter::secure(),
+            None => Transmitter::new(),
+        };
+
+        // Sample timer
+        let sample_rate = u32::from(settings.sample_rate_hz);
+        let mut sample_timer = Timer::tim7(dp.TIM7, sample_rate.hz(), clocks, &mut rcc.apb1);
+        sample_timer.listen(Event::TimeOut);
+
+        let cycles_per_ms = clocks.sysclk().0 / 1000;
+        let led_period = clocks.sysclk().0 / LED_RATE_HZ;
+        or_reset(cx.schedule.status(cx.start + led_period.cycles()));
+
+        let (mut samples, queue) = QUEUE.split();
+
+        if reset_by_watchdog {
+            let _ = samples.enqueue(Message::Fault(Fault::Watchdog));
+        }
+
+        init::LateResources {
+            sensors,
+            sensor_recovery: Recovery::default(),
+            sample_timer,
+            encoding: settings.encoding,
+            batcher: Batcher::new(),
+            fusion: Fusion::new(ORIENTATION_INTERVAL),
+            clock,
+            samples,
+            queue,
+            radio,
+            radio_recovery: Recovery::default(),
+            transmitter,
+            rx_window: RxWindow::new(RX_WINDOW_INTERVAL),
+            rx_window_period: cycles_per_ms * RX_WINDOW_US / 1000,
+            commands: CommandReceiver::new(),
+            negotiation: Negotiation::new(RADIO_CONFIRM_TICKS),
+            failsafe: Failsafe::new(),
+            settings,
+            config,
+            config_store,
+            blackbox,
+            default_link,
+            uid,
+            nonce,
+            responder,
+            cycles_per_ms,
+            exti: dp.EXTI,
+            led_w: Led(led_w.downgrade()),
+            led_s: Led(led_s.downgrade()),
+            led_period,
+            indicator: Status::default(),
+            watchdog,
+        }
+    }
+
+    #[task(
+        binds = TIM7,
+        priority = 3,
+        resources = [
+            sensors,
+            sensor_recovery,
+            sample_timer,
+            encoding,
+            batcher,
+            fusion,
+            clock,
+            samples,
+            dropped
+        ],
+        spawn = [record]
+    )]
+    fn sample(cx: sample::Context) {
+        // Clears the update flag
+        let _ = cx.resources.sample_timer.wait();
+
+        let timestamp = cx.resources.clock.now(DWT::get_cycle_count());
+        let recovery = cx.resources.sensor_recovery;
+        let samples = cx.resources.samples;
+        let batcher = cx.resources.batcher;
+        let encoding = *cx.resources.encoding;
+
+        let sample = recovery
+            .run(
+                cx.resources.sensors,
+                |sensors| match encoding {
+                    Encoding::Float => sampler::sample(sensors, timestamp).map(Message::Telemetry),
+                    Encoding::Compact => {
+                        sampler::sample_compact(sensors, timestamp).map(Message::CompactTelemetry)
+                    }
+                },
+                sampler::reset,
+            )
+            .ok();
+
+        // Fused and recorded whichever encoding the sample is sent in
+        let value = match &sample {
+            _ if !cfg!(any(feature = "fusion", feature = "blackbox")) => None,
+            Some(Message::Telemetry(value)) => Some(value.clone()),
+            Some(Message::CompactTelemetry(compact)) => Some(compact.decode()),
+            _ => None,
+        };
+        let orientation = match &value {
+            Some(value) if cfg!(feature = "fusion") => cx.resources.fusion.update(value),
+            _ => None,
+        };
+
+        // A batch that can't be queued counts as a single dropped sample
+        let queued = match sample.map(|sample| batcher.push(sample)) {
+            Some(Some(message)) => samples.enqueue(message).is_ok(),
+            // Collected into a batch that isn't full yet
+            Some(None) => true,
+            None => false,
+        };
+
+        if !queued {
+            *cx.resources.dropped += 1;
+        }
+
+        // An orientation that doesn't fit is dropped, the next one replaces it anyway
+        if let Some(orientation) = orientation {
+            let _ = samples.enqueue(Message::Orientation(orientation));
+        }
+
+        // Samples that come faster than they are written are missing from the blackbox
+        if let Some(value) = value.filter(|_| cfg!(feature = "blackbox")) {
+            let _ = cx.spawn.record(value);
+        }
+
+        if let Some(fault) = recovery.take_fault() {
+            warn!(Fault, "sensors reset after fault {:u8}", fault as u8);
+            let _ = samples.enqueue(Message::Fault(fault));
+        }
+
+        // The radio only raises IRQ after a transmission, so an idle radio has to be woken up
+        rtic::pend(stm32f30x::Interrupt::EXTI1);
+    }
+
+    #[task(
+        binds = EXTI1,
+        priority = 2,
+        resources = [
+            radio,
+            radio_recovery,
+            transmitter,
+            rx_window,
+            rx_window_period,
+            spectrum,
+            queue,
+            blackbox,
+            exti
+        ],
+        schedule = [close_window],
+        spawn = [command]
+    )]
+    fn radio(cx: radio::Context) {
+        cx.resources.exti.pr1.write(|w| w.pr1().set_bit());
+
+        let radio = cx.resources.radio;
+        let recovery = cx.resources.radio_recovery;
+        let transmitter = cx.resources.transmitter;
+        let window = cx.resources.rx_window;
+        let spectrum = cx.resources.spectrum;
+        let queue = cx.resources.queue;
+        let blackbox = cx.resources.blackbox;
+
+        if radio.is_scanning() {
+            return;
+        }
+
+        if radio.is_listening() {
+            let _ = radio.clear_interrupts();
+
+            // Commands that don't fit in the task's queue are repeated by the relay
+            let mut frame = [0u8; PAYLOAD_SIZE];
+            while let Ok(Some(len)) = radio.read(&mut frame) {
+                let _ = cx.spawn.command(frame, len);
+            }
+
+            return;
+        }
+
+        // Messages that could not be sent are lost
+        let sent = recovery
+            .run(
+                radio,
+                |radio| {
+                    // A scan result goes out before the samples that were queued during the scan
+                    transmitter.service(radio, || {
+                        spectrum
+                            .as_mut()
+                            .and_then(|report| report.next())
+                            .map(Message::Spectrum)
+                            .or_else(log::next)
+                            .or_else(|| queue.dequeue())
+                            .or_else(|| {
+                                blackbox
+                                    .as_mut()
+                                    .and_then(Blackbox::next_chunk)
+                                    .map(Message::Blackbox)
+                            })
+                    })
+                },
+                |radio, _| retry(MAX_ATTEMPTS, || radio.reset()),
+            )
+            .unwrap_or(0);
+
+        // The TX FIFO is empty after a reset, so there is room for the fault
+        if let Some(fault) = recovery.take_fault() {
+            warn!(Fault, "radio reset after fault {:u8}", fault as u8);
+            let _ = transmitter.send(radio, &Message::Fault(fault));
+        }
+
+        if window.record(sent) && transmitter.open_window(radio).is_ok() {
+            window.opened();
+
+            let period = *cx.resources.rx_window_period;
+            let _ = cx.schedule.close_window(cx.start + period.cycles());
+        }
+    }
+
+    #[task(priority = 2, resources = [radio])]
+    fn close_window(cx: close_window::Context) {
+        // A radio that fails to switch back is reset by the next send
+        let _ = cx.resources.radio.stop_listening();
+
+        // Resume sending the samples that were queued during the window
+        rtic::pend(stm32f30x::Interrupt::EXTI1);
+    }
+
+    #[task(priority = 2, capacity = 2, resources = [radio])]
+    fn switch_radio(cx: switch_radio::Context, config: RadioConfig) {
+        // A radio that fails to switch is reset with the new configuration by the next send
+        let _ = cx.resources.radio.configure(config);
+
+        let channel = config.channel;
+        let paired = pairing::is_paired(&config);
+        info!(
+            RadioSwitched,
+            "radio on channel {:u8}, paired: {:bool}", channel, paired
+        );
+
+        rtic::pend(stm32f30x::Interrupt::EXTI1);
+    }
+
+    #[task(priority = 2, resources = [radio, scan, cycles_per_ms], schedule = [scan])]
+    fn start_scan(cx: start_scan::Context, sweeps: u8) {
+        let radio = cx.resources.radio;
+        let scan = Scan::new(sweeps);
+        let dwell = *cx.resources.cycles_per_ms * SCAN_DWELL_MS;
+
+        if radio.scan(scan.channel()).is_ok()
+            && cx.schedule.scan(cx.scheduled + dwell.cycles()).is_ok()
+        {
+            *cx.resources.scan = Some(scan);
+        } else {
+            // Back to the link's configuration
+            let _ = radio.reset();
+        }
+    }
+
+    #[task(
+        priority = 2,
+        resources = [radio, scan, spectrum, cycles_per_ms],
+        schedule = [scan]
+    )]
+    fn scan(cx: scan::Context) {
+        let radio = cx.resources.radio;
+        let scan = match cx.resources.scan {
+            Some(scan) => scan,
+            None => return,
+        };
+
+        match radio.carrier().map(|carrier| scan.record(carrier)) {
+            Ok(true) => {
+                let dwell = *cx.resources.cycles_per_ms * SCAN_DWELL_MS;
+
+                if radio.scan(scan.channel()).is_ok()
+                    && cx.schedule.scan(cx.scheduled + dwell.cycles()).is_ok()
+                {
+                    return;
+                }
+            }
+            Ok(false) => *cx.resources.spectrum = Some(scan.report()),
+            // An incomplete scan is not reported
+            Err(_) => {}
+        }
+
+        *cx.resources.scan = None;
+
+        // Back to the link's configuration, a radio that fails is reset again by the next send
+        let _ = radio.reset();
+        rtic::pend(stm32f30x::Interrupt::EXTI1);
+    }
+
+    #[task(
+        priority = 1,
+        capacity = 2,
+        resources = [
+            sensors,
+            sample_timer,
+            encoding,
+            batcher,
+            clock,
+            samples,
+            dropped,
+            radio,
+            commands,
+            negotiation,
+            failsafe,
+            settings,
+            config,
+            config_store,
+            blackbox,
+            default_link,
+            uid,
+            responder,
+            transmitter,
+            indicator,
+            cycles_per_ms
+        ],
+        schedule = [reboot, switch_radio, start_scan]
+    )]
+    fn command(mut cx: command::Context, mut frame: [u8; PAYLOAD_SIZE], len: usize) {
+        // With a pre-shared key, `Command::Pair` comes with the key of the session it starts
+        let (frame, session) = match cx.resources.responder {
+            Some(responder) => match responder.open(&mut frame[..len]) {
+                Some(opened) => opened,
+                None => return,
+            },
+            None => (&frame[..len], None),
+        };
+
+        let incoming = cx.resources.commands.receive(frame);
+
+        match incoming {
+            // Unpaired drones share the default addresses, only the one being paired answers
+            Some(Incoming::Command(Uplink {
+                command: Command::Pair(id),
+                ..
+            })) if id != *cx.resources.uid => return,
+            Some(Incoming::Command(Uplink {
+                command: Command::Pair(_),
+                ..
+            })) => {}
+            // Only pairing is sealed with the pairing key
+            Some(Incoming::Command(_)) if session.is_some() => return,
+            _ => {}
+        }
+
+        // Any command but the proposal itself confirms a new radio configuration
+        match incoming {
+            Some(Incoming::Command(uplink)) => cx.resources.negotiation.received(uplink.id),
+            Some(Incoming::Repeat { id, .. }) => cx.resources.negotiation.received(id),
+            None => {}
+        }
+
+        // Any command shows the client is still there
+        if incoming.is_some() && cx.resources.failsafe.received() {
+            info!(LinkRestored, "link restored");
+            let hz = cx.resources.settings.sample_rate_hz;
+            cx.resources
+                .sample_timer
+                .lock(|timer| timer.start(u32::from(hz).hz()));
+            cx.resources.indicator.set_link_lost(false);
+        }
+
+        let uplink = match incoming {
+            Some(Incoming::Command(uplink)) => uplink,
+            Some(Incoming::Repeat { id, result }) => {
+                let ack = Message::Ack { id, result };
+                let _ = cx.resources.samples.lock(|samples| samples.enqueue(ack));
+                return;
+            }
+            None => return,
+        };
+
+        let id = uplink.id;
+        debug!(Command, "command {:u8}", id);
+
+        let settings = cx.resources.settings;
+        let mut reply = None;
+        let mut result = uplink.command.validate();
+
+        if result == CommandResult::Ok {
+            match uplink.command {
+                Command::SetSampleRate(hz) => {
+                    cx.resources
+                        .sample_timer
+                        .lock(|timer| timer.start(u32::from(hz).hz()));
+                    settings.sample_rate_hz = hz;
+                }
+                Command::Heartbeat { timeout_ms } => {
+                    cx.resources.failsafe.arm(timeout_ticks(timeout_ms));
+                }
+                Command::Ping { sent } => {
+                    let timestamp = cx
+                        .resources
+                        .clock
+                        .lock(|clock| clock.now(DWT::get_cycle_count()));
+                    reply = Some(Message::Pong { sent, timestamp });
+                }
+                Command::SetGyroScale(scale) => {
+                    match cx.resources.sensors.lock(|s| s.set_gyro_scale(scale)) {
+                        Ok(()) => {
+                            settings.gyro_scale = scale;
+                            cx.resources.config.gyro_scale = scale;
+                        }
+                        Err(_) => result = CommandResult::Failed,
+                    }
+                }
+                Command::RequestStatus => {
+                    let dropped = cx.resources.dropped.lock(|dropped| *dropped);
+                    reply = Some(Message::Status(DeviceStatus {
+                        settings: *settings,
+                        dropped,
+                    }));
+                }
+                Command::Reboot => {
+                    let delay = *cx.resources.cycles_per_ms * ACK_DELAY_MS;
+                    if cx.schedule.reboot(Instant::now() + delay.cycles()).is_err() {
+                        result = CommandResult::Failed;
+                    }
+                }
+                Command::LedTest => cx.resources.indicator.led_test(LED_TEST_TICKS),
+                Command::SetRadio { channel, data_rate } => {
+                    let config = cx
+                        .resources
+                        .radio
+                        .lock(|radio| radio.config().with_link(channel, data_rate));
+                    let delay = *cx.resources.cycles_per_ms * ACK_DELAY_MS;
+
+                    if cx
+                        .schedule
+                        .switch_radio(Instant::now() + delay.cycles(), config)
+                        .is_ok()
+                    {
+                        cx.resources
+                            .negotiation
+                            .switched(uplink.id, config.fallback());
+                    } else {
+                        result = CommandResult::Failed;
+                    }
+                }
+                Command::ScanSpectrum { sweeps } => {
+                    let delay = *cx.resources.cycles_per_ms * ACK_DELAY_MS;
+                    if cx
+                        .schedule
+                        .start_scan(Instant::now() + delay.cycles(), sweeps)
+                        .is_err()
+                    {
+                        result = CommandResult::Failed;
+                    }
+                }
+                Command::SetEncoding {
+                    encoding,
+                    batch_size,
+                } => {
+                    cx.resources.encoding.lock(|e| *e = encoding);
+                    cx.resources
+                        .batcher
+                        .lock(|batcher| batcher.set_size(batch_size));
+                    settings.encoding = encoding;
+                    settings.batch_size = batch_size;
+                }
+                Command::Pair(id) => {
+                    let config = cx
+                        .resources
+                        .radio
+                        .lock(|radio| pairing::private_config(radio.config(), &id));
+                    let delay = *cx.resources.cycles_per_ms * ACK_DELAY_MS;
+
+                    // Back to the default addresses, and advertising, unless the client confirms
+                    if cx
+                        .schedule
+                        .switch_radio(Instant::now() + delay.cycles(), config)
+                        .is_ok()
+                    {
+                        cx.resources
+                            .negotiation
+                            .switched(uplink.id, *cx.resources.default_link);
+
+                        // The acknowledgement is already sealed with the new key
+                        if let (Some(key), Some(responder)) =
+                            (session, cx.resources.responder.as_mut())
+                        {
+                            responder.start_session(&key);
+                            cx.resources
+                                .transmitter
+                                .lock(|transmitter| transmitter.start_session(&key));
+                        }
+                    } else {
+                        result = CommandResult::Failed;
+                    }
+                }
+                Command::RequestConfig => reply = Some(Message::Config(*cx.resources.config)),
+                Command::SetMagOdr(odr) => {
+                    match cx.resources.sensors.lock(|s| s.set_mag_odr(odr)) {
+                        Ok(()) => cx.resources.config.mag_odr = odr,
+                        Err(_) => result = CommandResult::Failed,
+                    }
+                }
+                Command::SetCalibration { sensor, offset } => {
+                    let calibration = &mut cx.resources.config.calibration;
+                    calibration.set(sensor, offset);
+
+                    let calibration = *calibration;
+                    cx.resources
+                        .sensors
+                        .lock(|s| s.set_calibration(calibration));
+                }
+                Command::SetChannel(channel) => cx.resources.config.channel = channel,
+                Command::SaveConfig => {
+                    // Stalls every task while a full page is erased
+                    let config = *cx.resources.config;
+                    let saved = cx
+                        .resources
+                        .config_store
+                        .as_mut()
+                        .map(|store| store.save(&config));
+
+                    match saved {
+                        Some(Ok(())) => info!(ConfigSaved, "config saved"),
+                        Some(Err(_)) | None => result = CommandResult::Failed,
+                    }
+                }
+                Command::ReadBlackbox { from } => {
+                    let started = cx.resources.blackbox.lock(|blackbox| {
+                        blackbox
+                            .as_mut()
+                            .map(|blackbox| blackbox.start_download(from))
+                    });
+
+                    if started.is_none() {
+                        result = CommandResult::Failed;
+                    }
+                }
+            }
+        }
+
+        cx.resources.commands.handled(uplink.id, result);
+
+        let ack = Message::Ack {
+            id: uplink.id,
+            result,
+        };
+        cx.resources.samples.lock(|samples| {
+            let _ = samples.enqueue(ack);
+
+            if let Some(reply) = reply {
+                let _ = samples.enqueue(reply);
+            }
+        });
+    }
+
+    #[task(priority = 1, capacity = 4, resources = [blackbox])]
+    fn record(mut cx: record::Context, value: Telemetry) {
+        // A record that fails is lost, the next one is written after it
+        let _ = cx
+            .resources
+            .blackbox
+            .lock(|blackbox| blackbox.as_mut().map(|blackbox| blackbox.append(&value)));
+    }
+
+    #[task(priority = 1)]
+    fn reboot(_: reboot::Context) {
+        SCB::sys_reset();
+    }
+
+    #[task(
+        priority = 1,
+        resources = [
+            led_w,
+            led_s,
+            led_period,
+            indicator,
+            watchdog,
+            dropped,
+            negotiation,
+            failsafe,
+            sample_timer,
+            settings,
+            radio,
+            samples,
+            commands,
+            transmitter,
+            responder,
+            uid,
+            nonce
+        ],
+        schedule = [status],
+        spawn = [switch_radio]
+    )]
+    fn status(mut cx: status::Context) {
+        static mut TICKS: u32 = 0;
+
+        let dropped = cx.resources.dropped.lock(|dropped| *dropped);
+
+        // `led_w` blinks while running, `led_s` lights up while samples are being dropped
+        cx.resources
+            .indicator
+            .tick(cx.resources.led_w, cx.resources.led_s, dropped);
+
+        cx.resources.watchdog.feed();
+
+        if let Some(fallback) = cx.resources.negotiation.tick() {
+            warn!(
+                RadioFallback,
+                "radio configuration not confirmed, falling back"
+            );
+
+            // Back on the default addresses the drone is unpaired, and has to pair again
+            if !pairing::is_paired(&fallback) {
+                if cx.resources.failsafe.disarm() {
+                    let hz = cx.resources.settings.sample_rate_hz;
+                    cx.resources
+                        .sample_timer
+                        .lock(|timer| timer.start(u32::from(hz).hz()));
+                    cx.resources.indicator.set_link_lost(false);
+                }
+
+                if let Some(responder) = cx.resources.responder {
+                    responder.end_session();
+                }
+                cx.resources
+                    .transmitter
+                    .lock(|transmitter| transmitter.end_session());
+                cx.resources.commands.end_session();
+            }
+
+            let _ = cx.spawn.switch_radio(fallback);
+        }
+
+        *TICKS = TICKS.wrapping_add(1);
+
+        let paired = cx
+            .resources
+            .radio
+            .lock(|radio| pairing::is_paired(radio.config()));
+        if !paired && !cx.resources.negotiation.is_pending() && *TICKS % ADVERTISE_TICKS == 0 {
+            let advert = Message::Advertise {
+                id: *cx.resources.uid,
+                nonce: *cx.resources.nonce,
+            };
+            let _ = cx.resources.samples.lock(|samples| samples.enqueue(advert));
+        }
+
+        if paired && *TICKS % HEARTBEAT_TICKS == 0 {
+            let _ = cx
+                .resources
+                .samples
+                .lock(|samples| samples.enqueue(Message::Heartbeat));
+        }
+
+        // Fewer samples are sent until a command arrives again, which restores the sample rate
+        if cx.resources.failsafe.tick() {
+            let hz = cx
+                .resources
+                .settings
+                .sample_rate_hz
+                .min(FAILSAFE_SAMPLE_RATE_HZ);
+            cx.resources
+                .sample_timer
+                .lock(|timer| timer.start(u32::from(hz).hz()));
+            cx.resources.indicator.set_link_lost(true);
+            warn!(LinkLost, "link lost, failsafe engaged");
+        }
+
+        // If this can't be scheduled the watchdog is no longer fed and resets the board
+        let period = *cx.resources.led_period;
+        let _ = cx.schedule.status(cx.scheduled + period.cycles());
+    }
+
+    // Interrupts that are not used by the hardware tasks, needed to dispatch software tasks
+    extern "C" {
+        fn UART4();
+        fn UART5();
+    }
+};
@@ -0,0 +1,90 @@
+//! Leveled logging, over RTT with the `rtt` feature and over the radio with `radio-log`
+//!
+//! A record is a `Code` with up to `MAX_ARGS` integers. Over RTT it is formatted by defmt on the
+//! host, with the level filtered at compile time by the `defmt-*` features. Records at or above
+//! `RADIO_LOG_LEVEL` are queued as `Message::Log`, which the drone sends before its samples. The
+//! relay has no radio link to the client, its records only go over RTT.
+use core::cell::RefCell;
+
+use cortex_m::interrupt::{self, Mutex};
+use heapless::{consts::U4, spsc::Queue};
+
+use portuni_common::{
+    log::{args, Code, Level},
+    protocol::Message,
+};
+
+#[cfg(feature = "rtt")]
+use defmt_rtt as _;
+
+/// Lowest level of the records forwarded over the radio
+const RADIO_LOG_LEVEL: Level = Level::Info;
+
+/// Records waiting to be sent, the ones that don't fit are dropped
+static RECORDS: Mutex<RefCell<Queue<Message, U4>>> =
+    Mutex::new(RefCell::new(Queue(heapless::i::Queue::new())));
+
+/// Log a record with defmt and queue it for the radio
+///
+/// The arguments are evaluated twice, so they should be plain values.
+macro_rules! record {
+    ($level:ident, $defmt:ident, $code:ident, $fmt:literal $(, $arg:expr)*) => {{
+        #[cfg(feature = "rtt")]
+        defmt::$defmt!($fmt $(, $arg)*);
+        $crate::log::forward(
+            portuni_common::log::Level::$level,
+            portuni_common::log::Code::$code,
+            &[$($arg as i32),*],
+        );
+    }};
+}
+
+macro_rules! trace {
+    ($code:ident, $($rest:tt)*) => { record!(Trace, trace, $code, $($rest)*) };
+}
+
+macro_rules! debug {
+    ($code:ident, $($rest:tt)*) => { record!(Debug, debug, $code, $($rest)*) };
+}
+
+macro_rules! info {
+    ($code:ident, $($rest:tt)*) => { record!(Info, info, $code, $($rest)*) };
+}
+
+macro_rules! warn {
+    ($code:ident, $($rest:tt)*) => { record!(Warn, warn, $code, $($rest)*) };
+}
+
+#[allow(unused_macros)]
+macro_rules! error {
+    ($code:ident, $($rest:tt)*) => { record!(Error, error, $code, $($rest)*) };
+}
+
+/// Queue a record for the radio, if the drone forwards records of its level
+pub fn forward(level: Level, code: Code, values: &[i32]) {
+    if !cfg!(all(feature = "tx", feature = "radio-log")) || level < RADIO_LOG_LEVEL {
+        return;
+    }
+
+    let record = Message::Log {
+        level,
+        code,
+        args: args(values),
+    };
+    interrupt::free(|cs| {
+        let _ = RECORDS.borrow(cs).borrow_mut().enqueue(record);
+    });
+}
+
+/// The oldest record that wasn't sent yet
+#[allow(dead_code)]
+pub fn next() -> Option<Message> {
+    interrupt::free(|cs| RECORDS.borrow(cs).borrow_mut().dequeue())
+}
+
+/// Records are timestamped with the cycle counter, which is started at boot
+#[cfg(feature = "rtt")]
+#[defmt::timestamp]
+fn timestamp() -> u64 {
+    u64::from(cortex_m::peripheral::DWT::get_cycle_count())
+}
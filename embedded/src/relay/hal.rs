@@ -0,0 +1,211 @@
+//! The relay's radio and serial port
+use embedded_nrf24l01::{Configuration, CrcMode, StandbyMode};
+
+use f3::hal::stm32f30x::USART1;
+
+use portuni_common::{protocol::DEFAULT_PIPE, radio::RadioConfig, relay::PIPES, Error};
+
+use crate::board::{data_rate, Nrf24Device, Nrf24Rx};
+
+/// Transmit power of the relay, from 0 (-18 dBm) to 3 (0 dBm). The drones are usually close by
+const RELAY_PA_LEVEL: u8 = 0;
+
+fn configure_relay(
+    radio: &mut StandbyMode<Nrf24Device>,
+    config: &RadioConfig,
+    addresses: &[Option<[u8; 5]>; PIPES],
+) -> Result<(), Error> {
+    radio
+        .set_frequency(config.channel)
+        .map_err(|_| Error::Radio)?;
+    radio.set_auto_retransmit(0, 0).map_err(|_| Error::Radio)?;
+    radio
+        .set_crc(Some(CrcMode::TwoBytes))
+        .map_err(|_| Error::Radio)?;
+    radio
+        .set_rf(data_rate(config.data_rate), RELAY_PA_LEVEL)
+        .map_err(|_| Error::Radio)?;
+    radio
+        .set_auto_ack(&[false; PIPES])
+        .map_err(|_| Error::Radio)?;
+
+    // The chip only takes the first byte of the address for pipes 2 to 5
+    let mut enabled = [false; PIPES];
+    for (pipe, address) in addresses.iter().enumerate() {
+        if let Some(address) = address {
+            radio.set_rx_addr(pipe, address).map_err(|_| Error::Radio)?;
+            enabled[pipe] = true;
+        }
+    }
+    radio
+        .set_pipes_rx_enable(&enabled)
+        .map_err(|_| Error::Radio)?;
+    radio
+        .set_pipes_rx_lengths(&[None; PIPES])
+        .map_err(|_| Error::Radio)?;
+
+    radio.flush_rx().map_err(|_| Error::Radio)?;
+    radio.flush_tx().map_err(|_| Error::Radio)
+}
+
+enum RelayState {
+    Rx(Nrf24Rx),
+    PoweredDown(Nrf24Device),
+}
+
+/// The nRF24L01+ of the relay, which listens on every pipe apart from sending uplinks
+pub struct RelayRadio {
+    state: Option<RelayState>,
+    // Channel and data rate, which are shared by all pipes
+    link: RadioConfig,
+    // Addresses the drones send to, by pipe
+    addresses: [Option<[u8; 5]>; PIPES],
+}
+
+impl RelayRadio {
+    /// Starts out listening for unpaired drones on `DEFAULT_PIPE`
+    pub fn new(device: Nrf24Device) -> Result<RelayRadio, Error> {
+        let link = RadioConfig::default();
+        let mut addresses = [None; PIPES];
+        addresses[usize::from(DEFAULT_PIPE)] = Some(link.tx_address);
+
+        let mut radio = RelayRadio {
+            state: Some(RelayState::PoweredDown(device)),
+            link,
+            addresses,
+        };
+
+        radio.reset()?;
+
+        Ok(radio)
+    }
+
+    /// Listen for the drone on `pipe` with the addresses of `config`, and move every pipe to its
+    /// channel and data rate
+    pub fn configure(&mut self, pipe: u8, config: RadioConfig) -> Result<(), Error> {
+        self.link = config;
+        self.addresses[usize::from(pipe)] = Some(config.tx_address);
+
+        self.reset()
+    }
+
+    /// Re-initialize the radio, which keeps its configuration
+    pub fn reset(&mut self) -> Result<(), Error> {
+        let device = match self.state.take() {
+            Some(RelayState::Rx(rx)) => rx.standby().power_down(),
+            Some(RelayState::PoweredDown(device)) => device,
+            None => return Err(Error::Radio),
+        };
+
+        let mut standby = match StandbyMode::power_up(device) {
+            Ok(standby) => standby,
+            Err((device, _)) => {
+                self.state = Some(RelayState::PoweredDown(device));
+                return Err(Error::Radio);
+            }
+        };
+
+        if configure_relay(&mut standby, &self.link, &self.addresses).is_err() {
+            self.state = Some(RelayState::PoweredDown(standby.power_down()));
+            return Err(Error::Radio);
+        }
+
+        self.listen(standby)
+    }
+
+    fn listen(&mut self, standby: StandbyMode<Nrf24Device>) -> Result<(), Error> {
+        match standby.rx() {
+            Ok(rx) => {
+                self.state = Some(RelayState::Rx(rx));
+                Ok(())
+            }
+            Err((device, _)) => {
+                self.state = Some(RelayState::PoweredDown(device));
+                Err(Error::Radio)
+            }
+        }
+    }
+
+    fn rx(&mut self) -> Result<&mut Nrf24Rx, Error> {
+        match &mut self.state {
+            Some(RelayState::Rx(rx)) => Ok(rx),
+            _ => Err(Error::Radio),
+        }
+    }
+
+    /// Clear the interrupt flags, which releases the IRQ line
+    pub fn clear_interrupts(&mut self) -> Result<(), Error> {
+        self.rx()?.clear_interrupts().map_err(|_| Error::Radio)
+    }
+
+    /// Copy a received payload into `buf`, returns its pipe, its length and whether the received
+    /// power detector was set
+    pub fn read(&mut self, buf: &mut [u8]) -> Result<Option<(u8, usize, bool)>, Error> {
+        let rx = self.rx()?;
+
+        let pipe = match rx.can_read().map_err(|_| Error::Radio)? {
+            Some(pipe) => pipe,
+            None => return Ok(None),
+        };
+
+        // Latched when the payload was received
+        let rpd = rx.has_carrier().map_err(|_| Error::Radio)?;
+        let payload = rx.read().map_err(|_| Error::Radio)?;
+        let len = payload.len().min(buf.len());
+        buf[..len].copy_from_slice(&payload[..len]);
+
+        Ok(Some((pipe, len, rpd)))
+    }
+
+    /// Send `payload` to `address` and return to listening once it is sent
+    pub fn send(&mut self, address: &[u8; 5], payload: &[u8]) -> Result<(), Error> {
+        let mut standby = match self.state.take() {
+            Some(RelayState::Rx(rx)) => rx.standby(),
+            Some(state) => {
+                self.state = Some(state);
+                return Err(Error::Radio);
+            }
+            None => return Err(Error::Radio),
+        };
+
+        if standby.set_tx_addr(address).is_err() {
+            return self.listen(standby).and(Err(Error::Radio));
+        }
+
+        let mut tx = match standby.tx() {
+            Ok(tx) => tx,
+            Err((device, _)) => {
+                self.state = Some(RelayState::PoweredDown(device));
+                return Err(Error::Radio);
+            }
+        };
+        let sent = tx.send(payload).map_err(|_| Error::Radio);
+
+        // Waits for the TX FIFO to be sent
+        match tx.standby() {
+            Ok(standby) => sent.and(self.listen(standby)),
+            Err((device, _)) => {
+                self.state = Some(RelayState::PoweredDown(device));
+                Err(Error::Radio)
+            }
+        }
+    }
+}
+
+/// Clear the error flags of USART1, which `Rx` only reports and would keep its interrupt pending
+#[allow(unsafe_code)]
+pub fn clear_serial_errors() {
+    // Only the error flags are written, which the receiver doesn't otherwise touch
+    unsafe {
+        (*USART1::ptr()).icr.write(|w| {
+            w.pecf()
+                .set_bit()
+                .fecf()
+                .set_bit()
+                .ncf()
+                .set_bit()
+                .orecf()
+                .set_bit()
+        })
+    };
+}
@@ -0,0 +1,239 @@
+//! Firmware of the relay between the client's serial port and the drones, built with the `rx`
+//! feature
+//!
+//! Every payload a drone sends is forwarded to the client as a `Relayed` frame. The client's
+//! `RelayFrame`s are handled by `portuni_common::relay`, which holds an uplink until its drone
+//! announces an RX window.
+mod hal;
+
+use embedded_hal::serial::{Read as _, Write as _};
+use rtic::cyccnt::U32Ext as _;
+
+use f3::hal::{
+    gpio::GpioExt,
+    prelude::*,
+    rcc::RccExt,
+    serial::{Event, Rx, Serial, Tx},
+    stm32f30x::{self, EXTI, USART1},
+};
+
+use heapless::{consts::U64, Vec};
+
+use portuni_common::{
+    pairing,
+    protocol::{encode, RelayFrame, Relayed, PAYLOAD_SIZE},
+    relay::{is_listening, Frames, Outgoing, Relay},
+};
+
+use crate::board::{self, Nrf24Pins};
+use crate::or_reset;
+use hal::RelayRadio;
+
+/// Baud rate of the serial port to the client
+const BAUD_RATE: u32 = 115_200;
+/// Time the drone takes to switch to receive mode after announcing an RX window
+const WINDOW_DELAY_US: u32 = 500;
+
+/// A COBS-encoded `Relayed`, which fits a full payload
+type Frame = Vec<u8, U64>;
+
+// Tasks, from the highest to the lowest priority:
+//
+// * `serial` runs on USART1 for every byte from the client, and spawns `frame` for every frame
+// * `radio` runs on the nRF24L01+ IRQ line and forwards the received payloads. When a drone
+//   announces an RX window, `uplink` is scheduled to send the uplink that is pending for it
+// * `frame` holds an uplink, or moves a pipe to another link right away
+// * `forward` writes a frame to the client
+#[rtic::app(device = f3::hal::stm32f30x, peripherals = true, monotonic = rtic::cyccnt::CYCCNT)]
+const APP: () = {
+    struct Resources {
+        radio: RelayRadio,
+        relay: Relay,
+        frames: Frames,
+        serial_rx: Rx<USART1>,
+        serial_tx: Tx<USART1>,
+        exti: EXTI,
+        window_delay: u32,
+    }
+
+    #[init]
+    fn init(cx: init::Context) -> init::LateResources {
+        // Cortex and device peripherals
+        let mut cp = cx.core;
+        let dp = cx.device;
+
+        // SYSCFG is needed to route the radio IRQ pin to EXTI
+        dp.RCC.apb2enr.modify(|_, w| w.syscfgen().set_bit());
+
+        let mut flash = dp.FLASH.constrain();
+        let mut rcc = dp.RCC.constrain();
+
+        let mut gpiob = dp.GPIOB.split(&mut rcc.ahb);
+        let mut gpioc = dp.GPIOC.split(&mut rcc.ahb);
+
+        let clocks = rcc.cfgr.freeze(&mut flash.acr);
+
+        // USART1 is the virtual COM port of the ST-LINK
+        let serial_tx = gpioc.pc4.into_af7(&mut gpioc.moder, &mut gpioc.afrl);
+        let serial_rx = gpioc.pc5.into_af7(&mut gpioc.moder, &mut gpioc.afrl);
+        let mut serial = Serial::usart1(
+            dp.USART1,
+            (serial_tx, serial_rx),
+            BAUD_RATE.bps(),
+            clocks,
+            &mut rcc.apb2,
+        );
+        serial.listen(Event::Rxne);
+        let (serial_tx, serial_rx) = serial.split();
+
+        let radio_pins = Nrf24Pins {
+            ce: gpiob.pb2,
+            csn: gpiob.pb0,
+            irq: gpiob.pb1,
+            sck: gpiob.pb13,
+            miso: gpiob.pb14,
+            mosi: gpiob.pb15,
+            moder: &mut gpiob.moder,
+            otyper: &mut gpiob.otyper,
+            pupdr: &mut gpiob.pupdr,
+            afrh: &mut gpiob.afrh,
+        };
+        let radio = or_reset(board::nrf24(radio_pins, dp.SPI2, clocks, &mut rcc.apb1));
+        let radio = or_reset(RelayRadio::new(radio));
+
+        board::listen_radio_irq(&dp.SYSCFG, &dp.EXTI);
+
+        // The cycle counter is the monotonic timer for scheduling, and timestamps the log records
+        board::start_cycle_counter(&mut cp.DCB, &mut cp.DWT);
+
+        info!(Boot, "relay boot");
+
+        init::LateResources {
+            radio,
+            relay: Relay::new(),
+            frames: Frames::new(),
+            serial_rx,
+            serial_tx,
+            exti: dp.EXTI,
+            window_delay: clocks.sysclk().0 / 1_000_000 * WINDOW_DELAY_US,
+        }
+    }
+
+    #[task(binds = USART1_EXTI25, priority = 3, resources = [serial_rx, frames], spawn = [frame])]
+    fn serial(cx: serial::Context) {
+        let frames = cx.resources.frames;
+
+        loop {
+            match cx.resources.serial_rx.read() {
+                Ok(byte) => {
+                    // Frames that don't fit in the task's queue are dropped, the client times
+                    // out on their commands
+                    if let Some(frame) = frames.push(byte) {
+                        let _ = cx.spawn.frame(frame);
+                    }
+                }
+                Err(nb::Error::WouldBlock) => break,
+                // The frame the lost bytes were part of doesn't decode
+                Err(nb::Error::Other(_)) => hal::clear_serial_errors(),
+            }
+        }
+    }
+
+    #[task(priority = 2, capacity = 4, resources = [radio, relay])]
+    fn frame(cx: frame::Context, frame: RelayFrame) {
+        let radio = cx.resources.radio;
+
+        if let Some((pipe, config)) = cx.resources.relay.frame(&frame) {
+            if radio.configure(pipe, config).is_err() {
+                warn!(RadioReset, "radio reset after configuring pipe {:u8}", pipe);
+                let _ = radio.reset();
+            }
+
+            let channel = config.channel;
+            let paired = pairing::is_paired(&config);
+            info!(
+                RadioSwitched,
+                "pipe {:u8} on channel {:u8}, paired: {:bool}", pipe, channel, paired
+            );
+
+            rtic::pend(stm32f30x::Interrupt::EXTI1);
+        }
+    }
+
+    #[task(
+        binds = EXTI1,
+        priority = 2,
+        resources = [radio, relay, exti, window_delay],
+        schedule = [uplink],
+        spawn = [forward]
+    )]
+    fn radio(cx: radio::Context) {
+        cx.resources.exti.pr1.write(|w| w.pr1().set_bit());
+
+        let radio = cx.resources.radio;
+        let relay = cx.resources.relay;
+        let _ = radio.clear_interrupts();
+
+        let mut buf = [0u8; PAYLOAD_SIZE];
+        loop {
+            let (pipe, len, rpd) = match radio.read(&mut buf) {
+                Ok(Some(received)) => received,
+                Ok(None) => break,
+                // The payloads in the RX FIFO are lost
+                Err(_) => {
+                    warn!(RadioReset, "radio reset after a failed read");
+                    let _ = radio.reset();
+                    break;
+                }
+            };
+            let payload = &buf[..len];
+
+            if is_listening(payload) {
+                if let Some(outgoing) = relay.window(pipe) {
+                    let delay = *cx.resources.window_delay;
+                    let _ = cx
+                        .schedule
+                        .uplink(cx.start + delay.cycles(), pipe, outgoing);
+                }
+                continue;
+            }
+
+            // Payloads that don't fit in the task's queue are lost, like a missed radio frame
+            let mut encoded = [0u8; 64];
+            let frame = encode(&Relayed { pipe, rpd, payload }, &mut encoded)
+                .ok()
+                .and_then(|frame| Frame::from_slice(frame).ok());
+            if let Some(frame) = frame {
+                let _ = cx.spawn.forward(frame);
+            }
+        }
+    }
+
+    #[task(priority = 2, resources = [radio])]
+    fn uplink(cx: uplink::Context, pipe: u8, outgoing: Outgoing) {
+        let radio = cx.resources.radio;
+
+        if radio.send(&outgoing.address, outgoing.payload()).is_err() {
+            warn!(RadioReset, "radio reset after a failed uplink");
+            let _ = radio.reset();
+        } else {
+            trace!(Uplink, "uplink on pipe {:u8}", pipe);
+        }
+
+        // Payloads that arrived while sending
+        rtic::pend(stm32f30x::Interrupt::EXTI1);
+    }
+
+    #[task(priority = 1, capacity = 8, resources = [serial_tx])]
+    fn forward(cx: forward::Context, frame: Frame) {
+        for &byte in frame.iter() {
+            let _ = nb::block!(cx.resources.serial_tx.write(byte));
+        }
+    }
+
+    // Interrupts that are not used by the hardware tasks, needed to dispatch software tasks
+    extern "C" {
+        fn UART4();
+        fn UART5();
+    }
+};